@@ -0,0 +1,97 @@
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// A rolling hash of the sequence of version IDs in a client's history, oldest first.
+///
+/// This is the 64-bit FNV-1a hash of the concatenated 16-byte (big-endian) version IDs, so it can
+/// be updated as each version is added, and a replica can compute the same hash from the versions
+/// it has seen. Two replicas with different hashes for the same latest version have diverged.
+///
+/// This is not a cryptographic hash, and only detects accidental divergence.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ChainHash(pub u64);
+
+impl ChainHash {
+    /// The hash of an empty history.
+    pub const EMPTY: ChainHash = ChainHash(FNV_OFFSET_BASIS);
+
+    /// Compute the hash of a history consisting of the given versions, oldest first.
+    pub fn of_versions(version_ids: impl IntoIterator<Item = Uuid>) -> ChainHash {
+        version_ids
+            .into_iter()
+            .fold(ChainHash::EMPTY, ChainHash::next)
+    }
+
+    /// Compute the hash of this history, extended with the given version.
+    pub fn next(self, version_id: Uuid) -> ChainHash {
+        ChainHash(version_id.as_bytes().iter().fold(self.0, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+        }))
+    }
+}
+
+/// A chain hash is displayed as 16 lower-case hexadecimal digits.
+impl fmt::Display for ChainHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for ChainHash {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u64::from_str_radix(s, 16).map(ChainHash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn empty() {
+        assert_eq!(ChainHash::of_versions([]), ChainHash::EMPTY);
+    }
+
+    #[test]
+    fn known_value() {
+        // FNV-1a of sixteen zero bytes
+        assert_eq!(
+            ChainHash::of_versions([Uuid::nil()]).to_string(),
+            "88201fb960ff6465"
+        );
+    }
+
+    #[test]
+    fn rolling() {
+        let version_ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+        let mut hash = ChainHash::EMPTY;
+        for (i, version_id) in version_ids.iter().enumerate() {
+            hash = hash.next(*version_id);
+            assert_eq!(hash, ChainHash::of_versions(version_ids[..=i].to_vec()));
+        }
+    }
+
+    #[test]
+    fn order_matters() {
+        let (v1, v2) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_ne!(
+            ChainHash::of_versions([v1, v2]),
+            ChainHash::of_versions([v2, v1])
+        );
+    }
+
+    #[test]
+    fn display_and_parse() {
+        let hash = ChainHash(0x0123456789abcdef);
+        assert_eq!(hash.to_string(), "0123456789abcdef");
+        assert_eq!("0123456789abcdef".parse::<ChainHash>().unwrap(), hash);
+        assert!("xyz".parse::<ChainHash>().is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::Context;
+use std::ops::RangeInclusive;
+
+/// The byte beginning a history segment compressed with zstd, followed by the zstd frame.
+const ZSTD_HEADER: u8 = b'z';
+
+/// The magic number beginning every zstd frame, in the order it appears in the data.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// The compression applied to history segments before they are written to storage.
+///
+/// Compression is transparent to clients: segments are decompressed as they are read, and
+/// segments written without compression, including those written before compression was
+/// enabled, are read unchanged. A compressed segment begins with a one-byte header identifying
+/// the codec, followed by the compressed data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Store history segments as given.
+    #[default]
+    None,
+
+    /// Compress history segments with zstd, unless that would make them larger.
+    Zstd,
+}
+
+impl Compression {
+    /// The compression levels supported by zstd, for [`crate::ServerConfig::compression_level`].
+    /// Higher levels compress better, but more slowly, and 0 selects zstd's default level.
+    pub fn levels() -> RangeInclusive<i32> {
+        zstd::compression_level_range()
+    }
+
+    /// Prepare a history segment for storage, compressing it at the given level, which must be
+    /// in [`Compression::levels`].
+    pub(crate) fn compress(self, level: i32, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        // zstd would silently clamp an unsupported level
+        anyhow::ensure!(
+            Compression::levels().contains(&level),
+            "Unsupported compression level {level}"
+        );
+        // Data which would be mistaken for a compressed segment must be compressed, whatever the
+        // configuration, so that it reads back unchanged.
+        let ambiguous = is_zstd(&data);
+        if self == Compression::None && !ambiguous {
+            return Ok(data);
+        }
+
+        let mut compressed = vec![ZSTD_HEADER];
+        zstd::stream::copy_encode(data.as_slice(), &mut compressed, level)
+            .context("Error compressing history segment")?;
+        if compressed.len() >= data.len() && !ambiguous {
+            return Ok(data);
+        }
+        Ok(compressed)
+    }
+
+    /// Recover a history segment read from storage, whatever compression it was written with.
+    pub(crate) fn decompress(data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        if !is_zstd(&data) {
+            return Ok(data);
+        }
+        zstd::stream::decode_all(&data[1..]).context("Error decompressing history segment")
+    }
+}
+
+/// Determine whether stored data is a zstd-compressed history segment.
+fn is_zstd(data: &[u8]) -> bool {
+    data.first() == Some(&ZSTD_HEADER) && data.get(1..5) == Some(&ZSTD_MAGIC[..])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// A history segment which compresses well.
+    fn compressible() -> Vec<u8> {
+        b"some operations ".repeat(100)
+    }
+
+    #[test]
+    fn none_round_trip() -> anyhow::Result<()> {
+        let data = compressible();
+        let stored = Compression::None.compress(0, data.clone())?;
+        assert_eq!(stored, data);
+        assert_eq!(Compression::decompress(stored)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn zstd_round_trip() -> anyhow::Result<()> {
+        let data = compressible();
+        let stored = Compression::Zstd.compress(0, data.clone())?;
+        assert_eq!(stored[0], ZSTD_HEADER);
+        assert!(stored.len() < data.len());
+        assert_eq!(Compression::decompress(stored)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn zstd_levels() -> anyhow::Result<()> {
+        // varied enough that a higher level finds more to compress
+        let data: Vec<u8> = (0..2000u32)
+            .flat_map(|i| format!("op {} on task {}; ", i % 7, (i * 37) % 101).into_bytes())
+            .collect();
+        let fast = Compression::Zstd.compress(1, data.clone())?;
+        let best = Compression::Zstd.compress(*Compression::levels().end(), data.clone())?;
+        assert!(best.len() < fast.len());
+        assert_eq!(Compression::decompress(fast)?, data);
+        assert_eq!(Compression::decompress(best)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn zstd_unsupported_level() {
+        let level = Compression::levels().end() + 1;
+        assert!(Compression::Zstd.compress(level, compressible()).is_err());
+    }
+
+    #[test]
+    fn zstd_incompressible() -> anyhow::Result<()> {
+        // too short to benefit from compression
+        let data = b"abcd".to_vec();
+        let stored = Compression::Zstd.compress(0, data.clone())?;
+        assert_eq!(stored, data);
+        assert_eq!(Compression::decompress(stored)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn empty() -> anyhow::Result<()> {
+        for compression in [Compression::None, Compression::Zstd] {
+            let stored = compression.compress(0, vec![])?;
+            assert_eq!(Compression::decompress(stored)?, Vec::<u8>::new());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_uncompressed() -> anyhow::Result<()> {
+        // a segment written before compression was supported, which happens to begin with the
+        // header byte
+        let data = b"zebra".to_vec();
+        assert_eq!(Compression::decompress(data.clone())?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn ambiguous_data() -> anyhow::Result<()> {
+        let mut data = vec![ZSTD_HEADER];
+        data.extend_from_slice(&ZSTD_MAGIC);
+        data.extend_from_slice(b"not really zstd");
+        for compression in [Compression::None, Compression::Zstd] {
+            let stored = compression.compress(0, data.clone())?;
+            assert_ne!(stored, data);
+            assert_eq!(Compression::decompress(stored)?, data);
+        }
+        Ok(())
+    }
+}
@@ -8,6 +8,14 @@ pub enum ServerError {
     #[error("No such client")]
     NoSuchClient,
 
+    /// A client with the given ClientId already exists.
+    #[error("Client already exists")]
+    ClientExists,
+
+    /// The client's versions do not form a single linear history.
+    #[error("Client has a branched version history")]
+    BranchedHistory,
+
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
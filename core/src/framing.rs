@@ -0,0 +1,207 @@
+use crate::{HistorySegment, VersionId};
+use uuid::Uuid;
+
+/// The content-type for a stream of versions in the framing defined here.
+pub const VERSION_STREAM_CONTENT_TYPE: &str = "application/vnd.taskchampion.version-stream";
+
+/// The bytes beginning every frame.
+const FRAME_MAGIC: [u8; 4] = *b"TCV1";
+
+/// The size of a frame without its history segment: the magic, the version ID, the parent
+/// version ID, and the length of the history segment.
+const FRAME_HEADER_LEN: usize = 4 + 16 + 16 + 4;
+
+/// A single version in a version stream.
+///
+/// A stream is a sequence of frames, each laid out as:
+///
+/// | bytes | content                                           |
+/// |-------|---------------------------------------------------|
+/// | 4     | the magic `TCV1`                                  |
+/// | 16    | the version ID                                    |
+/// | 16    | the parent version ID                             |
+/// | 4     | the length of the history segment, big-endian     |
+/// | *n*   | the history segment                               |
+///
+/// Version IDs are given as the 16 bytes of the UUID. Frames are self-delimiting, so a stream
+/// can be parsed incrementally as it arrives, with [`decode_frame`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionFrame {
+    pub version_id: VersionId,
+    pub parent_version_id: VersionId,
+    pub history_segment: HistorySegment,
+}
+
+/// An error decoding or encoding a version stream.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum FramingError {
+    /// A frame does not begin with the expected magic.
+    #[error("Version frame has bad magic")]
+    BadMagic,
+
+    /// The stream ends part way through a frame.
+    #[error("Version stream ends within a frame")]
+    Truncated,
+
+    /// A history segment is too large for its length to be encoded.
+    #[error("History segment is too large to frame")]
+    TooLarge,
+}
+
+/// Append the frame for a version to `out`.
+pub fn encode_frame(frame: &VersionFrame, out: &mut Vec<u8>) -> Result<(), FramingError> {
+    let len = u32::try_from(frame.history_segment.len()).map_err(|_| FramingError::TooLarge)?;
+    out.reserve(FRAME_HEADER_LEN + frame.history_segment.len());
+    out.extend_from_slice(&FRAME_MAGIC);
+    out.extend_from_slice(frame.version_id.as_bytes());
+    out.extend_from_slice(frame.parent_version_id.as_bytes());
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(&frame.history_segment);
+    Ok(())
+}
+
+/// Encode a sequence of versions as a version stream.
+pub fn encode_versions<'a>(
+    frames: impl IntoIterator<Item = &'a VersionFrame>,
+) -> Result<Vec<u8>, FramingError> {
+    let mut out = Vec::new();
+    for frame in frames {
+        encode_frame(frame, &mut out)?;
+    }
+    Ok(out)
+}
+
+/// Decode the frame at the beginning of `data`, returning the frame and the number of bytes it
+/// occupies, or `None` if `data` does not yet contain the whole frame.
+pub fn decode_frame(data: &[u8]) -> Result<Option<(VersionFrame, usize)>, FramingError> {
+    if data.len() < FRAME_MAGIC.len() {
+        return if FRAME_MAGIC.starts_with(data) {
+            Ok(None)
+        } else {
+            Err(FramingError::BadMagic)
+        };
+    }
+    if data[..4] != FRAME_MAGIC {
+        return Err(FramingError::BadMagic);
+    }
+    if data.len() < FRAME_HEADER_LEN {
+        return Ok(None);
+    }
+    let uuid_at = |at: usize| Uuid::from_slice(&data[at..at + 16]).expect("slice is 16 bytes");
+    let len = u32::from_be_bytes(data[36..40].try_into().expect("slice is 4 bytes")) as usize;
+    let Some(history_segment) = data.get(FRAME_HEADER_LEN..FRAME_HEADER_LEN + len) else {
+        return Ok(None);
+    };
+    let frame = VersionFrame {
+        version_id: uuid_at(4),
+        parent_version_id: uuid_at(20),
+        history_segment: history_segment.to_vec(),
+    };
+    Ok(Some((frame, FRAME_HEADER_LEN + len)))
+}
+
+/// Decode a complete version stream.
+pub fn decode_versions(mut data: &[u8]) -> Result<Vec<VersionFrame>, FramingError> {
+    let mut frames = Vec::new();
+    while !data.is_empty() {
+        let (frame, len) = decode_frame(data)?.ok_or(FramingError::Truncated)?;
+        frames.push(frame);
+        data = &data[len..];
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::NIL_VERSION_ID;
+    use pretty_assertions::assert_eq;
+
+    /// A chain of versions, each with a distinct history segment.
+    fn versions(count: usize) -> Vec<VersionFrame> {
+        let mut parent_version_id = NIL_VERSION_ID;
+        (0..count)
+            .map(|i| {
+                let version_id = Uuid::new_v4();
+                let frame = VersionFrame {
+                    version_id,
+                    parent_version_id,
+                    history_segment: vec![i as u8; i * 10],
+                };
+                parent_version_id = version_id;
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frame_layout() -> anyhow::Result<()> {
+        let frame = VersionFrame {
+            version_id: Uuid::from_u128(1),
+            parent_version_id: Uuid::from_u128(2),
+            history_segment: b"abc".to_vec(),
+        };
+        let mut out = Vec::new();
+        encode_frame(&frame, &mut out)?;
+        let mut expected = b"TCV1".to_vec();
+        expected.extend_from_slice(&1u128.to_be_bytes());
+        expected.extend_from_slice(&2u128.to_be_bytes());
+        expected.extend_from_slice(&[0, 0, 0, 3]);
+        expected.extend_from_slice(b"abc");
+        assert_eq!(out, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let versions = versions(5);
+        let stream = encode_versions(&versions)?;
+        assert_eq!(decode_versions(&stream)?, versions);
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_empty() -> anyhow::Result<()> {
+        let stream = encode_versions(&[])?;
+        assert!(stream.is_empty());
+        assert_eq!(decode_versions(&stream)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_incrementally() -> anyhow::Result<()> {
+        let versions = versions(3);
+        let stream = encode_versions(&versions)?;
+
+        // feed the stream a byte at a time, decoding each frame as soon as it is complete
+        let mut buffer = Vec::new();
+        let mut decoded = Vec::new();
+        for byte in stream {
+            buffer.push(byte);
+            if let Some((frame, len)) = decode_frame(&buffer)? {
+                decoded.push(frame);
+                buffer.drain(..len);
+            }
+        }
+        assert!(buffer.is_empty());
+        assert_eq!(decoded, versions);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_truncated() -> anyhow::Result<()> {
+        let stream = encode_versions(&versions(2))?;
+        assert_eq!(
+            decode_versions(&stream[..stream.len() - 1]),
+            Err(FramingError::Truncated)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn decode_bad_magic() {
+        assert_eq!(decode_frame(b"XCV1"), Err(FramingError::BadMagic));
+        assert_eq!(decode_frame(b"TX"), Err(FramingError::BadMagic));
+        assert_eq!(decode_frame(b"TC"), Ok(None));
+    }
+}
@@ -0,0 +1,26 @@
+use crate::server::ClientId;
+use crate::storage::{Snapshot, Version};
+
+/// A hook invoked by [`crate::Server`] after changes have been committed to storage.
+///
+/// Hooks are called synchronously, after the transaction has committed, so they only ever see
+/// data that is durably stored. They are not called for changes that are rejected or rolled back.
+///
+/// Errors returned from a hook are logged, but do not affect the result of the protocol
+/// transaction.
+pub trait CommitHook: Send + Sync {
+    /// Called after a new version has been committed.
+    fn on_version_committed(&self, _client_id: ClientId, _version: &Version) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called after a new snapshot has been committed.
+    fn on_snapshot_committed(
+        &self,
+        _client_id: ClientId,
+        _snapshot: &Snapshot,
+        _data: &[u8],
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
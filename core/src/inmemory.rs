@@ -1,5 +1,9 @@
-use super::{Client, Snapshot, Storage, StorageTxn, Version};
-use std::collections::HashMap;
+use super::{
+    BackendInfo, ChainHash, Client, ClientUsage, GlobalStats, ServerError, Snapshot, Storage,
+    StorageTxn, Version,
+};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Mutex, MutexGuard};
 use uuid::Uuid;
 
@@ -10,11 +14,85 @@ struct Inner {
     /// Snapshot data, indexed by client id
     snapshots: HashMap<Uuid, Vec<u8>>,
 
+    /// Application metadata, indexed by client id
+    app_metadata: HashMap<Uuid, Vec<u8>>,
+
     /// Versions, indexed by (client_id, version_id)
     versions: HashMap<(Uuid, Uuid), Version>,
 
     /// Child versions, indexed by (client_id, parent_version_id)
     children: HashMap<(Uuid, Uuid), Uuid>,
+
+    /// Recency of use of each client, if clients are evicted when there are too many
+    lru: Option<Lru>,
+}
+
+/// The order in which clients were last used, for least-recently-used eviction.
+struct Lru {
+    /// The largest number of clients retained
+    max_clients: usize,
+
+    /// The tick at which each client was last used
+    last_used: HashMap<Uuid, u64>,
+
+    /// Clients, indexed by the tick at which they were last used
+    by_tick: BTreeMap<u64, Uuid>,
+
+    /// The tick of the most recent use
+    tick: u64,
+}
+
+impl Lru {
+    /// Record a use of the given client.
+    fn touch(&mut self, client_id: Uuid) {
+        self.tick += 1;
+        if let Some(tick) = self.last_used.insert(client_id, self.tick) {
+            self.by_tick.remove(&tick);
+        }
+        self.by_tick.insert(self.tick, client_id);
+    }
+
+    /// Remove and return the least-recently used client.
+    fn pop(&mut self) -> Option<Uuid> {
+        let (_, client_id) = self.by_tick.pop_first()?;
+        self.last_used.remove(&client_id);
+        Some(client_id)
+    }
+}
+
+impl Inner {
+    /// Remove all data for the given client, returning false if it did not exist.
+    fn remove_client(&mut self, client_id: Uuid) -> bool {
+        if self.clients.remove(&client_id).is_none() {
+            return false;
+        }
+        self.snapshots.remove(&client_id);
+        self.app_metadata.remove(&client_id);
+        self.versions.retain(|(cid, _), _| *cid != client_id);
+        self.children.retain(|(cid, _), _| *cid != client_id);
+        true
+    }
+
+    /// Record a use of the given client and, if there are then too many clients, evict the
+    /// least-recently used.
+    fn touch(&mut self, client_id: Uuid) {
+        let Some(mut lru) = self.lru.take() else {
+            return;
+        };
+        if self.clients.contains_key(&client_id) {
+            lru.touch(client_id);
+        }
+        while self.clients.len() > lru.max_clients {
+            // entries for renamed or deleted clients are skipped
+            let Some(evicted) = lru.pop() else {
+                break;
+            };
+            if self.remove_client(evicted) {
+                log::debug!("evicted least-recently used client {evicted}");
+            }
+        }
+        self.lru = Some(lru);
+    }
 }
 
 /// In-memory storage for testing and experimentation.
@@ -31,10 +109,30 @@ impl InMemoryStorage {
         Self(Mutex::new(Inner {
             clients: HashMap::new(),
             snapshots: HashMap::new(),
+            app_metadata: HashMap::new(),
             versions: HashMap::new(),
             children: HashMap::new(),
+            lru: None,
         }))
     }
+
+    /// Create a new storage which retains at most `max_clients` clients, evicting the data of the
+    /// least-recently used client when that is exceeded. An evicted client reads as absent, as if
+    /// it had never synced. This is useful for long-running tests, such as fuzzing, where storage
+    /// would otherwise grow without bound.
+    ///
+    /// A client is used by each transaction for it, and evicted when another transaction is
+    /// committed.
+    pub fn with_lru(max_clients: usize) -> Self {
+        let storage = Self::new();
+        storage.0.lock().expect("poisoned lock").lru = Some(Lru {
+            max_clients,
+            last_used: HashMap::new(),
+            by_tick: BTreeMap::new(),
+            tick: 0,
+        });
+        storage
+    }
 }
 
 struct InnerTxn<'a> {
@@ -46,13 +144,61 @@ struct InnerTxn<'a> {
 
 impl Storage for InMemoryStorage {
     fn txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+        let mut guard = self.0.lock().expect("poisoned lock");
+        let inner = &mut *guard;
+        if let Some(lru) = &mut inner.lru {
+            if inner.clients.contains_key(&client_id) {
+                lru.touch(client_id);
+            }
+        }
         Ok(Box::new(InnerTxn {
             client_id,
-            guard: self.0.lock().expect("poisoned lock"),
+            guard,
             written: false,
             committed: false,
         }))
     }
+
+    /// In-memory transactions are serialized by a single lock, so a read-only transaction is no
+    /// different from any other.
+    fn read_txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+        self.txn(client_id)
+    }
+
+    fn global_stats(&self) -> anyhow::Result<GlobalStats> {
+        let inner = self.0.lock().expect("poisoned lock");
+        Ok(GlobalStats {
+            clients: inner.clients.len() as u64,
+            clients_with_snapshot: inner
+                .clients
+                .values()
+                .filter(|c| c.snapshot.is_some())
+                .count() as u64,
+            versions: inner.versions.len() as u64,
+        })
+    }
+
+    fn list_clients(&self) -> anyhow::Result<Vec<Uuid>> {
+        let inner = self.0.lock().expect("poisoned lock");
+        Ok(inner.clients.keys().copied().collect())
+    }
+
+    fn backend_info(&self) -> anyhow::Result<BackendInfo> {
+        Ok(BackendInfo {
+            name: "inmemory".into(),
+            ..BackendInfo::default()
+        })
+    }
+
+    /// In-memory storage is never shared between versions of the server, so its schema always
+    /// matches.
+    fn schema_version(&self) -> anyhow::Result<u32> {
+        Ok(self.required_schema_version())
+    }
+
+    fn required_schema_version(&self) -> u32 {
+        1
+    }
 }
 
 impl StorageTxn for InnerTxn<'_> {
@@ -62,29 +208,40 @@ impl StorageTxn for InnerTxn<'_> {
 
     fn new_client(&mut self, latest_version_id: Uuid) -> anyhow::Result<()> {
         if self.guard.clients.contains_key(&self.client_id) {
-            return Err(anyhow::anyhow!("Client {} already exists", self.client_id));
+            return Err(ServerError::ClientExists.into());
         }
         self.guard.clients.insert(
             self.client_id,
             Client {
                 latest_version_id,
                 snapshot: None,
+                last_activity_at: None,
+                chain_hash: None,
+                acked_version_id: None,
             },
         );
         self.written = true;
         Ok(())
     }
 
-    fn set_snapshot(&mut self, snapshot: Snapshot, data: Vec<u8>) -> anyhow::Result<()> {
+    fn set_snapshot(
+        &mut self,
+        snapshot: Snapshot,
+        data: Vec<u8>,
+        expected_previous_version_id: Option<Uuid>,
+    ) -> anyhow::Result<bool> {
         let client = self
             .guard
             .clients
             .get_mut(&self.client_id)
             .ok_or_else(|| anyhow::anyhow!("no such client"))?;
+        if client.snapshot.as_ref().map(|snap| snap.version_id) != expected_previous_version_id {
+            return Ok(false);
+        }
         client.snapshot = Some(snapshot);
         self.guard.snapshots.insert(self.client_id, data);
         self.written = true;
-        Ok(())
+        Ok(true)
     }
 
     fn get_snapshot_data(&mut self, version_id: Uuid) -> anyhow::Result<Option<Vec<u8>>> {
@@ -97,6 +254,20 @@ impl StorageTxn for InnerTxn<'_> {
         Ok(self.guard.snapshots.get(&self.client_id).cloned())
     }
 
+    fn get_snapshot_size(&mut self, version_id: Uuid) -> anyhow::Result<Option<u64>> {
+        // sanity check
+        let client = self.guard.clients.get(&self.client_id);
+        let client = client.ok_or_else(|| anyhow::anyhow!("no such client"))?;
+        if Some(&version_id) != client.snapshot.as_ref().map(|snap| &snap.version_id) {
+            return Err(anyhow::anyhow!("unexpected snapshot_version_id"));
+        }
+        Ok(self
+            .guard
+            .snapshots
+            .get(&self.client_id)
+            .map(|data| data.len() as u64))
+    }
+
     fn get_version_by_parent(
         &mut self,
         parent_version_id: Uuid,
@@ -124,6 +295,90 @@ impl StorageTxn for InnerTxn<'_> {
             .cloned())
     }
 
+    fn get_head_version_ids(&mut self) -> anyhow::Result<Vec<Uuid>> {
+        Ok(self
+            .guard
+            .versions
+            .keys()
+            .filter(|(client_id, version_id)| {
+                *client_id == self.client_id
+                    && !self.guard.children.contains_key(&(*client_id, *version_id))
+            })
+            .map(|(_, version_id)| *version_id)
+            .collect())
+    }
+
+    fn get_client_usage(&mut self) -> anyhow::Result<ClientUsage> {
+        let mut usage = ClientUsage::default();
+        for ((client_id, _), version) in &self.guard.versions {
+            if *client_id == self.client_id {
+                usage.version_count += 1;
+                usage.total_history_bytes += version.history_segment.len() as u64;
+            }
+        }
+        if let Some(data) = self.guard.snapshots.get(&self.client_id) {
+            usage.snapshot_bytes = data.len() as u64;
+        }
+        Ok(usage)
+    }
+
+    fn set_latest_version_id(&mut self, latest_version_id: Uuid) -> anyhow::Result<()> {
+        let client = self
+            .guard
+            .clients
+            .get_mut(&self.client_id)
+            .ok_or_else(|| anyhow::anyhow!("no such client"))?;
+        client.latest_version_id = latest_version_id;
+        self.written = true;
+        Ok(())
+    }
+
+    fn set_last_activity(&mut self, timestamp: DateTime<Utc>) -> anyhow::Result<()> {
+        let client = self
+            .guard
+            .clients
+            .get_mut(&self.client_id)
+            .ok_or_else(|| anyhow::anyhow!("no such client"))?;
+        client.last_activity_at = Some(timestamp);
+        self.written = true;
+        Ok(())
+    }
+
+    fn set_chain_hash(&mut self, chain_hash: Option<ChainHash>) -> anyhow::Result<()> {
+        let client = self
+            .guard
+            .clients
+            .get_mut(&self.client_id)
+            .ok_or_else(|| anyhow::anyhow!("no such client"))?;
+        client.chain_hash = chain_hash;
+        self.written = true;
+        Ok(())
+    }
+
+    fn set_acked_version_id(&mut self, acked_version_id: Option<Uuid>) -> anyhow::Result<()> {
+        let client = self
+            .guard
+            .clients
+            .get_mut(&self.client_id)
+            .ok_or_else(|| anyhow::anyhow!("no such client"))?;
+        client.acked_version_id = acked_version_id;
+        self.written = true;
+        Ok(())
+    }
+
+    fn get_app_metadata(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.guard.app_metadata.get(&self.client_id).cloned())
+    }
+
+    fn set_app_metadata(&mut self, app_metadata: Vec<u8>) -> anyhow::Result<()> {
+        if !self.guard.clients.contains_key(&self.client_id) {
+            anyhow::bail!("no such client");
+        }
+        self.guard.app_metadata.insert(self.client_id, app_metadata);
+        self.written = true;
+        Ok(())
+    }
+
     fn add_version(
         &mut self,
         version_id: Uuid,
@@ -139,7 +394,7 @@ impl StorageTxn for InnerTxn<'_> {
         if let Some(client) = self.guard.clients.get_mut(&self.client_id) {
             client.latest_version_id = version_id;
             if let Some(ref mut snap) = client.snapshot {
-                snap.versions_since += 1;
+                snap.versions_since = snap.versions_since.saturating_add(1);
             }
         } else {
             anyhow::bail!("Client {} does not exist", self.client_id);
@@ -174,7 +429,76 @@ impl StorageTxn for InnerTxn<'_> {
         Ok(())
     }
 
+    fn delete_versions_before(&mut self, version_id: Uuid) -> anyhow::Result<()> {
+        let Some(version) = self.guard.versions.get(&(self.client_id, version_id)) else {
+            return Ok(());
+        };
+        let mut vid = version.parent_version_id;
+        // The child index entry for the oldest retained version is kept, so that it can still be
+        // found by its parent's ID.
+        while let Some(version) = self.guard.versions.remove(&(self.client_id, vid)) {
+            self.guard
+                .children
+                .remove(&(self.client_id, version.parent_version_id));
+            self.written = true;
+            vid = version.parent_version_id;
+        }
+        Ok(())
+    }
+
+    fn rename_client(&mut self, new_client_id: Uuid) -> anyhow::Result<bool> {
+        let (old_client_id, inner) = (self.client_id, &mut *self.guard);
+        if inner.clients.contains_key(&new_client_id) {
+            return Ok(false);
+        }
+        let Some(client) = inner.clients.remove(&old_client_id) else {
+            anyhow::bail!("Client {} does not exist", old_client_id);
+        };
+        inner.clients.insert(new_client_id, client);
+        if let Some(data) = inner.snapshots.remove(&old_client_id) {
+            inner.snapshots.insert(new_client_id, data);
+        }
+        if let Some(data) = inner.app_metadata.remove(&old_client_id) {
+            inner.app_metadata.insert(new_client_id, data);
+        }
+        inner.versions = std::mem::take(&mut inner.versions)
+            .into_iter()
+            .map(|((client_id, version_id), version)| {
+                let client_id = if client_id == old_client_id {
+                    new_client_id
+                } else {
+                    client_id
+                };
+                ((client_id, version_id), version)
+            })
+            .collect();
+        inner.children = std::mem::take(&mut inner.children)
+            .into_iter()
+            .map(|((client_id, parent_version_id), version_id)| {
+                let client_id = if client_id == old_client_id {
+                    new_client_id
+                } else {
+                    client_id
+                };
+                ((client_id, parent_version_id), version_id)
+            })
+            .collect();
+
+        self.client_id = new_client_id;
+        self.written = true;
+        Ok(true)
+    }
+
+    fn delete_client(&mut self) -> anyhow::Result<()> {
+        if self.guard.remove_client(self.client_id) {
+            self.written = true;
+        }
+        Ok(())
+    }
+
     fn commit(&mut self) -> anyhow::Result<()> {
+        let client_id = self.client_id;
+        self.guard.touch(client_id);
         self.committed = true;
         Ok(())
     }
@@ -191,7 +515,180 @@ impl Drop for InnerTxn<'_> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use chrono::Utc;
+    use crate::NIL_VERSION_ID;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_global_stats() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        assert_eq!(storage.global_stats()?, GlobalStats::default());
+
+        for i in 0..3 {
+            let mut txn = storage.txn(Uuid::new_v4())?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![])?;
+            if i == 0 {
+                let snap = Snapshot {
+                    version_id: Uuid::new_v4(),
+                    timestamp: Utc::now(),
+                    versions_since: 0,
+                };
+                txn.set_snapshot(snap, vec![1], None)?;
+            }
+            txn.commit()?;
+        }
+
+        assert_eq!(
+            storage.global_stats()?,
+            GlobalStats {
+                clients: 3,
+                clients_with_snapshot: 1,
+                versions: 3,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lru_eviction() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::with_lru(2);
+        let client_ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let add_client = |client_id| -> anyhow::Result<()> {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![])?;
+            txn.set_app_metadata(b"app".to_vec())?;
+            txn.commit()
+        };
+
+        add_client(client_ids[0])?;
+        add_client(client_ids[1])?;
+        // use the first client, so that the second is the least-recently used
+        drop(storage.read_txn(client_ids[0])?);
+        add_client(client_ids[2])?;
+
+        let mut txn = storage.txn(client_ids[1])?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(txn.get_app_metadata()?, None);
+        drop(txn);
+        for client_id in [client_ids[0], client_ids[2]] {
+            let mut txn = storage.txn(client_id)?;
+            assert!(txn.get_client()?.is_some());
+        }
+        assert_eq!(
+            storage.global_stats()?,
+            GlobalStats {
+                clients: 2,
+                clients_with_snapshot: 0,
+                versions: 2,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lru_renamed_and_deleted() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::with_lru(2);
+        let client_ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        for client_id in &client_ids[..2] {
+            let mut txn = storage.txn(*client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.commit()?;
+        }
+
+        // rename the first client, making it the most-recently used
+        let mut txn = storage.txn(client_ids[0])?;
+        assert!(txn.rename_client(client_ids[2])?);
+        txn.commit()?;
+        drop(txn);
+
+        // adding another client evicts the second client, not the renamed one
+        let mut txn = storage.txn(client_ids[3])?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut listed = storage.list_clients()?;
+        listed.sort();
+        let mut expected = vec![client_ids[2], client_ids[3]];
+        expected.sort();
+        assert_eq!(listed, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_clients() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        assert!(storage.list_clients()?.is_empty());
+
+        let client_ids: HashSet<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for client_id in &client_ids {
+            let mut txn = storage.txn(*client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.commit()?;
+        }
+
+        let listed: HashSet<Uuid> = storage.list_clients()?.into_iter().collect();
+        assert_eq!(listed, client_ids);
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema_version() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        assert_eq!(storage.schema_version()?, storage.required_schema_version());
+        crate::check_schema_version(&storage)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_backend_info() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let info = storage.backend_info()?;
+        assert_eq!(info.name, "inmemory");
+        assert_eq!(info.schema_version, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_client_usage() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_client_usage()?, ClientUsage::default());
+
+        let (v1, v2, v3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        txn.add_version(v1, NIL_VERSION_ID, vec![1; 10])?;
+        txn.add_version(v2, v1, vec![2; 20])?;
+        txn.add_version(v3, v2, vec![3; 30])?;
+        let snap = Snapshot {
+            version_id: v3,
+            timestamp: Utc::now(),
+            versions_since: 0,
+        };
+        txn.set_snapshot(snap, vec![4; 5], None)?;
+        txn.commit()?;
+        drop(txn);
+
+        // another client's versions are not counted
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![5; 100])?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(
+            txn.get_client_usage()?,
+            ClientUsage {
+                version_count: 3,
+                total_history_bytes: 60,
+                snapshot_bytes: 5,
+            }
+        );
+        Ok(())
+    }
 
     #[test]
     fn test_get_client_empty() -> anyhow::Result<()> {
@@ -227,7 +724,7 @@ mod test {
             timestamp: Utc::now(),
             versions_since: 4,
         };
-        txn.set_snapshot(snap.clone(), vec![1, 2, 3])?;
+        txn.set_snapshot(snap.clone(), vec![1, 2, 3], None)?;
 
         let client = txn.get_client()?.unwrap();
         assert_eq!(client.latest_version_id, latest_version_id);
@@ -237,6 +734,36 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_get_client_with_latest_version() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        // no such client
+        assert_eq!(txn.get_client_with_latest_version()?, None);
+
+        // client without any versions
+        txn.new_client(NIL_VERSION_ID)?;
+        let (client, version) = txn.get_client_with_latest_version()?.unwrap();
+        assert_eq!(Some(client), txn.get_client()?);
+        assert_eq!(version, None);
+
+        // client with versions
+        let version_id_1 = Uuid::new_v4();
+        let version_id_2 = Uuid::new_v4();
+        txn.add_version(version_id_1, NIL_VERSION_ID, b"v1".to_vec())?;
+        txn.add_version(version_id_2, version_id_1, b"v2".to_vec())?;
+        let (client, version) = txn.get_client_with_latest_version()?.unwrap();
+        assert_eq!(client.latest_version_id, version_id_2);
+        assert_eq!(Some(client), txn.get_client()?);
+        assert_eq!(version, txn.get_version(version_id_2)?);
+        assert_eq!(version.unwrap().history_segment, b"v2".to_vec());
+
+        txn.commit()?;
+        Ok(())
+    }
+
     #[test]
     fn test_gvbp_empty() -> anyhow::Result<()> {
         let storage = InMemoryStorage::new();
@@ -295,6 +822,388 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_head_version_ids() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        assert!(txn.get_head_version_ids()?.is_empty());
+
+        let version_id_1 = Uuid::new_v4();
+        let version_id_2 = Uuid::new_v4();
+        txn.add_version(version_id_1, NIL_VERSION_ID, vec![])?;
+        txn.add_version(version_id_2, version_id_1, vec![])?;
+        assert_eq!(txn.get_head_version_ids()?, vec![version_id_2]);
+
+        // a disconnected version is another head
+        let version_id_3 = Uuid::new_v4();
+        txn.add_version(version_id_3, Uuid::new_v4(), vec![])?;
+        let mut heads = txn.get_head_version_ids()?;
+        heads.sort();
+        let mut expected = vec![version_id_2, version_id_3];
+        expected.sort();
+        assert_eq!(heads, expected);
+
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_latest_version_id() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        let snap = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: Utc::now(),
+            versions_since: 3,
+        };
+        txn.set_snapshot(snap.clone(), vec![1], None)?;
+
+        let latest_version_id = Uuid::new_v4();
+        txn.set_latest_version_id(latest_version_id)?;
+
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, latest_version_id);
+        assert_eq!(client.snapshot, Some(snap));
+
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_last_activity() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_client()?.unwrap().last_activity_at, None);
+
+        let timestamp = "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap();
+        txn.set_last_activity(timestamp)?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.last_activity_at, Some(timestamp));
+        assert_eq!(client.latest_version_id, NIL_VERSION_ID);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_chain_hash() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_client()?.unwrap().chain_hash, None);
+
+        let chain_hash = ChainHash::of_versions([Uuid::new_v4()]);
+        txn.set_chain_hash(Some(chain_hash))?;
+        assert_eq!(txn.get_client()?.unwrap().chain_hash, Some(chain_hash));
+
+        txn.set_chain_hash(None)?;
+        assert_eq!(txn.get_client()?.unwrap().chain_hash, None);
+
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_acked_version_id() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_client()?.unwrap().acked_version_id, None);
+
+        let version_id = Uuid::new_v4();
+        txn.set_acked_version_id(Some(version_id))?;
+        assert_eq!(
+            txn.get_client()?.unwrap().acked_version_id,
+            Some(version_id)
+        );
+
+        txn.set_acked_version_id(None)?;
+        assert_eq!(txn.get_client()?.unwrap().acked_version_id, None);
+
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_app_metadata() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        assert_eq!(txn.get_app_metadata()?, None);
+        assert!(txn.set_app_metadata(b"meta".to_vec()).is_err());
+
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_app_metadata()?, None);
+        txn.set_app_metadata(b"meta".to_vec())?;
+        assert_eq!(txn.get_app_metadata()?, Some(b"meta".to_vec()));
+        txn.set_app_metadata(vec![])?;
+        assert_eq!(txn.get_app_metadata()?, Some(vec![]));
+        txn.commit()?;
+        drop(txn);
+
+        // other clients are not affected
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        assert_eq!(txn.get_app_metadata()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_version_versions_since_saturates() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        let snap = Snapshot {
+            version_id: NIL_VERSION_ID,
+            timestamp: Utc::now(),
+            versions_since: u32::MAX - 1,
+        };
+        txn.set_snapshot(snap, vec![1], None)?;
+
+        let mut parent_version_id = NIL_VERSION_ID;
+        for expected in [u32::MAX, u32::MAX] {
+            let version_id = Uuid::new_v4();
+            txn.add_version(version_id, parent_version_id, vec![])?;
+            parent_version_id = version_id;
+            let client = txn.get_client()?.unwrap();
+            assert_eq!(client.snapshot.unwrap().versions_since, expected);
+        }
+
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_versions_before() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        let version_ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        let mut parent_version_id = NIL_VERSION_ID;
+        for version_id in &version_ids {
+            txn.add_version(*version_id, parent_version_id, b"abc".to_vec())?;
+            parent_version_id = *version_id;
+        }
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        // deleting before a nonexistent version does nothing
+        txn.delete_versions_before(Uuid::new_v4())?;
+        txn.delete_versions_before(version_ids[2])?;
+        assert_eq!(txn.get_version(version_ids[1])?, None);
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version(version_ids[0])?, None);
+        assert_eq!(txn.get_version(version_ids[1])?, None);
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, None);
+        for version_id in &version_ids[2..] {
+            assert!(txn.get_version(*version_id)?.is_some());
+        }
+        // the oldest remaining version can still be found from its (deleted) parent
+        assert_eq!(
+            txn.get_version_by_parent(version_ids[1])?
+                .map(|v| v.version_id),
+            Some(version_ids[2])
+        );
+        assert_eq!(txn.get_head_version_ids()?, vec![version_ids[3]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_client() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let other_client_id = Uuid::new_v4();
+        let new_client_id = Uuid::new_v4();
+        let version_id_1 = Uuid::new_v4();
+        let version_id_2 = Uuid::new_v4();
+        let snap = Snapshot {
+            version_id: version_id_1,
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 1,
+        };
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id_1, NIL_VERSION_ID, b"v1".to_vec())?;
+        txn.add_version(version_id_2, version_id_1, b"v2".to_vec())?;
+        txn.set_snapshot(snap.clone(), vec![1, 2, 3], None)?;
+        txn.set_app_metadata(b"meta".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(other_client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
+
+        // renaming onto an existing client fails
+        let mut txn = storage.txn(client_id)?;
+        assert!(!txn.rename_client(other_client_id)?);
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert!(txn.rename_client(new_client_id)?);
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(txn.get_version(version_id_1)?, None);
+        assert_eq!(txn.get_app_metadata()?, None);
+        drop(txn);
+
+        let mut txn = storage.txn(new_client_id)?;
+        assert_eq!(
+            txn.get_client()?,
+            Some(Client {
+                latest_version_id: version_id_2,
+                snapshot: Some(snap),
+                last_activity_at: None,
+                chain_hash: None,
+                acked_version_id: None,
+            })
+        );
+        assert_eq!(txn.get_snapshot_data(version_id_1)?, Some(vec![1, 2, 3]));
+        assert_eq!(txn.get_app_metadata()?, Some(b"meta".to_vec()));
+        assert_eq!(
+            txn.get_version_by_parent(NIL_VERSION_ID)?
+                .map(|v| v.history_segment),
+            Some(b"v1".to_vec())
+        );
+        assert_eq!(
+            txn.get_version(version_id_2)?.map(|v| v.parent_version_id),
+            Some(version_id_1)
+        );
+        assert_eq!(txn.get_head_version_ids()?, vec![version_id_2]);
+        drop(txn);
+
+        assert_eq!(storage.global_stats()?.clients, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_client() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let other_client_id = Uuid::new_v4();
+        let version_id_1 = Uuid::new_v4();
+        let version_id_2 = Uuid::new_v4();
+        let other_version_id = Uuid::new_v4();
+        let snap = Snapshot {
+            version_id: version_id_1,
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 1,
+        };
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id_1, NIL_VERSION_ID, b"v1".to_vec())?;
+        txn.add_version(version_id_2, version_id_1, b"v2".to_vec())?;
+        txn.set_snapshot(snap, vec![1, 2, 3], None)?;
+        txn.set_app_metadata(b"meta".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(other_client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(other_version_id, NIL_VERSION_ID, b"other".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_client()?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(txn.get_version(version_id_1)?, None);
+        assert_eq!(txn.get_version(version_id_2)?, None);
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, None);
+        assert_eq!(txn.get_app_metadata()?, None);
+        assert_eq!(txn.get_client_usage()?, ClientUsage::default());
+
+        // deleting a client which does not exist does nothing
+        txn.delete_client()?;
+        txn.commit()?;
+        drop(txn);
+
+        // the other client is unaffected
+        let mut txn = storage.txn(other_client_id)?;
+        assert_eq!(
+            txn.get_version(other_version_id)?
+                .map(|v| v.history_segment),
+            Some(b"other".to_vec())
+        );
+        drop(txn);
+        assert_eq!(storage.list_clients()?, vec![other_client_id]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_reader() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+
+        // a 10MB payload
+        let data: Vec<u8> = (0..10 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let version_id = Uuid::new_v4();
+        txn.add_version_from_reader(
+            version_id,
+            NIL_VERSION_ID,
+            data.len() as u64,
+            &mut data.as_slice(),
+        )?;
+        let snap = Snapshot {
+            version_id,
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 0,
+        };
+        assert!(txn.set_snapshot_from_reader(
+            snap,
+            data.len() as u64,
+            &mut data.as_slice(),
+            None
+        )?);
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        assert_eq!(txn.get_version(version_id)?.unwrap().history_segment, data);
+        assert_eq!(txn.get_snapshot_data(version_id)?, Some(data));
+
+        // a reader with fewer bytes than promised is an error
+        assert!(txn
+            .add_version_from_reader(Uuid::new_v4(), version_id, 10, &mut &b"abc"[..])
+            .is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_snapshots() -> anyhow::Result<()> {
         let storage = InMemoryStorage::new();
@@ -309,20 +1218,20 @@ mod test {
             timestamp: Utc::now(),
             versions_since: 3,
         };
-        txn.set_snapshot(snap.clone(), vec![9, 8, 9])?;
+        assert!(txn.set_snapshot(snap.clone(), vec![9, 8, 9], None)?);
 
         assert_eq!(
             txn.get_snapshot_data(snap.version_id)?.unwrap(),
             vec![9, 8, 9]
         );
-        assert_eq!(txn.get_client()?.unwrap().snapshot, Some(snap));
+        assert_eq!(txn.get_client()?.unwrap().snapshot, Some(snap.clone()));
 
         let snap2 = Snapshot {
             version_id: Uuid::new_v4(),
             timestamp: Utc::now(),
             versions_since: 10,
         };
-        txn.set_snapshot(snap2.clone(), vec![0, 2, 4, 6])?;
+        assert!(txn.set_snapshot(snap2.clone(), vec![0, 2, 4, 6], Some(snap.version_id))?);
 
         assert_eq!(
             txn.get_snapshot_data(snap2.version_id)?.unwrap(),
@@ -336,4 +1245,61 @@ mod test {
         txn.commit()?;
         Ok(())
     }
+
+    #[test]
+    fn test_snapshot_size() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(Uuid::new_v4())?;
+        let snap = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: Utc::now(),
+            versions_since: 3,
+        };
+        assert!(txn.set_snapshot(snap.clone(), vec![7; 1000], None)?);
+
+        assert_eq!(txn.get_snapshot_size(snap.version_id)?, Some(1000));
+
+        // check that mismatched version is detected
+        assert!(txn.get_snapshot_size(Uuid::new_v4()).is_err());
+
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_snapshot_precondition() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(Uuid::new_v4())?;
+
+        let snap = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: Utc::now(),
+            versions_since: 3,
+        };
+        // there is no existing snapshot, so this precondition fails
+        assert!(!txn.set_snapshot(snap.clone(), vec![1], Some(Uuid::new_v4()))?);
+        assert_eq!(txn.get_client()?.unwrap().snapshot, None);
+
+        assert!(txn.set_snapshot(snap.clone(), vec![1], None)?);
+
+        let snap2 = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: Utc::now(),
+            versions_since: 0,
+        };
+        // the existing snapshot is not for the expected version, so this precondition fails
+        assert!(!txn.set_snapshot(snap2.clone(), vec![2], None)?);
+        assert!(!txn.set_snapshot(snap2.clone(), vec![2], Some(Uuid::new_v4()))?);
+        assert_eq!(txn.get_client()?.unwrap().snapshot, Some(snap.clone()));
+        assert_eq!(txn.get_snapshot_data(snap.version_id)?.unwrap(), vec![1]);
+
+        txn.commit()?;
+        Ok(())
+    }
 }
@@ -12,12 +12,20 @@
 //! To use, create a new [`Server`] instance and call the relevant protocol API methods. The
 //! arguments and return values correspond closely to the protocol documentation.
 
+mod chain_hash;
+mod compression;
 mod error;
+mod framing;
+mod hook;
 mod inmemory;
 mod server;
 mod storage;
 
+pub use chain_hash::*;
+pub use compression::*;
 pub use error::*;
+pub use framing::*;
+pub use hook::*;
 pub use inmemory::*;
 pub use server::*;
 pub use storage::*;
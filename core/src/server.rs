@@ -1,16 +1,22 @@
+use crate::chain_hash::ChainHash;
+use crate::compression::Compression;
 use crate::error::ServerError;
-use crate::storage::{Snapshot, Storage, StorageTxn};
-use chrono::Utc;
+use crate::framing::VersionFrame;
+use crate::hook::CommitHook;
+use crate::storage::{
+    read_to_vec, BackendInfo, Client, ClientUsage, GlobalStats, Snapshot, Storage, StorageTxn,
+    TxnConflict, Version,
+};
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::io::Read;
 use uuid::Uuid;
 
-/// The distinguished value for "no version"
+/// The distinguished value for "no version". A client whose latest version is this value has no
+/// versions, and accepts a new version with any parent. All checks for "no version" should use
+/// this constant, rather than comparing to a particular UUID.
 pub const NIL_VERSION_ID: VersionId = Uuid::nil();
 
-/// Number of versions to search back from the latest to find the
-/// version for a newly-added snapshot.  Snapshots for versions older
-/// than this will be rejected.
-const SNAPSHOT_SEARCH_LEN: i32 = 5;
-
 pub type HistorySegment = Vec<u8>;
 pub type ClientId = Uuid;
 pub type VersionId = Uuid;
@@ -22,6 +28,43 @@ pub struct ServerConfig {
 
     /// Target number of versions between snapshots.
     pub snapshot_versions: u32,
+
+    /// Kind of UUID to generate for new versions.
+    pub version_id_kind: VersionIdKind,
+
+    /// Record client activity on reads (GetChildVersion, and GetSnapshot by GET or HEAD) as well
+    /// as writes. This makes every read a write in the storage backend.
+    pub record_read_activity: bool,
+
+    /// Maintain a [`ChainHash`] of each client's history as versions are added, so that replicas
+    /// can detect divergence. If this is disabled, any stored chain hashes are cleared as versions
+    /// are added, since they would otherwise become stale.
+    pub chain_hash: bool,
+
+    /// Maximum storage, in bytes, used by each client's versions and snapshot, as given by
+    /// [`StorageTxn::get_client_usage`]. Versions which would take a client over this limit are
+    /// rejected, but snapshots are always accepted, as they allow older versions to be deleted.
+    pub max_client_bytes: Option<u64>,
+
+    /// Compression applied to history segments before they are written to storage. Segments are
+    /// always decompressed when read, so this can be changed without affecting existing data.
+    pub compression: Compression,
+
+    /// Level at which history segments are compressed, in [`Compression::levels`]. The default,
+    /// 0, selects the codec's default level.
+    pub compression_level: i32,
+
+    /// Accept only snapshots for the client's latest version, rejecting snapshots for the recent
+    /// but not latest versions which are otherwise accepted.
+    pub snapshot_latest_only: bool,
+
+    /// Number of versions to search back from the latest to find the version for a newly-added
+    /// snapshot. Snapshots for versions older than this are rejected.
+    pub snapshot_search_len: u32,
+
+    /// Number of times [`Server::retry_conflicts`] repeats an operation whose transaction
+    /// conflicted with a concurrent transaction for the same client, before failing.
+    pub conflict_retries: u32,
 }
 
 impl Default for ServerConfig {
@@ -29,6 +72,56 @@ impl Default for ServerConfig {
         ServerConfig {
             snapshot_days: 14,
             snapshot_versions: 100,
+            version_id_kind: VersionIdKind::default(),
+            record_read_activity: false,
+            chain_hash: false,
+            max_client_bytes: None,
+            compression: Compression::None,
+            compression_level: 0,
+            snapshot_latest_only: false,
+            snapshot_search_len: 5,
+            conflict_retries: 3,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Check that the configuration is valid, such as that the compression level is one supported
+    /// by the codec. Call this before creating a [`Server`] with a configuration from the user.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let levels = Compression::levels();
+        if !levels.contains(&self.compression_level) {
+            anyhow::bail!(
+                "Compression level {} is not in the supported range {} to {}",
+                self.compression_level,
+                levels.start(),
+                levels.end()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The kind of UUID generated for new version IDs.
+///
+/// Version IDs are opaque to clients, so this only affects how they are stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VersionIdKind {
+    /// Random (version 4) UUIDs.
+    Random,
+
+    /// Time-ordered (version 7) UUIDs. These are inserted in roughly increasing order, giving
+    /// better locality in storage indexes.
+    #[default]
+    TimeOrdered,
+}
+
+impl VersionIdKind {
+    /// Generate a new version ID of this kind.
+    fn new_version_id(self) -> VersionId {
+        match self {
+            VersionIdKind::Random => Uuid::new_v4(),
+            VersionIdKind::TimeOrdered => Uuid::now_v7(),
         }
     }
 }
@@ -45,6 +138,18 @@ pub enum GetVersionResult {
     },
 }
 
+/// Response to get_child_versions.
+#[derive(Clone, PartialEq, Debug)]
+pub enum GetVersionsResult {
+    /// As for [`GetVersionResult::NotFound`]
+    NotFound,
+    /// As for [`GetVersionResult::Gone`]
+    Gone,
+    /// The consecutive versions beginning with the child of the requested parent version, in
+    /// order. This is never empty.
+    Success(Vec<VersionFrame>),
+}
+
 /// Response to add_version
 #[derive(Clone, PartialEq, Debug)]
 pub enum AddVersionResult {
@@ -52,6 +157,56 @@ pub enum AddVersionResult {
     Ok(VersionId),
     /// Rejected; expected a version with the given parent version
     ExpectedParentVersion(VersionId),
+    /// Rejected; the version would take the client over [`ServerConfig::max_client_bytes`]
+    QuotaExceeded,
+}
+
+/// Response to add_versions
+#[derive(Clone, PartialEq, Debug)]
+pub enum AddVersionsResult {
+    /// OK, versions added with the given IDs, in order
+    Ok(Vec<VersionId>),
+    /// Rejected; expected a version with the given parent version
+    ExpectedParentVersion(VersionId),
+    /// Rejected; the versions would take the client over [`ServerConfig::max_client_bytes`]
+    QuotaExceeded,
+}
+
+/// A point from which [`Server::backfill_into`] can resume: every client with an ID up to and
+/// including `last_client_id` has been copied.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BackfillCheckpoint {
+    /// The ID of the last client copied
+    pub last_client_id: ClientId,
+}
+
+/// A client, as listed by [`Server::list_clients`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ClientSummary {
+    pub client_id: ClientId,
+    pub client: Client,
+    pub usage: ClientUsage,
+}
+
+/// Aggregate statistics about the snapshots of all clients, as calculated by
+/// [`Server::snapshot_stats`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct SnapshotStats {
+    /// Timestamp of the oldest of the clients' latest snapshots, or `None` if no client has a
+    /// snapshot
+    pub oldest_snapshot_timestamp: Option<DateTime<Utc>>,
+    /// The largest number of versions since the latest snapshot, across all clients with a
+    /// snapshot
+    pub max_versions_since_snapshot: u32,
+}
+
+/// A client's use of its storage quota, as returned by [`Server::storage_quota_usage`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct QuotaUsage {
+    /// Bytes used by the client's versions and snapshot
+    pub used: u64,
+    /// The client's quota, from [`ServerConfig::max_client_bytes`]
+    pub limit: u64,
 }
 
 /// Urgency of a snapshot for a client; used to create the `X-Snapshot-Request` header.
@@ -81,7 +236,8 @@ impl SnapshotUrgency {
 
     /// Calculate the urgency for a snapshot based on its age in versions
     fn for_versions_since(config: &ServerConfig, versions_since: u32) -> Self {
-        if versions_since >= config.snapshot_versions * 3 / 2 {
+        // compute the threshold for high urgency in u64, to avoid overflow
+        if u64::from(versions_since) >= u64::from(config.snapshot_versions) * 3 / 2 {
             SnapshotUrgency::High
         } else if versions_since >= config.snapshot_versions {
             SnapshotUrgency::Low
@@ -95,6 +251,7 @@ impl SnapshotUrgency {
 pub struct Server {
     config: ServerConfig,
     storage: Box<dyn Storage>,
+    commit_hooks: Vec<Box<dyn CommitHook>>,
 }
 
 impl Server {
@@ -102,43 +259,129 @@ impl Server {
         Self {
             config,
             storage: Box::new(storage),
+            commit_hooks: Vec::new(),
         }
     }
 
-    /// Implementation of the GetChildVersion protocol transaction.
+    /// Add a hook to be called after each committed change.
+    pub fn add_commit_hook<H: CommitHook + 'static>(&mut self, hook: H) {
+        self.commit_hooks.push(Box::new(hook));
+    }
+
+    /// Implementation of the GetChildVersion protocol transaction. The snapshot urgency is
+    /// calculated from the client's current snapshot, as for [`Server::add_version`], so that
+    /// clients which only read are also asked for snapshots.
     pub fn get_child_version(
         &self,
         client_id: ClientId,
         parent_version_id: VersionId,
-    ) -> Result<GetVersionResult, ServerError> {
-        let mut txn = self.storage.txn(client_id)?;
-        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+    ) -> Result<(GetVersionResult, SnapshotUrgency), ServerError> {
+        let mut txn = self.read_txn(client_id)?;
+        let (client, latest_version) = txn
+            .get_client_with_latest_version()?
+            .ok_or(ServerError::NoSuchClient)?;
+        let urgency = self.snapshot_urgency(&client);
 
         // If a version with parentVersionId equal to the requested parentVersionId exists, it is
-        // returned.
-        if let Some(version) = txn.get_version_by_parent(parent_version_id)? {
-            return Ok(GetVersionResult::Success {
+        // returned. For a client only one version behind, that is the latest version, which
+        // saves looking it up by its parent.
+        let version = match latest_version {
+            Some(version) if version.parent_version_id == parent_version_id => Some(version),
+            _ => txn.get_version_by_parent(parent_version_id)?,
+        };
+        if let Some(version) = version {
+            self.record_read_activity(txn.as_mut())?;
+            return Ok((
+                GetVersionResult::Success {
+                    version_id: version.version_id,
+                    parent_version_id: version.parent_version_id,
+                    history_segment: Compression::decompress(version.history_segment)?,
+                },
+                urgency,
+            ));
+        }
+
+        self.record_read_activity(txn.as_mut())?;
+        let result = if child_not_found(&client, parent_version_id) {
+            GetVersionResult::NotFound
+        } else {
+            GetVersionResult::Gone
+        };
+        Ok((result, urgency))
+    }
+
+    /// Get the version with the given ID, or `None` if the client has no such version. Unlike
+    /// [`Server::get_child_version`], this is not part of the sync protocol.
+    pub fn get_version(
+        &self,
+        client_id: ClientId,
+        version_id: VersionId,
+    ) -> Result<Option<Version>, ServerError> {
+        let mut txn = self.read_txn(client_id)?;
+        txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+        let version = txn.get_version(version_id)?;
+        self.record_read_activity(txn.as_mut())?;
+        let Some(version) = version else {
+            return Ok(None);
+        };
+        Ok(Some(Version {
+            history_segment: Compression::decompress(version.history_segment)?,
+            ..version
+        }))
+    }
+
+    /// Get the client's latest version, or `None` if it has no versions. Like
+    /// [`Server::get_version`], this is not part of the sync protocol.
+    pub fn get_latest_version(&self, client_id: ClientId) -> Result<Option<Version>, ServerError> {
+        let mut txn = self.read_txn(client_id)?;
+        let (_, version) = txn
+            .get_client_with_latest_version()?
+            .ok_or(ServerError::NoSuchClient)?;
+        self.record_read_activity(txn.as_mut())?;
+        let Some(version) = version else {
+            return Ok(None);
+        };
+        Ok(Some(Version {
+            history_segment: Compression::decompress(version.history_segment)?,
+            ..version
+        }))
+    }
+
+    /// Get up to `limit` consecutive versions in a single transaction, beginning with the child
+    /// of the given parent version, so that a client far behind can catch up with fewer
+    /// requests. If the parent version has no child, the result is as for
+    /// [`Server::get_child_version`].
+    pub fn get_child_versions(
+        &self,
+        client_id: ClientId,
+        parent_version_id: VersionId,
+        limit: usize,
+    ) -> Result<GetVersionsResult, ServerError> {
+        let mut txn = self.read_txn(client_id)?;
+        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+
+        let mut versions = Vec::new();
+        let mut next_parent_version_id = parent_version_id;
+        while versions.len() < limit {
+            let Some(version) = txn.get_version_by_parent(next_parent_version_id)? else {
+                break;
+            };
+            next_parent_version_id = version.version_id;
+            versions.push(VersionFrame {
                 version_id: version.version_id,
                 parent_version_id: version.parent_version_id,
-                history_segment: version.history_segment,
+                history_segment: Compression::decompress(version.history_segment)?,
             });
         }
 
-        // Return NotFound if an AddVersion with this parent_version_id would succeed, and
-        // otherwise return Gone.
-        //
-        // AddVersion will succeed if either
-        //  - the requested parent version is the latest version; or
-        //  - there is no latest version, meaning there are no versions stored for this client
-        Ok(
-            if client.latest_version_id == parent_version_id
-                || client.latest_version_id == NIL_VERSION_ID
-            {
-                GetVersionResult::NotFound
-            } else {
-                GetVersionResult::Gone
-            },
-        )
+        self.record_read_activity(txn.as_mut())?;
+        Ok(if !versions.is_empty() {
+            GetVersionsResult::Success(versions)
+        } else if child_not_found(&client, parent_version_id) {
+            GetVersionsResult::NotFound
+        } else {
+            GetVersionsResult::Gone
+        })
     }
 
     /// Implementation of the AddVersion protocol transaction
@@ -150,703 +393,3572 @@ impl Server {
     ) -> Result<(AddVersionResult, SnapshotUrgency), ServerError> {
         log::debug!("add_version(client_id: {client_id}, parent_version_id: {parent_version_id})");
 
+        // retain a copy of the history segment for the commit hooks, if there are any
+        let hook_segment = (!self.commit_hooks.is_empty()).then(|| history_segment.clone());
+
         let mut txn = self.storage.txn(client_id)?;
+        let result = self.add_version_in_txn(txn.as_mut(), parent_version_id, history_segment)?;
+        let (AddVersionResult::Ok(version_id), _) = result else {
+            return Ok(result);
+        };
+        txn.commit()?;
+        drop(txn);
+
+        if let Some(history_segment) = hook_segment {
+            let version = Version {
+                version_id,
+                parent_version_id,
+                history_segment,
+            };
+            self.version_committed(client_id, &version);
+        }
+        Ok(result)
+    }
+
+    /// Implementation of the AddVersion protocol transaction, in a transaction supplied by the
+    /// caller, for the client of that transaction.
+    ///
+    /// This does not commit the transaction, so that an application embedding the server can
+    /// make other changes in the same transaction, and commit or abandon them all together. As
+    /// the server cannot know whether the version is committed, commit hooks are not called.
+    pub fn add_version_in_txn(
+        &self,
+        txn: &mut dyn StorageTxn,
+        parent_version_id: VersionId,
+        history_segment: HistorySegment,
+    ) -> Result<(AddVersionResult, SnapshotUrgency), ServerError> {
         let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
 
         // check if this version is acceptable, under the protection of the transaction
-        if client.latest_version_id != NIL_VERSION_ID
-            && parent_version_id != client.latest_version_id
-        {
-            log::debug!("add_version request rejected: mismatched latest_version_id");
-            return Ok((
-                AddVersionResult::ExpectedParentVersion(client.latest_version_id),
-                SnapshotUrgency::None,
-            ));
+        if let Some(rejected) = check_parent_version(&client, parent_version_id) {
+            return Ok(rejected);
+        }
+        if !self.within_quota(txn, history_segment.len() as u64)? {
+            return Ok((AddVersionResult::QuotaExceeded, SnapshotUrgency::None));
         }
 
         // invent a version ID
-        let version_id = Uuid::new_v4();
+        let version_id = self.config.version_id_kind.new_version_id();
         log::debug!("add_version request accepted: new version_id: {version_id}");
 
         // update the DB
+        let history_segment = self
+            .config
+            .compression
+            .compress(self.config.compression_level, history_segment)?;
         txn.add_version(version_id, parent_version_id, history_segment)?;
-        txn.commit()?;
+        self.update_chain_hash(txn, &client, &[version_id])?;
+        txn.set_last_activity(Utc::now())?;
 
-        // calculate the urgency
-        let time_urgency = match client.snapshot {
-            None => SnapshotUrgency::High,
-            Some(Snapshot { timestamp, .. }) => {
-                SnapshotUrgency::for_days(&self.config, (Utc::now() - timestamp).num_days())
-            }
-        };
+        Ok((
+            AddVersionResult::Ok(version_id),
+            self.snapshot_urgency(&client),
+        ))
+    }
 
-        let version_urgency = match client.snapshot {
-            None => SnapshotUrgency::High,
-            Some(Snapshot { versions_since, .. }) => {
-                SnapshotUrgency::for_versions_since(&self.config, versions_since)
-            }
-        };
+    /// Implementation of the AddVersion protocol transaction, reading `size` bytes of history
+    /// segment from `history_segment`.
+    ///
+    /// This avoids holding a copy of the history segment in memory, if the storage backend can
+    /// write it incrementally. The history segment is only read if the version is accepted. If
+    /// there are commit hooks, which require the entire history segment, or history segments are
+    /// compressed, this reads it into memory and calls [`Server::add_version`].
+    pub fn add_version_streaming(
+        &self,
+        client_id: ClientId,
+        parent_version_id: VersionId,
+        size: u64,
+        mut history_segment: impl Read,
+    ) -> Result<(AddVersionResult, SnapshotUrgency), ServerError> {
+        if !self.commit_hooks.is_empty() || self.config.compression != Compression::None {
+            let history_segment = read_to_vec(&mut history_segment, size)?;
+            return self.add_version(client_id, parent_version_id, history_segment);
+        }
+
+        log::debug!("add_version_streaming(client_id: {client_id}, parent_version_id: {parent_version_id}, size: {size})");
+
+        let mut txn = self.storage.txn(client_id)?;
+        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+
+        // check if this version is acceptable, under the protection of the transaction
+        if let Some(rejected) = check_parent_version(&client, parent_version_id) {
+            return Ok(rejected);
+        }
+        if !self.within_quota(txn.as_mut(), size)? {
+            return Ok((AddVersionResult::QuotaExceeded, SnapshotUrgency::None));
+        }
+
+        // invent a version ID
+        let version_id = self.config.version_id_kind.new_version_id();
+        log::debug!("add_version request accepted: new version_id: {version_id}");
+
+        // update the DB
+        txn.add_version_from_reader(version_id, parent_version_id, size, &mut history_segment)?;
+        self.update_chain_hash(txn.as_mut(), &client, &[version_id])?;
+        txn.set_last_activity(Utc::now())?;
+        txn.commit()?;
 
         Ok((
             AddVersionResult::Ok(version_id),
-            std::cmp::max(time_urgency, version_urgency),
+            self.snapshot_urgency(&client),
         ))
     }
 
-    /// Implementation of the AddSnapshot protocol transaction
-    pub fn add_snapshot(
+    /// Add a batch of versions, each a child of the previous one, in a single transaction.
+    ///
+    /// This is equivalent to a sequence of AddVersion protocol transactions, except that either
+    /// all versions are added or none are. The snapshot urgency is calculated once, from the state
+    /// of the client after all versions are added.
+    pub fn add_versions(
         &self,
         client_id: ClientId,
-        version_id: VersionId,
-        data: Vec<u8>,
-    ) -> Result<(), ServerError> {
-        log::debug!("add_snapshot(client_id: {client_id}, version_id: {version_id})");
+        parent_version_id: VersionId,
+        history_segments: Vec<HistorySegment>,
+    ) -> Result<(AddVersionsResult, SnapshotUrgency), ServerError> {
+        log::debug!(
+            "add_versions(client_id: {client_id}, parent_version_id: {parent_version_id}, {} versions)",
+            history_segments.len()
+        );
 
         let mut txn = self.storage.txn(client_id)?;
         let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
 
-        // NOTE: if the snapshot is rejected, this function logs about it and returns
-        // Ok(()), as there's no reason to report an errot to the client / user.
-
-        let last_snapshot = client.snapshot.map(|snap| snap.version_id);
-        if Some(version_id) == last_snapshot {
-            log::debug!("rejecting snapshot for version {version_id}: already exists");
-            return Ok(());
+        // check if this batch is acceptable, under the protection of the transaction
+        if client.latest_version_id != NIL_VERSION_ID
+            && parent_version_id != client.latest_version_id
+        {
+            log::debug!("add_versions request rejected: mismatched latest_version_id");
+            return Ok((
+                AddVersionsResult::ExpectedParentVersion(client.latest_version_id),
+                SnapshotUrgency::None,
+            ));
+        }
+        let size = history_segments.iter().map(|seg| seg.len() as u64).sum();
+        if !self.within_quota(txn.as_mut(), size)? {
+            return Ok((AddVersionsResult::QuotaExceeded, SnapshotUrgency::None));
         }
 
-        // look for this version in the history of this client, starting at the latest version, and
-        // only iterating for a limited number of versions.
-        let mut search_len = SNAPSHOT_SEARCH_LEN;
-        let mut vid = client.latest_version_id;
+        let mut version_ids = Vec::with_capacity(history_segments.len());
+        let mut versions = Vec::new();
+        let mut parent_version_id = parent_version_id;
+        for history_segment in history_segments {
+            // invent a version ID
+            let version_id = self.config.version_id_kind.new_version_id();
 
-        loop {
-            if vid == version_id && version_id != NIL_VERSION_ID {
-                // the new snapshot is for a recent version, so proceed
-                break;
+            // retain a copy of the version for the commit hooks, if there are any
+            if !self.commit_hooks.is_empty() {
+                versions.push(Version {
+                    version_id,
+                    parent_version_id,
+                    history_segment: history_segment.clone(),
+                });
             }
 
-            if Some(vid) == last_snapshot {
-                // the new snapshot is older than the last snapshot, so ignore it
-                log::debug!("rejecting snapshot for version {version_id}: newer snapshot already exists or no such version");
-                return Ok(());
-            }
+            let history_segment = self
+                .config
+                .compression
+                .compress(self.config.compression_level, history_segment)?;
+            txn.add_version(version_id, parent_version_id, history_segment)?;
+            version_ids.push(version_id);
+            parent_version_id = version_id;
+        }
+        log::debug!("add_versions request accepted: new version_ids: {version_ids:?}");
+        self.update_chain_hash(txn.as_mut(), &client, &version_ids)?;
 
-            search_len -= 1;
-            if search_len <= 0 || vid == NIL_VERSION_ID {
-                // this should not happen in normal operation, so warn about it
-                log::warn!("rejecting snapshot for version {version_id}: version is too old or no such version");
-                return Ok(());
-            }
+        // calculate the urgency from the client state after the whole batch
+        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+        let urgency = self.snapshot_urgency(&client);
 
-            // get the parent version ID
-            if let Some(parent) = txn.get_version(vid)? {
-                vid = parent.parent_version_id;
-            } else {
-                // this version does not exist; "this should not happen" but if it does,
-                // we don't need a snapshot earlier than the missing version.
-                log::warn!("rejecting snapshot for version {version_id}: newer versions have already been deleted");
-                return Ok(());
-            }
+        txn.set_last_activity(Utc::now())?;
+        txn.commit()?;
+        drop(txn);
+
+        for version in &versions {
+            self.version_committed(client_id, version);
         }
 
-        log::debug!("accepting snapshot for version {version_id}");
-        txn.set_snapshot(
-            Snapshot {
-                version_id,
-                timestamp: Utc::now(),
-                versions_since: 0,
-            },
-            data,
-        )?;
-        txn.commit()?;
-        Ok(())
+        Ok((AddVersionsResult::Ok(version_ids), urgency))
     }
 
-    /// Implementation of the GetSnapshot protocol transaction
-    pub fn get_snapshot(
+    /// Add a version and a snapshot for that version, in a single transaction.
+    ///
+    /// This is equivalent to an AddVersion protocol transaction followed by an AddSnapshot for the
+    /// new version, except that either both are stored or neither is, so there is no time at
+    /// which the snapshot is older than the latest version. If the version is rejected, the
+    /// snapshot is not stored.
+    pub fn add_version_and_snapshot(
         &self,
         client_id: ClientId,
-    ) -> Result<Option<(Uuid, Vec<u8>)>, ServerError> {
+        parent_version_id: VersionId,
+        history_segment: HistorySegment,
+        snapshot_data: Vec<u8>,
+    ) -> Result<AddVersionResult, ServerError> {
+        log::debug!("add_version_and_snapshot(client_id: {client_id}, parent_version_id: {parent_version_id})");
+
         let mut txn = self.storage.txn(client_id)?;
         let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
 
-        Ok(if let Some(snap) = client.snapshot {
-            txn.get_snapshot_data(snap.version_id)?
-                .map(|data| (snap.version_id, data))
+        // check if this version is acceptable, under the protection of the transaction
+        if let Some((rejected, _)) = check_parent_version(&client, parent_version_id) {
+            return Ok(rejected);
+        }
+        if !self.within_quota(txn.as_mut(), history_segment.len() as u64)? {
+            return Ok(AddVersionResult::QuotaExceeded);
+        }
+
+        // invent a version ID
+        let version_id = self.config.version_id_kind.new_version_id();
+        log::debug!("add_version_and_snapshot request accepted: new version_id: {version_id}");
+
+        // retain copies of the version and snapshot for the commit hooks, if there are any
+        let hook_data = (!self.commit_hooks.is_empty()).then(|| {
+            let version = Version {
+                version_id,
+                parent_version_id,
+                history_segment: history_segment.clone(),
+            };
+            (version, snapshot_data.clone())
+        });
+
+        // update the DB
+        let history_segment = self
+            .config
+            .compression
+            .compress(self.config.compression_level, history_segment)?;
+        txn.add_version(version_id, parent_version_id, history_segment)?;
+        self.update_chain_hash(txn.as_mut(), &client, &[version_id])?;
+        let snapshot = Snapshot {
+            version_id,
+            timestamp: Utc::now(),
+            versions_since: 0,
+        };
+        let last_snapshot = client.snapshot.map(|snap| snap.version_id);
+        if !txn.set_snapshot(snapshot.clone(), snapshot_data, last_snapshot)? {
+            // the snapshot changed concurrently, and the version has been added, so the
+            // transaction must not be committed; a retry sees the concurrent change
+            return Err(anyhow::Error::new(TxnConflict(client_id)).into());
+        }
+        txn.delete_versions_before(version_id)?;
+        txn.set_last_activity(Utc::now())?;
+        txn.commit()?;
+        drop(txn);
+
+        if let Some((version, snapshot_data)) = hook_data {
+            self.version_committed(client_id, &version);
+            self.snapshot_committed(client_id, &snapshot, &snapshot_data);
+        }
+
+        Ok(AddVersionResult::Ok(version_id))
+    }
+
+    /// Check whether adding `size` bytes of history segments would keep the client within
+    /// [`ServerConfig::max_client_bytes`], under the protection of the transaction.
+    fn within_quota(&self, txn: &mut dyn StorageTxn, size: u64) -> Result<bool, ServerError> {
+        let Some(max_client_bytes) = self.config.max_client_bytes else {
+            return Ok(true);
+        };
+        let usage = txn.get_client_usage()?;
+        let total = usage.total_history_bytes + usage.snapshot_bytes + size;
+        if total > max_client_bytes {
+            log::debug!("add_version request rejected: {total} bytes exceeds the client quota");
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Update the client's chain hash for newly-added versions, given the state of the client
+    /// before they were added.
+    fn update_chain_hash(
+        &self,
+        txn: &mut dyn StorageTxn,
+        client: &Client,
+        version_ids: &[VersionId],
+    ) -> Result<(), ServerError> {
+        let chain_hash = if self.config.chain_hash {
+            // A client without versions has an empty history. Otherwise, if the client has no
+            // chain hash, its history before the hash was tracked is unknown.
+            let chain_hash = if client.latest_version_id == NIL_VERSION_ID {
+                Some(ChainHash::EMPTY)
+            } else {
+                client.chain_hash
+            };
+            chain_hash.map(|hash| version_ids.iter().copied().fold(hash, ChainHash::next))
         } else {
             None
-        })
+        };
+        if chain_hash != client.chain_hash {
+            txn.set_chain_hash(chain_hash)?;
+        }
+        Ok(())
     }
 
-    /// Convenience method to get a transaction for the embedded storage.
-    pub fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, ServerError> {
-        Ok(self.storage.txn(client_id)?)
+    /// Begin a transaction for a read, which is read-only unless read activity is recorded.
+    fn read_txn(&self, client_id: ClientId) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+        if self.config.record_read_activity {
+            self.storage.txn(client_id)
+        } else {
+            self.storage.read_txn(client_id)
+        }
     }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::inmemory::InMemoryStorage;
-    use crate::storage::{Snapshot, Storage, StorageTxn};
-    use chrono::{Duration, TimeZone, Utc};
-    use pretty_assertions::assert_eq;
 
-    fn setup<INIT, RES>(init: INIT) -> anyhow::Result<(Server, RES)>
-    where
-        INIT: FnOnce(&mut dyn StorageTxn, Uuid) -> anyhow::Result<RES>,
-    {
-        let _ = env_logger::builder().is_test(true).try_init();
-        let storage = InMemoryStorage::new();
-        let client_id = Uuid::new_v4();
-        let res;
-        {
-            let mut txn = storage.txn(client_id)?;
-            res = init(txn.as_mut(), client_id)?;
+    /// Record the client's activity in a read-only transaction, and commit it, if so configured.
+    fn record_read_activity(&self, txn: &mut dyn StorageTxn) -> Result<(), ServerError> {
+        if self.config.record_read_activity {
+            txn.set_last_activity(Utc::now())?;
             txn.commit()?;
         }
-        Ok((Server::new(ServerConfig::default(), storage), res))
+        Ok(())
     }
 
-    /// Utility setup function for add_version tests
-    fn av_setup(
-        num_versions: u32,
-        snapshot_version: Option<u32>,
-        snapshot_days_ago: Option<i64>,
-    ) -> anyhow::Result<(Server, Uuid, Vec<Uuid>)> {
-        let (server, (client_id, versions)) = setup(|txn, client_id| {
-            let mut versions = vec![];
+    /// Call the commit hooks for a newly-committed version.
+    fn version_committed(&self, client_id: ClientId, version: &Version) {
+        for hook in &self.commit_hooks {
+            if let Err(e) = hook.on_version_committed(client_id, version) {
+                log::error!(
+                    "commit hook failed for version {}: {e:?}",
+                    version.version_id
+                );
+            }
+        }
+    }
 
-            let mut version_id = Uuid::nil();
-            txn.new_client(Uuid::nil())?;
-            debug_assert!(num_versions < u8::MAX.into());
+    /// Call the commit hooks for a newly-committed snapshot.
+    fn snapshot_committed(&self, client_id: ClientId, snapshot: &Snapshot, data: &[u8]) {
+        for hook in &self.commit_hooks {
+            if let Err(e) = hook.on_snapshot_committed(client_id, snapshot, data) {
+                log::error!(
+                    "commit hook failed for snapshot {}: {e:?}",
+                    snapshot.version_id
+                );
+            }
+        }
+    }
+
+    /// Implementation of the AddSnapshot protocol transaction.
+    ///
+    /// Returns true if the snapshot was stored, or false if it was rejected. A rejected snapshot
+    /// is not an error, as there is nothing the client can do about it.
+    pub fn add_snapshot(
+        &self,
+        client_id: ClientId,
+        version_id: VersionId,
+        data: Vec<u8>,
+    ) -> Result<bool, ServerError> {
+        log::debug!("add_snapshot(client_id: {client_id}, version_id: {version_id})");
+
+        // retain a copy of the data for the commit hooks, if there are any
+        let hook_data = (!self.commit_hooks.is_empty()).then(|| data.clone());
+
+        let mut txn = self.storage.txn(client_id)?;
+        let Some(snapshot) = self.add_snapshot_in_txn(txn.as_mut(), version_id, data)? else {
+            return Ok(false);
+        };
+        txn.commit()?;
+        drop(txn);
+
+        if let Some(data) = hook_data {
+            self.snapshot_committed(client_id, &snapshot, &data);
+        }
+        Ok(true)
+    }
+
+    /// Implementation of the AddSnapshot protocol transaction, in a transaction supplied by the
+    /// caller, for the client of that transaction, returning the stored snapshot, or `None` if it
+    /// was rejected.
+    ///
+    /// As for [`Server::add_version_in_txn`], this does not commit the transaction, and commit
+    /// hooks are not called.
+    pub fn add_snapshot_in_txn(
+        &self,
+        txn: &mut dyn StorageTxn,
+        version_id: VersionId,
+        data: Vec<u8>,
+    ) -> Result<Option<Snapshot>, ServerError> {
+        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+
+        // NOTE: if the snapshot is rejected, this function logs about it and returns
+        // Ok(None), as there's no reason to report an errot to the client / user.
+        let Some(snapshot) = new_snapshot(txn, &client, version_id, &self.config)? else {
+            return Ok(None);
+        };
+
+        // Only replace the snapshot examined above, in case another snapshot was stored in the
+        // interim. If so, nothing is changed.
+        let last_snapshot = client.snapshot.map(|snap| snap.version_id);
+        if !txn.set_snapshot(snapshot.clone(), data, last_snapshot)? {
+            log::debug!(
+                "rejecting snapshot for version {version_id}: snapshot changed concurrently"
+            );
+            return Ok(None);
+        }
+        // Versions before the snapshot are no longer needed. They are deleted in the same
+        // transaction, so the snapshot and the remaining versions are always consistent.
+        txn.delete_versions_before(version_id)?;
+        txn.set_last_activity(Utc::now())?;
+        Ok(Some(snapshot))
+    }
+
+    /// Implementation of the AddSnapshot protocol transaction, reading `size` bytes of snapshot
+    /// data from `data`.
+    ///
+    /// As for [`Server::add_version_streaming`], this avoids holding a copy of the snapshot in
+    /// memory if the storage backend can write it incrementally, and the data is only read if the
+    /// snapshot is accepted. If there are commit hooks, this reads the data into memory and calls
+    /// [`Server::add_snapshot`].
+    ///
+    /// Returns true if the snapshot was stored, as for [`Server::add_snapshot`].
+    pub fn add_snapshot_streaming(
+        &self,
+        client_id: ClientId,
+        version_id: VersionId,
+        size: u64,
+        mut data: impl Read,
+    ) -> Result<bool, ServerError> {
+        if !self.commit_hooks.is_empty() {
+            let data = read_to_vec(&mut data, size)?;
+            return self.add_snapshot(client_id, version_id, data);
+        }
+
+        log::debug!(
+            "add_snapshot_streaming(client_id: {client_id}, version_id: {version_id}, size: {size})"
+        );
+
+        let mut txn = self.storage.txn(client_id)?;
+        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+
+        let Some(snapshot) = new_snapshot(txn.as_mut(), &client, version_id, &self.config)? else {
+            return Ok(false);
+        };
+
+        let last_snapshot = client.snapshot.map(|snap| snap.version_id);
+        if !txn.set_snapshot_from_reader(snapshot, size, &mut data, last_snapshot)? {
+            log::debug!(
+                "rejecting snapshot for version {version_id}: snapshot changed concurrently"
+            );
+            return Ok(false);
+        }
+        txn.delete_versions_before(version_id)?;
+        txn.set_last_activity(Utc::now())?;
+        txn.commit()?;
+        Ok(true)
+    }
+
+    /// Implementation of the GetSnapshot protocol transaction
+    pub fn get_snapshot(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<(Uuid, Vec<u8>)>, ServerError> {
+        let mut txn = self.read_txn(client_id)?;
+        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+
+        let snapshot = if let Some(snap) = client.snapshot {
+            txn.get_snapshot_data(snap.version_id)?
+                .map(|data| (snap.version_id, data))
+        } else {
+            None
+        };
+        self.record_read_activity(txn.as_mut())?;
+        Ok(snapshot)
+    }
+
+    /// Get the version ID and size, in bytes, of the client's snapshot, without loading the
+    /// snapshot data. Like [`Server::get_snapshot`], this is a read, recorded as activity if so
+    /// configured.
+    pub fn get_snapshot_size(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<(Uuid, u64)>, ServerError> {
+        let mut txn = self.read_txn(client_id)?;
+        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+
+        let size = if let Some(snap) = client.snapshot {
+            txn.get_snapshot_size(snap.version_id)?
+                .map(|size| (snap.version_id, size))
+        } else {
+            None
+        };
+        self.record_read_activity(txn.as_mut())?;
+        Ok(size)
+    }
+
+    /// Get the client's latest version ID and the [`ChainHash`] of its history up to that
+    /// version, or `None` if the chain hash is not known.
+    pub fn get_chain_hash(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<(VersionId, ChainHash)>, ServerError> {
+        let mut txn = self.storage.read_txn(client_id)?;
+        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+        if client.latest_version_id == NIL_VERSION_ID && self.config.chain_hash {
+            return Ok(Some((NIL_VERSION_ID, ChainHash::EMPTY)));
+        }
+        Ok(client
+            .chain_hash
+            .map(|chain_hash| (client.latest_version_id, chain_hash)))
+    }
+
+    /// List the snapshots retained for the client, newest first, each with the size of its data
+    /// in bytes.
+    pub fn list_snapshots(&self, client_id: ClientId) -> Result<Vec<(Snapshot, u64)>, ServerError> {
+        let mut txn = self.storage.read_txn(client_id)?;
+        txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+        Ok(txn.list_snapshots()?)
+    }
+
+    /// Record that the client has durably applied every version up to and including
+    /// `version_id`, making that its acked version, so that older versions may be pruned.
+    ///
+    /// The acked version only advances: acking an ancestor of the current acked version has no
+    /// effect. Returns false, without modifying the client, if the version is not in the client's
+    /// history.
+    pub fn ack_version(
+        &self,
+        client_id: ClientId,
+        version_id: VersionId,
+    ) -> Result<bool, ServerError> {
+        let mut txn = self.storage.txn(client_id)?;
+        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+        if version_id == NIL_VERSION_ID {
+            return Ok(false);
+        }
+
+        // Search back from the latest version, which finds the acked version first if it is
+        // newer than the version being acked.
+        let mut search_id = client.latest_version_id;
+        let mut seen = HashSet::new();
+        while search_id != version_id {
+            if Some(search_id) == client.acked_version_id {
+                return Ok(true);
+            }
+            match txn.get_version(search_id)? {
+                Some(version) if seen.insert(search_id) => search_id = version.parent_version_id,
+                _ => return Ok(false),
+            }
+        }
+
+        if client.acked_version_id != Some(version_id) {
+            txn.set_acked_version_id(Some(version_id))?;
+            txn.commit()?;
+        }
+        Ok(true)
+    }
+
+    /// Delete the client's versions which are no longer needed: those older than both its latest
+    /// snapshot and its acked version, if any.
+    ///
+    /// All replicas of a task database share a client ID, so the acked version only shows that
+    /// one replica has applied it. A replica which is behind, or new, must be able to begin from
+    /// the snapshot and apply every version after it, so versions are never pruned past the
+    /// snapshot, and nothing is pruned for a client without a snapshot.
+    ///
+    /// Versions older than the snapshot are deleted automatically when a snapshot is added, so
+    /// this is only needed for versions retained before that was the case.
+    pub fn prune_versions(&self, client_id: ClientId) -> Result<(), ServerError> {
+        let mut txn = self.storage.txn(client_id)?;
+        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+        let Some(snapshot) = client.snapshot else {
+            return Ok(());
+        };
+        let floor = match client.acked_version_id {
+            Some(acked_version_id)
+                if is_ancestor(txn.as_mut(), acked_version_id, snapshot.version_id)? =>
+            {
+                acked_version_id
+            }
+            _ => snapshot.version_id,
+        };
+        txn.delete_versions_before(floor)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Get the client's application metadata, or `None` if none has been set. See
+    /// [`StorageTxn::get_app_metadata`].
+    pub fn get_app_metadata(&self, client_id: ClientId) -> Result<Option<Vec<u8>>, ServerError> {
+        let mut txn = self.storage.read_txn(client_id)?;
+        txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+        Ok(txn.get_app_metadata()?)
+    }
+
+    /// Set the client's application metadata, replacing any existing metadata.
+    pub fn set_app_metadata(
+        &self,
+        client_id: ClientId,
+        app_metadata: Vec<u8>,
+    ) -> Result<(), ServerError> {
+        let mut txn = self.storage.txn(client_id)?;
+        txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+        txn.set_app_metadata(app_metadata)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Move a client, including its snapshot and all of its versions, to a new client ID. This
+    /// supports migrating a replica's history when its client ID changes.
+    ///
+    /// Returns [`ServerError::ClientExists`] if a client with the new ID already exists.
+    pub fn rename_client(
+        &self,
+        client_id: ClientId,
+        new_client_id: ClientId,
+    ) -> Result<(), ServerError> {
+        log::debug!("rename_client(client_id: {client_id}, new_client_id: {new_client_id})");
+
+        let mut txn = self.storage.txn(client_id)?;
+        txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+        if !txn.rename_client(new_client_id)? {
+            return Err(ServerError::ClientExists);
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Delete a client, including its snapshot, application metadata, and all of its versions.
+    pub fn delete_client(&self, client_id: ClientId) -> Result<(), ServerError> {
+        log::debug!("delete_client(client_id: {client_id})");
+
+        let mut txn = self.storage.txn(client_id)?;
+        txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+        txn.delete_client()?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Recompute the client's latest version from its stored versions, repairing the client if
+    /// necessary, and return the latest version.
+    ///
+    /// The latest version is the single version without a child, or the nil version if there are
+    /// no versions. If there is more than one such version, the history is ambiguous and this
+    /// returns [`ServerError::BranchedHistory`] without modifying the client.
+    pub fn recompute_latest_version(&self, client_id: ClientId) -> Result<VersionId, ServerError> {
+        let mut txn = self.storage.txn(client_id)?;
+        let client = txn.get_client()?.ok_or(ServerError::NoSuchClient)?;
+
+        let latest_version_id = match txn.get_head_version_ids()?.as_slice() {
+            [] => NIL_VERSION_ID,
+            [head] => *head,
+            heads => {
+                log::warn!("client {client_id} has {} head versions", heads.len());
+                return Err(ServerError::BranchedHistory);
+            }
+        };
+
+        if latest_version_id != client.latest_version_id {
+            log::warn!(
+                "repairing client {client_id}: latest version was {}, but is {latest_version_id}",
+                client.latest_version_id
+            );
+            txn.set_latest_version_id(latest_version_id)?;
+            // the chain hash was for the old latest version, so it is no longer valid
+            if client.chain_hash.is_some() {
+                txn.set_chain_hash(None)?;
+            }
+            txn.commit()?;
+        }
+        Ok(latest_version_id)
+    }
+
+    /// Copy all clients, with their versions, snapshots, and metadata, into another storage, such
+    /// as a new replica being brought up to date before it is kept in sync by other means.
+    ///
+    /// Clients are copied in order of client ID, each in its own transaction, and `checkpoint` is
+    /// called after each is committed. A backfill which fails or is interrupted can be resumed by
+    /// passing the last checkpoint as `since`. Clients which already exist in the target are left
+    /// unchanged, as are clients created in this storage after the backfill has begun. Versions
+    /// are copied as stored, and client tokens, which are provisioned outside of the sync server,
+    /// are not copied.
+    ///
+    /// Returns the number of clients copied.
+    pub fn backfill_into(
+        &self,
+        target: &dyn Storage,
+        since: Option<BackfillCheckpoint>,
+        mut checkpoint: impl FnMut(BackfillCheckpoint) -> anyhow::Result<()>,
+    ) -> Result<u64, ServerError> {
+        let mut client_ids = self.storage.list_clients()?;
+        client_ids.sort();
+        if let Some(since) = since {
+            client_ids.retain(|client_id| *client_id > since.last_client_id);
+        }
+        log::debug!("backfill_into: {} clients to copy", client_ids.len());
+
+        let mut copied = 0;
+        for client_id in client_ids {
+            // the transactions end before the checkpoint, which may access either storage
+            {
+                let mut source_txn = self.storage.txn(client_id)?;
+                let mut target_txn = target.txn(client_id)?;
+                if copy_client(source_txn.as_mut(), target_txn.as_mut())? {
+                    target_txn.commit()?;
+                    copied += 1;
+                } else {
+                    log::debug!("backfill_into: not copying client {client_id}");
+                }
+            }
+            checkpoint(BackfillCheckpoint {
+                last_client_id: client_id,
+            })?;
+        }
+        Ok(copied)
+    }
+
+    /// Calculate the urgency of a new snapshot for the given client.
+    pub fn snapshot_urgency(&self, client: &Client) -> SnapshotUrgency {
+        let time_urgency = match client.snapshot {
+            None => SnapshotUrgency::High,
+            Some(Snapshot { timestamp, .. }) => {
+                SnapshotUrgency::for_days(&self.config, (Utc::now() - timestamp).num_days())
+            }
+        };
+
+        let version_urgency = match client.snapshot {
+            None => SnapshotUrgency::High,
+            Some(Snapshot { versions_since, .. }) => {
+                SnapshotUrgency::for_versions_since(&self.config, versions_since)
+            }
+        };
+
+        std::cmp::max(time_urgency, version_urgency)
+    }
+
+    /// List up to `limit` clients, in order of client ID, beginning after the client with ID
+    /// `after`, if given. A full listing can be retrieved in pages by passing the last client ID
+    /// of each page as `after` for the next.
+    ///
+    /// Each client is read in its own transaction, so the listing as a whole need not be
+    /// consistent. Clients deleted while the listing is made are omitted.
+    pub fn list_clients(
+        &self,
+        after: Option<ClientId>,
+        limit: usize,
+    ) -> Result<Vec<ClientSummary>, ServerError> {
+        let mut client_ids = self.storage.list_clients()?;
+        client_ids.sort();
+        let mut clients = Vec::new();
+        for client_id in client_ids
+            .into_iter()
+            .filter(|client_id| after.is_none_or(|after| *client_id > after))
+        {
+            if clients.len() >= limit {
+                break;
+            }
+            let mut txn = self.storage.read_txn(client_id)?;
+            let Some(client) = txn.get_client()? else {
+                continue;
+            };
+            let usage = txn.get_client_usage()?;
+            clients.push(ClientSummary {
+                client_id,
+                client,
+                usage,
+            });
+        }
+        Ok(clients)
+    }
+
+    /// Get aggregate statistics about all clients.
+    pub fn global_stats(&self) -> Result<GlobalStats, ServerError> {
+        Ok(self.storage.global_stats()?)
+    }
+
+    /// Get the client's use of its storage quota, or `None` if there is no quota.
+    pub fn storage_quota_usage(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<QuotaUsage>, ServerError> {
+        let Some(limit) = self.config.max_client_bytes else {
+            return Ok(None);
+        };
+        let mut txn = self.storage.read_txn(client_id)?;
+        let usage = txn.get_client_usage()?;
+        Ok(Some(QuotaUsage {
+            used: usage.total_history_bytes + usage.snapshot_bytes,
+            limit,
+        }))
+    }
+
+    /// Get aggregate statistics about the snapshots of all clients.
+    ///
+    /// This reads every client, so it is too expensive to calculate for each request.
+    pub fn snapshot_stats(&self) -> Result<SnapshotStats, ServerError> {
+        let mut stats = SnapshotStats::default();
+        for client_id in self.storage.list_clients()? {
+            let mut txn = self.storage.read_txn(client_id)?;
+            let Some(snapshot) = txn.get_client()?.and_then(|client| client.snapshot) else {
+                continue;
+            };
+            stats.oldest_snapshot_timestamp = Some(match stats.oldest_snapshot_timestamp {
+                Some(oldest) => oldest.min(snapshot.timestamp),
+                None => snapshot.timestamp,
+            });
+            stats.max_versions_since_snapshot = stats
+                .max_versions_since_snapshot
+                .max(snapshot.versions_since);
+        }
+        Ok(stats)
+    }
+
+    /// Describe the storage backend, for diagnostic purposes.
+    pub fn backend_info(&self) -> Result<BackendInfo, ServerError> {
+        Ok(self.storage.backend_info()?)
+    }
+
+    /// Get the configuration of this server.
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Call `f`, which performs an operation in its own transaction, repeating it if the
+    /// transaction fails to commit with a [`TxnConflict`], up to
+    /// [`ServerConfig::conflict_retries`] times.
+    ///
+    /// Each attempt begins a new transaction, and so sees the changes made by the transaction with
+    /// which the last attempt conflicted. For example, a repeated AddVersion finds the version
+    /// added concurrently, and returns a clean [`AddVersionResult::ExpectedParentVersion`].
+    ///
+    /// The streaming methods, such as [`Server::add_version_streaming`], consume their data, so
+    /// `f` must supply it afresh on each attempt.
+    pub fn retry_conflicts<T>(
+        &self,
+        mut f: impl FnMut() -> Result<T, ServerError>,
+    ) -> Result<T, ServerError> {
+        let mut retries = 0;
+        loop {
+            match f() {
+                Err(ServerError::Other(err))
+                    if retries < self.config.conflict_retries
+                        && err.downcast_ref::<TxnConflict>().is_some() =>
+                {
+                    retries += 1;
+                    log::debug!(
+                        "{err}; retrying ({retries}/{})",
+                        self.config.conflict_retries
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Convenience method to get a transaction for the embedded storage.
+    pub fn txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, ServerError> {
+        Ok(self.storage.txn(client_id)?)
+    }
+
+    /// Convenience method to get a read-only transaction for the embedded storage, as with
+    /// [`Storage::read_txn`]. No changes may be made in the transaction, but unlike
+    /// [`Server::txn`], it need not wait for the storage's write lock.
+    pub fn read_only_txn(&self, client_id: Uuid) -> Result<Box<dyn StorageTxn + '_>, ServerError> {
+        Ok(self.storage.read_txn(client_id)?)
+    }
+}
+
+/// Check whether a new version with the given parent is acceptable for the client, returning
+/// the result of the AddVersion transaction if not.
+fn check_parent_version(
+    client: &Client,
+    parent_version_id: VersionId,
+) -> Option<(AddVersionResult, SnapshotUrgency)> {
+    if client.latest_version_id != NIL_VERSION_ID && parent_version_id != client.latest_version_id {
+        log::debug!("add_version request rejected: mismatched latest_version_id");
+        return Some((
+            AddVersionResult::ExpectedParentVersion(client.latest_version_id),
+            SnapshotUrgency::None,
+        ));
+    }
+    None
+}
+
+/// Determine whether a snapshot for the given version should be accepted, returning the new
+/// snapshot if so. Rejected snapshots are logged. Only snapshots for the client's latest version,
+/// or for recent versions as given by the config, are accepted.
+fn new_snapshot(
+    txn: &mut dyn StorageTxn,
+    client: &Client,
+    version_id: VersionId,
+    config: &ServerConfig,
+) -> Result<Option<Snapshot>, ServerError> {
+    let last_snapshot = client.snapshot.as_ref().map(|snap| snap.version_id);
+    if Some(version_id) == last_snapshot {
+        log::debug!("rejecting snapshot for version {version_id}: already exists");
+        return Ok(None);
+    }
+
+    if config.snapshot_latest_only {
+        // there is no need to search the history for an older version
+        if version_id != client.latest_version_id || version_id == NIL_VERSION_ID {
+            log::debug!("rejecting snapshot for version {version_id}: not the latest version");
+            return Ok(None);
+        }
+    } else {
+        // look for this version in the history of this client, starting at the latest version,
+        // and only iterating for a limited number of versions.
+        let mut search_len = config.snapshot_search_len;
+        let mut vid = client.latest_version_id;
+
+        loop {
+            if vid == version_id && version_id != NIL_VERSION_ID {
+                // the new snapshot is for a recent version, so proceed
+                break;
+            }
+
+            if Some(vid) == last_snapshot {
+                // the new snapshot is older than the last snapshot, so ignore it
+                log::debug!("rejecting snapshot for version {version_id}: newer snapshot already exists or no such version");
+                return Ok(None);
+            }
+
+            search_len = search_len.saturating_sub(1);
+            if search_len == 0 || vid == NIL_VERSION_ID {
+                // this should not happen in normal operation, so warn about it
+                log::warn!("rejecting snapshot for version {version_id}: version is too old or no such version");
+                return Ok(None);
+            }
+
+            // get the parent version ID
+            if let Some(parent) = txn.get_version(vid)? {
+                vid = parent.parent_version_id;
+            } else {
+                // this version does not exist; "this should not happen" but if it does,
+                // we don't need a snapshot earlier than the missing version.
+                log::warn!("rejecting snapshot for version {version_id}: newer versions have already been deleted");
+                return Ok(None);
+            }
+        }
+    }
+
+    log::debug!("accepting snapshot for version {version_id}");
+    Ok(Some(Snapshot {
+        version_id,
+        timestamp: Utc::now(),
+        versions_since: 0,
+    }))
+}
+
+/// Determine whether a missing child of the given parent version is not found, rather than
+/// gone: that is, whether an AddVersion with this parent version would succeed.
+///
+/// AddVersion will succeed if either
+///  - the requested parent version is the latest version; or
+///  - there is no latest version, meaning there are no versions stored for this client
+fn child_not_found(client: &Client, parent_version_id: VersionId) -> bool {
+    client.latest_version_id == parent_version_id || client.latest_version_id == NIL_VERSION_ID
+}
+
+/// Determine whether `ancestor` is `version_id` or one of its ancestors, following the chain of
+/// parent versions back from `version_id` until it ends or a version is missing.
+fn is_ancestor(
+    txn: &mut dyn StorageTxn,
+    ancestor: VersionId,
+    version_id: VersionId,
+) -> anyhow::Result<bool> {
+    let mut search_id = version_id;
+    let mut seen = HashSet::new();
+    while search_id != ancestor {
+        match txn.get_version(search_id)? {
+            Some(version) if seen.insert(search_id) => search_id = version.parent_version_id,
+            _ => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+/// Copy the client of the `source` transaction into the `target` transaction, without committing
+/// it. Returns false, without making any changes, if the client does not exist in the source or
+/// already exists in the target.
+fn copy_client(
+    source: &mut dyn StorageTxn,
+    target: &mut dyn StorageTxn,
+) -> Result<bool, ServerError> {
+    let Some(client) = source.get_client()? else {
+        return Ok(false);
+    };
+    if target.get_client()?.is_some() {
+        return Ok(false);
+    }
+
+    // find the retained versions, following the chain of parents back from the latest version
+    let mut versions = Vec::new();
+    let mut version_id = client.latest_version_id;
+    while version_id != NIL_VERSION_ID {
+        let Some(version) = source.get_version(version_id)? else {
+            break;
+        };
+        version_id = version.parent_version_id;
+        versions.push(version);
+    }
+
+    target.new_client(NIL_VERSION_ID)?;
+    for version in versions.into_iter().rev() {
+        target.add_version(
+            version.version_id,
+            version.parent_version_id,
+            version.history_segment,
+        )?;
+    }
+    target.set_latest_version_id(client.latest_version_id)?;
+
+    // the snapshot is set after adding versions, which would otherwise change its versions_since
+    if let Some(snapshot) = client.snapshot {
+        if let Some(data) = source.get_snapshot_data(snapshot.version_id)? {
+            target.set_snapshot(snapshot, data, None)?;
+        }
+    }
+    if let Some(last_activity_at) = client.last_activity_at {
+        target.set_last_activity(last_activity_at)?;
+    }
+    if client.chain_hash.is_some() {
+        target.set_chain_hash(client.chain_hash)?;
+    }
+    if client.acked_version_id.is_some() {
+        target.set_acked_version_id(client.acked_version_id)?;
+    }
+    if let Some(app_metadata) = source.get_app_metadata()? {
+        target.set_app_metadata(app_metadata)?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inmemory::InMemoryStorage;
+    use crate::storage::{BackendInfo, ClientUsage, Snapshot, Storage, StorageTxn};
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    fn setup<INIT, RES>(init: INIT) -> anyhow::Result<(Server, RES)>
+    where
+        INIT: FnOnce(&mut dyn StorageTxn, Uuid) -> anyhow::Result<RES>,
+    {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let storage = InMemoryStorage::new();
+        let client_id = Uuid::new_v4();
+        let res;
+        {
+            let mut txn = storage.txn(client_id)?;
+            res = init(txn.as_mut(), client_id)?;
+            txn.commit()?;
+        }
+        Ok((Server::new(ServerConfig::default(), storage), res))
+    }
+
+    /// Utility setup function for add_version tests
+    fn av_setup(
+        num_versions: u32,
+        snapshot_version: Option<u32>,
+        snapshot_days_ago: Option<i64>,
+    ) -> anyhow::Result<(Server, Uuid, Vec<Uuid>)> {
+        let (server, (client_id, versions)) = setup(|txn, client_id| {
+            let mut versions = vec![];
+
+            let mut version_id = NIL_VERSION_ID;
+            txn.new_client(NIL_VERSION_ID)?;
+            debug_assert!(num_versions < u8::MAX.into());
             for vnum in 0..num_versions {
                 let parent_version_id = version_id;
                 version_id = Uuid::new_v4();
-                versions.push(version_id);
-                txn.add_version(
-                    version_id,
+                versions.push(version_id);
+                txn.add_version(
+                    version_id,
+                    parent_version_id,
+                    // Generate some unique data for this version.
+                    vec![0, 0, vnum as u8],
+                )?;
+                if Some(vnum) == snapshot_version {
+                    txn.set_snapshot(
+                        Snapshot {
+                            version_id,
+                            versions_since: 0,
+                            timestamp: Utc::now() - Duration::days(snapshot_days_ago.unwrap_or(0)),
+                        },
+                        // Generate some unique data for this snapshot.
+                        vec![vnum as u8],
+                        None,
+                    )?;
+                }
+            }
+
+            Ok((client_id, versions))
+        })?;
+        Ok((server, client_id, versions))
+    }
+
+    /// Utility function to check the results of an add_version call
+    fn av_success_check(
+        server: &Server,
+        client_id: Uuid,
+        existing_versions: &[Uuid],
+        (add_version_result, snapshot_urgency): (AddVersionResult, SnapshotUrgency),
+        expected_history: Vec<u8>,
+        expected_urgency: SnapshotUrgency,
+    ) -> anyhow::Result<()> {
+        if let AddVersionResult::Ok(new_version_id) = add_version_result {
+            // check that it invented a new version ID
+            for v in existing_versions {
+                assert_ne!(&new_version_id, v);
+            }
+
+            // verify that the storage was updated
+            let mut txn = server.txn(client_id)?;
+            let client = txn.get_client()?.unwrap();
+            assert_eq!(client.latest_version_id, new_version_id);
+
+            let parent_version_id = existing_versions.last().cloned().unwrap_or_else(Uuid::nil);
+            let version = txn.get_version(new_version_id)?.unwrap();
+            assert_eq!(version.version_id, new_version_id);
+            assert_eq!(version.parent_version_id, parent_version_id);
+            assert_eq!(version.history_segment, expected_history);
+        } else {
+            panic!("did not get Ok from add_version: {:?}", add_version_result);
+        }
+
+        assert_eq!(snapshot_urgency, expected_urgency);
+
+        Ok(())
+    }
+
+    type Recorded<T> = Arc<Mutex<Vec<T>>>;
+
+    /// A commit hook which records the calls made to it.
+    #[derive(Default, Clone)]
+    struct RecordingHook {
+        versions: Recorded<(ClientId, Version)>,
+        snapshots: Recorded<(ClientId, Snapshot, Vec<u8>)>,
+    }
+
+    impl CommitHook for RecordingHook {
+        fn on_version_committed(
+            &self,
+            client_id: ClientId,
+            version: &Version,
+        ) -> anyhow::Result<()> {
+            self.versions
+                .lock()
+                .unwrap()
+                .push((client_id, version.clone()));
+            Ok(())
+        }
+
+        fn on_snapshot_committed(
+            &self,
+            client_id: ClientId,
+            snapshot: &Snapshot,
+            data: &[u8],
+        ) -> anyhow::Result<()> {
+            self.snapshots
+                .lock()
+                .unwrap()
+                .push((client_id, snapshot.clone(), data.to_vec()));
+            Ok(())
+        }
+    }
+
+    /// A commit hook which always fails.
+    struct FailingHook;
+
+    impl CommitHook for FailingHook {
+        fn on_version_committed(&self, _: ClientId, _: &Version) -> anyhow::Result<()> {
+            anyhow::bail!("uhoh")
+        }
+
+        fn on_snapshot_committed(&self, _: ClientId, _: &Snapshot, _: &[u8]) -> anyhow::Result<()> {
+            anyhow::bail!("uhoh")
+        }
+    }
+
+    #[test]
+    fn snapshot_urgency_max() {
+        use SnapshotUrgency::*;
+        assert_eq!(std::cmp::max(None, None), None);
+        assert_eq!(std::cmp::max(None, Low), Low);
+        assert_eq!(std::cmp::max(None, High), High);
+        assert_eq!(std::cmp::max(Low, None), Low);
+        assert_eq!(std::cmp::max(Low, Low), Low);
+        assert_eq!(std::cmp::max(Low, High), High);
+        assert_eq!(std::cmp::max(High, None), High);
+        assert_eq!(std::cmp::max(High, Low), High);
+        assert_eq!(std::cmp::max(High, High), High);
+    }
+
+    #[test]
+    fn snapshot_urgency_for_days() {
+        use SnapshotUrgency::*;
+        let config = ServerConfig::default();
+        assert_eq!(SnapshotUrgency::for_days(&config, 0), None);
+        assert_eq!(
+            SnapshotUrgency::for_days(&config, config.snapshot_days),
+            Low
+        );
+        assert_eq!(
+            SnapshotUrgency::for_days(&config, config.snapshot_days * 2),
+            High
+        );
+    }
+
+    #[test]
+    fn snapshot_urgency_for_versions_since() {
+        use SnapshotUrgency::*;
+        let config = ServerConfig::default();
+        assert_eq!(SnapshotUrgency::for_versions_since(&config, 0), None);
+        assert_eq!(
+            SnapshotUrgency::for_versions_since(&config, config.snapshot_versions),
+            Low
+        );
+        assert_eq!(
+            SnapshotUrgency::for_versions_since(&config, config.snapshot_versions * 2),
+            High
+        );
+        assert_eq!(SnapshotUrgency::for_versions_since(&config, u32::MAX), High);
+    }
+
+    #[test]
+    fn snapshot_urgency_for_versions_since_max_config() {
+        let config = ServerConfig {
+            snapshot_versions: u32::MAX,
+            ..ServerConfig::default()
+        };
+        assert_eq!(
+            SnapshotUrgency::for_versions_since(&config, u32::MAX - 1),
+            SnapshotUrgency::None
+        );
+        // the high-urgency threshold is beyond u32::MAX, so is never reached
+        assert_eq!(
+            SnapshotUrgency::for_versions_since(&config, u32::MAX),
+            SnapshotUrgency::Low
+        );
+    }
+
+    #[test]
+    fn server_config_validate() {
+        assert!(ServerConfig::default().validate().is_ok());
+        for compression_level in [*Compression::levels().start(), *Compression::levels().end()] {
+            let config = ServerConfig {
+                compression_level,
+                ..ServerConfig::default()
+            };
+            assert!(config.validate().is_ok());
+        }
+        let config = ServerConfig {
+            compression_level: Compression::levels().end() + 1,
+            ..ServerConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn version_id_kind_random() {
+        let version_id = VersionIdKind::Random.new_version_id();
+        assert_eq!(version_id.get_version_num(), 4);
+    }
+
+    #[test]
+    fn version_id_kind_time_ordered() {
+        let version_ids: Vec<_> = (0..100)
+            .map(|_| VersionIdKind::TimeOrdered.new_version_id())
+            .collect();
+        for version_id in &version_ids {
+            assert_eq!(version_id.get_version_num(), 7);
+        }
+        // IDs generated in sequence sort in the same order
+        let mut sorted = version_ids.clone();
+        sorted.sort();
+        assert_eq!(sorted, version_ids);
+    }
+
+    #[test]
+    fn add_version_random_version_id() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(1, None, None)?;
+        server.config.version_id_kind = VersionIdKind::Random;
+
+        let (result, _) = server.add_version(client_id, versions[0], vec![1])?;
+        let AddVersionResult::Ok(version_id) = result else {
+            panic!("did not get Ok from add_version: {:?}", result);
+        };
+        assert_eq!(version_id.get_version_num(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_time_ordered_version_id() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(1, None, None)?;
+
+        let (result, _) = server.add_version(client_id, versions[0], vec![1])?;
+        let AddVersionResult::Ok(version_id) = result else {
+            panic!("did not get Ok from add_version: {:?}", result);
+        };
+        assert_eq!(version_id.get_version_num(), 7);
+
+        Ok(())
+    }
+
+    #[test]
+    fn nil_version_id_is_nil_uuid() {
+        // The TaskChampion protocol represents "no version" as the nil UUID.
+        assert_eq!(NIL_VERSION_ID, Uuid::nil());
+    }
+
+    #[test]
+    fn add_version_no_latest_accepts_any_parent() -> anyhow::Result<()> {
+        let (server, client_id) = setup(|txn, client_id| {
+            txn.new_client(NIL_VERSION_ID)?;
+            Ok(client_id)
+        })?;
+
+        // with no latest version, any parent version is acceptable
+        let (result, _) = server.add_version(client_id, Uuid::new_v4(), vec![1])?;
+        let AddVersionResult::Ok(version_id) = result else {
+            panic!("did not get Ok from add_version: {:?}", result);
+        };
+
+        // now that there is a latest version, the parent must match it
+        let (result, _) = server.add_version(client_id, Uuid::new_v4(), vec![2])?;
+        assert_eq!(result, AddVersionResult::ExpectedParentVersion(version_id));
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_child_version_not_found_initial_nil() -> anyhow::Result<()> {
+        let (server, client_id) = setup(|txn, client_id| {
+            txn.new_client(NIL_VERSION_ID)?;
+
+            Ok(client_id)
+        })?;
+        // when no latest version exists, the first version is NotFound
+        assert_eq!(
+            server.get_child_version(client_id, NIL_VERSION_ID)?.0,
+            GetVersionResult::NotFound
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_child_version_not_found_initial_continuing() -> anyhow::Result<()> {
+        let (server, client_id) = setup(|txn, client_id| {
+            txn.new_client(NIL_VERSION_ID)?;
+
+            Ok(client_id)
+        })?;
+
+        // when no latest version exists, _any_ child version is NOT_FOUND. This allows syncs to
+        // start to a new server even if the client already has been uploading to another service.
+        assert_eq!(
+            server.get_child_version(client_id, Uuid::new_v4())?.0,
+            GetVersionResult::NotFound
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_child_version_not_found_up_to_date() -> anyhow::Result<()> {
+        let (server, (client_id, parent_version_id)) = setup(|txn, client_id| {
+            // add a parent version, but not the requested child version
+            let parent_version_id = Uuid::new_v4();
+            txn.new_client(parent_version_id)?;
+            txn.add_version(parent_version_id, NIL_VERSION_ID, vec![])?;
+
+            Ok((client_id, parent_version_id))
+        })?;
+
+        assert_eq!(
+            server.get_child_version(client_id, parent_version_id)?.0,
+            GetVersionResult::NotFound
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_child_version_gone_not_latest() -> anyhow::Result<()> {
+        let (server, client_id) = setup(|txn, client_id| {
+            // Add a parent version, but not the requested parent version
+            let parent_version_id = Uuid::new_v4();
+            txn.new_client(parent_version_id)?;
+            txn.add_version(parent_version_id, NIL_VERSION_ID, vec![])?;
+
+            Ok(client_id)
+        })?;
+
+        assert_eq!(
+            server.get_child_version(client_id, Uuid::new_v4())?.0,
+            GetVersionResult::Gone
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_child_version_found() -> anyhow::Result<()> {
+        let (server, (client_id, version_id, parent_version_id, history_segment)) =
+            setup(|txn, client_id| {
+                let version_id = Uuid::new_v4();
+                let parent_version_id = Uuid::new_v4();
+                let history_segment = b"abcd".to_vec();
+
+                txn.new_client(version_id)?;
+                txn.add_version(version_id, parent_version_id, history_segment.clone())?;
+
+                Ok((client_id, version_id, parent_version_id, history_segment))
+            })?;
+        assert_eq!(
+            server.get_child_version(client_id, parent_version_id)?.0,
+            GetVersionResult::Success {
+                version_id,
+                parent_version_id,
+                history_segment,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_child_version_found_not_latest() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+        assert_eq!(
+            server.get_child_version(client_id, versions[0])?.0,
+            GetVersionResult::Success {
+                version_id: versions[1],
+                parent_version_id: versions[0],
+                history_segment: vec![0, 0, 1],
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_child_version_snapshot_urgency() -> anyhow::Result<()> {
+        for (snapshot_days_ago, expected_urgency) in [
+            (None, SnapshotUrgency::High),
+            (Some(0), SnapshotUrgency::None),
+            (Some(14), SnapshotUrgency::Low),
+            (Some(21), SnapshotUrgency::High),
+        ] {
+            let (server, (client_id, version_id)) = setup(|txn, client_id| {
+                let version_id = Uuid::new_v4();
+                txn.new_client(version_id)?;
+                txn.add_version(version_id, NIL_VERSION_ID, b"abcd".to_vec())?;
+                if let Some(days) = snapshot_days_ago {
+                    txn.set_snapshot(
+                        Snapshot {
+                            version_id,
+                            timestamp: Utc::now() - Duration::days(days),
+                            versions_since: 0,
+                        },
+                        b"snap".to_vec(),
+                        None,
+                    )?;
+                }
+                Ok((client_id, version_id))
+            })?;
+
+            // the urgency is returned whether or not the child version exists
+            let (result, urgency) = server.get_child_version(client_id, NIL_VERSION_ID)?;
+            assert!(matches!(result, GetVersionResult::Success { .. }));
+            assert_eq!(urgency, expected_urgency, "with {snapshot_days_ago:?}");
+            let (result, urgency) = server.get_child_version(client_id, version_id)?;
+            assert_eq!(result, GetVersionResult::NotFound);
+            assert_eq!(urgency, expected_urgency, "with {snapshot_days_ago:?}");
+        }
+        Ok(())
+    }
+
+    /// The frames expected for the versions created by `av_setup`.
+    fn av_frames(versions: &[Uuid]) -> Vec<VersionFrame> {
+        let mut parent_version_id = NIL_VERSION_ID;
+        versions
+            .iter()
+            .enumerate()
+            .map(|(vnum, version_id)| {
+                let frame = VersionFrame {
+                    version_id: *version_id,
+                    parent_version_id,
+                    history_segment: vec![0, 0, vnum as u8],
+                };
+                parent_version_id = *version_id;
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn get_child_versions_fewer_than_limit() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+        assert_eq!(
+            server.get_child_versions(client_id, versions[0], 10)?,
+            GetVersionsResult::Success(av_frames(&versions)[1..].to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_child_versions_limit() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(5, None, None)?;
+        assert_eq!(
+            server.get_child_versions(client_id, NIL_VERSION_ID, 3)?,
+            GetVersionsResult::Success(av_frames(&versions)[..3].to_vec())
+        );
+        assert_eq!(
+            server.get_child_versions(client_id, NIL_VERSION_ID, 5)?,
+            GetVersionsResult::Success(av_frames(&versions))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_child_versions_not_found_and_gone() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+        assert_eq!(
+            server.get_child_versions(client_id, versions[2], 10)?,
+            GetVersionsResult::NotFound
+        );
+        assert_eq!(
+            server.get_child_versions(client_id, Uuid::new_v4(), 10)?,
+            GetVersionsResult::Gone
+        );
+        assert!(matches!(
+            server.get_child_versions(Uuid::new_v4(), NIL_VERSION_ID, 10),
+            Err(ServerError::NoSuchClient)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn get_version() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+        assert_eq!(
+            server.get_version(client_id, versions[1])?,
+            Some(Version {
+                version_id: versions[1],
+                parent_version_id: versions[0],
+                history_segment: vec![0, 0, 1],
+            })
+        );
+        assert_eq!(server.get_version(client_id, Uuid::new_v4())?, None);
+        assert!(matches!(
+            server.get_version(Uuid::new_v4(), versions[1]),
+            Err(ServerError::NoSuchClient)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn get_latest_version() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+        assert_eq!(
+            server.get_latest_version(client_id)?,
+            Some(Version {
+                version_id: versions[2],
+                parent_version_id: versions[1],
+                history_segment: vec![0, 0, 2],
+            })
+        );
+        assert!(matches!(
+            server.get_latest_version(Uuid::new_v4()),
+            Err(ServerError::NoSuchClient)
+        ));
+
+        let (server, client_id) = setup(|txn, client_id| {
+            txn.new_client(NIL_VERSION_ID)?;
+            Ok(client_id)
+        })?;
+        assert_eq!(server.get_latest_version(client_id)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_within_quota() -> anyhow::Result<()> {
+        // three 3-byte versions and a 1-byte snapshot use 10 bytes
+        let (mut server, client_id, versions) = av_setup(3, Some(2), None)?;
+        server.config.max_client_bytes = Some(15);
+
+        let (result, _) = server.add_version(client_id, versions[2], vec![0; 5])?;
+        assert!(matches!(result, AddVersionResult::Ok(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn storage_quota_usage() -> anyhow::Result<()> {
+        // three 3-byte versions and a 1-byte snapshot use 10 bytes
+        let (mut server, client_id, _) = av_setup(3, Some(2), None)?;
+        assert_eq!(server.storage_quota_usage(client_id)?, None);
+
+        server.config.max_client_bytes = Some(15);
+        assert_eq!(
+            server.storage_quota_usage(client_id)?,
+            Some(QuotaUsage {
+                used: 10,
+                limit: 15
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_quota_exceeded() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(3, Some(2), None)?;
+        server.config.max_client_bytes = Some(14);
+
+        let (result, urgency) = server.add_version(client_id, versions[2], vec![0; 5])?;
+        assert_eq!(result, AddVersionResult::QuotaExceeded);
+        assert_eq!(urgency, SnapshotUrgency::None);
+        let (result, _) = server.add_version_streaming(client_id, versions[2], 5, &[0u8; 5][..])?;
+        assert_eq!(result, AddVersionResult::QuotaExceeded);
+        let (result, _) = server.add_versions(client_id, versions[2], vec![vec![0; 2]; 3])?;
+        assert_eq!(result, AddVersionsResult::QuotaExceeded);
+
+        // nothing was added
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, versions[2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_compressed() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(1, None, None)?;
+        server.config.compression = Compression::Zstd;
+        let history_segment = b"compressible ".repeat(100);
+
+        let (result, _) = server.add_version(client_id, versions[0], history_segment.clone())?;
+        let AddVersionResult::Ok(v1) = result else {
+            panic!("unexpected result {result:?}");
+        };
+        let (result, _) = server.add_version_streaming(
+            client_id,
+            v1,
+            history_segment.len() as u64,
+            history_segment.as_slice(),
+        )?;
+        let AddVersionResult::Ok(v2) = result else {
+            panic!("unexpected result {result:?}");
+        };
+        let (result, _) = server.add_versions(client_id, v2, vec![history_segment.clone()])?;
+        let AddVersionsResult::Ok(v3) = result else {
+            panic!("unexpected result {result:?}");
+        };
+
+        // each version is stored compressed, but read back as it was given
+        let mut parent_version_id = versions[0];
+        for version_id in [v1, v2, v3[0]] {
+            let mut txn = server.txn(client_id)?;
+            let stored = txn.get_version(version_id)?.unwrap().history_segment;
+            assert!(stored.len() < history_segment.len());
+            drop(txn);
+
+            assert_eq!(
+                server.get_child_version(client_id, parent_version_id)?.0,
+                GetVersionResult::Success {
+                    version_id,
+                    parent_version_id,
+                    history_segment: history_segment.clone(),
+                }
+            );
+            parent_version_id = version_id;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_child_version_uncompressed_with_compression() -> anyhow::Result<()> {
+        // a version stored before compression was enabled
+        let (mut server, client_id, versions) = av_setup(2, None, None)?;
+        server.config.compression = Compression::Zstd;
+
+        assert_eq!(
+            server.get_child_version(client_id, versions[0])?.0,
+            GetVersionResult::Success {
+                version_id: versions[1],
+                parent_version_id: versions[0],
+                history_segment: vec![0, 0, 1],
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_conflict() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+
+        // try to add a child of a version other than the latest
+        assert_eq!(
+            server.add_version(client_id, versions[1], vec![3, 6, 9])?.0,
+            AddVersionResult::ExpectedParentVersion(versions[2])
+        );
+
+        // verify that the storage wasn't updated
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, versions[2]);
+        assert_eq!(txn.get_version_by_parent(versions[2])?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn retry_conflicts() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(1, None, None)?;
+
+        let mut attempts = 0;
+        let result = server.retry_conflicts(|| {
+            attempts += 1;
+            if attempts == 1 {
+                // another transaction adds a version, and this one conflicts with it
+                server.add_version(client_id, versions[0], vec![1])?;
+                return Err(anyhow::Error::new(TxnConflict(client_id)).into());
+            }
+            server.add_version(client_id, versions[0], vec![2])
+        })?;
+        assert_eq!(attempts, 2);
+
+        // the retry sees the other version, and is rejected cleanly
+        let mut txn = server.txn(client_id)?;
+        let latest_version_id = txn.get_client()?.unwrap().latest_version_id;
+        assert_eq!(
+            result.0,
+            AddVersionResult::ExpectedParentVersion(latest_version_id)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn retry_conflicts_exhausted() -> anyhow::Result<()> {
+        let (mut server, client_id) = setup(|_, client_id| Ok(client_id))?;
+        server.config.conflict_retries = 2;
+
+        let mut attempts = 0;
+        let result: Result<(), _> = server.retry_conflicts(|| {
+            attempts += 1;
+            Err(anyhow::Error::new(TxnConflict(client_id))
+                .context("Error committing transaction")
+                .into())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+
+        // other errors are not retried
+        let mut attempts = 0;
+        let result: Result<(), _> = server.retry_conflicts(|| {
+            attempts += 1;
+            Err(ServerError::NoSuchClient)
+        });
+        assert!(matches!(result, Err(ServerError::NoSuchClient)));
+        assert_eq!(attempts, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_records_activity() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(1, None, None)?;
+        assert_eq!(
+            server
+                .txn(client_id)?
+                .get_client()?
+                .unwrap()
+                .last_activity_at,
+            None
+        );
+
+        let before = Utc::now();
+        server.add_version(client_id, versions[0], vec![3, 6, 9])?;
+        let last_activity_at = server
+            .txn(client_id)?
+            .get_client()?
+            .unwrap()
+            .last_activity_at
+            .unwrap();
+        assert!(last_activity_at >= before);
+        assert!(last_activity_at <= Utc::now());
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_conflict_does_not_record_activity() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+        server.add_version(client_id, versions[1], vec![3, 6, 9])?;
+        assert_eq!(
+            server
+                .txn(client_id)?
+                .get_client()?
+                .unwrap()
+                .last_activity_at,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_child_version_records_activity() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(2, None, None)?;
+
+        // by default, reads do not record activity
+        server.get_child_version(client_id, versions[0])?;
+        assert_eq!(
+            server
+                .txn(client_id)?
+                .get_client()?
+                .unwrap()
+                .last_activity_at,
+            None
+        );
+
+        server.config.record_read_activity = true;
+        let before = Utc::now();
+        server.get_child_version(client_id, versions[1])?;
+        let last_activity_at = server
+            .txn(client_id)?
+            .get_client()?
+            .unwrap()
+            .last_activity_at
+            .unwrap();
+        assert!(last_activity_at >= before);
+        assert!(last_activity_at <= Utc::now());
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_streaming() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(1, None, None)?;
+
+        let data = vec![7u8; 1024];
+        let result = server.add_version_streaming(
+            client_id,
+            versions[0],
+            data.len() as u64,
+            data.as_slice(),
+        )?;
+        let AddVersionResult::Ok(version_id) = result.0 else {
+            panic!("did not get Ok from add_version_streaming: {:?}", result.0);
+        };
+
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        assert_eq!(txn.get_version(version_id)?.unwrap().history_segment, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_streaming_conflict() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+
+        // the history segment is not read for a rejected version
+        let mut data: &[u8] = &[3, 6, 9];
+        assert_eq!(
+            server
+                .add_version_streaming(client_id, versions[1], 3, &mut data)?
+                .0,
+            AddVersionResult::ExpectedParentVersion(versions[2])
+        );
+        assert_eq!(data, &[3, 6, 9]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_streaming_short() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(1, None, None)?;
+
+        // a reader with fewer bytes than promised is an error
+        assert!(server
+            .add_version_streaming(client_id, versions[0], 10, &[1u8, 2, 3][..])
+            .is_err());
+
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, versions[0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_with_existing_history() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(1, None, None)?;
+
+        let result = server.add_version(client_id, versions[0], vec![3, 6, 9])?;
+
+        av_success_check(
+            &server,
+            client_id,
+            &versions,
+            result,
+            vec![3, 6, 9],
+            // urgency=high because there are no snapshots yet
+            SnapshotUrgency::High,
+        )?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_with_no_history() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(0, None, None)?;
+
+        let parent_version_id = NIL_VERSION_ID;
+        let result = server.add_version(client_id, parent_version_id, vec![3, 6, 9])?;
+
+        av_success_check(
+            &server,
+            client_id,
+            &versions,
+            result,
+            vec![3, 6, 9],
+            // urgency=high because there are no snapshots yet
+            SnapshotUrgency::High,
+        )?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_success_recent_snapshot() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(1, Some(0), None)?;
+
+        let result = server.add_version(client_id, versions[0], vec![1, 2, 3])?;
+
+        av_success_check(
+            &server,
+            client_id,
+            &versions,
+            result,
+            vec![1, 2, 3],
+            // no snapshot request since the previous version has a snapshot
+            SnapshotUrgency::None,
+        )?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_success_aged_snapshot() -> anyhow::Result<()> {
+        // one snapshot, but it was 50 days ago
+        let (server, client_id, versions) = av_setup(1, Some(0), Some(50))?;
+
+        let result = server.add_version(client_id, versions[0], vec![1, 2, 3])?;
+
+        av_success_check(
+            &server,
+            client_id,
+            &versions,
+            result,
+            vec![1, 2, 3],
+            // urgency=high due to days since the snapshot
+            SnapshotUrgency::High,
+        )?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_success_snapshot_many_versions_ago() -> anyhow::Result<()> {
+        // one snapshot, but it was 50 versions ago
+        let (mut server, client_id, versions) = av_setup(50, Some(0), None)?;
+        server.config.snapshot_versions = 30;
+
+        let result = server.add_version(client_id, versions[49], vec![1, 2, 3])?;
+
+        av_success_check(
+            &server,
+            client_id,
+            &versions,
+            result,
+            vec![1, 2, 3],
+            // urgency=high due to number of versions since the snapshot
+            SnapshotUrgency::High,
+        )?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_in_txn() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(1, None, None)?;
+
+        // add a version and the caller's own change in the same transaction
+        let mut txn = server.txn(client_id)?;
+        let (result, _) = server.add_version_in_txn(txn.as_mut(), versions[0], vec![3, 6, 9])?;
+        let AddVersionResult::Ok(version_id) = result else {
+            panic!("version not added: {result:?}");
+        };
+        txn.set_app_metadata(b"app".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = server.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, version_id);
+        assert_eq!(txn.get_app_metadata()?, Some(b"app".to_vec()));
+        let version = txn.get_version(version_id)?.unwrap();
+        assert_eq!(version.parent_version_id, versions[0]);
+        assert_eq!(version.history_segment, vec![3, 6, 9]);
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_in_txn_conflict() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(2, None, None)?;
+
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(
+            server.add_version_in_txn(txn.as_mut(), versions[0], vec![3, 6, 9])?,
+            (
+                AddVersionResult::ExpectedParentVersion(versions[1]),
+                SnapshotUrgency::None
+            )
+        );
+        // nothing was written, so the transaction can be dropped
+        drop(txn);
+
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, versions[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn add_snapshot_in_txn() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(2, None, None)?;
+
+        let mut txn = server.txn(client_id)?;
+        let snapshot = server
+            .add_snapshot_in_txn(txn.as_mut(), versions[1], vec![1, 2, 3])?
+            .unwrap();
+        assert_eq!(snapshot.version_id, versions[1]);
+        txn.set_app_metadata(b"app".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = server.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.snapshot, Some(snapshot));
+        assert_eq!(txn.get_snapshot_data(versions[1])?, Some(vec![1, 2, 3]));
+        assert_eq!(txn.get_app_metadata()?, Some(b"app".to_vec()));
+        // the version before the snapshot was deleted in the same transaction
+        assert_eq!(txn.get_version(versions[0])?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn add_snapshot_success_latest() -> anyhow::Result<()> {
+        let (server, (client_id, version_id)) = setup(|txn, client_id| {
+            let version_id = Uuid::new_v4();
+
+            // set up a task DB with one version in it
+            txn.new_client(version_id)?;
+            txn.add_version(version_id, NIL_VERSION_ID, vec![])?;
+
+            // add a snapshot for that version
+            Ok((client_id, version_id))
+        })?;
+        assert!(server.add_snapshot(client_id, version_id, vec![1, 2, 3])?);
+
+        // verify the snapshot
+        let mut txn = server.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        let snapshot = client.snapshot.unwrap();
+        assert_eq!(snapshot.version_id, version_id);
+        assert_eq!(snapshot.versions_since, 0);
+        assert_eq!(
+            txn.get_snapshot_data(version_id).unwrap(),
+            Some(vec![1, 2, 3])
+        );
+        assert!(client.last_activity_at.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_snapshot_success_older() -> anyhow::Result<()> {
+        let (server, (client_id, version_id_1)) = setup(|txn, client_id| {
+            let version_id_1 = Uuid::new_v4();
+            let version_id_2 = Uuid::new_v4();
+
+            // set up a task DB with two versions in it
+            txn.new_client(version_id_2)?;
+            txn.add_version(version_id_1, NIL_VERSION_ID, vec![])?;
+            txn.add_version(version_id_2, version_id_1, vec![])?;
+
+            Ok((client_id, version_id_1))
+        })?;
+        // add a snapshot for version 1
+        assert!(server.add_snapshot(client_id, version_id_1, vec![1, 2, 3])?);
+
+        // verify the snapshot
+        let mut txn = server.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        let snapshot = client.snapshot.unwrap();
+        assert_eq!(snapshot.version_id, version_id_1);
+        assert_eq!(snapshot.versions_since, 0);
+        assert_eq!(
+            txn.get_snapshot_data(version_id_1).unwrap(),
+            Some(vec![1, 2, 3])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_snapshot_latest_only() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(2, None, None)?;
+        server.config.snapshot_latest_only = true;
+
+        // a snapshot for a recent, but not the latest, version is rejected
+        assert!(!server.add_snapshot(client_id, versions[0], vec![1, 2, 3])?);
+        assert!(!server.add_snapshot_streaming(client_id, versions[0], 3, &[1u8, 2, 3][..])?);
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().snapshot, None);
+        drop(txn);
+
+        // a snapshot for the latest version is stored
+        assert!(server.add_snapshot(client_id, versions[1], vec![4, 5, 6])?);
+        assert_eq!(
+            server.get_snapshot(client_id)?,
+            Some((versions[1], vec![4, 5, 6]))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_snapshot_latest_only_nil_version() -> anyhow::Result<()> {
+        let (mut server, client_id, _) = av_setup(0, None, None)?;
+        server.config.snapshot_latest_only = true;
+
+        assert!(!server.add_snapshot(client_id, NIL_VERSION_ID, vec![1, 2, 3])?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_snapshot_fails_no_such() -> anyhow::Result<()> {
+        let (server, client_id) = setup(|txn, client_id| {
+            let version_id_1 = Uuid::new_v4();
+            let version_id_2 = Uuid::new_v4();
+
+            // set up a task DB with two versions in it
+            txn.new_client(version_id_2)?;
+            txn.add_version(version_id_1, NIL_VERSION_ID, vec![])?;
+            txn.add_version(version_id_2, version_id_1, vec![])?;
+
+            // add a snapshot for unknown version
+            Ok(client_id)
+        })?;
+
+        let version_id_unk = Uuid::new_v4();
+        assert!(!server.add_snapshot(client_id, version_id_unk, vec![1, 2, 3])?);
+
+        // verify the snapshot does not exist
+        let mut txn = server.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert!(client.snapshot.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_snapshot_fails_too_old() -> anyhow::Result<()> {
+        let (server, (client_id, version_ids)) = setup(|txn, client_id| {
+            let mut version_id = Uuid::new_v4();
+            let mut parent_version_id = NIL_VERSION_ID;
+            let mut version_ids = vec![];
+
+            // set up a task DB with 10 versions in it (oldest to newest)
+            txn.new_client(NIL_VERSION_ID)?;
+            for _ in 0..10 {
+                txn.add_version(version_id, parent_version_id, vec![])?;
+                version_ids.push(version_id);
+                parent_version_id = version_id;
+                version_id = Uuid::new_v4();
+            }
+
+            // add a snapshot for the earliest of those
+            Ok((client_id, version_ids))
+        })?;
+        assert!(!server.add_snapshot(client_id, version_ids[0], vec![1, 2, 3])?);
+
+        // verify the snapshot does not exist
+        let mut txn = server.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert!(client.snapshot.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_snapshot_search_len() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(10, None, None)?;
+
+        // the version six back from the latest is too old at the default search length
+        assert!(!server.add_snapshot(client_id, versions[3], vec![1, 2, 3])?);
+
+        server.config.snapshot_search_len = 10;
+        assert!(server.add_snapshot(client_id, versions[3], vec![1, 2, 3])?);
+
+        let mut txn = server.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.snapshot.unwrap().version_id, versions[3]);
+        Ok(())
+    }
+
+    #[test]
+    fn add_snapshot_fails_newer_exists() -> anyhow::Result<()> {
+        let (server, (client_id, version_ids)) = setup(|txn, client_id| {
+            let mut version_id = Uuid::new_v4();
+            let mut parent_version_id = NIL_VERSION_ID;
+            let mut version_ids = vec![];
+
+            // set up a task DB with 5 versions in it (oldest to newest) and a snapshot of the
+            // middle one
+            txn.new_client(NIL_VERSION_ID)?;
+            for _ in 0..5 {
+                txn.add_version(version_id, parent_version_id, vec![])?;
+                version_ids.push(version_id);
+                parent_version_id = version_id;
+                version_id = Uuid::new_v4();
+            }
+            txn.set_snapshot(
+                Snapshot {
+                    version_id: version_ids[2],
+                    versions_since: 2,
+                    timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
+                },
+                vec![1, 2, 3],
+                None,
+            )?;
+
+            // add a snapshot for the earliest of those
+            Ok((client_id, version_ids))
+        })?;
+
+        assert!(!server.add_snapshot(client_id, version_ids[0], vec![9, 9, 9])?);
+
+        // verify the snapshot was not replaced
+        let mut txn = server.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        let snapshot = client.snapshot.unwrap();
+        assert_eq!(snapshot.version_id, version_ids[2]);
+        assert_eq!(snapshot.versions_since, 2);
+        assert_eq!(
+            txn.get_snapshot_data(version_ids[2]).unwrap(),
+            Some(vec![1, 2, 3])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_snapshot_fails_nil_version() -> anyhow::Result<()> {
+        let (server, client_id) = setup(|txn, client_id| {
+            // just set up the client
+            txn.new_client(NIL_VERSION_ID)?;
+
+            // add a snapshot for the nil version
+            Ok(client_id)
+        })?;
+
+        assert!(!server.add_snapshot(client_id, NIL_VERSION_ID, vec![9, 9, 9])?);
+
+        // verify the snapshot does not exist
+        let mut txn = server.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert!(client.snapshot.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_snapshot_prunes_versions() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(5, None, None)?;
+
+        server.add_snapshot(client_id, versions[2], vec![1, 2, 3])?;
+
+        // versions before the snapshot are gone, and the snapshot and newer versions remain
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_version(versions[0])?, None);
+        assert_eq!(txn.get_version(versions[1])?, None);
+        for version_id in &versions[2..] {
+            assert!(txn.get_version(*version_id)?.is_some());
+        }
+        assert_eq!(txn.get_snapshot_data(versions[2])?, Some(vec![1, 2, 3]));
+        drop(txn);
+
+        // the client can still sync from the snapshot
+        assert_eq!(
+            server.get_child_version(client_id, versions[2])?.0,
+            GetVersionResult::Success {
+                version_id: versions[3],
+                parent_version_id: versions[2],
+                history_segment: vec![0, 0, 3],
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_snapshot_rejected_does_not_prune() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, Some(2), None)?;
+
+        // a snapshot older than the existing snapshot is rejected
+        server.add_snapshot(client_id, versions[1], vec![1, 2, 3])?;
+
+        let mut txn = server.txn(client_id)?;
+        for version_id in &versions {
+            assert!(txn.get_version(*version_id)?.is_some());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn prune_versions() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(4, Some(1), None)?;
+
+        server.prune_versions(client_id)?;
+
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_version(versions[0])?, None);
+        for version_id in &versions[1..] {
+            assert!(txn.get_version(*version_id)?.is_some());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn prune_versions_no_snapshot() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+
+        server.prune_versions(client_id)?;
+
+        let mut txn = server.txn(client_id)?;
+        for version_id in &versions {
+            assert!(txn.get_version(*version_id)?.is_some());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn prune_versions_acked_no_snapshot() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(4, None, None)?;
+
+        assert!(server.ack_version(client_id, versions[2])?);
+        server.prune_versions(client_id)?;
+
+        // without a snapshot, a new replica needs every version, so nothing is pruned
+        let mut txn = server.txn(client_id)?;
+        for version_id in &versions {
+            assert!(txn.get_version(*version_id)?.is_some());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn prune_versions_acked_before_snapshot() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(4, Some(2), None)?;
+
+        assert!(server.ack_version(client_id, versions[1])?);
+        server.prune_versions(client_id)?;
+
+        // the acked version is the older of the two, so only versions before it are pruned
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_version(versions[0])?, None);
+        for version_id in &versions[1..] {
+            assert!(txn.get_version(*version_id)?.is_some());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn prune_versions_acked_after_snapshot() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(5, Some(1), None)?;
+
+        // another replica acks a version newer than the snapshot
+        assert!(server.ack_version(client_id, versions[3])?);
+        server.prune_versions(client_id)?;
+
+        // only versions before the snapshot are pruned
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_version(versions[0])?, None);
+        for version_id in &versions[1..] {
+            assert!(txn.get_version(*version_id)?.is_some());
+        }
+        drop(txn);
+
+        // a replica beginning from the snapshot can still catch up
+        let (snapshot_version_id, _) = server.get_snapshot(client_id)?.unwrap();
+        assert_eq!(snapshot_version_id, versions[1]);
+        let mut parent_version_id = snapshot_version_id;
+        for (vnum, version_id) in versions.iter().enumerate().skip(2) {
+            assert_eq!(
+                server.get_child_version(client_id, parent_version_id)?.0,
+                GetVersionResult::Success {
+                    version_id: *version_id,
                     parent_version_id,
-                    // Generate some unique data for this version.
-                    vec![0, 0, vnum as u8],
-                )?;
-                if Some(vnum) == snapshot_version {
-                    txn.set_snapshot(
-                        Snapshot {
-                            version_id,
-                            versions_since: 0,
-                            timestamp: Utc::now() - Duration::days(snapshot_days_ago.unwrap_or(0)),
-                        },
-                        // Generate some unique data for this snapshot.
-                        vec![vnum as u8],
-                    )?;
+                    history_segment: vec![0, 0, vnum as u8],
                 }
-            }
+            );
+            parent_version_id = *version_id;
+        }
+        assert_eq!(
+            server.get_child_version(client_id, parent_version_id)?.0,
+            GetVersionResult::NotFound
+        );
 
-            Ok((client_id, versions))
+        Ok(())
+    }
+
+    #[test]
+    fn ack_version_advances() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(4, None, None)?;
+        let acked_version_id = |server: &Server| -> anyhow::Result<Option<Uuid>> {
+            Ok(server
+                .txn(client_id)?
+                .get_client()?
+                .unwrap()
+                .acked_version_id)
+        };
+
+        assert_eq!(acked_version_id(&server)?, None);
+        assert!(server.ack_version(client_id, versions[1])?);
+        assert_eq!(acked_version_id(&server)?, Some(versions[1]));
+        assert!(server.ack_version(client_id, versions[3])?);
+        assert_eq!(acked_version_id(&server)?, Some(versions[3]));
+
+        // acking an older version does not move the acked version back
+        assert!(server.ack_version(client_id, versions[2])?);
+        assert_eq!(acked_version_id(&server)?, Some(versions[3]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ack_version_unknown() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(2, None, None)?;
+
+        assert!(!server.ack_version(client_id, Uuid::new_v4())?);
+        assert!(!server.ack_version(client_id, NIL_VERSION_ID)?);
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().acked_version_id, None);
+        drop(txn);
+
+        assert!(matches!(
+            server.ack_version(Uuid::new_v4(), versions[0]),
+            Err(ServerError::NoSuchClient)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn prune_versions_no_such_client() -> anyhow::Result<()> {
+        let (server, _) = setup(|_, _| Ok(()))?;
+
+        assert!(matches!(
+            server.prune_versions(Uuid::new_v4()),
+            Err(ServerError::NoSuchClient)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_snapshot_found() -> anyhow::Result<()> {
+        let (server, (client_id, data, snapshot_version_id)) = setup(|txn, client_id| {
+            let data = vec![1, 2, 3];
+            let snapshot_version_id = Uuid::new_v4();
+
+            txn.new_client(snapshot_version_id)?;
+            txn.set_snapshot(
+                Snapshot {
+                    version_id: snapshot_version_id,
+                    versions_since: 3,
+                    timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
+                },
+                data.clone(),
+                None,
+            )?;
+            Ok((client_id, data, snapshot_version_id))
         })?;
-        Ok((server, client_id, versions))
+        assert_eq!(
+            server.get_snapshot(client_id)?,
+            Some((snapshot_version_id, data))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_snapshot_not_found() -> anyhow::Result<()> {
+        let (server, client_id) = setup(|txn, client_id| {
+            txn.new_client(NIL_VERSION_ID)?;
+            Ok(client_id)
+        })?;
+
+        assert_eq!(server.get_snapshot(client_id)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_snapshot_size_found() -> anyhow::Result<()> {
+        let (server, (client_id, snapshot_version_id)) = setup(|txn, client_id| {
+            let snapshot_version_id = Uuid::new_v4();
+
+            txn.new_client(snapshot_version_id)?;
+            txn.set_snapshot(
+                Snapshot {
+                    version_id: snapshot_version_id,
+                    versions_since: 3,
+                    timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
+                },
+                vec![1, 2, 3],
+                None,
+            )?;
+            Ok((client_id, snapshot_version_id))
+        })?;
+        assert_eq!(
+            server.get_snapshot_size(client_id)?,
+            Some((snapshot_version_id, 3))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_snapshot_size_records_activity() -> anyhow::Result<()> {
+        let (mut server, client_id, _) = av_setup(2, Some(1), None)?;
+        let last_activity_at = |server: &Server| -> anyhow::Result<_> {
+            Ok(server
+                .txn(client_id)?
+                .get_client()?
+                .unwrap()
+                .last_activity_at)
+        };
+
+        // by default, reads do not record activity
+        server.get_snapshot_size(client_id)?;
+        assert_eq!(last_activity_at(&server)?, None);
+
+        server.config.record_read_activity = true;
+        let before = Utc::now();
+        server.get_snapshot_size(client_id)?;
+        let last_activity_at = last_activity_at(&server)?.unwrap();
+        assert!(last_activity_at >= before);
+        assert!(last_activity_at <= Utc::now());
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_snapshot_size_not_found() -> anyhow::Result<()> {
+        let (server, client_id) = setup(|txn, client_id| {
+            txn.new_client(NIL_VERSION_ID)?;
+            Ok(client_id)
+        })?;
+
+        assert_eq!(server.get_snapshot_size(client_id)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn list_snapshots_none() -> anyhow::Result<()> {
+        let (server, client_id) = setup(|txn, client_id| {
+            txn.new_client(NIL_VERSION_ID)?;
+            Ok(client_id)
+        })?;
+
+        assert_eq!(server.list_snapshots(client_id)?, vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn list_snapshots_current() -> anyhow::Result<()> {
+        let (server, (client_id, snapshot)) = setup(|txn, client_id| {
+            let snapshot = Snapshot {
+                version_id: Uuid::new_v4(),
+                versions_since: 3,
+                timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
+            };
+            txn.new_client(snapshot.version_id)?;
+            txn.set_snapshot(snapshot.clone(), vec![1, 2, 3], None)?;
+            Ok((client_id, snapshot))
+        })?;
+
+        assert_eq!(server.list_snapshots(client_id)?, vec![(snapshot, 3)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn list_snapshots_no_such_client() -> anyhow::Result<()> {
+        let (server, _) = setup(|_, _| Ok(()))?;
+
+        assert!(matches!(
+            server.list_snapshots(Uuid::new_v4()),
+            Err(ServerError::NoSuchClient)
+        ));
+
+        Ok(())
+    }
+
+    /// A storage backend which retains every snapshot, wrapping [`InMemoryStorage`].
+    struct RetainingStorage {
+        inner: InMemoryStorage,
+        snapshots: Mutex<HashMap<ClientId, Vec<(Snapshot, u64)>>>,
+    }
+
+    struct RetainingTxn<'a> {
+        inner: Box<dyn StorageTxn + 'a>,
+        snapshots: &'a Mutex<HashMap<ClientId, Vec<(Snapshot, u64)>>>,
+        client_id: ClientId,
+    }
+
+    impl Storage for RetainingStorage {
+        fn txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+            Ok(Box::new(RetainingTxn {
+                inner: self.inner.txn(client_id)?,
+                snapshots: &self.snapshots,
+                client_id,
+            }))
+        }
+
+        fn read_txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+            Ok(Box::new(RetainingTxn {
+                inner: self.inner.read_txn(client_id)?,
+                snapshots: &self.snapshots,
+                client_id,
+            }))
+        }
+
+        fn global_stats(&self) -> anyhow::Result<GlobalStats> {
+            self.inner.global_stats()
+        }
+
+        fn list_clients(&self) -> anyhow::Result<Vec<Uuid>> {
+            self.inner.list_clients()
+        }
+
+        fn backend_info(&self) -> anyhow::Result<BackendInfo> {
+            self.inner.backend_info()
+        }
+
+        fn schema_version(&self) -> anyhow::Result<u32> {
+            self.inner.schema_version()
+        }
+
+        fn required_schema_version(&self) -> u32 {
+            self.inner.required_schema_version()
+        }
     }
 
-    /// Utility function to check the results of an add_version call
-    fn av_success_check(
-        server: &Server,
-        client_id: Uuid,
-        existing_versions: &[Uuid],
-        (add_version_result, snapshot_urgency): (AddVersionResult, SnapshotUrgency),
-        expected_history: Vec<u8>,
-        expected_urgency: SnapshotUrgency,
-    ) -> anyhow::Result<()> {
-        if let AddVersionResult::Ok(new_version_id) = add_version_result {
-            // check that it invented a new version ID
-            for v in existing_versions {
-                assert_ne!(&new_version_id, v);
-            }
+    impl StorageTxn for RetainingTxn<'_> {
+        fn get_client(&mut self) -> anyhow::Result<Option<Client>> {
+            self.inner.get_client()
+        }
+
+        fn new_client(&mut self, latest_version_id: Uuid) -> anyhow::Result<()> {
+            self.inner.new_client(latest_version_id)
+        }
+
+        fn set_snapshot(
+            &mut self,
+            snapshot: Snapshot,
+            data: Vec<u8>,
+            expected_previous_version_id: Option<Uuid>,
+        ) -> anyhow::Result<bool> {
+            let size = data.len() as u64;
+            let set =
+                self.inner
+                    .set_snapshot(snapshot.clone(), data, expected_previous_version_id)?;
+            if set {
+                let mut snapshots = self.snapshots.lock().unwrap();
+                let snapshots = snapshots.entry(self.client_id).or_default();
+                snapshots.insert(0, (snapshot, size));
+            }
+            Ok(set)
+        }
+
+        fn get_snapshot_data(&mut self, version_id: Uuid) -> anyhow::Result<Option<Vec<u8>>> {
+            self.inner.get_snapshot_data(version_id)
+        }
+
+        fn get_snapshot_size(&mut self, version_id: Uuid) -> anyhow::Result<Option<u64>> {
+            self.inner.get_snapshot_size(version_id)
+        }
+
+        fn list_snapshots(&mut self) -> anyhow::Result<Vec<(Snapshot, u64)>> {
+            let snapshots = self.snapshots.lock().unwrap();
+            Ok(snapshots.get(&self.client_id).cloned().unwrap_or_default())
+        }
+
+        fn get_version_by_parent(
+            &mut self,
+            parent_version_id: Uuid,
+        ) -> anyhow::Result<Option<Version>> {
+            self.inner.get_version_by_parent(parent_version_id)
+        }
+
+        fn get_version(&mut self, version_id: Uuid) -> anyhow::Result<Option<Version>> {
+            self.inner.get_version(version_id)
+        }
+
+        fn get_head_version_ids(&mut self) -> anyhow::Result<Vec<Uuid>> {
+            self.inner.get_head_version_ids()
+        }
+
+        fn get_client_usage(&mut self) -> anyhow::Result<ClientUsage> {
+            self.inner.get_client_usage()
+        }
+
+        fn set_latest_version_id(&mut self, latest_version_id: Uuid) -> anyhow::Result<()> {
+            self.inner.set_latest_version_id(latest_version_id)
+        }
+
+        fn set_last_activity(&mut self, timestamp: DateTime<Utc>) -> anyhow::Result<()> {
+            self.inner.set_last_activity(timestamp)
+        }
+
+        fn set_chain_hash(&mut self, chain_hash: Option<ChainHash>) -> anyhow::Result<()> {
+            self.inner.set_chain_hash(chain_hash)
+        }
+
+        fn set_acked_version_id(&mut self, acked_version_id: Option<Uuid>) -> anyhow::Result<()> {
+            self.inner.set_acked_version_id(acked_version_id)
+        }
+
+        fn get_app_metadata(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+            self.inner.get_app_metadata()
+        }
+
+        fn set_app_metadata(&mut self, app_metadata: Vec<u8>) -> anyhow::Result<()> {
+            self.inner.set_app_metadata(app_metadata)
+        }
+
+        fn add_version(
+            &mut self,
+            version_id: Uuid,
+            parent_version_id: Uuid,
+            history_segment: Vec<u8>,
+        ) -> anyhow::Result<()> {
+            self.inner
+                .add_version(version_id, parent_version_id, history_segment)
+        }
 
-            // verify that the storage was updated
-            let mut txn = server.txn(client_id)?;
-            let client = txn.get_client()?.unwrap();
-            assert_eq!(client.latest_version_id, new_version_id);
+        fn delete_versions_before(&mut self, version_id: Uuid) -> anyhow::Result<()> {
+            self.inner.delete_versions_before(version_id)
+        }
 
-            let parent_version_id = existing_versions.last().cloned().unwrap_or_else(Uuid::nil);
-            let version = txn.get_version(new_version_id)?.unwrap();
-            assert_eq!(version.version_id, new_version_id);
-            assert_eq!(version.parent_version_id, parent_version_id);
-            assert_eq!(version.history_segment, expected_history);
-        } else {
-            panic!("did not get Ok from add_version: {:?}", add_version_result);
+        fn rename_client(&mut self, new_client_id: Uuid) -> anyhow::Result<bool> {
+            self.inner.rename_client(new_client_id)
         }
 
-        assert_eq!(snapshot_urgency, expected_urgency);
+        fn delete_client(&mut self) -> anyhow::Result<()> {
+            self.inner.delete_client()
+        }
 
-        Ok(())
+        fn commit(&mut self) -> anyhow::Result<()> {
+            self.inner.commit()
+        }
     }
 
     #[test]
-    fn snapshot_urgency_max() {
-        use SnapshotUrgency::*;
-        assert_eq!(std::cmp::max(None, None), None);
-        assert_eq!(std::cmp::max(None, Low), Low);
-        assert_eq!(std::cmp::max(None, High), High);
-        assert_eq!(std::cmp::max(Low, None), Low);
-        assert_eq!(std::cmp::max(Low, Low), Low);
-        assert_eq!(std::cmp::max(Low, High), High);
-        assert_eq!(std::cmp::max(High, None), High);
-        assert_eq!(std::cmp::max(High, Low), High);
-        assert_eq!(std::cmp::max(High, High), High);
+    fn list_snapshots_retained() -> anyhow::Result<()> {
+        let storage = RetainingStorage {
+            inner: InMemoryStorage::new(),
+            snapshots: Mutex::default(),
+        };
+        let server = Server::new(ServerConfig::default(), storage);
+        let client_id = Uuid::new_v4();
+        let mut txn = server.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+
+        let mut expected = vec![];
+        let mut previous_version_id = None;
+        for i in 1..=3u8 {
+            let snapshot = Snapshot {
+                version_id: Uuid::new_v4(),
+                versions_since: 0,
+                timestamp: Utc.with_ymd_and_hms(2001, 9, i.into(), 1, 46, 40).unwrap(),
+            };
+            txn.set_snapshot(snapshot.clone(), vec![0; i.into()], previous_version_id)?;
+            previous_version_id = Some(snapshot.version_id);
+            expected.insert(0, (snapshot, i.into()));
+        }
+        txn.commit()?;
+        drop(txn);
+
+        assert_eq!(server.list_snapshots(client_id)?, expected);
+
+        Ok(())
     }
 
     #[test]
-    fn snapshot_urgency_for_days() {
-        use SnapshotUrgency::*;
-        let config = ServerConfig::default();
-        assert_eq!(SnapshotUrgency::for_days(&config, 0), None);
-        assert_eq!(
-            SnapshotUrgency::for_days(&config, config.snapshot_days),
-            Low
-        );
+    fn add_version_commit_hook() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(1, None, None)?;
+        let hook = RecordingHook::default();
+        server.add_commit_hook(hook.clone());
+
+        let result = server.add_version(client_id, versions[0], vec![3, 6, 9])?;
+        let AddVersionResult::Ok(version_id) = result.0 else {
+            panic!("did not get Ok from add_version: {:?}", result.0);
+        };
+
         assert_eq!(
-            SnapshotUrgency::for_days(&config, config.snapshot_days * 2),
-            High
+            *hook.versions.lock().unwrap(),
+            vec![(
+                client_id,
+                Version {
+                    version_id,
+                    parent_version_id: versions[0],
+                    history_segment: vec![3, 6, 9],
+                }
+            )]
         );
+        assert!(hook.snapshots.lock().unwrap().is_empty());
+
+        Ok(())
     }
 
     #[test]
-    fn snapshot_urgency_for_versions_since() {
-        use SnapshotUrgency::*;
-        let config = ServerConfig::default();
-        assert_eq!(SnapshotUrgency::for_versions_since(&config, 0), None);
-        assert_eq!(
-            SnapshotUrgency::for_versions_since(&config, config.snapshot_versions),
-            Low
-        );
-        assert_eq!(
-            SnapshotUrgency::for_versions_since(&config, config.snapshot_versions * 2),
-            High
-        );
+    fn add_version_conflict_commit_hook() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(3, None, None)?;
+        let hook = RecordingHook::default();
+        server.add_commit_hook(hook.clone());
+
+        // a conflicting version is not committed, so the hook is not called
+        server.add_version(client_id, versions[1], vec![3, 6, 9])?;
+        assert!(hook.versions.lock().unwrap().is_empty());
+
+        Ok(())
     }
 
     #[test]
-    fn get_child_version_not_found_initial_nil() -> anyhow::Result<()> {
-        let (server, client_id) = setup(|txn, client_id| {
-            txn.new_client(NIL_VERSION_ID)?;
+    fn add_version_failing_commit_hook() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(1, None, None)?;
+        server.add_commit_hook(FailingHook);
+
+        let result = server.add_version(client_id, versions[0], vec![3, 6, 9])?;
+        av_success_check(
+            &server,
+            client_id,
+            &versions,
+            result,
+            vec![3, 6, 9],
+            SnapshotUrgency::High,
+        )?;
 
-            Ok(client_id)
-        })?;
-        // when no latest version exists, the first version is NotFound
-        assert_eq!(
-            server.get_child_version(client_id, NIL_VERSION_ID)?,
-            GetVersionResult::NotFound
-        );
         Ok(())
     }
 
     #[test]
-    fn get_child_version_not_found_initial_continuing() -> anyhow::Result<()> {
-        let (server, client_id) = setup(|txn, client_id| {
-            txn.new_client(NIL_VERSION_ID)?;
+    fn add_snapshot_commit_hook() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(3, None, None)?;
+        let hook = RecordingHook::default();
+        server.add_commit_hook(hook.clone());
+
+        server.add_snapshot(client_id, versions[2], vec![1, 2, 3])?;
+
+        let snapshots = hook.snapshots.lock().unwrap();
+        assert_eq!(snapshots.len(), 1);
+        let (hook_client_id, snapshot, data) = &snapshots[0];
+        assert_eq!(*hook_client_id, client_id);
+        assert_eq!(snapshot.version_id, versions[2]);
+        assert_eq!(snapshot.versions_since, 0);
+        assert_eq!(data, &vec![1, 2, 3]);
+        assert!(hook.versions.lock().unwrap().is_empty());
 
-            Ok(client_id)
-        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn add_version_streaming_commit_hook() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(1, None, None)?;
+        let hook = RecordingHook::default();
+        server.add_commit_hook(hook.clone());
+
+        let result = server.add_version_streaming(client_id, versions[0], 3, &[3u8, 6, 9][..])?;
+        let AddVersionResult::Ok(version_id) = result.0 else {
+            panic!("did not get Ok from add_version_streaming: {:?}", result.0);
+        };
 
-        // when no latest version exists, _any_ child version is NOT_FOUND. This allows syncs to
-        // start to a new server even if the client already has been uploading to another service.
         assert_eq!(
-            server.get_child_version(client_id, Uuid::new_v4(),)?,
-            GetVersionResult::NotFound
+            *hook.versions.lock().unwrap(),
+            vec![(
+                client_id,
+                Version {
+                    version_id,
+                    parent_version_id: versions[0],
+                    history_segment: vec![3, 6, 9],
+                }
+            )]
         );
+
         Ok(())
     }
 
     #[test]
-    fn get_child_version_not_found_up_to_date() -> anyhow::Result<()> {
-        let (server, (client_id, parent_version_id)) = setup(|txn, client_id| {
-            // add a parent version, but not the requested child version
-            let parent_version_id = Uuid::new_v4();
-            txn.new_client(parent_version_id)?;
-            txn.add_version(parent_version_id, NIL_VERSION_ID, vec![])?;
+    fn add_snapshot_streaming() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
 
-            Ok((client_id, parent_version_id))
-        })?;
+        assert!(server.add_snapshot_streaming(client_id, versions[2], 3, &[1u8, 2, 3][..])?);
 
         assert_eq!(
-            server.get_child_version(client_id, parent_version_id)?,
-            GetVersionResult::NotFound
+            server.get_snapshot(client_id)?,
+            Some((versions[2], vec![1, 2, 3]))
         );
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_version(versions[1])?, None);
+
         Ok(())
     }
 
     #[test]
-    fn get_child_version_gone_not_latest() -> anyhow::Result<()> {
-        let (server, client_id) = setup(|txn, client_id| {
-            // Add a parent version, but not the requested parent version
-            let parent_version_id = Uuid::new_v4();
-            txn.new_client(parent_version_id)?;
-            txn.add_version(parent_version_id, NIL_VERSION_ID, vec![])?;
+    fn add_snapshot_streaming_commit_hook() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(3, None, None)?;
+        let hook = RecordingHook::default();
+        server.add_commit_hook(hook.clone());
 
-            Ok(client_id)
-        })?;
+        server.add_snapshot_streaming(client_id, versions[2], 3, &[1u8, 2, 3][..])?;
+
+        let snapshots = hook.snapshots.lock().unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].2, vec![1, 2, 3]);
 
-        assert_eq!(
-            server.get_child_version(client_id, Uuid::new_v4(),)?,
-            GetVersionResult::Gone
-        );
         Ok(())
     }
 
     #[test]
-    fn get_child_version_found() -> anyhow::Result<()> {
-        let (server, (client_id, version_id, parent_version_id, history_segment)) =
-            setup(|txn, client_id| {
-                let version_id = Uuid::new_v4();
-                let parent_version_id = Uuid::new_v4();
-                let history_segment = b"abcd".to_vec();
+    fn add_snapshot_rejected_commit_hook() -> anyhow::Result<()> {
+        let (mut server, client_id, _) = av_setup(3, None, None)?;
+        let hook = RecordingHook::default();
+        server.add_commit_hook(hook.clone());
 
-                txn.new_client(version_id)?;
-                txn.add_version(version_id, parent_version_id, history_segment.clone())?;
+        // a snapshot for an unknown version is rejected, so the hook is not called
+        server.add_snapshot(client_id, Uuid::new_v4(), vec![1, 2, 3])?;
+        assert!(hook.snapshots.lock().unwrap().is_empty());
 
-                Ok((client_id, version_id, parent_version_id, history_segment))
-            })?;
-        assert_eq!(
-            server.get_child_version(client_id, parent_version_id)?,
-            GetVersionResult::Success {
-                version_id,
-                parent_version_id,
-                history_segment,
-            }
-        );
         Ok(())
     }
 
     #[test]
-    fn add_version_conflict() -> anyhow::Result<()> {
-        let (server, client_id, versions) = av_setup(3, None, None)?;
+    fn add_snapshot_failing_commit_hook() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(3, None, None)?;
+        server.add_commit_hook(FailingHook);
 
-        // try to add a child of a version other than the latest
+        server.add_snapshot(client_id, versions[2], vec![1, 2, 3])?;
         assert_eq!(
-            server.add_version(client_id, versions[1], vec![3, 6, 9])?.0,
-            AddVersionResult::ExpectedParentVersion(versions[2])
+            server.get_snapshot(client_id)?,
+            Some((versions[2], vec![1, 2, 3]))
         );
 
-        // verify that the storage wasn't updated
-        let mut txn = server.txn(client_id)?;
-        assert_eq!(txn.get_client()?.unwrap().latest_version_id, versions[2]);
-        assert_eq!(txn.get_version_by_parent(versions[2])?, None);
-
         Ok(())
     }
 
     #[test]
-    fn add_version_with_existing_history() -> anyhow::Result<()> {
-        let (server, client_id, versions) = av_setup(1, None, None)?;
+    fn add_versions_success() -> anyhow::Result<()> {
+        // a snapshot 8 versions ago, which is not yet urgent
+        let (mut server, client_id, versions) = av_setup(9, Some(0), None)?;
+        server.config.snapshot_versions = 10;
+        let hook = RecordingHook::default();
+        server.add_commit_hook(hook.clone());
+
+        let history_segments: Vec<_> = (0..5u8).map(|i| vec![i]).collect();
+        let (result, urgency) =
+            server.add_versions(client_id, versions[8], history_segments.clone())?;
+        let AddVersionsResult::Ok(version_ids) = result else {
+            panic!("did not get Ok from add_versions: {:?}", result);
+        };
+        assert_eq!(version_ids.len(), 5);
 
-        let result = server.add_version(client_id, versions[0], vec![3, 6, 9])?;
+        // urgency reflects the 13 versions since the snapshot after the batch, not the 8 before
+        // it.
+        assert_eq!(urgency, SnapshotUrgency::Low);
 
-        av_success_check(
-            &server,
-            client_id,
-            &versions,
-            result,
-            vec![3, 6, 9],
-            // urgency=high because there are no snapshots yet
-            SnapshotUrgency::High,
-        )?;
+        // verify that the storage was updated, with each version a child of the previous one
+        let mut txn = server.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, version_ids[4]);
+        assert_eq!(client.snapshot.unwrap().versions_since, 13);
+        let mut parent_version_id = versions[8];
+        for (version_id, history_segment) in version_ids.iter().zip(history_segments.iter()) {
+            let version = txn.get_version(*version_id)?.unwrap();
+            assert_eq!(version.parent_version_id, parent_version_id);
+            assert_eq!(&version.history_segment, history_segment);
+            parent_version_id = *version_id;
+        }
+
+        // the commit hook was called for each version, in order
+        let hooked: Vec<_> = hook
+            .versions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, v)| v.version_id)
+            .collect();
+        assert_eq!(hooked, version_ids);
 
         Ok(())
     }
 
     #[test]
-    fn add_version_with_no_history() -> anyhow::Result<()> {
-        let (server, client_id, versions) = av_setup(0, None, None)?;
+    fn add_versions_conflict() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
 
-        let parent_version_id = Uuid::nil();
-        let result = server.add_version(client_id, parent_version_id, vec![3, 6, 9])?;
+        assert_eq!(
+            server
+                .add_versions(client_id, versions[1], vec![vec![1], vec![2]])?
+                .0,
+            AddVersionsResult::ExpectedParentVersion(versions[2])
+        );
 
-        av_success_check(
-            &server,
-            client_id,
-            &versions,
-            result,
-            vec![3, 6, 9],
-            // urgency=high because there are no snapshots yet
-            SnapshotUrgency::High,
-        )?;
+        // verify that the storage wasn't updated
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, versions[2]);
+        assert_eq!(txn.get_version_by_parent(versions[2])?, None);
 
         Ok(())
     }
 
     #[test]
-    fn add_version_success_recent_snapshot() -> anyhow::Result<()> {
-        let (server, client_id, versions) = av_setup(1, Some(0), None)?;
-
-        let result = server.add_version(client_id, versions[0], vec![1, 2, 3])?;
-
-        av_success_check(
-            &server,
-            client_id,
-            &versions,
-            result,
-            vec![1, 2, 3],
-            // no snapshot request since the previous version has a snapshot
-            SnapshotUrgency::None,
-        )?;
+    fn add_version_and_snapshot_success() -> anyhow::Result<()> {
+        let (mut server, client_id, versions) = av_setup(3, Some(0), None)?;
+        let hook = RecordingHook::default();
+        server.add_commit_hook(hook.clone());
+
+        let result =
+            server.add_version_and_snapshot(client_id, versions[2], vec![3], b"snap".to_vec())?;
+        let AddVersionResult::Ok(version_id) = result else {
+            panic!("did not get Ok from add_version_and_snapshot: {:?}", result);
+        };
 
+        // both the version and the snapshot for it are stored, and older versions deleted
+        let mut txn = server.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, version_id);
+        let snapshot = client.snapshot.unwrap();
+        assert_eq!(snapshot.version_id, version_id);
+        assert_eq!(snapshot.versions_since, 0);
+        assert_eq!(txn.get_snapshot_data(version_id)?, Some(b"snap".to_vec()));
+        let version = txn.get_version(version_id)?.unwrap();
+        assert_eq!(version.parent_version_id, versions[2]);
+        assert_eq!(version.history_segment, vec![3]);
+        assert_eq!(txn.get_version(versions[2])?, None);
+        drop(txn);
+
+        // the commit hooks were called for both
+        assert_eq!(hook.versions.lock().unwrap().len(), 1);
+        assert_eq!(hook.snapshots.lock().unwrap().len(), 1);
         Ok(())
     }
 
     #[test]
-    fn add_version_success_aged_snapshot() -> anyhow::Result<()> {
-        // one snapshot, but it was 50 days ago
-        let (server, client_id, versions) = av_setup(1, Some(0), Some(50))?;
+    fn add_version_and_snapshot_conflict() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, Some(0), None)?;
 
-        let result = server.add_version(client_id, versions[0], vec![1, 2, 3])?;
+        assert_eq!(
+            server.add_version_and_snapshot(client_id, versions[1], vec![3], b"snap".to_vec())?,
+            AddVersionResult::ExpectedParentVersion(versions[2])
+        );
 
-        av_success_check(
-            &server,
-            client_id,
-            &versions,
-            result,
-            vec![1, 2, 3],
-            // urgency=high due to days since the snapshot
-            SnapshotUrgency::High,
-        )?;
+        // neither the version nor the snapshot was stored
+        let mut txn = server.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, versions[2]);
+        assert_eq!(client.snapshot.unwrap().version_id, versions[0]);
+        assert_eq!(txn.get_version_by_parent(versions[2])?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn rename_client() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, Some(1), None)?;
+        let new_client_id = Uuid::new_v4();
+
+        server.rename_client(client_id, new_client_id)?;
+
+        assert!(matches!(
+            server.get_child_version(client_id, NIL_VERSION_ID),
+            Err(ServerError::NoSuchClient)
+        ));
+
+        // the full chain and the snapshot are available under the new client ID
+        let mut parent_version_id = NIL_VERSION_ID;
+        for version_id in &versions {
+            let GetVersionResult::Success {
+                version_id: child_version_id,
+                ..
+            } = server
+                .get_child_version(new_client_id, parent_version_id)?
+                .0
+            else {
+                panic!("version {version_id} not found");
+            };
+            assert_eq!(child_version_id, *version_id);
+            parent_version_id = child_version_id;
+        }
+        assert_eq!(
+            server.get_snapshot(new_client_id)?,
+            Some((versions[1], vec![1]))
+        );
 
         Ok(())
     }
 
     #[test]
-    fn add_version_success_snapshot_many_versions_ago() -> anyhow::Result<()> {
-        // one snapshot, but it was 50 versions ago
-        let (mut server, client_id, versions) = av_setup(50, Some(0), None)?;
-        server.config.snapshot_versions = 30;
+    fn rename_client_exists() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(2, None, None)?;
+        let other_client_id = Uuid::new_v4();
+        {
+            let mut txn = server.txn(other_client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.commit()?;
+        }
 
-        let result = server.add_version(client_id, versions[49], vec![1, 2, 3])?;
+        assert!(matches!(
+            server.rename_client(client_id, other_client_id),
+            Err(ServerError::ClientExists)
+        ));
 
-        av_success_check(
-            &server,
-            client_id,
-            &versions,
-            result,
-            vec![1, 2, 3],
-            // urgency=high due to number of versions since the snapshot
-            SnapshotUrgency::High,
-        )?;
+        // neither client was modified
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, versions[1]);
+        drop(txn);
+        let mut txn = server.txn(other_client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, NIL_VERSION_ID);
 
         Ok(())
     }
 
     #[test]
-    fn add_snapshot_success_latest() -> anyhow::Result<()> {
-        let (server, (client_id, version_id)) = setup(|txn, client_id| {
-            let version_id = Uuid::new_v4();
+    fn delete_client() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, Some(1), None)?;
+        server.set_app_metadata(client_id, b"name".to_vec())?;
 
-            // set up a task DB with one version in it
-            txn.new_client(version_id)?;
-            txn.add_version(version_id, NIL_VERSION_ID, vec![])?;
+        server.delete_client(client_id)?;
 
-            // add a snapshot for that version
-            Ok((client_id, version_id))
-        })?;
-        server.add_snapshot(client_id, version_id, vec![1, 2, 3])?;
+        let mut txn = server.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        for version_id in versions {
+            assert_eq!(txn.get_version(version_id)?, None);
+        }
+        assert_eq!(txn.get_app_metadata()?, None);
+        assert_eq!(txn.get_client_usage()?, ClientUsage::default());
+        drop(txn);
 
-        // verify the snapshot
+        // the client can be created again, from scratch
         let mut txn = server.txn(client_id)?;
-        let client = txn.get_client()?.unwrap();
-        let snapshot = client.snapshot.unwrap();
-        assert_eq!(snapshot.version_id, version_id);
-        assert_eq!(snapshot.versions_since, 0);
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
         assert_eq!(
-            txn.get_snapshot_data(version_id).unwrap(),
-            Some(vec![1, 2, 3])
+            server.get_child_version(client_id, NIL_VERSION_ID)?.0,
+            GetVersionResult::NotFound
         );
 
         Ok(())
     }
 
     #[test]
-    fn add_snapshot_success_older() -> anyhow::Result<()> {
-        let (server, (client_id, version_id_1)) = setup(|txn, client_id| {
-            let version_id_1 = Uuid::new_v4();
-            let version_id_2 = Uuid::new_v4();
+    fn delete_client_no_such_client() -> anyhow::Result<()> {
+        let (server, _) = setup(|_, _| Ok(()))?;
 
-            // set up a task DB with two versions in it
-            txn.new_client(version_id_2)?;
-            txn.add_version(version_id_1, NIL_VERSION_ID, vec![])?;
-            txn.add_version(version_id_2, version_id_1, vec![])?;
+        assert!(matches!(
+            server.delete_client(Uuid::new_v4()),
+            Err(ServerError::NoSuchClient)
+        ));
 
-            Ok((client_id, version_id_1))
-        })?;
-        // add a snapshot for version 1
-        server.add_snapshot(client_id, version_id_1, vec![1, 2, 3])?;
+        Ok(())
+    }
+
+    #[test]
+    fn rename_client_no_such_client() -> anyhow::Result<()> {
+        let (server, _) = setup(|_, _| Ok(()))?;
+
+        assert!(matches!(
+            server.rename_client(Uuid::new_v4(), Uuid::new_v4()),
+            Err(ServerError::NoSuchClient)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn recompute_latest_version_repairs() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+
+        // corrupt the latest version
+        {
+            let mut txn = server.txn(client_id)?;
+            txn.set_latest_version_id(versions[0])?;
+            txn.set_chain_hash(Some(ChainHash::of_versions([versions[0]])))?;
+            txn.commit()?;
+        }
+
+        assert_eq!(server.recompute_latest_version(client_id)?, versions[2]);
 
-        // verify the snapshot
         let mut txn = server.txn(client_id)?;
         let client = txn.get_client()?.unwrap();
-        let snapshot = client.snapshot.unwrap();
-        assert_eq!(snapshot.version_id, version_id_1);
-        assert_eq!(snapshot.versions_since, 0);
-        assert_eq!(
-            txn.get_snapshot_data(version_id_1).unwrap(),
-            Some(vec![1, 2, 3])
-        );
+        assert_eq!(client.latest_version_id, versions[2]);
+        // the chain hash for the corrupted latest version is discarded
+        assert_eq!(client.chain_hash, None);
 
         Ok(())
     }
 
     #[test]
-    fn add_snapshot_fails_no_such() -> anyhow::Result<()> {
+    fn recompute_latest_version_no_versions() -> anyhow::Result<()> {
         let (server, client_id) = setup(|txn, client_id| {
-            let version_id_1 = Uuid::new_v4();
-            let version_id_2 = Uuid::new_v4();
+            txn.new_client(Uuid::new_v4())?;
+            Ok(client_id)
+        })?;
 
-            // set up a task DB with two versions in it
-            txn.new_client(version_id_2)?;
-            txn.add_version(version_id_1, NIL_VERSION_ID, vec![])?;
-            txn.add_version(version_id_2, version_id_1, vec![])?;
+        assert_eq!(server.recompute_latest_version(client_id)?, NIL_VERSION_ID);
 
-            // add a snapshot for unknown version
+        Ok(())
+    }
+
+    #[test]
+    fn chain_hash() -> anyhow::Result<()> {
+        let (mut server, client_id) = setup(|txn, client_id| {
+            txn.new_client(NIL_VERSION_ID)?;
             Ok(client_id)
         })?;
+        server.config.chain_hash = true;
+        assert_eq!(
+            server.get_chain_hash(client_id)?,
+            Some((NIL_VERSION_ID, ChainHash::EMPTY))
+        );
 
-        let version_id_unk = Uuid::new_v4();
-        server.add_snapshot(client_id, version_id_unk, vec![1, 2, 3])?;
+        let mut version_ids = vec![];
+        let mut parent_version_id = NIL_VERSION_ID;
+        for _ in 0..3 {
+            let (AddVersionResult::Ok(version_id), _) =
+                server.add_version(client_id, parent_version_id, vec![1])?
+            else {
+                panic!("version not added");
+            };
+            version_ids.push(version_id);
+            parent_version_id = version_id;
+
+            // the hash changes with each version, and matches a recomputation from the chain
+            assert_eq!(
+                server.get_chain_hash(client_id)?,
+                Some((version_id, ChainHash::of_versions(version_ids.clone())))
+            );
+        }
 
-        // verify the snapshot does not exist
-        let mut txn = server.txn(client_id)?;
-        let client = txn.get_client()?.unwrap();
-        assert!(client.snapshot.is_none());
+        let (AddVersionsResult::Ok(batch), _) =
+            server.add_versions(client_id, parent_version_id, vec![vec![2], vec![3]])?
+        else {
+            panic!("versions not added");
+        };
+        version_ids.extend(batch);
+        assert_eq!(
+            server.get_chain_hash(client_id)?,
+            Some((version_ids[4], ChainHash::of_versions(version_ids.clone())))
+        );
 
         Ok(())
     }
 
     #[test]
-    fn add_snapshot_fails_too_old() -> anyhow::Result<()> {
-        let (server, (client_id, version_ids)) = setup(|txn, client_id| {
-            let mut version_id = Uuid::new_v4();
-            let mut parent_version_id = Uuid::nil();
-            let mut version_ids = vec![];
+    fn chain_hash_unknown_history() -> anyhow::Result<()> {
+        // versions added before the chain hash was tracked
+        let (mut server, client_id, versions) = av_setup(3, None, None)?;
+        server.config.chain_hash = true;
+        assert_eq!(server.get_chain_hash(client_id)?, None);
 
-            // set up a task DB with 10 versions in it (oldest to newest)
-            txn.new_client(Uuid::nil())?;
-            for _ in 0..10 {
-                txn.add_version(version_id, parent_version_id, vec![])?;
-                version_ids.push(version_id);
-                parent_version_id = version_id;
-                version_id = Uuid::new_v4();
-            }
+        server.add_version(client_id, versions[2], vec![1])?;
+        assert_eq!(server.get_chain_hash(client_id)?, None);
 
-            // add a snapshot for the earliest of those
-            Ok((client_id, version_ids))
+        Ok(())
+    }
+
+    #[test]
+    fn chain_hash_disabled() -> anyhow::Result<()> {
+        let (server, client_id, versions) = av_setup(3, None, None)?;
+        {
+            let mut txn = server.txn(client_id)?;
+            txn.set_chain_hash(Some(ChainHash::of_versions(versions.clone())))?;
+            txn.commit()?;
+        }
+
+        // adding a version without tracking the chain hash clears it
+        server.add_version(client_id, versions[2], vec![1])?;
+        assert_eq!(server.get_chain_hash(client_id)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn recompute_latest_version_branched() -> anyhow::Result<()> {
+        let (server, (client_id, version_id)) = setup(|txn, client_id| {
+            let version_id = Uuid::new_v4();
+            txn.new_client(version_id)?;
+            txn.add_version(version_id, NIL_VERSION_ID, vec![])?;
+            // a version disconnected from the rest of the history
+            txn.add_version(Uuid::new_v4(), Uuid::new_v4(), vec![])?;
+            txn.set_latest_version_id(version_id)?;
+            Ok((client_id, version_id))
         })?;
-        server.add_snapshot(client_id, version_ids[0], vec![1, 2, 3])?;
 
-        // verify the snapshot does not exist
+        assert!(matches!(
+            server.recompute_latest_version(client_id),
+            Err(ServerError::BranchedHistory)
+        ));
+
+        // the client was not modified
         let mut txn = server.txn(client_id)?;
-        let client = txn.get_client()?.unwrap();
-        assert!(client.snapshot.is_none());
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
 
         Ok(())
     }
 
     #[test]
-    fn add_snapshot_fails_newer_exists() -> anyhow::Result<()> {
-        let (server, (client_id, version_ids)) = setup(|txn, client_id| {
-            let mut version_id = Uuid::new_v4();
-            let mut parent_version_id = Uuid::nil();
-            let mut version_ids = vec![];
+    fn recompute_latest_version_no_such_client() -> anyhow::Result<()> {
+        let (server, _) = setup(|_, _| Ok(()))?;
 
-            // set up a task DB with 5 versions in it (oldest to newest) and a snapshot of the
-            // middle one
-            txn.new_client(Uuid::nil())?;
-            for _ in 0..5 {
-                txn.add_version(version_id, parent_version_id, vec![])?;
+        assert!(matches!(
+            server.recompute_latest_version(Uuid::new_v4()),
+            Err(ServerError::NoSuchClient)
+        ));
+
+        Ok(())
+    }
+
+    /// Create a storage containing `num_clients` clients, each with a few versions, and some with
+    /// a snapshot, activity, a chain hash, or application metadata. Returns the client IDs.
+    fn backfill_source(num_clients: u8) -> anyhow::Result<(InMemoryStorage, Vec<ClientId>)> {
+        let storage = InMemoryStorage::new();
+        let mut client_ids = Vec::new();
+        for i in 0..num_clients {
+            let client_id = Uuid::new_v4();
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            let mut version_ids = Vec::new();
+            let mut parent_version_id = NIL_VERSION_ID;
+            for j in 0..3 {
+                let version_id = Uuid::new_v4();
+                txn.add_version(version_id, parent_version_id, vec![i, j])?;
                 version_ids.push(version_id);
                 parent_version_id = version_id;
-                version_id = Uuid::new_v4();
             }
-            txn.set_snapshot(
-                Snapshot {
-                    version_id: version_ids[2],
-                    versions_since: 2,
-                    timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
-                },
-                vec![1, 2, 3],
-            )?;
+            if i % 2 == 0 {
+                txn.set_snapshot(
+                    Snapshot {
+                        version_id: version_ids[1],
+                        timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
+                        versions_since: 1,
+                    },
+                    vec![i; 5],
+                    None,
+                )?;
+                txn.set_last_activity(Utc.with_ymd_and_hms(2002, 1, 1, 0, 0, 0).unwrap())?;
+                txn.set_chain_hash(Some(ChainHash::of_versions(version_ids)))?;
+                txn.set_app_metadata(vec![i])?;
+            }
+            txn.commit()?;
+            client_ids.push(client_id);
+        }
+        Ok((storage, client_ids))
+    }
 
-            // add a snapshot for the earliest of those
-            Ok((client_id, version_ids))
-        })?;
+    /// Assert that the given client is the same in both storages.
+    fn assert_client_copied(
+        source: &dyn Storage,
+        target: &dyn Storage,
+        client_id: ClientId,
+    ) -> anyhow::Result<()> {
+        let mut source_txn = source.txn(client_id)?;
+        let mut target_txn = target.txn(client_id)?;
+        let client = source_txn.get_client()?.unwrap();
+        assert_eq!(target_txn.get_client()?, Some(client.clone()));
+
+        let mut version_id = client.latest_version_id;
+        while version_id != NIL_VERSION_ID {
+            let version = source_txn.get_version(version_id)?.unwrap();
+            assert_eq!(target_txn.get_version(version_id)?, Some(version.clone()));
+            version_id = version.parent_version_id;
+        }
+        if let Some(snapshot) = client.snapshot {
+            assert_eq!(
+                target_txn.get_snapshot_data(snapshot.version_id)?,
+                source_txn.get_snapshot_data(snapshot.version_id)?
+            );
+        }
+        assert_eq!(
+            target_txn.get_app_metadata()?,
+            source_txn.get_app_metadata()?
+        );
+        Ok(())
+    }
 
-        server.add_snapshot(client_id, version_ids[0], vec![9, 9, 9])?;
+    #[test]
+    fn list_clients() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let mut client_ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+        client_ids.sort();
+        for (i, client_id) in client_ids.iter().enumerate() {
+            let mut txn = storage.txn(*client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            let mut parent_version_id = NIL_VERSION_ID;
+            for _ in 0..i {
+                let version_id = Uuid::new_v4();
+                txn.add_version(version_id, parent_version_id, vec![1, 2])?;
+                parent_version_id = version_id;
+            }
+            txn.commit()?;
+        }
+        let server = Server::new(ServerConfig::default(), storage);
 
-        // verify the snapshot was not replaced
-        let mut txn = server.txn(client_id)?;
-        let client = txn.get_client()?.unwrap();
-        let snapshot = client.snapshot.unwrap();
-        assert_eq!(snapshot.version_id, version_ids[2]);
-        assert_eq!(snapshot.versions_since, 2);
+        let clients = server.list_clients(None, 10)?;
         assert_eq!(
-            txn.get_snapshot_data(version_ids[2]).unwrap(),
-            Some(vec![1, 2, 3])
+            clients.iter().map(|c| c.client_id).collect::<Vec<_>>(),
+            client_ids
+        );
+        assert_eq!(
+            clients
+                .iter()
+                .map(|c| c.usage.version_count)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 4]
         );
 
+        // page through the clients, two at a time
+        let mut listed = Vec::new();
+        let mut after = None;
+        loop {
+            let page = server.list_clients(after, 2)?;
+            assert!(page.len() <= 2);
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = Some(last.client_id);
+            listed.extend(page.into_iter().map(|c| c.client_id));
+        }
+        assert_eq!(listed, client_ids);
+
+        assert!(server.list_clients(Some(client_ids[4]), 10)?.is_empty());
         Ok(())
     }
 
     #[test]
-    fn add_snapshot_fails_nil_version() -> anyhow::Result<()> {
-        let (server, client_id) = setup(|txn, client_id| {
-            // just set up the client
+    fn snapshot_stats() -> anyhow::Result<()> {
+        let storage = InMemoryStorage::new();
+        let server = Server::new(ServerConfig::default(), storage);
+        assert_eq!(server.snapshot_stats()?, SnapshotStats::default());
+
+        // one client without a snapshot, and two with snapshots of different ages
+        let oldest = Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap();
+        for snapshot in [
+            None,
+            Some((oldest, 3)),
+            Some((oldest + Duration::days(2), 7)),
+        ] {
+            let mut txn = server.txn(Uuid::new_v4())?;
+            let version_id = Uuid::new_v4();
             txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(version_id, NIL_VERSION_ID, vec![1])?;
+            if let Some((timestamp, versions_since)) = snapshot {
+                let snapshot = Snapshot {
+                    version_id,
+                    timestamp,
+                    versions_since,
+                };
+                txn.set_snapshot(snapshot, vec![2], None)?;
+            }
+            txn.commit()?;
+        }
 
-            // add a snapshot for the nil version
-            Ok(client_id)
-        })?;
+        assert_eq!(
+            server.snapshot_stats()?,
+            SnapshotStats {
+                oldest_snapshot_timestamp: Some(oldest),
+                max_versions_since_snapshot: 7,
+            }
+        );
+        Ok(())
+    }
 
-        server.add_snapshot(client_id, NIL_VERSION_ID, vec![9, 9, 9])?;
+    #[test]
+    fn backfill_into() -> anyhow::Result<()> {
+        let (source, client_ids) = backfill_source(4)?;
+        let server = Server::new(ServerConfig::default(), source);
+        let target = InMemoryStorage::new();
+
+        let mut checkpoints = Vec::new();
+        let copied = server.backfill_into(&target, None, |checkpoint| {
+            checkpoints.push(checkpoint.last_client_id);
+            Ok(())
+        })?;
+        assert_eq!(copied, 4);
 
-        // verify the snapshot does not exist
-        let mut txn = server.txn(client_id)?;
-        let client = txn.get_client()?.unwrap();
-        assert!(client.snapshot.is_none());
+        let mut sorted_client_ids = client_ids.clone();
+        sorted_client_ids.sort();
+        assert_eq!(checkpoints, sorted_client_ids);
 
+        for client_id in client_ids {
+            assert_client_copied(server.storage.as_ref(), &target, client_id)?;
+        }
+        assert_eq!(target.global_stats()?, server.global_stats()?);
         Ok(())
     }
 
     #[test]
-    fn get_snapshot_found() -> anyhow::Result<()> {
-        let (server, (client_id, data, snapshot_version_id)) = setup(|txn, client_id| {
-            let data = vec![1, 2, 3];
-            let snapshot_version_id = Uuid::new_v4();
+    fn backfill_into_resume() -> anyhow::Result<()> {
+        let (source, client_ids) = backfill_source(5)?;
+        let server = Server::new(ServerConfig::default(), source);
+        let target = InMemoryStorage::new();
+
+        // interrupt the backfill after the second client
+        let mut last_checkpoint = None;
+        let result = server.backfill_into(&target, None, |checkpoint| {
+            last_checkpoint = Some(checkpoint);
+            if target.global_stats()?.clients == 2 {
+                anyhow::bail!("interrupted");
+            }
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(target.global_stats()?.clients, 2);
 
-            txn.new_client(snapshot_version_id)?;
-            txn.set_snapshot(
-                Snapshot {
-                    version_id: snapshot_version_id,
-                    versions_since: 3,
-                    timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
-                },
-                data.clone(),
-            )?;
-            Ok((client_id, data, snapshot_version_id))
-        })?;
-        assert_eq!(
-            server.get_snapshot(client_id)?,
-            Some((snapshot_version_id, data))
-        );
+        // resuming copies only the remaining clients
+        let copied = server.backfill_into(&target, last_checkpoint, |_| Ok(()))?;
+        assert_eq!(copied, 3);
 
+        for client_id in client_ids {
+            assert_client_copied(server.storage.as_ref(), &target, client_id)?;
+        }
         Ok(())
     }
 
     #[test]
-    fn get_snapshot_not_found() -> anyhow::Result<()> {
-        let (server, client_id) = setup(|txn, client_id| {
+    fn backfill_into_existing_client() -> anyhow::Result<()> {
+        let (source, client_ids) = backfill_source(2)?;
+        let server = Server::new(ServerConfig::default(), source);
+        let target = InMemoryStorage::new();
+        {
+            let mut txn = target.txn(client_ids[0])?;
             txn.new_client(NIL_VERSION_ID)?;
-            Ok(client_id)
-        })?;
+            txn.commit()?;
+        }
 
-        assert_eq!(server.get_snapshot(client_id)?, None);
+        let copied = server.backfill_into(&target, None, |_| Ok(()))?;
+        assert_eq!(copied, 1);
 
+        // the existing client was left unchanged
+        let mut txn = target.txn(client_ids[0])?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, NIL_VERSION_ID);
+        drop(txn);
+        assert_client_copied(server.storage.as_ref(), &target, client_ids[1])?;
         Ok(())
     }
 }
@@ -1,13 +1,24 @@
+use crate::ChainHash;
+use anyhow::Context;
 use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::io::Read;
 use uuid::Uuid;
 
 /// A representation of stored metadata about a client.
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Client {
-    /// The latest version for this client (may be the nil version)
+    /// The latest version for this client (may be [`crate::NIL_VERSION_ID`])
     pub latest_version_id: Uuid,
     /// Data about the latest snapshot for this client
     pub snapshot: Option<Snapshot>,
+    /// Timestamp of the client's most recent activity, if any has been recorded
+    pub last_activity_at: Option<DateTime<Utc>>,
+    /// Hash of the client's history, up to `latest_version_id`, if it is being tracked
+    pub chain_hash: Option<ChainHash>,
+    /// The latest version the client has reported applying, if it has reported any. Versions
+    /// before this are no longer needed by the client.
+    pub acked_version_id: Option<Uuid>,
 }
 
 /// Metadata about a snapshot, not including the snapshot data itself.
@@ -33,6 +44,39 @@ pub struct Version {
     pub history_segment: Vec<u8>,
 }
 
+/// The storage used by a single client.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct ClientUsage {
+    /// Number of versions
+    pub version_count: u64,
+    /// Total size, in bytes, of the history segments of all versions
+    pub total_history_bytes: u64,
+    /// Size, in bytes, of the snapshot data, or zero if there is no snapshot
+    pub snapshot_bytes: u64,
+}
+
+/// Aggregate statistics about all clients in storage.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct GlobalStats {
+    /// Number of clients
+    pub clients: u64,
+    /// Number of clients which have a snapshot
+    pub clients_with_snapshot: u64,
+    /// Total number of versions, across all clients
+    pub versions: u64,
+}
+
+/// A description of a storage backend, for diagnostic purposes.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct BackendInfo {
+    /// Short name of the backend, such as `sqlite`
+    pub name: String,
+    /// Version of the backend's schema, if it has one
+    pub schema_version: Option<String>,
+    /// Backend-specific details, such as the location of the data
+    pub details: BTreeMap<String, String>,
+}
+
 /// A transaction in the storage backend.
 ///
 /// Transactions must be sequentially consistent. That is, the results of transactions performed
@@ -48,17 +92,88 @@ pub trait StorageTxn {
     /// Get information about the client for this transaction
     fn get_client(&mut self) -> anyhow::Result<Option<Client>>;
 
+    /// Get information about the client for this transaction, along with its latest version, if
+    /// that version exists.
+    ///
+    /// The default implementation calls `get_client` and `get_version`, but backends may override
+    /// this to do the same in a single query.
+    fn get_client_with_latest_version(
+        &mut self,
+    ) -> anyhow::Result<Option<(Client, Option<Version>)>> {
+        let Some(client) = self.get_client()? else {
+            return Ok(None);
+        };
+        let version = self.get_version(client.latest_version_id)?;
+        Ok(Some((client, version)))
+    }
+
+    /// Get the hash of the client's authentication token, if it has one, as the lower-case hex
+    /// SHA-256 digest of the token.
+    ///
+    /// Tokens are provisioned outside of the sync server, so the default implementation returns
+    /// `None`. Backends whose storage has a place for tokens may override this.
+    fn get_client_token_hash(&mut self) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+
     /// Create the client for this transaction, with the given latest_version_id. The client must
     /// not already exist.
+    ///
+    /// If the client does exist, such as when another transaction created it concurrently, this
+    /// fails with an error which downcasts to [`crate::ServerError::ClientExists`], and leaves the
+    /// existing client unchanged.
     fn new_client(&mut self, latest_version_id: Uuid) -> anyhow::Result<()>;
 
-    /// Set the client's most recent snapshot.
-    fn set_snapshot(&mut self, snapshot: Snapshot, data: Vec<u8>) -> anyhow::Result<()>;
+    /// Set the client's most recent snapshot, but only if the version of the currently-stored
+    /// snapshot is `expected_previous_version_id` (`None` meaning there is no snapshot).
+    ///
+    /// Returns false, without making any changes, if that precondition does not hold.
+    fn set_snapshot(
+        &mut self,
+        snapshot: Snapshot,
+        data: Vec<u8>,
+        expected_previous_version_id: Option<Uuid>,
+    ) -> anyhow::Result<bool>;
+
+    /// Set the client's most recent snapshot, as for `set_snapshot`, reading `size` bytes of
+    /// snapshot data from `data`.
+    ///
+    /// The default implementation reads the data into memory and calls `set_snapshot`, but
+    /// backends may override this to write the data to storage incrementally.
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        size: u64,
+        data: &mut dyn Read,
+        expected_previous_version_id: Option<Uuid>,
+    ) -> anyhow::Result<bool> {
+        let data = read_to_vec(data, size)?;
+        self.set_snapshot(snapshot, data, expected_previous_version_id)
+    }
 
     /// Get the data for the most recent snapshot.  The version_id
     /// is used to verify that the snapshot is for the correct version.
     fn get_snapshot_data(&mut self, version_id: Uuid) -> anyhow::Result<Option<Vec<u8>>>;
 
+    /// Get the size, in bytes, of the data for the most recent snapshot, without loading the data
+    /// itself.  The version_id is used to verify that the snapshot is for the correct version.
+    fn get_snapshot_size(&mut self, version_id: Uuid) -> anyhow::Result<Option<u64>>;
+
+    /// List the snapshots retained for this client, newest first, each with the size of its data
+    /// in bytes.
+    ///
+    /// The default implementation returns only the most recent snapshot, if any, which is
+    /// appropriate for backends which do not retain older snapshots.
+    fn list_snapshots(&mut self) -> anyhow::Result<Vec<(Snapshot, u64)>> {
+        let Some(snapshot) = self.get_client()?.and_then(|client| client.snapshot) else {
+            return Ok(vec![]);
+        };
+        Ok(self
+            .get_snapshot_size(snapshot.version_id)?
+            .map(|size| vec![(snapshot, size)])
+            .unwrap_or_default())
+    }
+
     /// Get a version, indexed by parent version id
     fn get_version_by_parent(&mut self, parent_version_id: Uuid)
         -> anyhow::Result<Option<Version>>;
@@ -66,9 +181,40 @@ pub trait StorageTxn {
     /// Get a version, indexed by its own version id
     fn get_version(&mut self, version_id: Uuid) -> anyhow::Result<Option<Version>>;
 
+    /// Get the IDs of all versions for this client which have no child version. For a client
+    /// with a linear history, this is a single version: the latest.
+    fn get_head_version_ids(&mut self) -> anyhow::Result<Vec<Uuid>>;
+
+    /// Get the storage used by this client, including any changes made in this transaction. A
+    /// client which does not exist uses no storage.
+    fn get_client_usage(&mut self) -> anyhow::Result<ClientUsage>;
+
+    /// Set the client's latest_version_id, without otherwise modifying the client.
+    fn set_latest_version_id(&mut self, latest_version_id: Uuid) -> anyhow::Result<()>;
+
+    /// Set the timestamp of the client's most recent activity, without otherwise modifying the
+    /// client.
+    fn set_last_activity(&mut self, timestamp: DateTime<Utc>) -> anyhow::Result<()>;
+
+    /// Set the client's chain hash, without otherwise modifying the client.
+    fn set_chain_hash(&mut self, chain_hash: Option<ChainHash>) -> anyhow::Result<()>;
+
+    /// Set the client's acked version, without otherwise modifying the client.
+    fn set_acked_version_id(&mut self, acked_version_id: Option<Uuid>) -> anyhow::Result<()>;
+
+    /// Get the client's application metadata, if any has been set.
+    ///
+    /// Application metadata is an opaque blob which applications built around the sync server
+    /// may use to attach their own data to a client. The sync server never interprets it.
+    fn get_app_metadata(&mut self) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Set the client's application metadata, replacing any existing metadata, without otherwise
+    /// modifying the client.
+    fn set_app_metadata(&mut self, app_metadata: Vec<u8>) -> anyhow::Result<()>;
+
     /// Add a version (that must not already exist), and
     ///  - update latest_version_id
-    ///  - increment snapshot.versions_since
+    ///  - increment snapshot.versions_since, saturating at `u32::MAX`
     fn add_version(
         &mut self,
         version_id: Uuid,
@@ -76,14 +222,128 @@ pub trait StorageTxn {
         history_segment: Vec<u8>,
     ) -> anyhow::Result<()>;
 
+    /// Add a version, as for `add_version`, reading `size` bytes of history segment from
+    /// `history_segment`.
+    ///
+    /// The default implementation reads the history segment into memory and calls `add_version`,
+    /// but backends may override this to write it to storage incrementally.
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        size: u64,
+        history_segment: &mut dyn Read,
+    ) -> anyhow::Result<()> {
+        let history_segment = read_to_vec(history_segment, size)?;
+        self.add_version(version_id, parent_version_id, history_segment)
+    }
+
+    /// Delete all versions older than the given version, found by following the chain of parent
+    /// versions backward from it. The given version itself, and any newer versions, are retained.
+    /// If the given version does not exist, this does nothing.
+    fn delete_versions_before(&mut self, version_id: Uuid) -> anyhow::Result<()>;
+
+    /// Move this client, including its snapshot and all of its versions, to the given client ID.
+    ///
+    /// Returns false, without making any changes, if a client with the new ID already exists. No
+    /// further changes may be made in the transaction after the client is moved, but it must
+    /// still be committed.
+    fn rename_client(&mut self, new_client_id: Uuid) -> anyhow::Result<bool>;
+
+    /// Delete this client, including its snapshot, application metadata, and all of its
+    /// versions. If the client does not exist, this does nothing. No further changes may be made
+    /// in the transaction after the client is deleted, but it must still be committed.
+    fn delete_client(&mut self) -> anyhow::Result<()>;
+
     /// Commit any changes made in the transaction.  It is an error to call this more than
     /// once.  It is safe to skip this call for read-only operations.
+    ///
+    /// A backend which detects that a concurrent transaction modified the client, and so does not
+    /// commit the changes, should fail with a [`TxnConflict`], so that the operation can be
+    /// retried in a new transaction.
     fn commit(&mut self) -> anyhow::Result<()>;
 }
 
+/// Read exactly `size` bytes from `reader` into a new vector.
+pub(crate) fn read_to_vec(reader: &mut dyn Read, size: u64) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(size.try_into().context("Data too large")?);
+    reader
+        .take(size)
+        .read_to_end(&mut data)
+        .context("Error reading data")?;
+    if data.len() as u64 != size {
+        anyhow::bail!("Expected {size} bytes of data, but got {}", data.len());
+    }
+    Ok(data)
+}
+
+/// The error with which [`StorageTxn::commit`] fails when a concurrent transaction for the same
+/// client modified it, so that this transaction's changes were not committed. The operation may
+/// succeed if it is repeated, from the beginning, in a new transaction; see
+/// [`crate::Server::retry_conflicts`].
+///
+/// This is returned within an [`anyhow::Error`], and can be detected with
+/// [`anyhow::Error::downcast_ref`].
+#[derive(Debug, thiserror::Error)]
+#[error("Client {0} was modified by a concurrent transaction")]
+pub struct TxnConflict(pub Uuid);
+
 /// A trait for objects able to act as storage.  Most of the interesting behavior is in the
 /// [`crate::storage::StorageTxn`] trait.
 pub trait Storage: Send + Sync {
     /// Begin a transaction for the given client ID.
     fn txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>>;
+
+    /// Begin a read-only transaction for the given client ID. No changes may be made in the
+    /// transaction. Backends may allow read-only transactions to proceed concurrently with one
+    /// another and with a transaction begun with [`Storage::txn`]; others may simply begin a
+    /// read-write transaction.
+    fn read_txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>>;
+
+    /// Get aggregate statistics about all clients. These need not be transactionally consistent.
+    fn global_stats(&self) -> anyhow::Result<GlobalStats>;
+
+    /// List the IDs of all clients, in no particular order. This need not be transactionally
+    /// consistent.
+    fn list_clients(&self) -> anyhow::Result<Vec<Uuid>>;
+
+    /// Describe this backend, for diagnostic purposes.
+    fn backend_info(&self) -> anyhow::Result<BackendInfo>;
+
+    /// Get the version of the schema of the data in this storage, as recorded in the storage.
+    fn schema_version(&self) -> anyhow::Result<u32>;
+
+    /// Get the version of the schema which this implementation reads and writes.
+    fn required_schema_version(&self) -> u32;
+
+    /// Perform maintenance which reclaims space left by deleted data, such as after pruning
+    /// versions or deleting clients. This may be slow, and may block other access to the storage
+    /// while it runs. The default implementation does nothing, for backends which need no such
+    /// maintenance.
+    fn maintenance(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Check that the storage's schema is the version its implementation requires, failing with
+/// guidance for the operator if not.
+///
+/// This should be called at startup, before serving any requests, so that a server which cannot
+/// correctly use the storage, such as an older server sharing storage with a newer one which has
+/// already migrated it, fails fast rather than misinterpreting the data.
+pub fn check_schema_version(storage: &dyn Storage) -> anyhow::Result<()> {
+    let actual = storage.schema_version()?;
+    let required = storage.required_schema_version();
+    match actual.cmp(&required) {
+        std::cmp::Ordering::Equal => Ok(()),
+        std::cmp::Ordering::Greater => anyhow::bail!(
+            "Storage schema version {actual} is newer than version {required}, required by this \
+             server; it has been migrated by a newer server, so upgrade this server to match"
+        ),
+        std::cmp::Ordering::Less => anyhow::bail!(
+            "Storage schema version {actual} is older than version {required}, required by this \
+             server, and has not been migrated; restart the server so that it can migrate the \
+             storage, or migrate it manually"
+        ),
+    }
 }
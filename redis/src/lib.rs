@@ -0,0 +1,1655 @@
+//! This crate implements a Redis storage backend for the TaskChampion sync server.
+//!
+//! Data is stored in the following keys, all beginning with a configurable prefix:
+//!
+//!  - `{prefix}:schema_version` - the version of this layout, if it has been recorded; if not,
+//!    the layout is version 1
+//!  - `{prefix}:clients` - a set containing the ID of every client
+//!  - `{prefix}:client:{client_id}` - a hash containing `latest_version_id`; if the client has
+//!    a snapshot, `snapshot_version_id`, `snapshot_timestamp`, and `versions_since_snapshot`; if
+//!    any activity has been recorded, `last_activity_at`; if it is known, `chain_hash`; and if
+//!    the client has acked a version, `acked_version_id`
+//!  - `{prefix}:client:{client_id}:snapshot` - the client's snapshot data
+//!  - `{prefix}:client:{client_id}:app_metadata` - the client's application metadata, if set
+//!  - `{prefix}:client:{client_id}:parents` - a hash mapping each version ID to its parent
+//!  - `{prefix}:client:{client_id}:children` - a hash mapping parent version IDs to a child
+//!  - `{prefix}:client:{client_id}:segments` - a hash mapping each version ID to its history
+//!    segment
+//!
+//! Only a standalone Redis server is supported, not Redis Cluster. A transaction's commit writes
+//! the client's keys and the `{prefix}:clients` set together, and these keys hash to different
+//! cluster slots, which Redis Cluster rejects.
+use anyhow::Context;
+use chrono::{DateTime, TimeZone, Utc};
+use redis::{Commands, Connection};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use taskchampion_sync_server_core::{
+    BackendInfo, ChainHash, Client, ClientUsage, GlobalStats, ServerError, Snapshot, Storage,
+    StorageTxn, TxnConflict, Version,
+};
+use uuid::Uuid;
+
+/// The default prefix for all keys.
+const DEFAULT_PREFIX: &str = "taskchampion";
+
+/// The version of the key layout used by [`RedisStorage`]. Increment this whenever the layout
+/// changes.
+const SCHEMA_VERSION: u32 = 1;
+
+/// A storage backend which uses Redis.
+///
+/// A new connection is opened for each transaction. Transactions are optimistic: the client's
+/// key is watched when the transaction begins, and writes are buffered and applied atomically on
+/// commit. If another transaction modified the client in the interim, the commit fails with a
+/// [`TxnConflict`] and the caller should retry.
+pub struct RedisStorage {
+    client: redis::Client,
+    prefix: String,
+}
+
+impl RedisStorage {
+    /// Create a new instance using the Redis server at the given URL, such as
+    /// `redis://localhost:6379/`.
+    pub fn new(url: &str) -> anyhow::Result<RedisStorage> {
+        Self::with_prefix(url, DEFAULT_PREFIX)
+    }
+
+    /// Create a new instance using the Redis server at the given URL, with all keys beginning
+    /// with the given prefix. This allows several servers to share a Redis database.
+    pub fn with_prefix(url: &str, prefix: impl Into<String>) -> anyhow::Result<RedisStorage> {
+        let client = redis::Client::open(url).context("Invalid Redis URL")?;
+        Ok(RedisStorage {
+            client,
+            prefix: prefix.into(),
+        })
+    }
+
+    fn new_connection(&self) -> anyhow::Result<Connection> {
+        self.client
+            .get_connection()
+            .context("Error connecting to Redis")
+    }
+
+    fn clients_key(&self) -> String {
+        format!("{}:clients", self.prefix)
+    }
+
+    fn client_key(&self, client_id: Uuid) -> String {
+        format!("{}:client:{}", self.prefix, client_id)
+    }
+}
+
+impl Storage for RedisStorage {
+    fn txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+        let mut con = self.new_connection()?;
+        let client_key = self.client_key(client_id);
+        // Every write modifies the client's key, so watching it detects any conflicting
+        // transaction.
+        redis::cmd("WATCH")
+            .arg(&client_key)
+            .query::<()>(&mut con)
+            .context("Error watching client")?;
+        Ok(Box::new(Txn {
+            con,
+            client_id,
+            prefix: self.prefix.clone(),
+            clients_key: self.clients_key(),
+            client_key,
+            client: None,
+            client_dirty: false,
+            snapshot_data: None,
+            app_metadata: None,
+            versions: Vec::new(),
+            deleted_versions: HashMap::new(),
+            renamed_to: None,
+            deleted: false,
+        }))
+    }
+
+    /// Redis transactions are optimistic, and never block one another, so a read-only
+    /// transaction is no different from any other.
+    fn read_txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+        self.txn(client_id)
+    }
+
+    fn global_stats(&self) -> anyhow::Result<GlobalStats> {
+        let mut con = self.new_connection()?;
+        let client_ids: Vec<String> = con
+            .smembers(self.clients_key())
+            .context("Error listing clients")?;
+        let mut stats = GlobalStats {
+            clients: client_ids.len() as u64,
+            ..GlobalStats::default()
+        };
+        for client_id in client_ids {
+            let client_key = format!("{}:client:{}", self.prefix, client_id);
+            let (has_snapshot, versions): (bool, u64) = redis::pipe()
+                .hexists(&client_key, "snapshot_version_id")
+                .hlen(format!("{client_key}:parents"))
+                .query(&mut con)
+                .context("Error counting client data")?;
+            stats.clients_with_snapshot += u64::from(has_snapshot);
+            stats.versions += versions;
+        }
+        Ok(stats)
+    }
+
+    fn list_clients(&self) -> anyhow::Result<Vec<Uuid>> {
+        let mut con = self.new_connection()?;
+        let client_ids: Vec<String> = con
+            .smembers(self.clients_key())
+            .context("Error listing clients")?;
+        client_ids.iter().map(|id| parse_uuid(id)).collect()
+    }
+
+    fn backend_info(&self) -> anyhow::Result<BackendInfo> {
+        let mut con = self.new_connection()?;
+        let info: redis::InfoDict = redis::cmd("INFO")
+            .arg("server")
+            .query(&mut con)
+            .context("Error getting Redis server info")?;
+        let mut details = BTreeMap::new();
+        details.insert("prefix".to_string(), self.prefix.clone());
+        if let Some(version) = info.get::<String>("redis_version") {
+            details.insert("redis_version".to_string(), version);
+        }
+        Ok(BackendInfo {
+            name: "redis".into(),
+            schema_version: Some(self.schema_version()?.to_string()),
+            details,
+        })
+    }
+
+    fn schema_version(&self) -> anyhow::Result<u32> {
+        let mut con = self.new_connection()?;
+        let version: Option<u32> = con
+            .get(format!("{}:schema_version", self.prefix))
+            .context("Error reading Redis schema version")?;
+        Ok(version.unwrap_or(1))
+    }
+
+    fn required_schema_version(&self) -> u32 {
+        SCHEMA_VERSION
+    }
+}
+
+struct Txn {
+    con: Connection,
+    client_id: Uuid,
+    prefix: String,
+    clients_key: String,
+    client_key: String,
+
+    /// The client, as read from Redis and modified in this transaction. This is `None` until it
+    /// is first read.
+    client: Option<Option<Client>>,
+    /// True if the client has been modified in this transaction.
+    client_dirty: bool,
+    /// Snapshot data set in this transaction.
+    snapshot_data: Option<Vec<u8>>,
+    /// Application metadata set in this transaction.
+    app_metadata: Option<Vec<u8>>,
+    /// Versions added in this transaction.
+    versions: Vec<Version>,
+    /// Stored versions deleted in this transaction, mapped to their parent version IDs.
+    deleted_versions: HashMap<Uuid, Uuid>,
+    /// The client ID to which this client is moved in this transaction, if any.
+    renamed_to: Option<Uuid>,
+    /// True if the client is deleted in this transaction.
+    deleted: bool,
+}
+
+impl Txn {
+    fn snapshot_key(&self) -> String {
+        format!("{}:snapshot", self.client_key)
+    }
+
+    fn app_metadata_key(&self) -> String {
+        format!("{}:app_metadata", self.client_key)
+    }
+
+    fn parents_key(&self) -> String {
+        format!("{}:parents", self.client_key)
+    }
+
+    fn children_key(&self) -> String {
+        format!("{}:children", self.client_key)
+    }
+
+    fn segments_key(&self) -> String {
+        format!("{}:segments", self.client_key)
+    }
+
+    /// Get the client, reading it from Redis if necessary.
+    fn client(&mut self) -> anyhow::Result<&mut Option<Client>> {
+        if self.client.is_none() {
+            let fields: HashMap<String, String> = self
+                .con
+                .hgetall(&self.client_key)
+                .context("Error getting client")?;
+            self.client = Some(client_from_fields(&fields)?);
+        }
+        Ok(self.client.as_mut().expect("client was just loaded"))
+    }
+
+    /// Get a version stored in Redis, given its version ID.
+    fn get_stored_version(&mut self, version_id: Uuid) -> anyhow::Result<Option<Version>> {
+        if self.deleted_versions.contains_key(&version_id) {
+            return Ok(None);
+        }
+        let (parent_version_id, history_segment): (Option<String>, Option<Vec<u8>>) = redis::pipe()
+            .hget(self.parents_key(), version_id.to_string())
+            .hget(self.segments_key(), version_id.to_string())
+            .query(&mut self.con)
+            .context("Error getting version")?;
+        let Some(parent_version_id) = parent_version_id else {
+            return Ok(None);
+        };
+        Ok(Some(Version {
+            version_id,
+            parent_version_id: parse_uuid(&parent_version_id)?,
+            history_segment: history_segment.unwrap_or_default(),
+        }))
+    }
+
+    /// Check that the client has a snapshot with the given version ID.
+    fn check_snapshot_version(&mut self, version_id: Uuid) -> anyhow::Result<bool> {
+        let Some(client) = self.client()? else {
+            return Ok(false);
+        };
+        if Some(version_id) != client.snapshot.as_ref().map(|snap| snap.version_id) {
+            return Err(anyhow::anyhow!("unexpected snapshot_version_id"));
+        }
+        Ok(true)
+    }
+}
+
+/// Parse a UUID stored in Redis.
+fn parse_uuid(s: &str) -> anyhow::Result<Uuid> {
+    Uuid::parse_str(s).with_context(|| format!("Invalid UUID {s:?} in Redis"))
+}
+
+/// Build a `Client` from the fields of its hash, or `None` if the hash does not exist.
+fn client_from_fields(fields: &HashMap<String, String>) -> anyhow::Result<Option<Client>> {
+    let Some(latest_version_id) = fields.get("latest_version_id") else {
+        return Ok(None);
+    };
+
+    // if all of the snapshot fields are present, return a snapshot
+    let snapshot = match (
+        fields.get("snapshot_version_id"),
+        fields.get("snapshot_timestamp"),
+        fields.get("versions_since_snapshot"),
+    ) {
+        (Some(v), Some(ts), Some(vs)) => Some(Snapshot {
+            version_id: parse_uuid(v)?,
+            timestamp: Utc
+                .timestamp_opt(ts.parse().context("Invalid snapshot_timestamp")?, 0)
+                .single()
+                .context("Invalid snapshot_timestamp")?,
+            versions_since: vs.parse().context("Invalid versions_since_snapshot")?,
+        }),
+        _ => None,
+    };
+    let last_activity_at = match fields.get("last_activity_at") {
+        Some(ts) => Some(
+            Utc.timestamp_opt(ts.parse().context("Invalid last_activity_at")?, 0)
+                .single()
+                .context("Invalid last_activity_at")?,
+        ),
+        None => None,
+    };
+    let chain_hash = match fields.get("chain_hash") {
+        Some(hash) => Some(hash.parse().context("Invalid chain_hash")?),
+        None => None,
+    };
+    let acked_version_id = match fields.get("acked_version_id") {
+        Some(v) => Some(parse_uuid(v)?),
+        None => None,
+    };
+    Ok(Some(Client {
+        latest_version_id: parse_uuid(latest_version_id)?,
+        snapshot,
+        last_activity_at,
+        chain_hash,
+        acked_version_id,
+    }))
+}
+
+impl StorageTxn for Txn {
+    fn get_client(&mut self) -> anyhow::Result<Option<Client>> {
+        Ok(self.client()?.clone())
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> anyhow::Result<()> {
+        if self.client()?.is_some() {
+            return Err(ServerError::ClientExists.into());
+        }
+        self.client = Some(Some(Client {
+            latest_version_id,
+            snapshot: None,
+            last_activity_at: None,
+            chain_hash: None,
+            acked_version_id: None,
+        }));
+        self.client_dirty = true;
+        self.snapshot_data = None;
+        Ok(())
+    }
+
+    fn set_snapshot(
+        &mut self,
+        snapshot: Snapshot,
+        data: Vec<u8>,
+        expected_previous_version_id: Option<Uuid>,
+    ) -> anyhow::Result<bool> {
+        let Some(client) = self.client()? else {
+            return Ok(false);
+        };
+        if client.snapshot.as_ref().map(|snap| snap.version_id) != expected_previous_version_id {
+            return Ok(false);
+        }
+        client.snapshot = Some(snapshot);
+        self.client_dirty = true;
+        self.snapshot_data = Some(data);
+        Ok(true)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> anyhow::Result<Option<Vec<u8>>> {
+        if !self.check_snapshot_version(version_id)? {
+            return Ok(None);
+        }
+        if let Some(data) = &self.snapshot_data {
+            return Ok(Some(data.clone()));
+        }
+        let snapshot_key = self.snapshot_key();
+        self.con.get(snapshot_key).context("Error getting snapshot")
+    }
+
+    fn get_snapshot_size(&mut self, version_id: Uuid) -> anyhow::Result<Option<u64>> {
+        if !self.check_snapshot_version(version_id)? {
+            return Ok(None);
+        }
+        if let Some(data) = &self.snapshot_data {
+            return Ok(Some(data.len() as u64));
+        }
+        let snapshot_key = self.snapshot_key();
+        let size: u64 = self
+            .con
+            .strlen(snapshot_key)
+            .context("Error getting snapshot size")?;
+        Ok(Some(size))
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> anyhow::Result<Option<Version>> {
+        if let Some(version) = self
+            .versions
+            .iter()
+            .find(|v| v.parent_version_id == parent_version_id)
+        {
+            return Ok(Some(version.clone()));
+        }
+        let children_key = self.children_key();
+        let version_id: Option<String> = self
+            .con
+            .hget(children_key, parent_version_id.to_string())
+            .context("Error getting version by parent")?;
+        match version_id {
+            Some(version_id) => self.get_stored_version(parse_uuid(&version_id)?),
+            None => Ok(None),
+        }
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> anyhow::Result<Option<Version>> {
+        if let Some(version) = self.versions.iter().find(|v| v.version_id == version_id) {
+            return Ok(Some(version.clone()));
+        }
+        self.get_stored_version(version_id)
+    }
+
+    fn get_head_version_ids(&mut self) -> anyhow::Result<Vec<Uuid>> {
+        let parents_key = self.parents_key();
+        let stored: HashMap<String, String> = self
+            .con
+            .hgetall(parents_key)
+            .context("Error getting head versions")?;
+        let mut parents = HashMap::new();
+        for (version_id, parent_version_id) in stored {
+            parents.insert(parse_uuid(&version_id)?, parse_uuid(&parent_version_id)?);
+        }
+        for version_id in self.deleted_versions.keys() {
+            parents.remove(version_id);
+        }
+        for version in &self.versions {
+            parents.insert(version.version_id, version.parent_version_id);
+        }
+        let has_child: HashSet<Uuid> = parents.values().copied().collect();
+        Ok(parents
+            .into_keys()
+            .filter(|version_id| !has_child.contains(version_id))
+            .collect())
+    }
+
+    fn get_client_usage(&mut self) -> anyhow::Result<ClientUsage> {
+        let stored: Vec<String> = self
+            .con
+            .hkeys(self.parents_key())
+            .context("Error listing versions")?;
+        let mut stored_ids = vec![];
+        for version_id in stored {
+            if !self
+                .deleted_versions
+                .contains_key(&parse_uuid(&version_id)?)
+            {
+                stored_ids.push(version_id);
+            }
+        }
+        let sizes: Vec<u64> = if stored_ids.is_empty() {
+            vec![]
+        } else {
+            let mut pipe = redis::pipe();
+            for version_id in &stored_ids {
+                pipe.cmd("HSTRLEN").arg(self.segments_key()).arg(version_id);
+            }
+            pipe.query(&mut self.con)
+                .context("Error getting history segment sizes")?
+        };
+
+        let mut usage = ClientUsage {
+            version_count: (stored_ids.len() + self.versions.len()) as u64,
+            total_history_bytes: sizes.iter().sum(),
+            snapshot_bytes: 0,
+        };
+        for version in &self.versions {
+            usage.total_history_bytes += version.history_segment.len() as u64;
+        }
+        let snapshot_version_id = self
+            .client()?
+            .as_ref()
+            .and_then(|client| client.snapshot.as_ref())
+            .map(|snap| snap.version_id);
+        if let Some(version_id) = snapshot_version_id {
+            usage.snapshot_bytes = self.get_snapshot_size(version_id)?.unwrap_or(0);
+        }
+        Ok(usage)
+    }
+
+    fn set_latest_version_id(&mut self, latest_version_id: Uuid) -> anyhow::Result<()> {
+        if let Some(client) = self.client()? {
+            client.latest_version_id = latest_version_id;
+            self.client_dirty = true;
+        }
+        Ok(())
+    }
+
+    fn set_last_activity(&mut self, timestamp: DateTime<Utc>) -> anyhow::Result<()> {
+        if let Some(client) = self.client()? {
+            client.last_activity_at = Some(timestamp);
+            self.client_dirty = true;
+        }
+        Ok(())
+    }
+
+    fn set_chain_hash(&mut self, chain_hash: Option<ChainHash>) -> anyhow::Result<()> {
+        if let Some(client) = self.client()? {
+            client.chain_hash = chain_hash;
+            self.client_dirty = true;
+        }
+        Ok(())
+    }
+
+    fn set_acked_version_id(&mut self, acked_version_id: Option<Uuid>) -> anyhow::Result<()> {
+        if let Some(client) = self.client()? {
+            client.acked_version_id = acked_version_id;
+            self.client_dirty = true;
+        }
+        Ok(())
+    }
+
+    fn get_app_metadata(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        if let Some(data) = &self.app_metadata {
+            return Ok(Some(data.clone()));
+        }
+        let app_metadata_key = self.app_metadata_key();
+        self.con
+            .get(app_metadata_key)
+            .context("Error getting app metadata")
+    }
+
+    fn set_app_metadata(&mut self, app_metadata: Vec<u8>) -> anyhow::Result<()> {
+        if self.client()?.is_some() {
+            // The client is rewritten, too, so that concurrent transactions conflict.
+            self.client_dirty = true;
+            self.app_metadata = Some(app_metadata);
+        }
+        Ok(())
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        if self.get_version(version_id)?.is_some() {
+            anyhow::bail!("Version {} already exists", version_id);
+        }
+        self.versions.push(Version {
+            version_id,
+            parent_version_id,
+            history_segment,
+        });
+
+        if let Some(client) = self.client()? {
+            client.latest_version_id = version_id;
+            if let Some(ref mut snap) = client.snapshot {
+                snap.versions_since = snap.versions_since.saturating_add(1);
+            }
+            self.client_dirty = true;
+        }
+        Ok(())
+    }
+
+    fn delete_versions_before(&mut self, version_id: Uuid) -> anyhow::Result<()> {
+        let Some(version) = self.get_version(version_id)? else {
+            return Ok(());
+        };
+        let mut vid = version.parent_version_id;
+        while let Some(version) = self.get_version(vid)? {
+            if let Some(i) = self.versions.iter().position(|v| v.version_id == vid) {
+                self.versions.remove(i);
+            } else {
+                self.deleted_versions.insert(vid, version.parent_version_id);
+            }
+            vid = version.parent_version_id;
+        }
+        // Mark the client as modified, so that the commit modifies its key and any concurrent
+        // transaction for the same client fails.
+        if !self.deleted_versions.is_empty() && self.client()?.is_some() {
+            self.client_dirty = true;
+        }
+        Ok(())
+    }
+
+    fn rename_client(&mut self, new_client_id: Uuid) -> anyhow::Result<bool> {
+        if self.client()?.is_none() {
+            anyhow::bail!("Client {} does not exist", self.client_id);
+        }
+        // Watch the new client's key, too, so that the commit fails if that client is created
+        // concurrently.
+        let new_client_key = format!("{}:client:{}", self.prefix, new_client_id);
+        redis::cmd("WATCH")
+            .arg(&new_client_key)
+            .query::<()>(&mut self.con)
+            .context("Error watching client")?;
+        let exists: bool = self
+            .con
+            .exists(&new_client_key)
+            .context("Error checking for client")?;
+        if exists {
+            return Ok(false);
+        }
+        self.renamed_to = Some(new_client_id);
+        Ok(true)
+    }
+
+    fn delete_client(&mut self) -> anyhow::Result<()> {
+        if self.client()?.is_some() {
+            self.deleted = true;
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> anyhow::Result<()> {
+        if !self.client_dirty
+            && self.versions.is_empty()
+            && self.deleted_versions.is_empty()
+            && self.renamed_to.is_none()
+            && !self.deleted
+        {
+            redis::cmd("UNWATCH")
+                .query::<()>(&mut self.con)
+                .context("Error unwatching client")?;
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        if self.client_dirty {
+            if let Some(Some(client)) = &self.client {
+                let mut fields = vec![("latest_version_id", client.latest_version_id.to_string())];
+                if let Some(snap) = &client.snapshot {
+                    fields.push(("snapshot_version_id", snap.version_id.to_string()));
+                    fields.push(("snapshot_timestamp", snap.timestamp.timestamp().to_string()));
+                    fields.push(("versions_since_snapshot", snap.versions_since.to_string()));
+                } else {
+                    pipe.del(self.snapshot_key()).ignore();
+                }
+                if let Some(last_activity_at) = client.last_activity_at {
+                    fields.push(("last_activity_at", last_activity_at.timestamp().to_string()));
+                }
+                if let Some(chain_hash) = client.chain_hash {
+                    fields.push(("chain_hash", chain_hash.to_string()));
+                }
+                if let Some(acked_version_id) = client.acked_version_id {
+                    fields.push(("acked_version_id", acked_version_id.to_string()));
+                }
+                pipe.del(&self.client_key)
+                    .ignore()
+                    .hset_multiple(&self.client_key, &fields)
+                    .ignore()
+                    .sadd(&self.clients_key, self.client_id.to_string())
+                    .ignore();
+            }
+        }
+        if let Some(data) = &self.snapshot_data {
+            pipe.set(self.snapshot_key(), data).ignore();
+        }
+        if let Some(data) = &self.app_metadata {
+            pipe.set(self.app_metadata_key(), data).ignore();
+        }
+        for version in &self.versions {
+            let version_id = version.version_id.to_string();
+            let parent_version_id = version.parent_version_id.to_string();
+            pipe.hset(self.parents_key(), &version_id, &parent_version_id)
+                .ignore()
+                .hset(self.children_key(), &parent_version_id, &version_id)
+                .ignore()
+                .hset(self.segments_key(), &version_id, &version.history_segment)
+                .ignore();
+        }
+        // The child index entry for the oldest retained version is kept, so that it can still be
+        // found by its parent's ID.
+        for (version_id, parent_version_id) in &self.deleted_versions {
+            let version_id = version_id.to_string();
+            pipe.hdel(self.parents_key(), &version_id)
+                .ignore()
+                .hdel(self.segments_key(), &version_id)
+                .ignore()
+                .hdel(self.children_key(), parent_version_id.to_string())
+                .ignore();
+        }
+
+        if self.deleted {
+            pipe.del(&[
+                self.client_key.clone(),
+                self.snapshot_key(),
+                self.app_metadata_key(),
+                self.parents_key(),
+                self.children_key(),
+                self.segments_key(),
+            ])
+            .ignore()
+            .srem(&self.clients_key, self.client_id.to_string())
+            .ignore();
+        }
+
+        if let Some(new_client_id) = self.renamed_to {
+            // RENAME fails if the key does not exist, so only rename those keys which exist or
+            // are written above.
+            let new_client_key = format!("{}:client:{}", self.prefix, new_client_id);
+            let has_snapshot =
+                matches!(&self.client, Some(Some(client)) if client.snapshot.is_some());
+            let has_versions: bool = self
+                .con
+                .exists(self.parents_key())
+                .context("Error checking for versions")?;
+            let has_versions = has_versions || !self.versions.is_empty();
+            let has_app_metadata: bool = self
+                .con
+                .exists(self.app_metadata_key())
+                .context("Error checking for app metadata")?;
+            let has_app_metadata = has_app_metadata || self.app_metadata.is_some();
+            pipe.rename(&self.client_key, &new_client_key).ignore();
+            if has_snapshot {
+                pipe.rename(self.snapshot_key(), format!("{new_client_key}:snapshot"))
+                    .ignore();
+            }
+            if has_app_metadata {
+                pipe.rename(
+                    self.app_metadata_key(),
+                    format!("{new_client_key}:app_metadata"),
+                )
+                .ignore();
+            }
+            if has_versions {
+                for suffix in ["parents", "children", "segments"] {
+                    pipe.rename(
+                        format!("{}:{suffix}", self.client_key),
+                        format!("{new_client_key}:{suffix}"),
+                    )
+                    .ignore();
+                }
+            }
+            pipe.srem(&self.clients_key, self.client_id.to_string())
+                .ignore()
+                .sadd(&self.clients_key, new_client_id.to_string())
+                .ignore();
+        }
+
+        let result: Option<redis::Value> = pipe
+            .query(&mut self.con)
+            .context("Error committing transaction")?;
+        if result.is_none() {
+            return Err(TxnConflict(self.client_id).into());
+        }
+
+        self.client_dirty = false;
+        self.snapshot_data = None;
+        self.app_metadata = None;
+        self.versions.clear();
+        self.deleted_versions.clear();
+        if std::mem::take(&mut self.deleted) {
+            self.client = Some(None);
+        }
+        if let Some(new_client_id) = self.renamed_to.take() {
+            self.client_key = format!("{}:client:{}", self.prefix, new_client_id);
+            self.client_id = new_client_id;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{
+        check_schema_version, AddVersionResult, Server, ServerConfig, NIL_VERSION_ID,
+    };
+
+    /// Get a storage instance for testing, with a unique prefix, using the Redis server given by
+    /// the `TEST_REDIS_URL` environment variable. If that is not set, the test is skipped.
+    fn storage() -> anyhow::Result<Option<RedisStorage>> {
+        let Ok(url) = std::env::var("TEST_REDIS_URL") else {
+            eprintln!("TEST_REDIS_URL not set; skipping test");
+            return Ok(None);
+        };
+        Ok(Some(RedisStorage::with_prefix(
+            &url,
+            format!("test-{}", Uuid::new_v4()),
+        )?))
+    }
+
+    #[test]
+    fn test_invalid_url() {
+        assert!(RedisStorage::new("not a url").is_err());
+    }
+
+    #[test]
+    fn test_global_stats() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        assert_eq!(storage.global_stats()?, GlobalStats::default());
+
+        for i in 0..3 {
+            let mut txn = storage.txn(Uuid::new_v4())?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![])?;
+            if i == 0 {
+                let snap = Snapshot {
+                    version_id: Uuid::new_v4(),
+                    timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+                    versions_since: 0,
+                };
+                txn.set_snapshot(snap, vec![1], None)?;
+            }
+            txn.commit()?;
+        }
+
+        assert_eq!(
+            storage.global_stats()?,
+            GlobalStats {
+                clients: 3,
+                clients_with_snapshot: 1,
+                versions: 3,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_clients() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        assert!(storage.list_clients()?.is_empty());
+
+        let client_ids: HashSet<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for client_id in &client_ids {
+            let mut txn = storage.txn(*client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.commit()?;
+        }
+
+        let listed: HashSet<Uuid> = storage.list_clients()?.into_iter().collect();
+        assert_eq!(listed, client_ids);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backend_info() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let info = storage.backend_info()?;
+        assert_eq!(info.name, "redis");
+        assert_eq!(info.details.get("prefix"), Some(&storage.prefix));
+        assert!(info.details.contains_key("redis_version"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema_version() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        assert_eq!(storage.schema_version()?, SCHEMA_VERSION);
+        check_schema_version(&storage)?;
+
+        let mut con = storage.new_connection()?;
+        let key = format!("{}:schema_version", storage.prefix);
+        con.set::<_, _, ()>(&key, SCHEMA_VERSION + 1)?;
+        assert_eq!(storage.schema_version()?, SCHEMA_VERSION + 1);
+        assert!(check_schema_version(&storage).is_err());
+        con.del::<_, ()>(&key)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_client_usage() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_client_usage()?, ClientUsage::default());
+
+        let (v1, v2, v3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        txn.add_version(v1, NIL_VERSION_ID, vec![1; 10])?;
+        txn.add_version(v2, v1, vec![2; 20])?;
+        txn.add_version(v3, v2, vec![3; 30])?;
+        let snap = Snapshot {
+            version_id: v3,
+            timestamp: Utc::now(),
+            versions_since: 0,
+        };
+        txn.set_snapshot(snap, vec![4; 5], None)?;
+        txn.commit()?;
+        drop(txn);
+
+        // another client's versions are not counted
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![5; 100])?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(
+            txn.get_client_usage()?,
+            ClientUsage {
+                version_count: 3,
+                total_history_bytes: 60,
+                snapshot_bytes: 5,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_client_empty() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        let maybe_client = txn.get_client()?;
+        assert!(maybe_client.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_storage() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        let latest_version_id = Uuid::new_v4();
+        txn.new_client(latest_version_id)?;
+
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, latest_version_id);
+        assert!(client.snapshot.is_none());
+
+        let latest_version_id = Uuid::new_v4();
+        txn.add_version(latest_version_id, Uuid::new_v4(), vec![1, 1])?;
+
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, latest_version_id);
+        assert!(client.snapshot.is_none());
+
+        let snap = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: "2014-11-28T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 4,
+        };
+        txn.set_snapshot(snap.clone(), vec![1, 2, 3], None)?;
+
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, latest_version_id);
+        assert_eq!(client.snapshot.clone().unwrap(), snap);
+        txn.commit()?;
+
+        // the committed client is visible in a new transaction
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, Some(client));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_uncommitted() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![])?;
+            // dropped without committing
+        }
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        assert!(txn.get_version_by_parent(NIL_VERSION_ID)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_uncommitted_existing_client() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, b"v1".to_vec())?;
+        txn.commit()?;
+
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.add_version(Uuid::new_v4(), version_id, b"v2".to_vec())?;
+            let snap = Snapshot {
+                version_id,
+                timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+                versions_since: 0,
+            };
+            txn.set_snapshot(snap, vec![1, 2, 3], None)?;
+            // dropped without committing
+        }
+
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, version_id);
+        assert_eq!(client.snapshot, None);
+        assert!(txn.get_version_by_parent(version_id)?.is_none());
+        assert_eq!(txn.get_head_version_ids()?, vec![version_id]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn1 = storage.txn(client_id)?;
+        let mut txn2 = storage.txn(client_id)?;
+
+        assert!(txn1.get_client()?.is_none());
+        assert!(txn2.get_client()?.is_none());
+        txn1.new_client(NIL_VERSION_ID)?;
+        txn2.new_client(NIL_VERSION_ID)?;
+
+        txn1.commit()?;
+        let err = txn2.commit().unwrap_err();
+        assert!(err.downcast_ref::<TxnConflict>().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_conflicts() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
+        let server = Server::new(ServerConfig::default(), storage);
+
+        let mut attempts = 0;
+        let (result, _) = server.retry_conflicts(|| {
+            attempts += 1;
+            let mut txn = server.txn(client_id)?;
+            let result = server.add_version_in_txn(txn.as_mut(), NIL_VERSION_ID, vec![1])?;
+            if attempts == 1 {
+                // add a version in another transaction, before this one commits
+                server.add_version(client_id, NIL_VERSION_ID, vec![2])?;
+            }
+            txn.commit()?;
+            Ok(result)
+        })?;
+        assert_eq!(attempts, 2);
+
+        // the retry sees the other version, and is rejected cleanly
+        let latest_version_id = server
+            .txn(client_id)?
+            .get_client()?
+            .unwrap()
+            .latest_version_id;
+        assert_eq!(
+            result,
+            AddVersionResult::ExpectedParentVersion(latest_version_id)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_client_with_latest_version() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        // no such client
+        assert_eq!(txn.get_client_with_latest_version()?, None);
+
+        // client without any versions
+        txn.new_client(NIL_VERSION_ID)?;
+        let (client, version) = txn.get_client_with_latest_version()?.unwrap();
+        assert_eq!(Some(client), txn.get_client()?);
+        assert_eq!(version, None);
+
+        // client with versions
+        let version_id_1 = Uuid::new_v4();
+        let version_id_2 = Uuid::new_v4();
+        txn.add_version(version_id_1, NIL_VERSION_ID, b"v1".to_vec())?;
+        txn.add_version(version_id_2, version_id_1, b"v2".to_vec())?;
+        let (client, version) = txn.get_client_with_latest_version()?.unwrap();
+        assert_eq!(client.latest_version_id, version_id_2);
+        assert_eq!(Some(client), txn.get_client()?);
+        assert_eq!(version, txn.get_version(version_id_2)?);
+        assert_eq!(version.unwrap().history_segment, b"v2".to_vec());
+
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_gvbp_empty() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        let maybe_version = txn.get_version_by_parent(Uuid::new_v4())?;
+        assert!(maybe_version.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_version_and_get_version() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        let version_id = Uuid::new_v4();
+        let parent_version_id = Uuid::new_v4();
+        let history_segment = b"abc".to_vec();
+        txn.add_version(version_id, parent_version_id, history_segment.clone())?;
+
+        let expected = Version {
+            version_id,
+            parent_version_id,
+            history_segment,
+        };
+
+        let version = txn.get_version_by_parent(parent_version_id)?.unwrap();
+        assert_eq!(version, expected);
+
+        let version = txn.get_version(version_id)?.unwrap();
+        assert_eq!(version, expected);
+
+        txn.commit()?;
+
+        // the committed version is visible in a new transaction
+        let mut txn = storage.txn(client_id)?;
+        let version = txn.get_version_by_parent(parent_version_id)?.unwrap();
+        assert_eq!(version, expected);
+
+        let version = txn.get_version(version_id)?.unwrap();
+        assert_eq!(version, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_version_empty() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        let version_id = Uuid::new_v4();
+        let parent_version_id = Uuid::new_v4();
+        txn.add_version(version_id, parent_version_id, vec![])?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        let version = txn.get_version(version_id)?.unwrap();
+        assert_eq!(version.history_segment, Vec::<u8>::new());
+
+        let version = txn.get_version_by_parent(parent_version_id)?.unwrap();
+        assert_eq!(version.history_segment, Vec::<u8>::new());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_version_exists() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        let version_id = Uuid::new_v4();
+        let parent_version_id = Uuid::new_v4();
+        let history_segment = b"abc".to_vec();
+        txn.add_version(version_id, parent_version_id, history_segment.clone())?;
+        assert!(txn
+            .add_version(version_id, parent_version_id, history_segment.clone())
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_head_version_ids() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+
+        // versions for another client are not included
+        {
+            let mut txn = storage.txn(Uuid::new_v4())?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![])?;
+            txn.commit()?;
+        }
+
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        assert!(txn.get_head_version_ids()?.is_empty());
+
+        let version_id_1 = Uuid::new_v4();
+        let version_id_2 = Uuid::new_v4();
+        txn.add_version(version_id_1, NIL_VERSION_ID, vec![])?;
+        txn.add_version(version_id_2, version_id_1, vec![])?;
+        assert_eq!(txn.get_head_version_ids()?, vec![version_id_2]);
+        txn.commit()?;
+
+        // a second child of the same parent is another head
+        let mut txn = storage.txn(client_id)?;
+        let version_id_3 = Uuid::new_v4();
+        txn.add_version(version_id_3, version_id_1, vec![])?;
+        let mut heads = txn.get_head_version_ids()?;
+        heads.sort();
+        let mut expected = vec![version_id_2, version_id_3];
+        expected.sort();
+        assert_eq!(heads, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_latest_version_id() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        let snap = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 3,
+        };
+        txn.set_snapshot(snap.clone(), vec![1], None)?;
+
+        let latest_version_id = Uuid::new_v4();
+        txn.set_latest_version_id(latest_version_id)?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, latest_version_id);
+        assert_eq!(client.snapshot, Some(snap));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_last_activity() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_client()?.unwrap().last_activity_at, None);
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        let timestamp = "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap();
+        txn.set_last_activity(timestamp)?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.last_activity_at, Some(timestamp));
+        assert_eq!(client.latest_version_id, NIL_VERSION_ID);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_chain_hash() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_client()?.unwrap().chain_hash, None);
+        let chain_hash = ChainHash(u64::MAX);
+        txn.set_chain_hash(Some(chain_hash))?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().chain_hash, Some(chain_hash));
+        txn.set_chain_hash(None)?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().chain_hash, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_acked_version_id() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_client()?.unwrap().acked_version_id, None);
+        let version_id = Uuid::new_v4();
+        txn.set_acked_version_id(Some(version_id))?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(
+            txn.get_client()?.unwrap().acked_version_id,
+            Some(version_id)
+        );
+        txn.set_acked_version_id(None)?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().acked_version_id, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_app_metadata() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_app_metadata()?, None);
+
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.set_app_metadata(b"meta".to_vec())?;
+        assert_eq!(txn.get_app_metadata()?, Some(b"meta".to_vec()));
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_app_metadata()?, Some(b"meta".to_vec()));
+        txn.set_app_metadata(vec![])?;
+        drop(txn);
+
+        // the new value was not committed
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_app_metadata()?, Some(b"meta".to_vec()));
+        txn.set_app_metadata(vec![])?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_app_metadata()?, Some(vec![]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_versions_before() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        let version_ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        let mut parent_version_id = NIL_VERSION_ID;
+        for version_id in &version_ids {
+            txn.add_version(*version_id, parent_version_id, b"abc".to_vec())?;
+            parent_version_id = *version_id;
+        }
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        // deleting before a nonexistent version does nothing
+        txn.delete_versions_before(Uuid::new_v4())?;
+        txn.delete_versions_before(version_ids[2])?;
+        assert_eq!(txn.get_version(version_ids[1])?, None);
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version(version_ids[0])?, None);
+        assert_eq!(txn.get_version(version_ids[1])?, None);
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, None);
+        for version_id in &version_ids[2..] {
+            assert!(txn.get_version(*version_id)?.is_some());
+        }
+        // the oldest remaining version can still be found from its (deleted) parent
+        assert_eq!(
+            txn.get_version_by_parent(version_ids[1])?
+                .map(|v| v.version_id),
+            Some(version_ids[2])
+        );
+        assert_eq!(txn.get_head_version_ids()?, vec![version_ids[3]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_client() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let other_client_id = Uuid::new_v4();
+        let new_client_id = Uuid::new_v4();
+        let version_id_1 = Uuid::new_v4();
+        let version_id_2 = Uuid::new_v4();
+        let snap = Snapshot {
+            version_id: version_id_1,
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 1,
+        };
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id_1, NIL_VERSION_ID, b"v1".to_vec())?;
+        txn.add_version(version_id_2, version_id_1, b"v2".to_vec())?;
+        txn.set_snapshot(snap.clone(), vec![1, 2, 3], None)?;
+        txn.set_app_metadata(b"meta".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(other_client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
+
+        // renaming onto an existing client fails
+        let mut txn = storage.txn(client_id)?;
+        assert!(!txn.rename_client(other_client_id)?);
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert!(txn.rename_client(new_client_id)?);
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(txn.get_version(version_id_1)?, None);
+        assert_eq!(txn.get_app_metadata()?, None);
+        drop(txn);
+
+        let mut txn = storage.txn(new_client_id)?;
+        assert_eq!(
+            txn.get_client()?,
+            Some(Client {
+                latest_version_id: version_id_2,
+                snapshot: Some(snap),
+                last_activity_at: None,
+                chain_hash: None,
+                acked_version_id: None,
+            })
+        );
+        assert_eq!(txn.get_snapshot_data(version_id_1)?, Some(vec![1, 2, 3]));
+        assert_eq!(txn.get_app_metadata()?, Some(b"meta".to_vec()));
+        assert_eq!(
+            txn.get_version_by_parent(NIL_VERSION_ID)?
+                .map(|v| v.history_segment),
+            Some(b"v1".to_vec())
+        );
+        assert_eq!(
+            txn.get_version(version_id_2)?.map(|v| v.parent_version_id),
+            Some(version_id_1)
+        );
+        assert_eq!(txn.get_head_version_ids()?, vec![version_id_2]);
+        drop(txn);
+
+        assert_eq!(storage.global_stats()?.clients, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_client() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let other_client_id = Uuid::new_v4();
+        let version_id_1 = Uuid::new_v4();
+        let version_id_2 = Uuid::new_v4();
+        let other_version_id = Uuid::new_v4();
+        let snap = Snapshot {
+            version_id: version_id_1,
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 1,
+        };
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id_1, NIL_VERSION_ID, b"v1".to_vec())?;
+        txn.add_version(version_id_2, version_id_1, b"v2".to_vec())?;
+        txn.set_snapshot(snap, vec![1, 2, 3], None)?;
+        txn.set_app_metadata(b"meta".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(other_client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(other_version_id, NIL_VERSION_ID, b"other".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_client()?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(txn.get_version(version_id_1)?, None);
+        assert_eq!(txn.get_version(version_id_2)?, None);
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, None);
+        assert_eq!(txn.get_app_metadata()?, None);
+        assert_eq!(txn.get_client_usage()?, ClientUsage::default());
+
+        // deleting a client which does not exist does nothing
+        txn.delete_client()?;
+        txn.commit()?;
+        drop(txn);
+
+        // the other client is unaffected
+        let mut txn = storage.txn(other_client_id)?;
+        assert_eq!(
+            txn.get_version(other_version_id)?
+                .map(|v| v.history_segment),
+            Some(b"other".to_vec())
+        );
+        drop(txn);
+        assert_eq!(storage.list_clients()?, vec![other_client_id]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshots() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(Uuid::new_v4())?;
+        assert!(txn.get_client()?.unwrap().snapshot.is_none());
+
+        let snap = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 3,
+        };
+        assert!(txn.set_snapshot(snap.clone(), vec![9, 8, 9], None)?);
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(
+            txn.get_snapshot_data(snap.version_id)?.unwrap(),
+            vec![9, 8, 9]
+        );
+        assert_eq!(txn.get_snapshot_size(snap.version_id)?, Some(3));
+        assert_eq!(txn.get_client()?.unwrap().snapshot, Some(snap.clone()));
+
+        let snap2 = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: "2014-11-28T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 10,
+        };
+        assert!(txn.set_snapshot(snap2.clone(), vec![0, 2, 4, 6], Some(snap.version_id))?);
+
+        assert_eq!(
+            txn.get_snapshot_data(snap2.version_id)?.unwrap(),
+            vec![0, 2, 4, 6]
+        );
+        assert_eq!(txn.get_client()?.unwrap().snapshot, Some(snap2));
+
+        // check that mismatched version is detected
+        assert!(txn.get_snapshot_data(Uuid::new_v4()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_snapshot_precondition() -> anyhow::Result<()> {
+        let Some(storage) = storage()? else {
+            return Ok(());
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(Uuid::new_v4())?;
+
+        let snap = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 3,
+        };
+        // there is no existing snapshot, so this precondition fails
+        assert!(!txn.set_snapshot(snap.clone(), vec![1], Some(Uuid::new_v4()))?);
+        assert_eq!(txn.get_client()?.unwrap().snapshot, None);
+
+        assert!(txn.set_snapshot(snap.clone(), vec![1], None)?);
+
+        let snap2 = Snapshot {
+            version_id: Uuid::new_v4(),
+            ..snap.clone()
+        };
+        // the existing snapshot does not match, so this precondition fails
+        assert!(!txn.set_snapshot(snap2.clone(), vec![2], Some(Uuid::new_v4()))?);
+        assert!(!txn.set_snapshot(snap2.clone(), vec![2], None)?);
+        assert_eq!(txn.get_client()?.unwrap().snapshot, Some(snap.clone()));
+        assert_eq!(txn.get_snapshot_data(snap.version_id)?.unwrap(), vec![1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_from_fields_versions_since() -> anyhow::Result<()> {
+        let version_id = Uuid::new_v4();
+        let fields: HashMap<String, String> = [
+            ("latest_version_id", version_id.to_string()),
+            ("snapshot_version_id", version_id.to_string()),
+            ("snapshot_timestamp", "1381233609".to_string()),
+            ("versions_since_snapshot", "7".to_string()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        let client = client_from_fields(&fields)?.unwrap();
+        assert_eq!(
+            client.snapshot,
+            Some(Snapshot {
+                version_id,
+                timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+                versions_since: 7,
+            })
+        );
+        assert_eq!(client.last_activity_at, None);
+        assert_eq!(client_from_fields(&HashMap::new())?, None);
+        Ok(())
+    }
+}
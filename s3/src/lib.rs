@@ -0,0 +1,594 @@
+//! This crate implements a decorator for TaskChampion sync server storage which keeps snapshot
+//! data in an S3-compatible object store, rather than in the storage backend itself.
+//!
+//! Snapshots contain a client's entire task database, and so usually dominate the size of the
+//! storage. With [`S3SnapshotStore`], each snapshot is uploaded to the bucket under the key
+//! `{client_id}/{version_id}`, and the backend stores only a short reference to that object, in
+//! place of the snapshot data. Everything else, including the snapshot's version ID and
+//! timestamp, remains in the backend.
+//!
+//! Snapshots stored in the backend before it was wrapped are still read from the backend, and
+//! are moved to the bucket when next replaced. A snapshot whose object is missing from the bucket
+//! is treated as no snapshot at all, so the client will be asked for a new one.
+//!
+//! The object for a replaced or deleted snapshot is deleted after the transaction commits. An
+//! object uploaded in a transaction which is not committed is left in the bucket.
+//!
+//! Storage usage reported by the backend, such as for client quotas, counts the size of the
+//! references rather than of the snapshots.
+use std::io::Read;
+use taskchampion_sync_server_core::{
+    BackendInfo, ChainHash, Client, ClientUsage, GlobalStats, Snapshot, Storage, StorageTxn,
+    Version,
+};
+use uuid::Uuid;
+
+mod client;
+#[cfg(test)]
+mod mock;
+
+use client::S3Client;
+pub use client::S3Config;
+
+/// The prefix of the reference to an object stored in place of the snapshot data.
+const REFERENCE_PREFIX: &[u8] = b"taskchampion-s3-snapshot:";
+
+/// The longest reference stored in place of the snapshot data. Snapshot data longer than this
+/// is never a reference.
+const MAX_REFERENCE_LEN: u64 = 256;
+
+/// A storage which keeps snapshot data in an S3-compatible bucket, and everything else in the
+/// wrapped storage.
+pub struct S3SnapshotStore<S> {
+    inner: S,
+    client: S3Client,
+}
+
+impl<S: Storage> S3SnapshotStore<S> {
+    /// Wrap the given storage, storing snapshot data in the configured bucket. The bucket is not
+    /// accessed until a snapshot is read or written.
+    pub fn new(inner: S, config: S3Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            inner,
+            client: S3Client::new(config)?,
+        })
+    }
+
+    fn wrap<'a>(&'a self, client_id: Uuid, inner: Box<dyn StorageTxn + 'a>) -> Txn<'a> {
+        Txn {
+            inner,
+            client: &self.client,
+            client_id,
+            deleted_keys: Vec::new(),
+        }
+    }
+}
+
+impl<S: Storage> Storage for S3SnapshotStore<S> {
+    fn txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+        Ok(Box::new(self.wrap(client_id, self.inner.txn(client_id)?)))
+    }
+
+    fn read_txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+        Ok(Box::new(
+            self.wrap(client_id, self.inner.read_txn(client_id)?),
+        ))
+    }
+
+    fn global_stats(&self) -> anyhow::Result<GlobalStats> {
+        self.inner.global_stats()
+    }
+
+    fn list_clients(&self) -> anyhow::Result<Vec<Uuid>> {
+        self.inner.list_clients()
+    }
+
+    fn backend_info(&self) -> anyhow::Result<BackendInfo> {
+        let mut info = self.inner.backend_info()?;
+        info.details
+            .insert("snapshot_bucket".into(), self.client.bucket().into());
+        Ok(info)
+    }
+
+    fn schema_version(&self) -> anyhow::Result<u32> {
+        self.inner.schema_version()
+    }
+
+    fn required_schema_version(&self) -> u32 {
+        self.inner.required_schema_version()
+    }
+
+    fn maintenance(&self) -> anyhow::Result<()> {
+        self.inner.maintenance()
+    }
+}
+
+/// The snapshot data stored in the wrapped storage.
+enum StoredSnapshot {
+    /// The key of the object containing the snapshot data.
+    Object(String),
+    /// The snapshot data itself, stored before the storage was wrapped.
+    Inline(Vec<u8>),
+}
+
+impl StoredSnapshot {
+    fn new(data: Vec<u8>) -> Self {
+        match data.strip_prefix(REFERENCE_PREFIX) {
+            Some(key) => match String::from_utf8(key.to_vec()) {
+                Ok(key) => StoredSnapshot::Object(key),
+                Err(_) => StoredSnapshot::Inline(data),
+            },
+            None => StoredSnapshot::Inline(data),
+        }
+    }
+}
+
+struct Txn<'a> {
+    inner: Box<dyn StorageTxn + 'a>,
+    client: &'a S3Client,
+    client_id: Uuid,
+    /// Keys of objects to delete once the transaction commits.
+    deleted_keys: Vec<String>,
+}
+
+impl Txn<'_> {
+    /// Get the snapshot data stored in the wrapped storage for the given version.
+    fn stored_snapshot(&mut self, version_id: Uuid) -> anyhow::Result<Option<StoredSnapshot>> {
+        Ok(self
+            .inner
+            .get_snapshot_data(version_id)?
+            .map(StoredSnapshot::new))
+    }
+
+    /// Get the key of the object for the client's current snapshot, if it is stored in the
+    /// bucket.
+    fn current_key(&mut self) -> anyhow::Result<Option<String>> {
+        let Some(snapshot) = self.inner.get_client()?.and_then(|client| client.snapshot) else {
+            return Ok(None);
+        };
+        let size = self.inner.get_snapshot_size(snapshot.version_id)?;
+        if size.is_none_or(|size| size > MAX_REFERENCE_LEN) {
+            return Ok(None);
+        }
+        Ok(match self.stored_snapshot(snapshot.version_id)? {
+            Some(StoredSnapshot::Object(key)) => Some(key),
+            _ => None,
+        })
+    }
+
+    /// Determine whether the snapshot for the given version is stored in the bucket, but its
+    /// object is missing.
+    fn snapshot_missing(&mut self, version_id: Uuid) -> anyhow::Result<bool> {
+        let size = self.inner.get_snapshot_size(version_id)?;
+        if size.is_none_or(|size| size > MAX_REFERENCE_LEN) {
+            return Ok(false);
+        }
+        match self.stored_snapshot(version_id)? {
+            Some(StoredSnapshot::Object(key)) => {
+                if self.client.head_object(&key)?.is_none() {
+                    log::warn!("Snapshot object {key} is missing from the bucket");
+                    return Ok(true);
+                }
+                Ok(false)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Remove the client's snapshot if its object is missing, so that the client is treated as
+    /// having no snapshot and is asked for a new one.
+    fn hide_missing_snapshot(&mut self, client: &mut Client) -> anyhow::Result<()> {
+        if let Some(snapshot) = &client.snapshot {
+            if self.snapshot_missing(snapshot.version_id)? {
+                client.snapshot = None;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageTxn for Txn<'_> {
+    fn get_client(&mut self) -> anyhow::Result<Option<Client>> {
+        let Some(mut client) = self.inner.get_client()? else {
+            return Ok(None);
+        };
+        self.hide_missing_snapshot(&mut client)?;
+        Ok(Some(client))
+    }
+
+    fn get_client_with_latest_version(
+        &mut self,
+    ) -> anyhow::Result<Option<(Client, Option<Version>)>> {
+        let Some((mut client, version)) = self.inner.get_client_with_latest_version()? else {
+            return Ok(None);
+        };
+        self.hide_missing_snapshot(&mut client)?;
+        Ok(Some((client, version)))
+    }
+
+    fn get_client_token_hash(&mut self) -> anyhow::Result<Option<String>> {
+        self.inner.get_client_token_hash()
+    }
+
+    fn new_client(&mut self, latest_version_id: Uuid) -> anyhow::Result<()> {
+        self.inner.new_client(latest_version_id)
+    }
+
+    fn set_snapshot(
+        &mut self,
+        snapshot: Snapshot,
+        data: Vec<u8>,
+        expected_previous_version_id: Option<Uuid>,
+    ) -> anyhow::Result<bool> {
+        self.set_snapshot_from_reader(
+            snapshot,
+            data.len() as u64,
+            &mut data.as_slice(),
+            expected_previous_version_id,
+        )
+    }
+
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        size: u64,
+        data: &mut dyn Read,
+        expected_previous_version_id: Option<Uuid>,
+    ) -> anyhow::Result<bool> {
+        // check the precondition before uploading, to avoid uploading a snapshot which will not
+        // be used
+        let stored_previous_version_id = self
+            .inner
+            .get_client()?
+            .and_then(|client| client.snapshot)
+            .map(|snapshot| snapshot.version_id);
+        // a snapshot whose object is missing is reported as no snapshot, so it is replaced as if
+        // there were none
+        let previous_version_id = match stored_previous_version_id {
+            Some(version_id) if self.snapshot_missing(version_id)? => None,
+            version_id => version_id,
+        };
+        if previous_version_id != expected_previous_version_id {
+            return Ok(false);
+        }
+        let previous_key = self.current_key()?;
+
+        let key = format!("{}/{}", self.client_id, snapshot.version_id);
+        self.client.put_object(&key, size, data)?;
+        let reference = [REFERENCE_PREFIX, key.as_bytes()].concat();
+        if !self
+            .inner
+            .set_snapshot(snapshot, reference, stored_previous_version_id)?
+        {
+            return Ok(false);
+        }
+        if let Some(previous_key) = previous_key.filter(|previous_key| *previous_key != key) {
+            self.deleted_keys.push(previous_key);
+        }
+        Ok(true)
+    }
+
+    fn get_snapshot_data(&mut self, version_id: Uuid) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.stored_snapshot(version_id)? {
+            Some(StoredSnapshot::Object(key)) => {
+                let data = self.client.get_object(&key)?;
+                if data.is_none() {
+                    log::warn!("Snapshot object {key} is missing from the bucket");
+                }
+                Ok(data)
+            }
+            Some(StoredSnapshot::Inline(data)) => Ok(Some(data)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_snapshot_size(&mut self, version_id: Uuid) -> anyhow::Result<Option<u64>> {
+        match self.inner.get_snapshot_size(version_id)? {
+            Some(size) if size <= MAX_REFERENCE_LEN => match self.stored_snapshot(version_id)? {
+                Some(StoredSnapshot::Object(key)) => self.client.head_object(&key),
+                Some(StoredSnapshot::Inline(data)) => Ok(Some(data.len() as u64)),
+                None => Ok(None),
+            },
+            size => Ok(size),
+        }
+    }
+
+    fn get_version_by_parent(
+        &mut self,
+        parent_version_id: Uuid,
+    ) -> anyhow::Result<Option<Version>> {
+        self.inner.get_version_by_parent(parent_version_id)
+    }
+
+    fn get_version(&mut self, version_id: Uuid) -> anyhow::Result<Option<Version>> {
+        self.inner.get_version(version_id)
+    }
+
+    fn get_head_version_ids(&mut self) -> anyhow::Result<Vec<Uuid>> {
+        self.inner.get_head_version_ids()
+    }
+
+    fn get_client_usage(&mut self) -> anyhow::Result<ClientUsage> {
+        self.inner.get_client_usage()
+    }
+
+    fn set_latest_version_id(&mut self, latest_version_id: Uuid) -> anyhow::Result<()> {
+        self.inner.set_latest_version_id(latest_version_id)
+    }
+
+    fn set_last_activity(
+        &mut self,
+        timestamp: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<()> {
+        self.inner.set_last_activity(timestamp)
+    }
+
+    fn set_chain_hash(&mut self, chain_hash: Option<ChainHash>) -> anyhow::Result<()> {
+        self.inner.set_chain_hash(chain_hash)
+    }
+
+    fn set_acked_version_id(&mut self, acked_version_id: Option<Uuid>) -> anyhow::Result<()> {
+        self.inner.set_acked_version_id(acked_version_id)
+    }
+
+    fn get_app_metadata(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        self.inner.get_app_metadata()
+    }
+
+    fn set_app_metadata(&mut self, app_metadata: Vec<u8>) -> anyhow::Result<()> {
+        self.inner.set_app_metadata(app_metadata)
+    }
+
+    fn add_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        self.inner
+            .add_version(version_id, parent_version_id, history_segment)
+    }
+
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        size: u64,
+        history_segment: &mut dyn Read,
+    ) -> anyhow::Result<()> {
+        self.inner
+            .add_version_from_reader(version_id, parent_version_id, size, history_segment)
+    }
+
+    fn delete_versions_before(&mut self, version_id: Uuid) -> anyhow::Result<()> {
+        self.inner.delete_versions_before(version_id)
+    }
+
+    fn rename_client(&mut self, new_client_id: Uuid) -> anyhow::Result<bool> {
+        // the reference names the object by its key, so the object need not be renamed
+        self.inner.rename_client(new_client_id)
+    }
+
+    fn delete_client(&mut self) -> anyhow::Result<()> {
+        if let Some(key) = self.current_key()? {
+            self.deleted_keys.push(key);
+        }
+        self.inner.delete_client()
+    }
+
+    fn commit(&mut self) -> anyhow::Result<()> {
+        self.inner.commit()?;
+        for key in self.deleted_keys.drain(..) {
+            if let Err(err) = self.client.delete_object(&key) {
+                log::warn!("Could not delete snapshot object {key}: {err:#}");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockS3;
+    use chrono::Utc;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{
+        AddVersionResult, InMemoryStorage, Server, ServerConfig, SnapshotUrgency, NIL_VERSION_ID,
+    };
+
+    fn snapshot(version_id: Uuid) -> Snapshot {
+        Snapshot {
+            version_id,
+            timestamp: Utc::now(),
+            versions_since: 0,
+        }
+    }
+
+    /// Create a storage wrapping the given storage, with a bucket in the given mock.
+    fn storage(mock: &MockS3, inner: InMemoryStorage) -> S3SnapshotStore<InMemoryStorage> {
+        S3SnapshotStore::new(inner, mock.config()).unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_in_bucket() -> anyhow::Result<()> {
+        let mock = MockS3::start();
+        let storage = storage(&mock, InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(version_id)?;
+        assert!(txn.set_snapshot(snapshot(version_id), b"snapshot data".to_vec(), None)?);
+        txn.commit()?;
+        drop(txn);
+
+        let key = format!("{client_id}/{version_id}");
+        assert_eq!(mock.object(&key), Some(b"snapshot data".to_vec()));
+
+        let mut txn = storage.read_txn(client_id)?;
+        assert_eq!(
+            txn.get_snapshot_data(version_id)?,
+            Some(b"snapshot data".to_vec())
+        );
+        assert_eq!(txn.get_snapshot_size(version_id)?, Some(13));
+        assert_eq!(txn.list_snapshots()?.len(), 1);
+        assert!(txn.get_snapshot_data(Uuid::new_v4()).is_err());
+
+        // only the reference is in the wrapped storage
+        let usage = txn.get_client_usage()?;
+        assert_eq!(
+            usage.snapshot_bytes,
+            (REFERENCE_PREFIX.len() + key.len()) as u64
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_snapshot() -> anyhow::Result<()> {
+        let mock = MockS3::start();
+        let storage = storage(&mock, InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+        let version_ids = [Uuid::new_v4(), Uuid::new_v4()];
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        assert!(txn.set_snapshot(snapshot(version_ids[0]), b"first".to_vec(), None)?);
+        txn.commit()?;
+        drop(txn);
+
+        // a snapshot not following the expected snapshot is not uploaded
+        let mut txn = storage.txn(client_id)?;
+        assert!(!txn.set_snapshot(snapshot(version_ids[1]), b"second".to_vec(), None)?);
+        assert_eq!(mock.len(), 1);
+
+        assert!(txn.set_snapshot(
+            snapshot(version_ids[1]),
+            b"second".to_vec(),
+            Some(version_ids[0])
+        )?);
+        // the replaced object is deleted only on commit
+        assert_eq!(mock.len(), 2);
+        txn.commit()?;
+        drop(txn);
+
+        assert_eq!(
+            mock.object(&format!("{client_id}/{}", version_ids[0])),
+            None
+        );
+        let mut txn = storage.read_txn(client_id)?;
+        assert_eq!(
+            txn.get_snapshot_data(version_ids[1])?,
+            Some(b"second".to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_object() -> anyhow::Result<()> {
+        let mock = MockS3::start();
+        let storage = storage(&mock, InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(version_id)?;
+        assert!(txn.set_snapshot(snapshot(version_id), b"snapshot data".to_vec(), None)?);
+        txn.commit()?;
+        drop(txn);
+
+        mock.remove(&format!("{client_id}/{version_id}"));
+        let mut txn = storage.read_txn(client_id)?;
+        assert_eq!(txn.get_snapshot_data(version_id)?, None);
+        assert_eq!(txn.get_snapshot_size(version_id)?, None);
+        assert_eq!(txn.list_snapshots()?, vec![]);
+        assert_eq!(txn.get_client()?.unwrap().snapshot, None);
+        drop(txn);
+
+        // the client is asked for a new snapshot, which replaces the missing one
+        let server = Server::new(ServerConfig::default(), storage);
+        let (result, urgency) = server.add_version(client_id, version_id, b"v".to_vec())?;
+        let AddVersionResult::Ok(new_version_id) = result else {
+            panic!("version not added: {result:?}");
+        };
+        assert_eq!(urgency, SnapshotUrgency::High);
+        assert!(server.add_snapshot(client_id, new_version_id, b"new snapshot".to_vec())?);
+        assert_eq!(
+            server.get_snapshot(client_id)?,
+            Some((new_version_id, b"new snapshot".to_vec()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_snapshot() -> anyhow::Result<()> {
+        let mock = MockS3::start();
+        let client_id = Uuid::new_v4();
+        let version_ids = [Uuid::new_v4(), Uuid::new_v4()];
+        let inner = InMemoryStorage::new();
+        {
+            let mut txn = inner.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.set_snapshot(snapshot(version_ids[0]), vec![7; 1000], None)?;
+            txn.commit()?;
+        }
+        let storage = storage(&mock, inner);
+
+        // a snapshot stored before wrapping is read from the wrapped storage
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_snapshot_data(version_ids[0])?, Some(vec![7; 1000]));
+        assert_eq!(txn.get_snapshot_size(version_ids[0])?, Some(1000));
+
+        // and replaced by one in the bucket
+        assert!(txn.set_snapshot(
+            snapshot(version_ids[1]),
+            b"snapshot data".to_vec(),
+            Some(version_ids[0])
+        )?);
+        txn.commit()?;
+        assert_eq!(mock.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_and_delete_client() -> anyhow::Result<()> {
+        let mock = MockS3::start();
+        let storage = storage(&mock, InMemoryStorage::new());
+        let client_id = Uuid::new_v4();
+        let new_client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(version_id)?;
+        assert!(txn.set_snapshot(snapshot(version_id), b"snapshot data".to_vec(), None)?);
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert!(txn.rename_client(new_client_id)?);
+        txn.commit()?;
+        drop(txn);
+
+        // the renamed client still has the snapshot
+        let mut txn = storage.txn(new_client_id)?;
+        assert_eq!(
+            txn.get_snapshot_data(version_id)?,
+            Some(b"snapshot data".to_vec())
+        );
+        txn.delete_client()?;
+        assert_eq!(mock.len(), 1);
+        txn.commit()?;
+        assert_eq!(mock.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backend_info() -> anyhow::Result<()> {
+        let mock = MockS3::start();
+        let storage = storage(&mock, InMemoryStorage::new());
+        let info = storage.backend_info()?;
+        assert_eq!(info.name, "inmemory");
+        assert_eq!(info.details["snapshot_bucket"], "snapshots");
+        Ok(())
+    }
+}
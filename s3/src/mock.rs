@@ -0,0 +1,134 @@
+//! A mock of the object operations of the S3 API, for testing.
+use crate::S3Config;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+/// The bucket served by the mock.
+const BUCKET: &str = "snapshots";
+
+type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+/// An HTTP server, running in background threads, which stores objects in memory. It accepts
+/// any request with a Signature Version 4 `Authorization` header, without checking the
+/// signature.
+pub(crate) struct MockS3 {
+    endpoint: String,
+    objects: Objects,
+}
+
+impl MockS3 {
+    pub(crate) fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let objects = Objects::default();
+        let server_objects = objects.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    return;
+                };
+                let objects = server_objects.clone();
+                std::thread::spawn(move || handle(stream, objects));
+            }
+        });
+        Self { endpoint, objects }
+    }
+
+    /// Get a configuration for the mock's bucket.
+    pub(crate) fn config(&self) -> S3Config {
+        S3Config {
+            endpoint: self.endpoint.clone(),
+            bucket: BUCKET.into(),
+            region: "us-east-1".into(),
+            access_key_id: "test-key".into(),
+            secret_access_key: "test-secret".into(),
+        }
+    }
+
+    /// Get the data of the object with the given key.
+    pub(crate) fn object(&self, key: &str) -> Option<Vec<u8>> {
+        self.objects.lock().unwrap().get(key).cloned()
+    }
+
+    /// Remove the object with the given key.
+    pub(crate) fn remove(&self, key: &str) {
+        self.objects.lock().unwrap().remove(key);
+    }
+
+    /// Get the number of objects.
+    pub(crate) fn len(&self) -> usize {
+        self.objects.lock().unwrap().len()
+    }
+}
+
+/// Handle requests on a connection until it is closed.
+fn handle(stream: TcpStream, objects: Objects) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+
+        let mut content_length = 0;
+        let mut authorized = false;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header)?;
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header.split_once(':').unwrap_or((header, ""));
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse().unwrap_or(0),
+                "authorization" => {
+                    authorized = value.trim().starts_with("AWS4-HMAC-SHA256 Credential=")
+                }
+                _ => {}
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+
+        let key = path
+            .strip_prefix(&format!("/{BUCKET}/"))
+            .map(str::to_string);
+        let mut objects = objects.lock().unwrap();
+        let (status, data) = match (authorized, key) {
+            (false, _) => (403, vec![]),
+            (true, None) => (404, vec![]),
+            (true, Some(key)) => match method.as_str() {
+                "PUT" => {
+                    objects.insert(key, body);
+                    (200, vec![])
+                }
+                "GET" | "HEAD" => match objects.get(&key) {
+                    Some(data) => (200, data.clone()),
+                    None => (404, vec![]),
+                },
+                "DELETE" => {
+                    objects.remove(&key);
+                    (204, vec![])
+                }
+                _ => (405, vec![]),
+            },
+        };
+        drop(objects);
+
+        write!(
+            writer,
+            "HTTP/1.1 {status} Mock\r\nContent-Length: {}\r\n\r\n",
+            data.len()
+        )?;
+        if method != "HEAD" {
+            writer.write_all(&data)?;
+        }
+    }
+}
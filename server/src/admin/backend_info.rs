@@ -0,0 +1,95 @@
+use crate::admin::response::respond;
+use crate::api::{server_error_to_actix, ServerState};
+use actix_web::{get, web, HttpRequest, HttpResponse, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+struct BackendInfo {
+    name: String,
+    schema_version: Option<String>,
+    details: BTreeMap<String, String>,
+}
+
+/// Describe the storage backend in use, for diagnosing deployment problems.
+///
+/// The response is a 200 OK with an object containing the backend's `name` (such as `sqlite`),
+/// its `schema_version` (null if the backend has no versioned schema), and an object of
+/// backend-specific `details`, as JSON or text depending on the `Accept` header.
+#[get("/v1/admin/backend-info")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+) -> Result<HttpResponse> {
+    let info = server_state
+        .server
+        .backend_info()
+        .map_err(server_error_to_actix)?;
+    respond(
+        &req,
+        &BackendInfo {
+            name: info.name,
+            schema_version: info.schema_version,
+            details: info.details,
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::admin::{admin_authorization, test_web_config};
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::InMemoryStorage;
+    use taskchampion_sync_server_storage_sqlite::SqliteStorage;
+    use tempfile::TempDir;
+
+    #[actix_rt::test]
+    async fn test_inmemory() {
+        let server = WebServer::new(
+            Default::default(),
+            test_web_config(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/admin/backend-info")
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "name": "inmemory",
+                "schema_version": null,
+                "details": {},
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_sqlite() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = SqliteStorage::new(tmp_dir.path()).unwrap();
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/admin/backend-info")
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["name"], "sqlite");
+        assert!(body["schema_version"].is_string());
+        assert_eq!(body["details"]["external_segments"], "false");
+    }
+}
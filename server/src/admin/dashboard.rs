@@ -0,0 +1,132 @@
+use crate::api::{server_error_to_actix, ServerState};
+use crate::error::ApiError;
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpResponse, Result};
+use std::sync::Arc;
+
+/// Render a simple HTML page summarizing the state of the server.
+///
+/// This is only available if the dashboard is enabled in the configuration, and otherwise
+/// returns a 404 NOT FOUND. Like the other admin endpoints, it requires the admin token.
+#[get("/dashboard")]
+pub(crate) async fn service(server_state: web::Data<Arc<ServerState>>) -> Result<HttpResponse> {
+    if !server_state.web_config.dashboard {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "dashboard_disabled",
+            "dashboard is not enabled",
+        )
+        .into());
+    }
+
+    let stats = server_state
+        .server
+        .global_stats()
+        .map_err(server_error_to_actix)?;
+    let body = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><title>TaskChampion sync server</title></head>
+<body>
+<h1>TaskChampion sync server v{version}</h1>
+<table>
+<tr><th>Clients</th><td id="clients">{clients}</td></tr>
+<tr><th>Clients with a snapshot</th><td id="clients-with-snapshot">{clients_with_snapshot}</td></tr>
+<tr><th>Versions</th><td id="versions">{versions}</td></tr>
+</table>
+</body>
+</html>
+"#,
+        version = env!("CARGO_PKG_VERSION"),
+        clients = stats.clients,
+        clients_with_snapshot = stats.clients_with_snapshot,
+        versions = stats.versions,
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::admin::{admin_authorization, test_web_config};
+    use crate::{WebConfig, WebServer};
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn test_enabled() {
+        let storage = InMemoryStorage::new();
+
+        // set up two clients, one with a version
+        for i in 0..2 {
+            let mut txn = storage.txn(Uuid::new_v4()).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            if i == 0 {
+                txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, b"abcd".to_vec())
+                    .unwrap();
+            }
+            txn.commit().unwrap();
+        }
+
+        let web_config = WebConfig {
+            dashboard: true,
+            ..test_web_config()
+        };
+        let server = WebServer::new(Default::default(), web_config, storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri("/dashboard")
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            &"text/html; charset=utf-8".to_string()
+        );
+
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains(r#"<td id="clients">2</td>"#));
+        assert!(body.contains(r#"<td id="clients-with-snapshot">0</td>"#));
+        assert!(body.contains(r#"<td id="versions">1</td>"#));
+    }
+
+    #[actix_rt::test]
+    async fn test_disabled() {
+        let server = WebServer::new(
+            Default::default(),
+            test_web_config(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri("/dashboard")
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_unauthorized() {
+        let web_config = WebConfig {
+            dashboard: true,
+            ..test_web_config()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get().uri("/dashboard").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
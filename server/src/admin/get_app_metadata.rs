@@ -0,0 +1,110 @@
+use crate::api::{server_error_to_actix, ServerState};
+use crate::error::ApiError;
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpResponse, Result};
+use std::sync::Arc;
+use taskchampion_sync_server_core::ClientId;
+
+/// Get a client's application metadata, an opaque blob which the sync server stores on behalf of
+/// other applications but never interprets.
+///
+/// On success, the response is a 200 OK with the metadata as an `application/octet-stream` body.
+/// If the client has no metadata, or does not exist, the response is a 404 NOT FOUND.
+#[get("/v1/admin/client/{client_id}/app-metadata")]
+pub(crate) async fn service(
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<ClientId>,
+) -> Result<HttpResponse> {
+    let client_id = path.into_inner();
+    let app_metadata = server_state
+        .server
+        .get_app_metadata(client_id)
+        .map_err(server_error_to_actix)?
+        .ok_or_else(|| {
+            ApiError::new(StatusCode::NOT_FOUND, "no_app_metadata", "no app metadata")
+        })?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .body(app_metadata))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::admin::{admin_authorization, test_web_config};
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn test_success() {
+        let client_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.set_app_metadata(b"meta".to_vec()).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{client_id}/app-metadata");
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            "application/octet-stream"
+        );
+        assert_eq!(test::read_body(resp).await.as_ref(), b"meta");
+    }
+
+    #[actix_rt::test]
+    async fn test_no_metadata() {
+        let client_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{client_id}/app-metadata");
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_no_such_client() {
+        let server = WebServer::new(
+            Default::default(),
+            test_web_config(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{}/app-metadata", Uuid::new_v4());
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,307 @@
+use crate::admin::response::respond;
+use crate::api::{server_error_to_actix, ServerState};
+use crate::error::ApiError;
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpRequest, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use taskchampion_sync_server_core::{ClientId, VersionId};
+
+/// The number of clients listed when the request does not give a limit.
+const DEFAULT_LIMIT: usize = 100;
+
+/// The largest number of clients listed in one response.
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+struct ListClientsQuery {
+    limit: Option<usize>,
+    after: Option<ClientId>,
+}
+
+#[derive(Serialize)]
+struct ClientMetadata {
+    client_id: ClientId,
+    latest_version_id: VersionId,
+    snapshot_version_id: Option<VersionId>,
+    snapshot_timestamp: Option<DateTime<Utc>>,
+    version_count: u64,
+    last_activity_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct ListClients {
+    clients: Vec<ClientMetadata>,
+    next: Option<ClientId>,
+}
+
+/// List the clients, in order of client ID, a page at a time.
+///
+/// The `limit` query parameter gives the number of clients in the page, defaulting to 100 and at
+/// most 1000, and `after` gives the client ID after which the page begins. On success, the
+/// response is a 200 OK with an object containing `clients`, the metadata of each client in the
+/// page, and `next`, the value of `after` for the following page, or null if this is the last.
+/// A client's `last_activity_at` is the time of its most recent write, or of any request if the
+/// server records read activity, so clients which are no longer used can be found. The response
+/// is JSON or text depending on the `Accept` header.
+#[get("/v1/admin/clients")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+) -> Result<HttpResponse> {
+    let query = web::Query::<ListClientsQuery>::from_query(req.query_string())
+        .map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, "bad_query", err.to_string()))?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    // list one more client than requested, to determine whether there is another page
+    let mut clients = server_state
+        .server
+        .list_clients(query.after, limit + 1)
+        .map_err(server_error_to_actix)?;
+    let next = if clients.len() > limit {
+        clients.truncate(limit);
+        clients.last().map(|summary| summary.client_id)
+    } else {
+        None
+    };
+
+    let clients = clients
+        .into_iter()
+        .map(|summary| ClientMetadata {
+            client_id: summary.client_id,
+            latest_version_id: summary.client.latest_version_id,
+            snapshot_version_id: summary.client.snapshot.as_ref().map(|snap| snap.version_id),
+            snapshot_timestamp: summary.client.snapshot.as_ref().map(|snap| snap.timestamp),
+            version_count: summary.usage.version_count,
+            last_activity_at: summary.client.last_activity_at,
+        })
+        .collect();
+    respond(&req, &ListClients { clients, next })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::admin::{admin_authorization, test_web_config};
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::{WebConfig, WebServer};
+    use actix_web::{http::StatusCode, test, App};
+    use chrono::{DateTime, TimeZone, Utc};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Snapshot, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    /// Create a storage containing three clients, returning it, their IDs in order, and the ID
+    /// of the version added to the first two. The first client also has a snapshot, and the
+    /// third has no versions.
+    fn storage_with_clients() -> (InMemoryStorage, Vec<Uuid>, Uuid) {
+        let storage = InMemoryStorage::new();
+        let mut client_ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        client_ids.sort();
+        let version_id = Uuid::new_v4();
+        for (i, client_id) in client_ids.iter().enumerate() {
+            let mut txn = storage.txn(*client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            if i < 2 {
+                txn.add_version(version_id, NIL_VERSION_ID, b"abcd".to_vec())
+                    .unwrap();
+            }
+            if i == 0 {
+                let snapshot = Snapshot {
+                    version_id,
+                    timestamp: Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap(),
+                    versions_since: 0,
+                };
+                txn.set_snapshot(snapshot, b"snap".to_vec(), None).unwrap();
+            }
+            txn.commit().unwrap();
+        }
+        (storage, client_ids, version_id)
+    }
+
+    #[actix_rt::test]
+    async fn test_list_clients() {
+        let (storage, client_ids, version_id) = storage_with_clients();
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/admin/clients")
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "clients": [
+                    {
+                        "client_id": client_ids[0],
+                        "latest_version_id": version_id,
+                        "snapshot_version_id": version_id,
+                        "snapshot_timestamp": "2025-01-02T03:04:05Z",
+                        "version_count": 1,
+                        "last_activity_at": null,
+                    },
+                    {
+                        "client_id": client_ids[1],
+                        "latest_version_id": version_id,
+                        "snapshot_version_id": null,
+                        "snapshot_timestamp": null,
+                        "version_count": 1,
+                        "last_activity_at": null,
+                    },
+                    {
+                        "client_id": client_ids[2],
+                        "latest_version_id": NIL_VERSION_ID,
+                        "snapshot_version_id": null,
+                        "snapshot_timestamp": null,
+                        "version_count": 0,
+                        "last_activity_at": null,
+                    },
+                ],
+                "next": null,
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_list_clients_last_activity() -> anyhow::Result<()> {
+        let client_id = Uuid::new_v4();
+        let server = WebServer::new(
+            Default::default(),
+            test_web_config(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let mut parent_version_id = NIL_VERSION_ID;
+        let mut previous: Option<DateTime<Utc>> = None;
+        for _ in 0..2 {
+            let before = Utc::now();
+            let req = test::TestRequest::post()
+                .uri(&format!("/v1/client/add-version/{parent_version_id}"))
+                .append_header((
+                    "Content-Type",
+                    "application/vnd.taskchampion.history-segment",
+                ))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .set_payload(b"abcd".to_vec())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            parent_version_id = resp
+                .headers()
+                .get("X-Version-Id")
+                .unwrap()
+                .to_str()?
+                .parse()?;
+
+            let req = test::TestRequest::get()
+                .uri("/v1/admin/clients")
+                .insert_header(admin_authorization())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            let listed: DateTime<Utc> =
+                serde_json::from_value(body["clients"][0]["last_activity_at"].clone())?;
+            assert!(listed >= before);
+            assert!(listed <= Utc::now());
+            // the activity advances with each version
+            if let Some(previous) = previous {
+                assert!(listed > previous);
+            }
+            previous = Some(listed);
+        }
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_list_clients_paginated() {
+        let (storage, client_ids, _) = storage_with_clients();
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let mut listed = Vec::new();
+        let mut uri = "/v1/admin/clients?limit=2".to_string();
+        let mut pages = 0;
+        loop {
+            let req = test::TestRequest::get()
+                .uri(&uri)
+                .insert_header(admin_authorization())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            pages += 1;
+            for client in body["clients"].as_array().unwrap() {
+                listed.push(
+                    client["client_id"]
+                        .as_str()
+                        .unwrap()
+                        .parse::<Uuid>()
+                        .unwrap(),
+                );
+            }
+            match body["next"].as_str() {
+                Some(next) => {
+                    assert_eq!(next, listed.last().unwrap().to_string());
+                    uri = format!("/v1/admin/clients?limit=2&after={next}");
+                }
+                None => break,
+            }
+        }
+        assert_eq!(pages, 2);
+        assert_eq!(listed, client_ids);
+    }
+
+    #[actix_rt::test]
+    async fn test_list_clients_bad_query() {
+        let server = WebServer::new(
+            Default::default(),
+            test_web_config(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/admin/clients?after=not-a-uuid")
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "bad_query");
+    }
+
+    #[actix_rt::test]
+    async fn test_list_clients_admin_token() {
+        let web_config = WebConfig {
+            require_token: Some("client-token".into()),
+            admin_token: Some("admin-token".into()),
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        for (authorization, status) in [
+            (None, StatusCode::UNAUTHORIZED),
+            (Some("Bearer client-token"), StatusCode::UNAUTHORIZED),
+            (Some("Bearer admin-token"), StatusCode::OK),
+        ] {
+            let mut req = test::TestRequest::get().uri("/v1/admin/clients");
+            if let Some(authorization) = authorization {
+                req = req.append_header(("Authorization", authorization));
+            }
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), status, "with {authorization:?}");
+        }
+    }
+}
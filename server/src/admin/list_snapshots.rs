@@ -0,0 +1,235 @@
+use crate::admin::response::respond;
+use crate::api::{server_error_to_actix, ServerState};
+use actix_web::{get, web, HttpRequest, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Arc;
+use taskchampion_sync_server_core::{ClientId, VersionId};
+
+#[derive(Serialize)]
+struct SnapshotMetadata {
+    version_id: VersionId,
+    timestamp: DateTime<Utc>,
+    versions_since: u32,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct ListSnapshots {
+    snapshots: Vec<SnapshotMetadata>,
+}
+
+/// List the snapshots retained for a client.
+///
+/// On success, the response is a 200 OK with an object containing `snapshots`, a list of the
+/// metadata of each snapshot, newest first, as JSON or text depending on the `Accept` header.
+/// Storage backends which retain only the most recent snapshot return at most one snapshot. If
+/// the client does not exist, the response is a 404 NOT FOUND.
+#[get("/v1/admin/client/{client_id}/snapshots")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<ClientId>,
+) -> Result<HttpResponse> {
+    let client_id = path.into_inner();
+    let snapshots = server_state
+        .server
+        .list_snapshots(client_id)
+        .map_err(server_error_to_actix)?
+        .into_iter()
+        .map(|(snapshot, size)| SnapshotMetadata {
+            version_id: snapshot.version_id,
+            timestamp: snapshot.timestamp,
+            versions_since: snapshot.versions_since,
+            size,
+        })
+        .collect();
+    respond(&req, &ListSnapshots { snapshots })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::admin::{admin_authorization, test_web_config};
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use chrono::{TimeZone, Utc};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Snapshot, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    /// Create a storage containing a client with a snapshot.
+    fn storage_with_snapshot(client_id: Uuid, version_id: Uuid) -> InMemoryStorage {
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(version_id).unwrap();
+            txn.set_snapshot(
+                Snapshot {
+                    version_id,
+                    versions_since: 3,
+                    timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
+                },
+                vec![1, 2, 3, 4],
+                None,
+            )
+            .unwrap();
+            txn.commit().unwrap();
+        }
+        storage
+    }
+
+    #[actix_rt::test]
+    async fn test_accept_json() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = storage_with_snapshot(client_id, version_id);
+
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{client_id}/snapshots");
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .append_header(("Accept", "application/json"))
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            "application/json"
+        );
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["snapshots"][0]["size"], 4);
+    }
+
+    #[actix_rt::test]
+    async fn test_accept_text() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = storage_with_snapshot(client_id, version_id);
+
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{client_id}/snapshots");
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .append_header(("Accept", "text/plain"))
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        let body = test::read_body(resp).await;
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            format!(
+                "snapshots:\n\
+                 size  timestamp             version_id                            versions_since\n\
+                 4     2001-09-09T01:46:40Z  {version_id}  3\n"
+            )
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_accept_other() {
+        let client_id = Uuid::new_v4();
+        let storage = storage_with_snapshot(client_id, Uuid::new_v4());
+
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{client_id}/snapshots");
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .append_header(("Accept", "text/html"))
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[actix_rt::test]
+    async fn test_snapshot() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = storage_with_snapshot(client_id, version_id);
+
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{client_id}/snapshots");
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "snapshots": [{
+                    "version_id": version_id,
+                    "timestamp": "2001-09-09T01:46:40Z",
+                    "versions_since": 3,
+                    "size": 4,
+                }]
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_no_snapshot() {
+        let client_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+
+        // set up the storage contents..
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{client_id}/snapshots");
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!({ "snapshots": [] }));
+    }
+
+    #[actix_rt::test]
+    async fn test_no_such_client() {
+        let server = WebServer::new(
+            Default::default(),
+            test_web_config(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{}/snapshots", Uuid::new_v4());
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
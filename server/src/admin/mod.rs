@@ -0,0 +1,50 @@
+//! Endpoints for monitoring and administering the server.
+//!
+//! These are only available when the `admin` feature is enabled, keeping the default build free
+//! of their dependencies and of any additional attack surface.
+
+#[cfg(test)]
+use crate::WebConfig;
+use actix_web::web;
+
+mod backend_info;
+mod dashboard;
+mod get_app_metadata;
+mod list_clients;
+mod list_snapshots;
+mod recompute_latest;
+mod rename_client;
+mod response;
+mod set_app_metadata;
+
+/// Add the admin services to the given configuration.
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(backend_info::service)
+        .service(dashboard::service)
+        .service(get_app_metadata::service)
+        .service(list_clients::service)
+        .service(list_snapshots::service)
+        .service(recompute_latest::service)
+        .service(rename_client::service)
+        .service(set_app_metadata::service);
+}
+
+/// The admin token configured by [`test_web_config`].
+#[cfg(test)]
+const TEST_ADMIN_TOKEN: &str = "admin-token";
+
+/// Get a configuration with an admin token, without which the admin endpoints reject every
+/// request.
+#[cfg(test)]
+pub(crate) fn test_web_config() -> WebConfig {
+    WebConfig {
+        admin_token: Some(TEST_ADMIN_TOKEN.into()),
+        ..WebConfig::default()
+    }
+}
+
+/// Get the `Authorization` header carrying the admin token of [`test_web_config`].
+#[cfg(test)]
+pub(crate) fn admin_authorization() -> (&'static str, String) {
+    ("Authorization", format!("Bearer {TEST_ADMIN_TOKEN}"))
+}
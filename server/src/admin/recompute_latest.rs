@@ -0,0 +1,134 @@
+use crate::admin::response::respond;
+use crate::api::{server_error_to_actix, ServerState};
+use actix_web::{post, web, HttpRequest, HttpResponse, Result};
+use serde::Serialize;
+use std::sync::Arc;
+use taskchampion_sync_server_core::{ClientId, VersionId};
+
+#[derive(Serialize)]
+struct RecomputeLatest {
+    latest_version_id: VersionId,
+}
+
+/// Recompute a client's latest version from its stored versions, repairing the client if its
+/// latest version is incorrect.
+///
+/// On success, the response is a 200 OK with an object containing the client's
+/// `latest_version_id`, as JSON or text depending on the `Accept` header. If the client's
+/// versions do not form a linear history, the response is a 409 CONFLICT and the client is not
+/// modified. If the client does not exist, the response is a 404 NOT FOUND.
+#[post("/v1/admin/client/{client_id}/recompute-latest")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<ClientId>,
+) -> Result<HttpResponse> {
+    let client_id = path.into_inner();
+    server_state.check_read_only()?;
+    let latest_version_id = server_state
+        .server
+        .recompute_latest_version(client_id)
+        .map_err(server_error_to_actix)?;
+    respond(&req, &RecomputeLatest { latest_version_id })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::admin::{admin_authorization, test_web_config};
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn test_repair() {
+        let client_id = Uuid::new_v4();
+        let version_id_1 = Uuid::new_v4();
+        let version_id_2 = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+
+        // set up the storage contents, with an incorrect latest version
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.add_version(version_id_1, NIL_VERSION_ID, b"v1".to_vec())
+                .unwrap();
+            txn.add_version(version_id_2, version_id_1, b"v2".to_vec())
+                .unwrap();
+            txn.set_latest_version_id(version_id_1).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{client_id}/recompute-latest");
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body,
+            serde_json::json!({ "latest_version_id": version_id_2 })
+        );
+
+        let mut txn = server.server_state.server.txn(client_id).unwrap();
+        assert_eq!(
+            txn.get_client().unwrap().unwrap().latest_version_id,
+            version_id_2
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_branched() {
+        let client_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+
+        // set up the storage contents, with two disconnected versions
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, b"v1".to_vec())
+                .unwrap();
+            txn.add_version(Uuid::new_v4(), Uuid::new_v4(), b"v2".to_vec())
+                .unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{client_id}/recompute-latest");
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+    }
+
+    #[actix_rt::test]
+    async fn test_no_such_client() {
+        let server = WebServer::new(
+            Default::default(),
+            test_web_config(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{}/recompute-latest", Uuid::new_v4());
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
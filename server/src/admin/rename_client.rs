@@ -0,0 +1,160 @@
+use crate::admin::response::respond;
+use crate::api::{server_error_to_actix, ServerState};
+use actix_web::{post, web, HttpRequest, HttpResponse, Result};
+use serde::Serialize;
+use std::sync::Arc;
+use taskchampion_sync_server_core::ClientId;
+
+#[derive(Serialize)]
+struct RenameClient {
+    client_id: ClientId,
+}
+
+/// Move a client, including its snapshot and all of its versions, to a new client ID.
+///
+/// On success, the response is a 200 OK with an object containing the new `client_id`, as JSON
+/// or text depending on the `Accept` header. If a client with the new ID already
+/// exists, the response is a 409 CONFLICT and neither client is modified. If the client does not
+/// exist, the response is a 404 NOT FOUND.
+#[post("/v1/admin/client/{client_id}/rename/{new_client_id}")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<(ClientId, ClientId)>,
+) -> Result<HttpResponse> {
+    let (client_id, new_client_id) = path.into_inner();
+    server_state.check_read_only()?;
+    server_state
+        .server
+        .rename_client(client_id, new_client_id)
+        .map_err(server_error_to_actix)?;
+    respond(
+        &req,
+        &RenameClient {
+            client_id: new_client_id,
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::admin::{admin_authorization, test_web_config};
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn test_rename() {
+        let client_id = Uuid::new_v4();
+        let new_client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, b"v1".to_vec())
+                .unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{client_id}/rename/{new_client_id}");
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let mut txn = server.server_state.server.txn(client_id).unwrap();
+        assert_eq!(txn.get_client().unwrap(), None);
+        drop(txn);
+        let mut txn = server.server_state.server.txn(new_client_id).unwrap();
+        assert_eq!(
+            txn.get_client().unwrap().unwrap().latest_version_id,
+            version_id
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_exists() {
+        let client_id = Uuid::new_v4();
+        let other_client_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+
+        for id in [client_id, other_client_id] {
+            let mut txn = storage.txn(id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{client_id}/rename/{other_client_id}");
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+    }
+
+    #[actix_rt::test]
+    async fn test_no_such_client() {
+        let server = WebServer::new(
+            Default::default(),
+            test_web_config(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!(
+            "/v1/admin/client/{}/rename/{}",
+            Uuid::new_v4(),
+            Uuid::new_v4()
+        );
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_unauthorized() {
+        let client_id = Uuid::new_v4();
+        let new_client_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{client_id}/rename/{new_client_id}");
+        let req = test::TestRequest::post().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // the client was not renamed
+        let mut txn = server.server_state.server.txn(client_id).unwrap();
+        assert!(txn.get_client().unwrap().is_some());
+        drop(txn);
+        let mut txn = server.server_state.server.txn(new_client_id).unwrap();
+        assert_eq!(txn.get_client().unwrap(), None);
+    }
+}
@@ -0,0 +1,209 @@
+use crate::error::ApiError;
+use actix_web::http::header::{Accept, ContentType, Header, Quality};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+/// The format of an admin response.
+#[derive(Debug, PartialEq, Eq)]
+enum Format {
+    Json,
+    Text,
+}
+
+/// Render the response to an admin request, as JSON for `application/json` or as aligned text
+/// for `text/plain`, as selected by the request's `Accept` header.
+///
+/// JSON is used if the request has no preference. If the request accepts neither format, the
+/// response is a 406 NOT ACCEPTABLE.
+pub(crate) fn respond<T: Serialize>(req: &HttpRequest, value: &T) -> Result<HttpResponse> {
+    match negotiate(req) {
+        Some(Format::Json) => Ok(HttpResponse::Ok().json(value)),
+        Some(Format::Text) => {
+            let value = serde_json::to_value(value).map_err(ApiError::internal)?;
+            Ok(HttpResponse::Ok()
+                .content_type(ContentType::plaintext())
+                .body(render_text(&value)))
+        }
+        None => Err(ApiError::new(
+            StatusCode::NOT_ACCEPTABLE,
+            "not_acceptable",
+            "Admin responses are available as application/json or text/plain",
+        )
+        .into()),
+    }
+}
+
+/// Select the response format from the request's `Accept` header.
+fn negotiate(req: &HttpRequest) -> Option<Format> {
+    let Ok(accept) = Accept::parse(req) else {
+        return Some(Format::Json);
+    };
+    if accept.is_empty() {
+        return Some(Format::Json);
+    }
+    // `ranked` does not exclude types with q=0, which are not acceptable
+    let accept = Accept(
+        accept
+            .iter()
+            .filter(|item| item.quality > Quality::ZERO)
+            .cloned()
+            .collect(),
+    );
+    accept.ranked().iter().find_map(
+        |mime| match (mime.type_().as_str(), mime.subtype().as_str()) {
+            ("application", "json") | ("application", "*") | ("*", "*") => Some(Format::Json),
+            ("text", "plain") | ("text", "*") => Some(Format::Text),
+            _ => None,
+        },
+    )
+}
+
+/// Render a value as human-readable text. The scalar fields of an object are shown as aligned
+/// `name value` lines, and lists of objects as tables with a column for each field.
+fn render_text(value: &Value) -> String {
+    match value {
+        Value::Object(map) => {
+            let (lists, scalars): (Vec<_>, Vec<_>) =
+                map.iter().partition(|(_, value)| value.is_array());
+            let width = scalars
+                .iter()
+                .map(|(name, _)| name.len())
+                .max()
+                .unwrap_or_default();
+            let mut text = String::new();
+            for (name, value) in &scalars {
+                text.push_str(&format!("{name:width$}  {}\n", render_scalar(value)));
+            }
+            for (name, value) in lists {
+                if !text.is_empty() {
+                    text.push('\n');
+                }
+                text.push_str(&format!("{name}:\n{}", render_text(value)));
+            }
+            text
+        }
+        Value::Array(items) => render_table(items),
+        value => format!("{}\n", render_scalar(value)),
+    }
+}
+
+/// Render a list of values as a table, with a column for each field of the first value.
+fn render_table(items: &[Value]) -> String {
+    let Some(Value::Object(first)) = items.first() else {
+        return items
+            .iter()
+            .map(|item| format!("{}\n", render_scalar(item)))
+            .collect();
+    };
+
+    let columns: Vec<&String> = first.keys().collect();
+    let rows: Vec<Vec<String>> = items
+        .iter()
+        .map(|item| {
+            columns
+                .iter()
+                .map(|column| {
+                    item.get(column.as_str())
+                        .map_or_else(String::new, render_scalar)
+                })
+                .collect()
+        })
+        .collect();
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            rows.iter()
+                .map(|row| row[i].len())
+                .chain([column.len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    let header: Vec<String> = columns.iter().map(|column| column.to_string()).collect();
+    let mut text = String::new();
+    for row in std::iter::once(header).chain(rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        text.push_str(line.join("  ").trim_end());
+        text.push('\n');
+    }
+    text
+}
+
+/// Render a single value, without quoting strings.
+fn render_scalar(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => "-".into(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn negotiate_formats() {
+        let format = |accept: Option<&str>| {
+            let mut req = TestRequest::get();
+            if let Some(accept) = accept {
+                req = req.insert_header(("Accept", accept));
+            }
+            negotiate(&req.to_http_request())
+        };
+        assert_eq!(format(None), Some(Format::Json));
+        assert_eq!(format(Some("*/*")), Some(Format::Json));
+        assert_eq!(format(Some("application/json")), Some(Format::Json));
+        assert_eq!(format(Some("text/plain")), Some(Format::Text));
+        assert_eq!(format(Some("text/*")), Some(Format::Text));
+        assert_eq!(
+            format(Some("application/json;q=0.5, text/plain")),
+            Some(Format::Text)
+        );
+        assert_eq!(
+            format(Some("text/plain;q=0, */*;q=0.1")),
+            Some(Format::Json)
+        );
+        assert_eq!(format(Some("text/html")), None);
+    }
+
+    #[test]
+    fn render_object() {
+        let value = json!({ "a": 1, "long_name": "x", "none": null });
+        assert_eq!(
+            render_text(&value),
+            "a          1\nlong_name  x\nnone       -\n"
+        );
+    }
+
+    #[test]
+    fn render_list() {
+        let value = json!({
+            "count": 2,
+            "items": [
+                { "id": "abc", "size": 1 },
+                { "id": "d", "size": 12345 },
+            ],
+        });
+        assert_eq!(
+            render_text(&value),
+            "count  2\n\nitems:\nid   size\nabc  1\nd    12345\n"
+        );
+    }
+
+    #[test]
+    fn render_empty_list() {
+        assert_eq!(render_text(&json!({ "items": [] })), "items:\n");
+    }
+}
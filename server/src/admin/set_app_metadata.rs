@@ -0,0 +1,89 @@
+use crate::api::{server_error_to_actix, ServerState};
+use actix_web::{put, web, HttpResponse, Result};
+use std::sync::Arc;
+use taskchampion_sync_server_core::ClientId;
+
+/// Set a client's application metadata to the request body, replacing any existing metadata. See
+/// [`super::get_app_metadata::service`].
+///
+/// The body may have any content type, and is limited to actix-web's default payload size of
+/// 256KiB. On success, the response is a 204 NO CONTENT. If the client does not exist, the
+/// response is a 404 NOT FOUND.
+#[put("/v1/admin/client/{client_id}/app-metadata")]
+pub(crate) async fn service(
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<ClientId>,
+    body: web::Bytes,
+) -> Result<HttpResponse> {
+    let client_id = path.into_inner();
+    server_state.check_read_only()?;
+    server_state
+        .server
+        .set_app_metadata(client_id, body.to_vec())
+        .map_err(server_error_to_actix)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::admin::{admin_authorization, test_web_config};
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn test_round_trip() {
+        let client_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), test_web_config(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{client_id}/app-metadata");
+        for metadata in [&br#"{"name": "laptop"}"#[..], b"\x00\xff"] {
+            let req = test::TestRequest::put()
+                .uri(&uri)
+                .set_payload(metadata.to_vec())
+                .insert_header(admin_authorization())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+            let req = test::TestRequest::get()
+                .uri(&uri)
+                .insert_header(admin_authorization())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(test::read_body(resp).await.as_ref(), metadata);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_no_such_client() {
+        let server = WebServer::new(
+            Default::default(),
+            test_web_config(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/admin/client/{}/app-metadata", Uuid::new_v4());
+        let req = test::TestRequest::put()
+            .uri(&uri)
+            .set_payload(b"meta".to_vec())
+            .insert_header(admin_authorization())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
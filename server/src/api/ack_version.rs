@@ -0,0 +1,124 @@
+use crate::api::{server_error_to_actix, ServerState};
+use crate::error::ApiError;
+use crate::rate_limit::EndpointClass;
+use actix_web::http::StatusCode;
+use actix_web::{post, web, HttpRequest, HttpResponse, Result};
+use std::sync::Arc;
+use taskchampion_sync_server_core::VersionId;
+
+/// Report that the client has durably applied every version up to and including the given
+/// version, so that the server may prune older versions. Clients are not required to do so.
+///
+/// On success, the response is a 204 NO CONTENT. Acking a version older than one already acked
+/// has no effect. If the version is not in the client's history, or the client does not exist,
+/// the response is a 404 NOT FOUND. Returns other 4xx or 5xx responses on other errors.
+#[post("/v1/client/ack-version/{version_id}")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<VersionId>,
+) -> Result<HttpResponse> {
+    let version_id = path.into_inner();
+    server_state.check_read_only()?;
+    let client_id = server_state.client_id_header(&req)?;
+    server_state.check_rate_limit(client_id, EndpointClass::Write)?;
+
+    let _lock = server_state.lock_client_writes(client_id).await;
+    if server_state
+        .server
+        .ack_version(client_id, version_id)
+        .map_err(server_error_to_actix)?
+    {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ApiError::new(StatusCode::NOT_FOUND, "no_such_version", "no such version").into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    /// Create a storage containing a client with a chain of two versions, returning their IDs.
+    fn storage_with_client(client_id: Uuid) -> (InMemoryStorage, Vec<Uuid>) {
+        let storage = InMemoryStorage::new();
+        let version_ids = vec![Uuid::new_v4(), Uuid::new_v4()];
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.add_version(version_ids[0], NIL_VERSION_ID, b"v1".to_vec())
+                .unwrap();
+            txn.add_version(version_ids[1], version_ids[0], b"v2".to_vec())
+                .unwrap();
+            txn.commit().unwrap();
+        }
+        (storage, version_ids)
+    }
+
+    /// Build a request acking the given version.
+    fn request(client_id: Uuid, version_id: Uuid) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri(&format!("/v1/client/ack-version/{version_id}"))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+    }
+
+    #[actix_rt::test]
+    async fn test_success() {
+        let client_id = Uuid::new_v4();
+        let (storage, version_ids) = storage_with_client(client_id);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let resp = test::call_service(&app, request(client_id, version_ids[1]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // the client has no snapshot, so pruning keeps the versions before the acked version
+        server
+            .server_state
+            .server
+            .prune_versions(client_id)
+            .unwrap();
+        let mut txn = server.server_state.server.txn(client_id).unwrap();
+        let client = txn.get_client().unwrap().unwrap();
+        assert_eq!(client.acked_version_id, Some(version_ids[1]));
+        assert!(txn.get_version(version_ids[0]).unwrap().is_some());
+        assert!(txn.get_version(version_ids[1]).unwrap().is_some());
+    }
+
+    #[actix_rt::test]
+    async fn test_no_such_version() {
+        let client_id = Uuid::new_v4();
+        let (storage, _) = storage_with_client(client_id);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let resp = test::call_service(&app, request(client_id, Uuid::new_v4()).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "no_such_version");
+    }
+
+    #[actix_rt::test]
+    async fn test_no_such_client() {
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let resp =
+            test::call_service(&app, request(Uuid::new_v4(), Uuid::new_v4()).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "no_such_client");
+    }
+}
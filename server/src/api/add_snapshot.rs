@@ -1,19 +1,41 @@
-use crate::api::{server_error_to_actix, ServerState, SNAPSHOT_CONTENT_TYPE};
-use actix_web::{error, post, web, HttpMessage, HttpRequest, HttpResponse, Result};
+use crate::api::{
+    server_error_to_actix, RequestBody, ServerState, SNAPSHOT_CONTENT_TYPE, SNAPSHOT_LENGTH_HEADER,
+};
+use crate::error::ApiError;
+use crate::rate_limit::EndpointClass;
+use crate::WebConfig;
+use actix_web::http::StatusCode;
+use actix_web::{post, web, HttpMessage, HttpRequest, HttpResponse, Result};
 use futures::StreamExt;
 use std::sync::Arc;
 use taskchampion_sync_server_core::VersionId;
 
-/// Max snapshot size: 100MB
-const MAX_SIZE: usize = 100 * 1024 * 1024;
+/// Get the maximum size of a snapshot, which is the maximum body size unless a separate limit on
+/// snapshots is configured.
+pub(crate) fn max_snapshot_size(web_config: &WebConfig) -> usize {
+    web_config
+        .max_snapshot_size
+        .unwrap_or(web_config.max_body_size)
+}
 
 /// Add a new snapshot, after checking prerequisites.  The snapshot should be transmitted in the
 /// request entity body and must have content-type `application/vnd.taskchampion.snapshot`.  The
 /// content can be encoded in any of the formats supported by actix-web.
 ///
-/// On success, the response is a 200 OK. Even in a 200 OK, the snapshot may not appear in a
+/// On success, the response is a 200 OK, or a 204 NO CONTENT if the server is configured for
+/// strict HTTP. Even in a successful response, the snapshot may not appear in a
 /// subsequent `GetSnapshot` call.
 ///
+/// The snapshot is held in memory, as received, until it is written to storage. See
+/// [`RequestBody`] for details.
+///
+/// If the request has an `X-Snapshot-Length` header, the snapshot is rejected with a 400 BAD
+/// REQUEST unless its length, after decoding any content-encoding, is the length given there.
+/// This catches truncated uploads before they are stored.
+///
+/// If the server is configured with a maximum snapshot size, larger snapshots are rejected with a
+/// 413 PAYLOAD TOO LARGE, regardless of the maximum body size.
+///
 /// Returns other 4xx or 5xx responses on other errors.
 #[post("/v1/client/add-snapshot/{version_id}")]
 pub(crate) async fn service(
@@ -23,40 +45,114 @@ pub(crate) async fn service(
     mut payload: web::Payload,
 ) -> Result<HttpResponse> {
     let version_id = path.into_inner();
+    server_state.check_read_only()?;
 
     // check content-type
     if req.content_type() != SNAPSHOT_CONTENT_TYPE {
-        return Err(error::ErrorBadRequest("Bad content-type"));
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "bad_content_type",
+            "Bad content-type",
+        )
+        .into());
     }
 
     let client_id = server_state.client_id_header(&req)?;
+    server_state.check_rate_limit(client_id, EndpointClass::Write)?;
+
+    let expected_len: Option<usize> = match req.headers().get(SNAPSHOT_LENGTH_HEADER) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| {
+                    ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "bad_snapshot_length",
+                        format!("Invalid {SNAPSHOT_LENGTH_HEADER} header"),
+                    )
+                })?,
+        ),
+        None => None,
+    };
 
     // read the body in its entirety
-    let mut body = web::BytesMut::new();
+    let mut body = RequestBody::default();
     while let Some(chunk) = payload.next().await {
         let chunk = chunk?;
         // limit max size of in-memory payload
-        if (body.len() + chunk.len()) > MAX_SIZE {
-            return Err(error::ErrorBadRequest("Snapshot over maximum allowed size"));
+        let max = max_snapshot_size(&server_state.web_config);
+        if (body.len() + chunk.len()) > max {
+            return Err(if server_state.web_config.max_snapshot_size.is_some() {
+                ApiError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "snapshot_too_large",
+                    format!("Snapshot exceeds the maximum snapshot size of {max} bytes"),
+                )
+            } else {
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "body_too_large",
+                    format!("Snapshot exceeds the maximum body size of {max} bytes"),
+                )
+            }
+            .into());
         }
-        body.extend_from_slice(&chunk);
+        body.push(chunk);
     }
 
+    server_state.log_request_body("add-snapshot request body", &body);
+    server_state.metrics.add_snapshot_body(body.len());
+
     if body.is_empty() {
-        return Err(error::ErrorBadRequest("No snapshot supplied"));
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "empty_snapshot",
+            "No snapshot supplied",
+        )
+        .into());
+    }
+    if let Some(expected_len) = expected_len {
+        if body.len() != expected_len {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "snapshot_length_mismatch",
+                format!(
+                    "Snapshot is {} bytes, but {SNAPSHOT_LENGTH_HEADER} is {expected_len}",
+                    body.len()
+                ),
+            )
+            .into());
+        }
     }
 
-    server_state
+    let _lock = server_state.lock_client_writes(client_id).await;
+    let accepted = server_state
         .server
-        .add_snapshot(client_id, version_id, body.to_vec())
+        .retry_conflicts(|| {
+            server_state.server.add_snapshot_streaming(
+                client_id,
+                version_id,
+                body.len() as u64,
+                body.clone(),
+            )
+        })
         .map_err(server_error_to_actix)?;
-    Ok(HttpResponse::Ok().body(""))
+    server_state.metrics.add_snapshot(accepted);
+    let mut rb = if server_state.web_config.strict_http {
+        HttpResponse::NoContent()
+    } else {
+        HttpResponse::Ok()
+    };
+    server_state.warn_quota_usage(client_id, &mut rb);
+    Ok(rb.finish())
 }
 
 #[cfg(test)]
 mod test {
     use crate::api::CLIENT_ID_HEADER;
-    use crate::WebServer;
+    use crate::{WebConfig, WebServer};
     use actix_web::{http::StatusCode, test, App};
     use pretty_assertions::assert_eq;
     use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
@@ -76,7 +172,7 @@ mod test {
             txn.commit()?;
         }
 
-        let server = WebServer::new(Default::default(), None, storage);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -106,6 +202,41 @@ mod test {
         Ok(())
     }
 
+    #[actix_rt::test]
+    async fn test_success_strict_http() -> anyhow::Result<()> {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+
+        // set up the storage contents..
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(version_id).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, vec![])?;
+            txn.commit()?;
+        }
+
+        let web_config = WebConfig {
+            strict_http: true,
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/client/add-snapshot/{}", version_id);
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .insert_header(("Content-Type", "application/vnd.taskchampion.snapshot"))
+            .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(b"abcd".to_vec())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn test_not_added_200() {
         let client_id = Uuid::new_v4();
@@ -119,7 +250,7 @@ mod test {
             txn.commit().unwrap();
         }
 
-        let server = WebServer::new(Default::default(), None, storage);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -144,12 +275,74 @@ mod test {
         assert_eq!(resp.status(), StatusCode::NOT_FOUND);
     }
 
+    /// Build a request adding a snapshot, for a client with a single version, with the given
+    /// `X-Snapshot-Length` header, if any.
+    fn storage_and_request(length: Option<&str>) -> (InMemoryStorage, Uuid, test::TestRequest) {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(version_id).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, vec![]).unwrap();
+            txn.commit().unwrap();
+        }
+        let mut req = test::TestRequest::post()
+            .uri(&format!("/v1/client/add-snapshot/{version_id}"))
+            .insert_header(("Content-Type", "application/vnd.taskchampion.snapshot"))
+            .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(b"abcd".to_vec());
+        if let Some(length) = length {
+            req = req.insert_header(("X-Snapshot-Length", length));
+        }
+        (storage, client_id, req)
+    }
+
+    #[actix_rt::test]
+    async fn test_snapshot_length() {
+        for (length, status, code) in [
+            (None, StatusCode::OK, None),
+            (Some("4"), StatusCode::OK, None),
+            (
+                Some("3"),
+                StatusCode::BAD_REQUEST,
+                Some("snapshot_length_mismatch"),
+            ),
+            (
+                Some("5"),
+                StatusCode::BAD_REQUEST,
+                Some("snapshot_length_mismatch"),
+            ),
+            (
+                Some("four"),
+                StatusCode::BAD_REQUEST,
+                Some("bad_snapshot_length"),
+            ),
+        ] {
+            let (storage, client_id, req) = storage_and_request(length);
+            let server = WebServer::new(Default::default(), Default::default(), storage);
+            let app = App::new().configure(|sc| server.config(sc));
+            let app = test::init_service(app).await;
+
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), status, "with {length:?}");
+            if let Some(code) = code {
+                let body: serde_json::Value = test::read_body_json(resp).await;
+                assert_eq!(body["code"], code, "with {length:?}");
+            }
+
+            // the snapshot is stored only if the length matched
+            let snapshot = server.server_state.server.get_snapshot(client_id).unwrap();
+            assert_eq!(snapshot.is_some(), code.is_none(), "with {length:?}");
+        }
+    }
+
     #[actix_rt::test]
     async fn test_bad_content_type() {
         let client_id = Uuid::new_v4();
         let version_id = Uuid::new_v4();
         let storage = InMemoryStorage::new();
-        let server = WebServer::new(Default::default(), None, storage);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -169,7 +362,7 @@ mod test {
         let client_id = Uuid::new_v4();
         let version_id = Uuid::new_v4();
         let storage = InMemoryStorage::new();
-        let server = WebServer::new(Default::default(), None, storage);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -185,4 +378,56 @@ mod test {
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
     }
+
+    #[actix_rt::test]
+    async fn test_max_snapshot_size() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(version_id).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, vec![]).unwrap();
+            txn.commit().unwrap();
+        }
+        let web_config = WebConfig {
+            max_body_size: 8,
+            max_snapshot_size: Some(16),
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let add_snapshot = |payload: Vec<u8>| {
+            test::TestRequest::post()
+                .uri(&format!("/v1/client/add-snapshot/{version_id}"))
+                .append_header(("Content-Type", "application/vnd.taskchampion.snapshot"))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .set_payload(payload)
+                .to_request()
+        };
+
+        // a snapshot over the limit is rejected, with the limit in the body
+        let resp = test::call_service(&app, add_snapshot(vec![1; 17])).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "snapshot_too_large");
+        assert!(body["error"].as_str().unwrap().contains("16 bytes"));
+        assert_eq!(
+            server.server_state.server.get_snapshot(client_id).unwrap(),
+            None
+        );
+
+        // a snapshot at the limit is accepted, even though it exceeds the maximum body size
+        let resp = test::call_service(&app, add_snapshot(vec![1; 16])).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let (_, data) = server
+            .server_state
+            .server
+            .get_snapshot(client_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(data, vec![1; 16]);
+    }
 }
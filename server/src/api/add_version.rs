@@ -1,16 +1,25 @@
 use crate::api::{
-    failure_to_ise, server_error_to_actix, ServerState, HISTORY_SEGMENT_CONTENT_TYPE,
-    PARENT_VERSION_ID_HEADER, SNAPSHOT_REQUEST_HEADER, VERSION_ID_HEADER,
+    server_error_to_actix, snapshot_request, RequestBody, ServerState,
+    HISTORY_SEGMENT_CONTENT_TYPE, PARENT_VERSION_ID_HEADER, SNAPSHOT_REQUEST_HEADER,
+    VERSION_ID_HEADER,
 };
-use actix_web::{error, post, web, HttpMessage, HttpRequest, HttpResponse, Result};
+use crate::error::ApiError;
+use crate::rate_limit::EndpointClass;
+use crate::WebConfig;
+use actix_web::http::StatusCode;
+use actix_web::{post, web, HttpMessage, HttpRequest, HttpResponse, Result};
 use futures::StreamExt;
 use std::sync::Arc;
-use taskchampion_sync_server_core::{
-    AddVersionResult, ServerError, SnapshotUrgency, VersionId, NIL_VERSION_ID,
-};
+use taskchampion_sync_server_core::{AddVersionResult, ServerError, VersionId};
 
-/// Max history segment size: 100MB
-const MAX_SIZE: usize = 100 * 1024 * 1024;
+/// Get the maximum size of a history segment, including any limit on versions smaller than the
+/// maximum body size.
+pub(crate) fn max_version_size(web_config: &WebConfig) -> usize {
+    let max_body_size = web_config.max_body_size;
+    web_config
+        .max_version_size
+        .map_or(max_body_size, |max| max.min(max_body_size))
+}
 
 /// Add a new version, after checking prerequisites.  The history segment should be transmitted in
 /// the request entity body and must have content-type
@@ -21,6 +30,19 @@ const MAX_SIZE: usize = 100 * 1024 * 1024;
 /// the version cannot be added due to a conflict, the response is a 409 CONFLICT with the expected
 /// parent version ID in the `X-Parent-Version-Id` header.
 ///
+/// If the client does not exist, it is created if the server is configured to do so, and
+/// otherwise the response is a 404 NOT FOUND. If the server is configured with a default
+/// snapshot, the new client begins with that snapshot.
+///
+/// An empty history segment is rejected with a 400 BAD REQUEST, unless the server is configured
+/// to allow empty versions. A history segment larger than the configured maximum version size is
+/// rejected with a 413 PAYLOAD TOO LARGE, with the maximum given in the response body. A version
+/// which would take the client over its storage quota is also rejected with a 413 PAYLOAD TOO
+/// LARGE.
+///
+/// The history segment is held in memory, as received, until it is written to storage. See
+/// [`RequestBody`] for details.
+///
 /// If included, a snapshot request appears in the `X-Snapshot-Request` header with value
 /// `urgency=low` or `urgency=high`.
 ///
@@ -33,61 +55,102 @@ pub(crate) async fn service(
     mut payload: web::Payload,
 ) -> Result<HttpResponse> {
     let parent_version_id = path.into_inner();
+    server_state.check_read_only()?;
 
     // check content-type
     if req.content_type() != HISTORY_SEGMENT_CONTENT_TYPE {
-        return Err(error::ErrorBadRequest("Bad content-type"));
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "bad_content_type",
+            "Bad content-type",
+        )
+        .into());
     }
 
     let client_id = server_state.client_id_header(&req)?;
+    server_state.check_rate_limit(client_id, EndpointClass::Write)?;
 
     // read the body in its entirety
-    let mut body = web::BytesMut::new();
+    let max_version_size = server_state.web_config.max_version_size;
+    let mut body = RequestBody::default();
     while let Some(chunk) = payload.next().await {
         let chunk = chunk?;
+        if let Some(max) = max_version_size {
+            if (body.len() + chunk.len()) > max {
+                return Err(ApiError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "version_too_large",
+                    format!("History segment exceeds the maximum version size of {max} bytes"),
+                )
+                .into());
+            }
+        }
         // limit max size of in-memory payload
-        if (body.len() + chunk.len()) > MAX_SIZE {
-            return Err(error::ErrorBadRequest("overflow"));
+        let max_body_size = server_state.web_config.max_body_size;
+        if (body.len() + chunk.len()) > max_body_size {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "body_too_large",
+                format!("History segment exceeds the maximum body size of {max_body_size} bytes"),
+            )
+            .into());
         }
-        body.extend_from_slice(&chunk);
+        body.push(chunk);
     }
 
-    if body.is_empty() {
-        return Err(error::ErrorBadRequest("Empty body"));
+    server_state.log_request_body("add-version request body", &body);
+    server_state.metrics.add_version_body(body.len());
+
+    if body.is_empty() && !server_state.web_config.allow_empty_version {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "empty_version", "Empty body").into());
     }
 
+    let _lock = server_state.lock_client_writes(client_id).await;
     loop {
-        return match server_state
-            .server
-            .add_version(client_id, parent_version_id, body.to_vec())
-        {
+        // Each attempt reads a clone of the body, so it is still available if the client must
+        // first be created, or the transaction conflicts with another.
+        let result = server_state.server.retry_conflicts(|| {
+            server_state.server.add_version_streaming(
+                client_id,
+                parent_version_id,
+                body.len() as u64,
+                body.clone(),
+            )
+        });
+        if let Ok((result, snap_urgency)) = &result {
+            server_state.metrics.add_version(result, *snap_urgency);
+        }
+        return match result {
             Ok((AddVersionResult::Ok(version_id), snap_urgency)) => {
                 let mut rb = HttpResponse::Ok();
                 rb.append_header((VERSION_ID_HEADER, version_id.to_string()));
-                match snap_urgency {
-                    SnapshotUrgency::None => {}
-                    SnapshotUrgency::Low => {
-                        rb.append_header((SNAPSHOT_REQUEST_HEADER, "urgency=low"));
-                    }
-                    SnapshotUrgency::High => {
-                        rb.append_header((SNAPSHOT_REQUEST_HEADER, "urgency=high"));
-                    }
-                };
+                if let Some(snapshot_request) = snapshot_request(snap_urgency) {
+                    rb.append_header((SNAPSHOT_REQUEST_HEADER, snapshot_request));
+                }
+                server_state.warn_quota_usage(client_id, &mut rb);
                 Ok(rb.finish())
             }
             Ok((AddVersionResult::ExpectedParentVersion(parent_version_id), _)) => {
-                let mut rb = HttpResponse::Conflict();
-                rb.append_header((PARENT_VERSION_ID_HEADER, parent_version_id.to_string()));
-                Ok(rb.finish())
+                Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    "conflict",
+                    "Version does not follow the latest version",
+                )
+                .with_header(PARENT_VERSION_ID_HEADER, parent_version_id.to_string())
+                .into())
             }
-            Err(ServerError::NoSuchClient) => {
+            Ok((AddVersionResult::QuotaExceeded, _)) => Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "quota_exceeded",
+                "Version exceeds the client's storage quota",
+            )
+            .into()),
+            Err(err @ ServerError::NoSuchClient) => {
+                if !server_state.may_create_client(client_id) {
+                    return Err(server_error_to_actix(err));
+                }
                 // Create a new client and repeat the `add_version` call.
-                let mut txn = server_state
-                    .server
-                    .txn(client_id)
-                    .map_err(server_error_to_actix)?;
-                txn.new_client(NIL_VERSION_ID).map_err(failure_to_ise)?;
-                txn.commit().map_err(failure_to_ise)?;
+                server_state.create_client(client_id)?;
                 continue;
             }
             Err(e) => Err(server_error_to_actix(e)),
@@ -98,10 +161,12 @@ pub(crate) async fn service(
 #[cfg(test)]
 mod test {
     use crate::api::CLIENT_ID_HEADER;
-    use crate::WebServer;
+    use crate::{CreateClients, WebConfig, WebServer};
     use actix_web::{http::StatusCode, test, App};
     use pretty_assertions::assert_eq;
-    use taskchampion_sync_server_core::{InMemoryStorage, Storage};
+    use taskchampion_sync_server_core::{InMemoryStorage, ServerConfig, Storage, NIL_VERSION_ID};
+    use taskchampion_sync_server_storage_sqlite::SqliteStorage;
+    use tempfile::TempDir;
     use uuid::Uuid;
 
     #[actix_rt::test]
@@ -114,11 +179,11 @@ mod test {
         // set up the storage contents..
         {
             let mut txn = storage.txn(client_id).unwrap();
-            txn.new_client(Uuid::nil()).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
             txn.commit().unwrap();
         }
 
-        let server = WebServer::new(Default::default(), None, storage);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -147,12 +212,61 @@ mod test {
         assert_eq!(resp.headers().get("X-Parent-Version-Id"), None);
     }
 
+    #[actix_rt::test]
+    async fn test_large_payload() {
+        let client_id = Uuid::new_v4();
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = SqliteStorage::new(tmp_dir.path()).unwrap();
+
+        // set up the storage contents..
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        // a 10MB history segment
+        let history_segment: Vec<u8> = (0..10 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let uri = format!("/v1/client/add-version/{NIL_VERSION_ID}");
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.history-segment",
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(history_segment.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let version_id: Uuid = resp
+            .headers()
+            .get("X-Version-Id")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        let mut txn = server.server_state.server.txn(client_id).unwrap();
+        let version = txn.get_version(version_id).unwrap().unwrap();
+        assert!(version.history_segment == history_segment);
+    }
+
     #[actix_rt::test]
     async fn test_auto_add_client() {
         let client_id = Uuid::new_v4();
         let version_id = Uuid::new_v4();
         let parent_version_id = Uuid::new_v4();
-        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -190,6 +304,127 @@ mod test {
         }
     }
 
+    #[actix_rt::test]
+    async fn test_auto_add_client_allowlisted() {
+        let client_id = Uuid::new_v4();
+        let web_config = WebConfig {
+            client_id_allowlist: Some([client_id].into()),
+            create_clients: CreateClients::AllowlistOnly,
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/client/add-version/{}", NIL_VERSION_ID);
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.history-segment",
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(b"abcd".to_vec())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Check that the client really was created
+        let mut txn = server.server_state.server.txn(client_id).unwrap();
+        assert!(txn.get_client().unwrap().is_some());
+    }
+
+    #[actix_rt::test]
+    async fn test_auto_add_client_default_snapshot() {
+        let client_id = Uuid::new_v4();
+        let snapshot_version_id = Uuid::new_v4();
+        let web_config = WebConfig {
+            default_snapshot: Some((snapshot_version_id, b"baseline".to_vec())),
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        // the new client's latest version is the snapshot's version, so this conflicts
+        let uri = format!("/v1/client/add-version/{}", NIL_VERSION_ID);
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.history-segment",
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(b"abcd".to_vec())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            resp.headers().get("X-Parent-Version-Id").unwrap(),
+            &snapshot_version_id.to_string()
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/v1/client/snapshot")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("X-Version-Id").unwrap(),
+            &snapshot_version_id.to_string()
+        );
+        assert_eq!(test::read_body(resp).await.as_ref(), b"baseline");
+
+        // a version based on the snapshot can then be added
+        let uri = format!("/v1/client/add-version/{}", snapshot_version_id);
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.history-segment",
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(b"abcd".to_vec())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_no_auto_add_client() {
+        for create_clients in [CreateClients::Never, CreateClients::AllowlistOnly] {
+            let client_id = Uuid::new_v4();
+            // All clients are allowed, but not created.
+            let web_config = WebConfig {
+                client_id_allowlist: None,
+                create_clients,
+                ..WebConfig::default()
+            };
+            let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+            let app = App::new().configure(|sc| server.config(sc));
+            let app = test::init_service(app).await;
+
+            let uri = format!("/v1/client/add-version/{}", NIL_VERSION_ID);
+            let req = test::TestRequest::post()
+                .uri(&uri)
+                .append_header((
+                    "Content-Type",
+                    "application/vnd.taskchampion.history-segment",
+                ))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .set_payload(b"abcd".to_vec())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+            assert_eq!(resp.headers().get("X-Version-Id"), None);
+
+            // Check that the client was not created
+            let mut txn = server.server_state.server.txn(client_id).unwrap();
+            assert_eq!(txn.get_client().unwrap(), None);
+        }
+    }
+
     #[actix_rt::test]
     async fn test_conflict() {
         let client_id = Uuid::new_v4();
@@ -204,7 +439,7 @@ mod test {
             txn.commit().unwrap();
         }
 
-        let server = WebServer::new(Default::default(), None, storage);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -225,6 +460,122 @@ mod test {
             resp.headers().get("X-Parent-Version-Id").unwrap(),
             &version_id.to_string()
         );
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "conflict");
+    }
+
+    #[actix_rt::test]
+    async fn test_bad_client_id() {
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/v1/client/add-version/{NIL_VERSION_ID}"))
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.history-segment",
+            ))
+            .append_header((CLIENT_ID_HEADER, "not-a-uuid"))
+            .set_payload(b"abcd".to_vec())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body,
+            serde_json::json!({"error": "bad x-client-id", "code": "bad_client_id"})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_quota_exceeded() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+
+        // set up the storage contents..
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, vec![0; 10])
+                .unwrap();
+            txn.commit().unwrap();
+        }
+
+        let config = ServerConfig {
+            max_client_bytes: Some(12),
+            ..ServerConfig::default()
+        };
+        let server = WebServer::new(config, Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/client/add-version/{version_id}");
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.history-segment",
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(b"abcd".to_vec())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(resp.headers().get("X-Version-Id"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_quota_usage() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+
+        // set up the storage contents..
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, vec![0; 10])
+                .unwrap();
+            txn.commit().unwrap();
+        }
+
+        let config = ServerConfig {
+            max_client_bytes: Some(20),
+            ..ServerConfig::default()
+        };
+        let server = WebServer::new(config, Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let add_version = |parent_version_id: Uuid, payload: &[u8]| {
+            test::TestRequest::post()
+                .uri(&format!("/v1/client/add-version/{parent_version_id}"))
+                .append_header((
+                    "Content-Type",
+                    "application/vnd.taskchampion.history-segment",
+                ))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .set_payload(payload.to_vec())
+                .to_request()
+        };
+
+        // 14 of 20 bytes is comfortably under the quota
+        let resp = test::call_service(&app, add_version(version_id, b"abcd")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("X-Quota-Usage"), None);
+        let version_id = resp.headers().get("X-Version-Id").unwrap();
+        let version_id: Uuid = version_id.to_str().unwrap().parse().unwrap();
+
+        // 18 of 20 bytes is just below the quota
+        let resp = test::call_service(&app, add_version(version_id, b"abcd")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("X-Quota-Usage").unwrap(), "bytes=18/20");
     }
 
     #[actix_rt::test]
@@ -232,7 +583,7 @@ mod test {
         let client_id = Uuid::new_v4();
         let parent_version_id = Uuid::new_v4();
         let storage = InMemoryStorage::new();
-        let server = WebServer::new(Default::default(), None, storage);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -252,7 +603,7 @@ mod test {
         let client_id = Uuid::new_v4();
         let parent_version_id = Uuid::new_v4();
         let storage = InMemoryStorage::new();
-        let server = WebServer::new(Default::default(), None, storage);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -268,4 +619,214 @@ mod test {
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
     }
+
+    #[actix_rt::test]
+    async fn test_empty_body_allowed() {
+        let client_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        let web_config = WebConfig {
+            allow_empty_version: true,
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/client/add-version/{}", NIL_VERSION_ID);
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.history-segment",
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let new_version_id = resp.headers().get("X-Version-Id").unwrap();
+        let new_version_id = Uuid::parse_str(new_version_id.to_str().unwrap()).unwrap();
+
+        // the empty version can be retrieved
+        let uri = format!("/v1/client/get-child-version/{}", NIL_VERSION_ID);
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("X-Version-Id").unwrap(),
+            &new_version_id.to_string()
+        );
+
+        use actix_web::body::MessageBody;
+        let bytes = resp.into_body().try_into_bytes().unwrap();
+        assert!(bytes.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_serialize_writes() {
+        let client_id = Uuid::new_v4();
+        let web_config = WebConfig {
+            serialize_writes: true,
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        // Concurrently add several versions for the same, not-yet-created, client. Exactly one
+        // should succeed, and the others should see a conflict rather than an error.
+        let uri = format!("/v1/client/add-version/{}", NIL_VERSION_ID);
+        let responses = futures::future::join_all((0..5).map(|_| {
+            let req = test::TestRequest::post()
+                .uri(&uri)
+                .append_header((
+                    "Content-Type",
+                    "application/vnd.taskchampion.history-segment",
+                ))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .set_payload(b"abcd".to_vec())
+                .to_request();
+            test::call_service(&app, req)
+        }))
+        .await;
+
+        let mut statuses: Vec<_> = responses.iter().map(|r| r.status()).collect();
+        statuses.sort();
+        assert_eq!(
+            statuses,
+            vec![
+                StatusCode::OK,
+                StatusCode::CONFLICT,
+                StatusCode::CONFLICT,
+                StatusCode::CONFLICT,
+                StatusCode::CONFLICT,
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_max_version_size() {
+        let client_id = Uuid::new_v4();
+        let web_config = WebConfig {
+            max_version_size: Some(16),
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let add_version = |payload: Vec<u8>| {
+            test::TestRequest::post()
+                .uri(&format!("/v1/client/add-version/{NIL_VERSION_ID}"))
+                .append_header((
+                    "Content-Type",
+                    "application/vnd.taskchampion.history-segment",
+                ))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .set_payload(payload)
+                .to_request()
+        };
+
+        // a version over the limit is rejected, with the limit in the body
+        let resp = test::call_service(&app, add_version(vec![1; 17])).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("16 bytes"));
+
+        // a version at the limit is accepted
+        let resp = test::call_service(&app, add_version(vec![1; 16])).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let version_id = resp.headers().get("X-Version-Id").unwrap();
+        let version_id = version_id.to_str().unwrap().to_string();
+
+        // a snapshot over the version limit is accepted
+        let req = test::TestRequest::post()
+            .uri(&format!("/v1/client/add-snapshot/{version_id}"))
+            .append_header(("Content-Type", "application/vnd.taskchampion.snapshot"))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(vec![1; 17])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_max_body_size() {
+        let client_id = Uuid::new_v4();
+        let web_config = WebConfig {
+            max_body_size: 16,
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let add_version = |payload: Vec<u8>| {
+            test::TestRequest::post()
+                .uri(&format!("/v1/client/add-version/{NIL_VERSION_ID}"))
+                .append_header((
+                    "Content-Type",
+                    "application/vnd.taskchampion.history-segment",
+                ))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .set_payload(payload)
+                .to_request()
+        };
+        let add_snapshot = |version_id: &str, payload: Vec<u8>| {
+            test::TestRequest::post()
+                .uri(&format!("/v1/client/add-snapshot/{version_id}"))
+                .append_header(("Content-Type", "application/vnd.taskchampion.snapshot"))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .set_payload(payload)
+                .to_request()
+        };
+
+        // a version over the limit is rejected, with the limit in the body
+        let resp = test::call_service(&app, add_version(vec![1; 17])).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("16 bytes"));
+
+        // a version at the limit is accepted
+        let resp = test::call_service(&app, add_version(vec![1; 16])).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let version_id = resp.headers().get("X-Version-Id").unwrap();
+        let version_id = version_id.to_str().unwrap().to_string();
+
+        // likewise for snapshots
+        let resp = test::call_service(&app, add_snapshot(&version_id, vec![1; 17])).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("16 bytes"));
+        let resp = test::call_service(&app, add_snapshot(&version_id, vec![1; 16])).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_malformed_parent_version_id() {
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/v1/client/add-version/not-a-uuid")
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.history-segment",
+            ))
+            .append_header((CLIENT_ID_HEADER, Uuid::new_v4().to_string()))
+            .set_payload(b"abcd".to_vec())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "bad_path");
+        assert_eq!(body["error"], "malformed parent_version_id (expected UUID)");
+    }
 }
@@ -0,0 +1,300 @@
+use crate::api::add_version::max_version_size;
+use crate::api::{
+    server_error_to_actix, RequestBody, ServerState, HISTORY_SEGMENT_LENGTH_HEADER,
+    PARENT_VERSION_ID_HEADER, VERSION_AND_SNAPSHOT_CONTENT_TYPE, VERSION_ID_HEADER,
+};
+use crate::error::ApiError;
+use crate::rate_limit::EndpointClass;
+use actix_web::http::StatusCode;
+use actix_web::{post, web, HttpMessage, HttpRequest, HttpResponse, Result};
+use futures::StreamExt;
+use std::io::Read;
+use std::sync::Arc;
+use taskchampion_sync_server_core::{AddVersionResult, ServerError, SnapshotUrgency, VersionId};
+
+/// Add a new version and a snapshot for that version, in a single transaction, after checking
+/// prerequisites. The request entity body must have content-type
+/// `application/vnd.taskchampion.version-and-snapshot`, and contain the history segment followed
+/// by the snapshot, with the length of the history segment, in bytes, given in the
+/// `X-History-Segment-Length` header.
+///
+/// On success, the response is a 200 OK with the new version ID in the `X-Version-Id` header, and
+/// the snapshot is stored for that version. If the version cannot be added due to a conflict, the
+/// response is a 409 CONFLICT with the expected parent version ID in the `X-Parent-Version-Id`
+/// header, and the snapshot is not stored either.
+///
+/// As for AddVersion, a client which does not exist is created if the server is configured to do
+/// so, and the history segment is subject to the maximum version size and the client's storage
+/// quota. The snapshot is subject to the maximum snapshot size, if configured, and the request as
+/// a whole to the maximum body size. An empty snapshot, or a missing or invalid history segment
+/// length, is rejected with a 400 BAD REQUEST.
+///
+/// Returns other 4xx or 5xx responses on other errors.
+#[post("/v1/client/add-version-and-snapshot/{parent_version_id}")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<VersionId>,
+    mut payload: web::Payload,
+) -> Result<HttpResponse> {
+    let parent_version_id = path.into_inner();
+    server_state.check_read_only()?;
+
+    // check content-type
+    if req.content_type() != VERSION_AND_SNAPSHOT_CONTENT_TYPE {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "bad_content_type",
+            "Bad content-type",
+        )
+        .into());
+    }
+
+    let client_id = server_state.client_id_header(&req)?;
+    server_state.check_rate_limit(client_id, EndpointClass::Write)?;
+
+    let bad_segment_length = || {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "bad_segment_length",
+            format!("Missing or invalid {HISTORY_SEGMENT_LENGTH_HEADER} header"),
+        )
+    };
+    let segment_len: usize = req
+        .headers()
+        .get(HISTORY_SEGMENT_LENGTH_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(bad_segment_length)?;
+    let max_version_size = max_version_size(&server_state.web_config);
+    if segment_len > max_version_size {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "version_too_large",
+            format!("History segment exceeds the maximum version size of {max_version_size} bytes"),
+        )
+        .into());
+    }
+
+    // read the body in its entirety
+    let mut body = RequestBody::default();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        // limit max size of in-memory payload
+        let max_body_size = server_state.web_config.max_body_size;
+        if (body.len() + chunk.len()) > max_body_size {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "body_too_large",
+                format!("Request body exceeds the maximum body size of {max_body_size} bytes"),
+            )
+            .into());
+        }
+        body.push(chunk);
+    }
+
+    server_state.log_request_body("add-version-and-snapshot request body", &body);
+    if segment_len > body.len() {
+        return Err(bad_segment_length().into());
+    }
+
+    // split the body into the history segment and the snapshot
+    let mut history_segment = vec![0; segment_len];
+    body.read_exact(&mut history_segment)
+        .map_err(ApiError::internal)?;
+    let mut snapshot = Vec::with_capacity(body.len());
+    body.read_to_end(&mut snapshot)
+        .map_err(ApiError::internal)?;
+    if let Some(max) = server_state.web_config.max_snapshot_size {
+        if snapshot.len() > max {
+            return Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "snapshot_too_large",
+                format!("Snapshot exceeds the maximum snapshot size of {max} bytes"),
+            )
+            .into());
+        }
+    }
+    server_state.metrics.add_version_body(history_segment.len());
+    server_state.metrics.add_snapshot_body(snapshot.len());
+
+    if history_segment.is_empty() && !server_state.web_config.allow_empty_version {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "empty_version",
+            "Empty history segment",
+        )
+        .into());
+    }
+    if snapshot.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "empty_snapshot",
+            "No snapshot supplied",
+        )
+        .into());
+    }
+
+    let _lock = server_state.lock_client_writes(client_id).await;
+    loop {
+        let result = server_state.server.retry_conflicts(|| {
+            server_state.server.add_version_and_snapshot(
+                client_id,
+                parent_version_id,
+                history_segment.clone(),
+                snapshot.clone(),
+            )
+        });
+        if let Ok(result) = &result {
+            server_state
+                .metrics
+                .add_version(result, SnapshotUrgency::None);
+        }
+        return match result {
+            Ok(AddVersionResult::Ok(version_id)) => {
+                server_state.metrics.add_snapshot(true);
+                let mut rb = HttpResponse::Ok();
+                rb.append_header((VERSION_ID_HEADER, version_id.to_string()));
+                server_state.warn_quota_usage(client_id, &mut rb);
+                Ok(rb.finish())
+            }
+            Ok(AddVersionResult::ExpectedParentVersion(parent_version_id)) => Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "conflict",
+                "Version does not follow the latest version",
+            )
+            .with_header(PARENT_VERSION_ID_HEADER, parent_version_id.to_string())
+            .into()),
+            Ok(AddVersionResult::QuotaExceeded) => Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "quota_exceeded",
+                "Version exceeds the client's storage quota",
+            )
+            .into()),
+            Err(err @ ServerError::NoSuchClient) => {
+                if !server_state.may_create_client(client_id) {
+                    return Err(server_error_to_actix(err));
+                }
+                // Create a new client and repeat the `add_version_and_snapshot` call.
+                server_state.create_client(client_id)?;
+                continue;
+            }
+            Err(e) => Err(server_error_to_actix(e)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    /// Build a request adding the given history segment and snapshot.
+    fn request(
+        client_id: Uuid,
+        parent_version_id: Uuid,
+        segment: &[u8],
+        snapshot: &[u8],
+    ) -> test::TestRequest {
+        let mut payload = segment.to_vec();
+        payload.extend_from_slice(snapshot);
+        test::TestRequest::post()
+            .uri(&format!(
+                "/v1/client/add-version-and-snapshot/{parent_version_id}"
+            ))
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.version-and-snapshot",
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .append_header(("X-History-Segment-Length", segment.len().to_string()))
+            .set_payload(payload)
+    }
+
+    #[actix_rt::test]
+    async fn test_success() -> anyhow::Result<()> {
+        let client_id = Uuid::new_v4();
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = request(client_id, NIL_VERSION_ID, b"abcd", b"snap").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let version_id = resp.headers().get("X-Version-Id").unwrap().to_str()?;
+
+        // the new version has the snapshot
+        let req = test::TestRequest::get()
+            .uri("/v1/client/snapshot")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("X-Version-Id").unwrap(), version_id);
+        assert_eq!(test::read_body(resp).await.as_ref(), b"snap");
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_conflict() -> anyhow::Result<()> {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(version_id)?;
+            txn.add_version(version_id, NIL_VERSION_ID, b"v1".to_vec())?;
+            txn.commit()?;
+        }
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = request(client_id, Uuid::new_v4(), b"abcd", b"snap").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            resp.headers().get("X-Parent-Version-Id").unwrap(),
+            &version_id.to_string()
+        );
+
+        // the snapshot was not stored
+        let req = test::TestRequest::get()
+            .uri("/v1/client/snapshot")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_bad_segment_length() {
+        let client_id = Uuid::new_v4();
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        for length in ["ten", "100"] {
+            let req = request(client_id, NIL_VERSION_ID, b"abcd", b"snap")
+                .insert_header(("X-History-Segment-Length", length))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "with {length}");
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(body["code"], "bad_segment_length");
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use crate::api::{add_snapshot, add_version, server_error_to_actix, ServerState};
+use crate::rate_limit::EndpointClass;
+use actix_web::{get, web, HttpRequest, HttpResponse, Result};
+use serde::Serialize;
+use std::sync::Arc;
+use taskchampion_sync_server_core::{SnapshotUrgency, VersionId, NIL_VERSION_ID};
+
+/// Server configuration relevant to clients.
+#[derive(Serialize)]
+struct BootstrapConfig {
+    snapshot_days: i64,
+    snapshot_versions: u32,
+    snapshot_latest_only: bool,
+}
+
+/// Limits enforced by the server.
+#[derive(Serialize)]
+struct BootstrapLimits {
+    max_history_segment_size: usize,
+    max_snapshot_size: usize,
+}
+
+/// Current status of the requesting client.
+#[derive(Serialize)]
+struct BootstrapClient {
+    exists: bool,
+    latest_version_id: VersionId,
+    snapshot_version_id: Option<VersionId>,
+    snapshot_urgency: &'static str,
+}
+
+#[derive(Serialize)]
+struct Bootstrap {
+    config: BootstrapConfig,
+    limits: BootstrapLimits,
+    client: BootstrapClient,
+}
+
+/// Get everything a replica needs to configure itself, in a single request.
+///
+/// The response is a JSON object with keys `config`, containing the server's snapshot
+/// configuration, `limits`, containing the maximum sizes of request bodies, and `client`,
+/// containing the status of the requesting client.
+///
+/// This succeeds even if the client does not exist yet, in which case `client.exists` is false
+/// and `client.latest_version_id` is the nil version.
+///
+/// Returns other 4xx or 5xx responses on other errors.
+#[get("/v1/client/bootstrap")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+) -> Result<HttpResponse> {
+    let client_id = server_state.client_id_header(&req)?;
+    server_state.check_rate_limit(client_id, EndpointClass::Read)?;
+    let server = &server_state.server;
+
+    let client = {
+        let mut txn = server
+            .read_only_txn(client_id)
+            .map_err(server_error_to_actix)?;
+        txn.get_client()
+            .map_err(|e| server_error_to_actix(e.into()))?
+    };
+
+    let client = match client {
+        Some(client) => {
+            let snapshot_urgency = match server.snapshot_urgency(&client) {
+                SnapshotUrgency::None => "none",
+                SnapshotUrgency::Low => "low",
+                SnapshotUrgency::High => "high",
+            };
+            BootstrapClient {
+                exists: true,
+                latest_version_id: client.latest_version_id,
+                snapshot_version_id: client.snapshot.map(|snap| snap.version_id),
+                snapshot_urgency,
+            }
+        }
+        None => BootstrapClient {
+            exists: false,
+            latest_version_id: NIL_VERSION_ID,
+            snapshot_version_id: None,
+            snapshot_urgency: "none",
+        },
+    };
+
+    let config = server.config();
+    Ok(HttpResponse::Ok().json(Bootstrap {
+        config: BootstrapConfig {
+            snapshot_days: config.snapshot_days,
+            snapshot_versions: config.snapshot_versions,
+            snapshot_latest_only: config.snapshot_latest_only,
+        },
+        limits: BootstrapLimits {
+            max_history_segment_size: add_version::max_version_size(&server_state.web_config),
+            max_snapshot_size: add_snapshot::max_snapshot_size(&server_state.web_config),
+        },
+        client,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn test_known_client() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+
+        // set up the storage contents..
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, b"abcd".to_vec())
+                .unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/client/bootstrap")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            &"application/json".to_string()
+        );
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "config": {
+                    "snapshot_days": 14,
+                    "snapshot_versions": 100,
+                    "snapshot_latest_only": false,
+                },
+                "limits": {
+                    "max_history_segment_size": 100 * 1024 * 1024,
+                    "max_snapshot_size": 100 * 1024 * 1024,
+                },
+                "client": {
+                    "exists": true,
+                    "latest_version_id": version_id,
+                    "snapshot_version_id": null,
+                    // there is no snapshot yet
+                    "snapshot_urgency": "high",
+                },
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_new_client() {
+        let client_id = Uuid::new_v4();
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/client/bootstrap")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body["client"],
+            serde_json::json!({
+                "exists": false,
+                "latest_version_id": NIL_VERSION_ID,
+                "snapshot_version_id": null,
+                "snapshot_urgency": "none",
+            })
+        );
+
+        // The request did not create the client.
+        let mut txn = server.server_state.server.txn(client_id).unwrap();
+        assert_eq!(txn.get_client().unwrap(), None);
+    }
+}
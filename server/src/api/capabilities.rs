@@ -0,0 +1,193 @@
+use crate::api::{
+    add_snapshot, add_version, ServerState, HISTORY_SEGMENT_CONTENT_TYPE, SNAPSHOT_CONTENT_TYPE,
+    VERSION_AND_SNAPSHOT_CONTENT_TYPE,
+};
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use std::sync::Arc;
+use taskchampion_sync_server_core::{Compression, VERSION_STREAM_CONTENT_TYPE};
+
+/// Snapshot configuration, as in [`taskchampion_sync_server_core::ServerConfig`].
+#[derive(Serialize)]
+struct CapabilitiesSnapshot {
+    snapshot_days: i64,
+    snapshot_versions: u32,
+    snapshot_latest_only: bool,
+}
+
+/// Maximum sizes, in bytes, of request bodies.
+#[derive(Serialize)]
+struct CapabilitiesLimits {
+    max_body_size: usize,
+    max_history_segment_size: usize,
+    max_snapshot_size: usize,
+}
+
+/// Endpoints and behaviors which are optional or configurable.
+#[derive(Serialize)]
+struct CapabilitiesFeatures {
+    get_version: bool,
+    get_child_versions: bool,
+    add_version_and_snapshot: bool,
+    client_deletion: bool,
+    response_compression: bool,
+    read_only: bool,
+}
+
+/// Compression applied by the server.
+#[derive(Serialize)]
+struct CapabilitiesCompression {
+    /// Algorithms with which new history segments are compressed in storage.
+    at_rest: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct Capabilities {
+    version: &'static str,
+    content_types: Vec<&'static str>,
+    limits: CapabilitiesLimits,
+    features: CapabilitiesFeatures,
+    snapshot: CapabilitiesSnapshot,
+    compression: CapabilitiesCompression,
+}
+
+/// Describe the server's capabilities, so that clients can negotiate features without trial and
+/// error.
+///
+/// The response is a JSON object with keys `version`, the version of the server; `content_types`,
+/// the content-types of request and response bodies it supports; `limits`, the maximum sizes of
+/// request bodies; `features`, whether each optional endpoint or behavior is enabled;
+/// `snapshot`, the server's snapshot configuration; and `compression`, the algorithms with which
+/// the server compresses history segments in storage, which is informational, as clients always
+/// receive the original bytes.
+///
+/// This contains no client data, so it requires no authentication.
+#[get("/v1/capabilities")]
+pub(crate) async fn service(server_state: web::Data<Arc<ServerState>>) -> HttpResponse {
+    let web_config = &server_state.web_config;
+    let config = server_state.server.config();
+    HttpResponse::Ok().json(Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        content_types: vec![
+            HISTORY_SEGMENT_CONTENT_TYPE,
+            SNAPSHOT_CONTENT_TYPE,
+            VERSION_AND_SNAPSHOT_CONTENT_TYPE,
+            VERSION_STREAM_CONTENT_TYPE,
+        ],
+        limits: CapabilitiesLimits {
+            max_body_size: web_config.max_body_size,
+            max_history_segment_size: add_version::max_version_size(web_config),
+            max_snapshot_size: add_snapshot::max_snapshot_size(web_config),
+        },
+        features: CapabilitiesFeatures {
+            get_version: web_config.enable_get_version,
+            get_child_versions: true,
+            add_version_and_snapshot: true,
+            client_deletion: web_config.allow_client_deletion,
+            response_compression: web_config.response_compression,
+            read_only: web_config.read_only,
+        },
+        snapshot: CapabilitiesSnapshot {
+            snapshot_days: config.snapshot_days,
+            snapshot_versions: config.snapshot_versions,
+            snapshot_latest_only: config.snapshot_latest_only,
+        },
+        compression: CapabilitiesCompression {
+            at_rest: match config.compression {
+                Compression::None => vec![],
+                Compression::Zstd => vec!["zstd"],
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{WebConfig, WebServer};
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{Compression, InMemoryStorage, ServerConfig};
+
+    #[actix_rt::test]
+    async fn test_capabilities() {
+        let config = ServerConfig {
+            snapshot_days: 7,
+            snapshot_versions: 50,
+            ..ServerConfig::default()
+        };
+        let web_config = WebConfig {
+            // capabilities are served without the token required for sync requests
+            require_token: Some("secret".into()),
+            max_body_size: 1000,
+            max_version_size: Some(100),
+            enable_get_version: true,
+            response_compression: true,
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(config, web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/capabilities")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "content_types": [
+                    "application/vnd.taskchampion.history-segment",
+                    "application/vnd.taskchampion.snapshot",
+                    "application/vnd.taskchampion.version-and-snapshot",
+                    "application/vnd.taskchampion.version-stream",
+                ],
+                "limits": {
+                    "max_body_size": 1000,
+                    "max_history_segment_size": 100,
+                    "max_snapshot_size": 1000,
+                },
+                "features": {
+                    "get_version": true,
+                    "get_child_versions": true,
+                    "add_version_and_snapshot": true,
+                    "client_deletion": false,
+                    "response_compression": true,
+                    "read_only": false,
+                },
+                "snapshot": {
+                    "snapshot_days": 7,
+                    "snapshot_versions": 50,
+                    "snapshot_latest_only": false,
+                },
+                "compression": {
+                    "at_rest": [],
+                },
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_capabilities_compression() {
+        let config = ServerConfig {
+            compression: Compression::Zstd,
+            ..ServerConfig::default()
+        };
+        let server = WebServer::new(config, WebConfig::default(), InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/capabilities")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body["compression"],
+            serde_json::json!({"at_rest": ["zstd"]})
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use futures::lock::{Mutex as AsyncMutex, OwnedMutexGuard};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use taskchampion_sync_server_core::ClientId;
+
+/// A keyed async mutex, used to serialize writes for each client within this process.
+///
+/// Writes for the same client wait for one another, rather than conflicting in the storage
+/// backend, while writes for different clients proceed in parallel. This does not replace the
+/// checks performed by the storage backend, and has no effect across multiple server processes.
+#[derive(Default)]
+pub(crate) struct ClientLocks(Mutex<HashMap<ClientId, Arc<AsyncMutex<()>>>>);
+
+impl ClientLocks {
+    /// Wait until no other write for the given client is in progress, and lock it. The lock is
+    /// held until the returned guard is dropped.
+    pub(crate) async fn lock(&self, client_id: ClientId) -> ClientLockGuard<'_> {
+        let mutex = {
+            let mut locks = self.0.lock().expect("poisoned lock");
+            locks.entry(client_id).or_default().clone()
+        };
+        let guard = mutex.lock_owned().await;
+        ClientLockGuard {
+            locks: self,
+            client_id,
+            guard: Some(guard),
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.0.lock().expect("poisoned lock").len()
+    }
+}
+
+/// A lock on writes for a single client, released when dropped.
+pub(crate) struct ClientLockGuard<'a> {
+    locks: &'a ClientLocks,
+    client_id: ClientId,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for ClientLockGuard<'_> {
+    fn drop(&mut self) {
+        let mut locks = self.locks.0.lock().expect("poisoned lock");
+        // Release the async mutex while holding the map lock, so that no other task can clone
+        // the mutex between this check and the removal.
+        drop(self.guard.take());
+        if let Some(mutex) = locks.get(&self.client_id) {
+            // If the map holds the only reference, no other task is waiting for this client,
+            // so remove the entry to avoid accumulating a mutex for every client ever seen.
+            if Arc::strong_count(mutex) == 1 {
+                locks.remove(&self.client_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+    use pretty_assertions::assert_eq;
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn same_client_waits() {
+        let locks = ClientLocks::default();
+        let client_id = Uuid::new_v4();
+
+        let guard = locks.lock(client_id).await;
+        let mut second = Box::pin(locks.lock(client_id));
+        assert!((&mut second).now_or_never().is_none());
+
+        drop(guard);
+        let guard = second.await;
+        drop(guard);
+        assert_eq!(locks.len(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn different_clients_proceed() {
+        let locks = ClientLocks::default();
+
+        let guard1 = locks.lock(Uuid::new_v4()).await;
+        let guard2 = locks.lock(Uuid::new_v4()).now_or_never();
+        assert!(guard2.is_some());
+        assert_eq!(locks.len(), 2);
+
+        drop(guard1);
+        drop(guard2);
+        assert_eq!(locks.len(), 0);
+    }
+}
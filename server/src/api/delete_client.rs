@@ -0,0 +1,134 @@
+use crate::api::{server_error_to_actix, ServerState};
+use crate::error::ApiError;
+use crate::rate_limit::EndpointClass;
+use actix_web::http::StatusCode;
+use actix_web::{delete, web, HttpRequest, HttpResponse, Result};
+use std::sync::Arc;
+
+/// Delete the client, including its snapshot and all of its versions, so that no data about it
+/// remains on the server.
+///
+/// This is only available if client deletion is enabled in the configuration, and otherwise
+/// returns a 403 FORBIDDEN. On success, the response is a 204 NO CONTENT. If the client does not
+/// exist, the response is a 404 NOT FOUND. Returns other 4xx or 5xx responses on other errors.
+#[delete("/v1/client")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+) -> Result<HttpResponse> {
+    server_state.check_read_only()?;
+    if !server_state.web_config.allow_client_deletion {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "client_deletion_disabled",
+            "client deletion is not enabled",
+        )
+        .into());
+    }
+
+    let client_id = server_state.client_id_header(&req)?;
+    server_state.check_rate_limit(client_id, EndpointClass::Write)?;
+
+    let _lock = server_state.lock_client_writes(client_id).await;
+    server_state
+        .server
+        .delete_client(client_id)
+        .map_err(server_error_to_actix)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::{WebConfig, WebServer};
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    /// Create a storage containing a client with a version, returning the version ID.
+    fn storage_with_client(client_id: Uuid) -> (InMemoryStorage, Uuid) {
+        let storage = InMemoryStorage::new();
+        let version_id = Uuid::new_v4();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, b"abcd".to_vec())
+                .unwrap();
+            txn.commit().unwrap();
+        }
+        (storage, version_id)
+    }
+
+    #[actix_rt::test]
+    async fn test_success() {
+        let client_id = Uuid::new_v4();
+        let (storage, version_id) = storage_with_client(client_id);
+        let web_config = WebConfig {
+            allow_client_deletion: true,
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::delete()
+            .uri("/v1/client")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // the client and its versions are gone
+        let mut txn = server.server_state.server.txn(client_id).unwrap();
+        assert_eq!(txn.get_client().unwrap(), None);
+        assert_eq!(txn.get_version(version_id).unwrap(), None);
+        drop(txn);
+
+        // so a second deletion finds nothing to delete
+        let req = test::TestRequest::delete()
+            .uri("/v1/client")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_no_such_client() {
+        let web_config = WebConfig {
+            allow_client_deletion: true,
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::delete()
+            .uri("/v1/client")
+            .append_header((CLIENT_ID_HEADER, Uuid::new_v4().to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_not_enabled() {
+        let client_id = Uuid::new_v4();
+        let (storage, version_id) = storage_with_client(client_id);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::delete()
+            .uri("/v1/client")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // the client was not deleted
+        let mut txn = server.server_state.server.txn(client_id).unwrap();
+        assert!(txn.get_version(version_id).unwrap().is_some());
+    }
+}
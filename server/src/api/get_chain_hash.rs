@@ -0,0 +1,151 @@
+use crate::api::{server_error_to_actix, ServerState};
+use crate::error::ApiError;
+use crate::rate_limit::EndpointClass;
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpRequest, HttpResponse, Result};
+use serde::Serialize;
+use std::sync::Arc;
+use taskchampion_sync_server_core::VersionId;
+
+#[derive(Serialize)]
+struct ChainHashResponse {
+    latest_version_id: VersionId,
+    chain_hash: String,
+}
+
+/// Get the hash of the client's history, for comparison with a replica's own hash of the versions
+/// it has seen.
+///
+/// The response is a JSON object with keys `latest_version_id` and `chain_hash`, the latter giving
+/// the hash of the history up to and including that version as 16 hexadecimal digits. See
+/// [`taskchampion_sync_server_core::ChainHash`] for the definition of the hash.
+///
+/// If the server does not know the client's chain hash, such as when the server is not configured
+/// to track it, returns a 404. Returns other 4xx or 5xx responses on other errors.
+#[get("/v1/client/chain-hash")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+) -> Result<HttpResponse> {
+    let client_id = server_state.client_id_header(&req)?;
+    server_state.check_rate_limit(client_id, EndpointClass::Read)?;
+
+    if let Some((latest_version_id, chain_hash)) = server_state
+        .server
+        .get_chain_hash(client_id)
+        .map_err(server_error_to_actix)?
+    {
+        Ok(HttpResponse::Ok().json(ChainHashResponse {
+            latest_version_id,
+            chain_hash: chain_hash.to_string(),
+        }))
+    } else {
+        Err(ApiError::new(StatusCode::NOT_FOUND, "no_chain_hash", "no chain hash").into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::{CLIENT_ID_HEADER, HISTORY_SEGMENT_CONTENT_TYPE, VERSION_ID_HEADER};
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{
+        ChainHash, InMemoryStorage, ServerConfig, Storage, NIL_VERSION_ID,
+    };
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn test_success() {
+        let client_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let config = ServerConfig {
+            chain_hash: true,
+            ..ServerConfig::default()
+        };
+        let server = WebServer::new(config, Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/v1/client/add-version/{NIL_VERSION_ID}"))
+            .append_header(("Content-Type", HISTORY_SEGMENT_CONTENT_TYPE))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .set_payload(b"abcd".to_vec())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let version_id: Uuid = resp
+            .headers()
+            .get(VERSION_ID_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/v1/client/chain-hash")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "latest_version_id": version_id,
+                "chain_hash": ChainHash::of_versions([version_id]).to_string(),
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_not_tracked() {
+        let client_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/client/chain-hash")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_no_such_client() {
+        let server = WebServer::new(
+            ServerConfig {
+                chain_hash: true,
+                ..ServerConfig::default()
+            },
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/client/chain-hash")
+            .append_header((CLIENT_ID_HEADER, Uuid::new_v4().to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
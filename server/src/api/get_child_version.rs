@@ -1,8 +1,11 @@
 use crate::api::{
-    server_error_to_actix, ServerState, HISTORY_SEGMENT_CONTENT_TYPE, PARENT_VERSION_ID_HEADER,
-    VERSION_ID_HEADER,
+    server_error_to_actix, snapshot_request, ServerState, HISTORY_SEGMENT_CONTENT_TYPE,
+    PARENT_VERSION_ID_HEADER, SNAPSHOT_REQUEST_HEADER, UNKNOWN_CLIENT_HEADER, VERSION_ID_HEADER,
 };
-use actix_web::{error, get, web, HttpRequest, HttpResponse, Result};
+use crate::error::ApiError;
+use crate::rate_limit::EndpointClass;
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpRequest, HttpResponse, Result};
 use std::sync::Arc;
 use taskchampion_sync_server_core::{GetVersionResult, ServerError, VersionId};
 
@@ -12,7 +15,14 @@ use taskchampion_sync_server_core::{GetVersionResult, ServerError, VersionId};
 /// with content-type `application/vnd.taskchampion.history-segment`.  The `X-Version-Id` and
 /// `X-Parent-Version-Id` headers contain the corresponding values.
 ///
-/// If no such child exists, returns a 404 with no content.
+/// As for AddVersion, a snapshot request may appear in the `X-Snapshot-Request` header, with value
+/// `urgency=low` or `urgency=high`, so that clients which are only catching up are also asked for
+/// snapshots. This header is included in 404 and 410 responses as well.
+///
+/// If no such child exists, returns a 404. If the client does not exist, the
+/// response is also a 404, with an `X-Unknown-Client: true` header if the server is configured to
+/// add one.
+///
 /// Returns other 4xx or 5xx responses on other errors.
 #[get("/v1/client/get-child-version/{parent_version_id}")]
 pub(crate) async fn service(
@@ -22,26 +32,58 @@ pub(crate) async fn service(
 ) -> Result<HttpResponse> {
     let parent_version_id = path.into_inner();
     let client_id = server_state.client_id_header(&req)?;
+    server_state.check_rate_limit(client_id, EndpointClass::Read)?;
+    server_state.check_download_quota(client_id)?;
 
-    match server_state
+    let result = server_state
         .server
-        .get_child_version(client_id, parent_version_id)
-    {
-        Ok(GetVersionResult::Success {
-            version_id,
-            parent_version_id,
-            history_segment,
-        }) => Ok(HttpResponse::Ok()
-            .content_type(HISTORY_SEGMENT_CONTENT_TYPE)
-            .append_header((VERSION_ID_HEADER, version_id.to_string()))
-            .append_header((PARENT_VERSION_ID_HEADER, parent_version_id.to_string()))
-            .body(history_segment)),
-        Ok(GetVersionResult::NotFound) => Err(error::ErrorNotFound("no such version")),
-        Ok(GetVersionResult::Gone) => Err(error::ErrorGone("version has been deleted")),
-        // Note that the HTTP client cannot differentiate `NotFound` and `NoSuchClient`, as both
-        // are a 404 NOT FOUND response. In either case, the HTTP client will typically attempt
-        // to add a new version, which may create the new client at the same time.
-        Err(ServerError::NoSuchClient) => Err(error::ErrorNotFound("no such client")),
+        .get_child_version(client_id, parent_version_id);
+    if let Ok((result, _)) = &result {
+        server_state.metrics.get_child_version(result);
+    }
+    let with_snapshot_request = |err: ApiError, urgency| match snapshot_request(urgency) {
+        Some(snapshot_request) => err.with_header(SNAPSHOT_REQUEST_HEADER, snapshot_request),
+        None => err,
+    };
+    match result {
+        Ok((
+            GetVersionResult::Success {
+                version_id,
+                parent_version_id,
+                history_segment,
+            },
+            urgency,
+        )) => {
+            server_state.log_body("get-child-version response body", &history_segment);
+            server_state.record_download(client_id, history_segment.len());
+            let mut rb = HttpResponse::Ok();
+            rb.content_type(HISTORY_SEGMENT_CONTENT_TYPE)
+                .append_header((VERSION_ID_HEADER, version_id.to_string()))
+                .append_header((PARENT_VERSION_ID_HEADER, parent_version_id.to_string()));
+            if let Some(snapshot_request) = snapshot_request(urgency) {
+                rb.append_header((SNAPSHOT_REQUEST_HEADER, snapshot_request));
+            }
+            Ok(rb.body(history_segment))
+        }
+        Ok((GetVersionResult::NotFound, urgency)) => Err(with_snapshot_request(
+            ApiError::new(StatusCode::NOT_FOUND, "no_such_version", "no such version"),
+            urgency,
+        )
+        .into()),
+        Ok((GetVersionResult::Gone, urgency)) => Err(with_snapshot_request(
+            ApiError::new(StatusCode::GONE, "version_gone", "version has been deleted"),
+            urgency,
+        )
+        .into()),
+        // Note that, unless configured to add the `X-Unknown-Client` header, the HTTP client
+        // cannot differentiate `NotFound` and `NoSuchClient`, as both are a 404 NOT FOUND
+        // response. In either case, the HTTP client will typically attempt to add a new version,
+        // which may create the new client at the same time.
+        Err(err @ ServerError::NoSuchClient) if server_state.web_config.unknown_client_header => {
+            Err(ApiError::from(err)
+                .with_header(UNKNOWN_CLIENT_HEADER, "true")
+                .into())
+        }
         Err(e) => Err(server_error_to_actix(e)),
     }
 }
@@ -49,10 +91,11 @@ pub(crate) async fn service(
 #[cfg(test)]
 mod test {
     use crate::api::CLIENT_ID_HEADER;
-    use crate::WebServer;
+    use crate::{WebConfig, WebServer};
     use actix_web::{http::StatusCode, test, App};
+    use chrono::{Duration, Utc};
     use pretty_assertions::assert_eq;
-    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use taskchampion_sync_server_core::{InMemoryStorage, Snapshot, Storage, NIL_VERSION_ID};
     use uuid::Uuid;
 
     #[actix_rt::test]
@@ -71,7 +114,7 @@ mod test {
             txn.commit().unwrap();
         }
 
-        let server = WebServer::new(Default::default(), None, storage);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -105,7 +148,7 @@ mod test {
         let client_id = Uuid::new_v4();
         let parent_version_id = Uuid::new_v4();
         let storage = InMemoryStorage::new();
-        let server = WebServer::new(Default::default(), None, storage);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -118,6 +161,58 @@ mod test {
         assert_eq!(resp.status(), StatusCode::NOT_FOUND);
         assert_eq!(resp.headers().get("X-Version-Id"), None);
         assert_eq!(resp.headers().get("X-Parent-Version-Id"), None);
+        assert_eq!(resp.headers().get("X-Unknown-Client"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_client_not_found_header() {
+        let client_id = Uuid::new_v4();
+        let parent_version_id = Uuid::new_v4();
+        let web_config = WebConfig {
+            unknown_client_header: true,
+            ..Default::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let uri = format!("/v1/client/get-child-version/{}", parent_version_id);
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers().get("X-Unknown-Client").unwrap(), "true");
+        assert_eq!(resp.headers().get("X-Version-Id"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_version_not_found_header() {
+        let client_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.commit().unwrap();
+        }
+        let web_config = WebConfig {
+            unknown_client_header: true,
+            ..Default::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        // for a known client, a missing version does not carry the header
+        let uri = format!("/v1/client/get-child-version/{}", NIL_VERSION_ID);
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers().get("X-Unknown-Client"), None);
     }
 
     #[actix_rt::test]
@@ -134,7 +229,7 @@ mod test {
                 .unwrap();
             txn.commit().unwrap();
         }
-        let server = WebServer::new(Default::default(), None, storage);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -178,4 +273,108 @@ mod test {
         assert_eq!(resp.headers().get("X-Version-Id"), None);
         assert_eq!(resp.headers().get("X-Parent-Version-Id"), None);
     }
+
+    #[actix_rt::test]
+    async fn test_malformed_parent_version_id() {
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/client/get-child-version/not-a-uuid")
+            .append_header((CLIENT_ID_HEADER, Uuid::new_v4().to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "bad_path");
+        assert_eq!(body["error"], "malformed parent_version_id (expected UUID)");
+    }
+
+    #[actix_rt::test]
+    async fn test_snapshot_request() {
+        for (snapshot_days_ago, expected) in [
+            (0, None),
+            (14, Some("urgency=low")),
+            (30, Some("urgency=high")),
+        ] {
+            let client_id = Uuid::new_v4();
+            let version_id = Uuid::new_v4();
+            let storage = InMemoryStorage::new();
+            {
+                let mut txn = storage.txn(client_id).unwrap();
+                txn.new_client(version_id).unwrap();
+                txn.add_version(version_id, NIL_VERSION_ID, b"abcd".to_vec())
+                    .unwrap();
+                let snapshot = Snapshot {
+                    version_id,
+                    timestamp: Utc::now() - Duration::days(snapshot_days_ago),
+                    versions_since: 0,
+                };
+                txn.set_snapshot(snapshot, b"snap".to_vec(), None).unwrap();
+                txn.commit().unwrap();
+            }
+            let server = WebServer::new(Default::default(), Default::default(), storage);
+            let app = App::new().configure(|sc| server.config(sc));
+            let app = test::init_service(app).await;
+
+            // the header appears on a found version, and on the 404 once caught up
+            for (parent_version_id, status) in [
+                (NIL_VERSION_ID, StatusCode::OK),
+                (version_id, StatusCode::NOT_FOUND),
+            ] {
+                let req = test::TestRequest::get()
+                    .uri(&format!("/v1/client/get-child-version/{parent_version_id}"))
+                    .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                    .to_request();
+                let resp = test::call_service(&app, req).await;
+                assert_eq!(resp.status(), status);
+                assert_eq!(
+                    resp.headers()
+                        .get("X-Snapshot-Request")
+                        .map(|value| value.to_str().unwrap()),
+                    expected,
+                    "with snapshot {snapshot_days_ago} days ago"
+                );
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_response_compression() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(version_id).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, vec![0; 1024])
+                .unwrap();
+            txn.commit().unwrap();
+        }
+        let web_config = WebConfig {
+            response_compression: true,
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/client/get-child-version/{NIL_VERSION_ID}"))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .append_header(("Accept-Encoding", "gzip"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("Content-Encoding").unwrap(), "gzip");
+        assert_eq!(
+            resp.headers().get("X-Version-Id").unwrap(),
+            &version_id.to_string()
+        );
+    }
 }
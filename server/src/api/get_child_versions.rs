@@ -0,0 +1,214 @@
+use crate::api::{server_error_to_actix, ServerState, UNKNOWN_CLIENT_HEADER};
+use crate::error::ApiError;
+use crate::rate_limit::EndpointClass;
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpRequest, HttpResponse, Result};
+use serde::Deserialize;
+use std::sync::Arc;
+use taskchampion_sync_server_core::{
+    encode_versions, GetVersionsResult, ServerError, VersionId, VERSION_STREAM_CONTENT_TYPE,
+};
+
+/// The number of versions returned when the request does not give a limit.
+const DEFAULT_LIMIT: usize = 100;
+
+/// The largest number of versions returned in one response.
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+struct GetChildVersionsQuery {
+    limit: Option<usize>,
+}
+
+/// Get a sequence of consecutive versions, beginning with the child of the given parent version.
+///
+/// The `limit` query parameter gives the largest number of versions returned, defaulting to 100
+/// and at most 1000. On success, the response has content-type
+/// `application/vnd.taskchampion.version-stream`, and contains at least one version, each as a
+/// frame giving its version ID, parent version ID, and history segment. See
+/// [`taskchampion_sync_server_core::VersionFrame`] for the layout of a frame. Fewer versions than
+/// the limit are returned when the client's latest version is reached.
+///
+/// If the parent version has no child, the response is a 404 or 410, as for GetChildVersion.
+///
+/// Returns other 4xx or 5xx responses on other errors.
+#[get("/v1/client/get-child-versions/{parent_version_id}")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<VersionId>,
+) -> Result<HttpResponse> {
+    let parent_version_id = path.into_inner();
+    let query = web::Query::<GetChildVersionsQuery>::from_query(req.query_string())
+        .map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, "bad_query", err.to_string()))?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let client_id = server_state.client_id_header(&req)?;
+    server_state.check_rate_limit(client_id, EndpointClass::Read)?;
+    server_state.check_download_quota(client_id)?;
+
+    match server_state
+        .server
+        .get_child_versions(client_id, parent_version_id, limit)
+    {
+        Ok(GetVersionsResult::Success(versions)) => {
+            let body = encode_versions(&versions).map_err(ApiError::internal)?;
+            server_state.log_body("get-child-versions response body", &body);
+            server_state.record_download(client_id, body.len());
+            Ok(HttpResponse::Ok()
+                .content_type(VERSION_STREAM_CONTENT_TYPE)
+                .body(body))
+        }
+        Ok(GetVersionsResult::NotFound) => {
+            Err(ApiError::new(StatusCode::NOT_FOUND, "no_such_version", "no such version").into())
+        }
+        Ok(GetVersionsResult::Gone) => {
+            Err(ApiError::new(StatusCode::GONE, "version_gone", "version has been deleted").into())
+        }
+        Err(err @ ServerError::NoSuchClient) if server_state.web_config.unknown_client_header => {
+            Err(ApiError::from(err)
+                .with_header(UNKNOWN_CLIENT_HEADER, "true")
+                .into())
+        }
+        Err(e) => Err(server_error_to_actix(e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{
+        decode_versions, InMemoryStorage, Storage, VersionFrame, NIL_VERSION_ID,
+    };
+    use uuid::Uuid;
+
+    /// Create a storage containing a client with a chain of versions, returning the storage and
+    /// the versions.
+    fn storage_with_versions(
+        client_id: Uuid,
+        count: usize,
+    ) -> (InMemoryStorage, Vec<VersionFrame>) {
+        let storage = InMemoryStorage::new();
+        let mut versions = Vec::new();
+        let mut parent_version_id = NIL_VERSION_ID;
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            for i in 0..count {
+                let version = VersionFrame {
+                    version_id: Uuid::new_v4(),
+                    parent_version_id,
+                    history_segment: format!("v{i}").into_bytes(),
+                };
+                txn.add_version(
+                    version.version_id,
+                    version.parent_version_id,
+                    version.history_segment.clone(),
+                )
+                .unwrap();
+                parent_version_id = version.version_id;
+                versions.push(version);
+            }
+            txn.commit().unwrap();
+        }
+        (storage, versions)
+    }
+
+    /// Get the child versions of the given parent version.
+    fn request(client_id: Uuid, parent_version_id: Uuid, limit: usize) -> test::TestRequest {
+        test::TestRequest::get()
+            .uri(&format!(
+                "/v1/client/get-child-versions/{parent_version_id}?limit={limit}"
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+    }
+
+    #[actix_rt::test]
+    async fn test_fewer_than_limit() {
+        let client_id = Uuid::new_v4();
+        let (storage, versions) = storage_with_versions(client_id, 3);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = request(client_id, versions[0].version_id, 10).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            "application/vnd.taskchampion.version-stream"
+        );
+        let body = test::read_body(resp).await;
+        assert_eq!(decode_versions(&body).unwrap(), versions[1..]);
+    }
+
+    #[actix_rt::test]
+    async fn test_exactly_limit() {
+        let client_id = Uuid::new_v4();
+        let (storage, versions) = storage_with_versions(client_id, 5);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = request(client_id, NIL_VERSION_ID, 3).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        assert_eq!(decode_versions(&body).unwrap(), versions[..3]);
+
+        // the next request continues from the last version returned
+        let req = request(client_id, versions[2].version_id, 3).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        assert_eq!(decode_versions(&body).unwrap(), versions[3..]);
+    }
+
+    #[actix_rt::test]
+    async fn test_not_found() {
+        let client_id = Uuid::new_v4();
+        let (storage, versions) = storage_with_versions(client_id, 2);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        // the latest version has no child yet
+        let req = request(client_id, versions[1].version_id, 10).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "no_such_version");
+
+        // an unknown version is gone
+        let req = request(client_id, Uuid::new_v4(), 10).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::GONE);
+
+        // an unknown client is not found
+        let req = request(Uuid::new_v4(), NIL_VERSION_ID, 10).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_bad_query() {
+        let client_id = Uuid::new_v4();
+        let (storage, _) = storage_with_versions(client_id, 1);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/v1/client/get-child-versions/{NIL_VERSION_ID}?limit=lots"
+            ))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "bad_query");
+    }
+}
@@ -1,5 +1,8 @@
 use crate::api::{server_error_to_actix, ServerState, SNAPSHOT_CONTENT_TYPE, VERSION_ID_HEADER};
-use actix_web::{error, get, web, HttpRequest, HttpResponse, Result};
+use crate::error::ApiError;
+use crate::rate_limit::EndpointClass;
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpRequest, HttpResponse, Result};
 use std::sync::Arc;
 
 /// Get a snapshot.
@@ -8,7 +11,7 @@ use std::sync::Arc;
 /// `application/vnd.taskchampion.snapshot`.  The `X-Version-Id` header contains the version of the
 /// snapshot.
 ///
-/// If no snapshot exists, returns a 404 with no content.  Returns other 4xx or 5xx responses on
+/// If no snapshot exists, returns a 404.  Returns other 4xx or 5xx responses on
 /// other errors.
 #[get("/v1/client/snapshot")]
 pub(crate) async fn service(
@@ -16,25 +19,29 @@ pub(crate) async fn service(
     server_state: web::Data<Arc<ServerState>>,
 ) -> Result<HttpResponse> {
     let client_id = server_state.client_id_header(&req)?;
+    server_state.check_rate_limit(client_id, EndpointClass::Read)?;
+    server_state.check_download_quota(client_id)?;
 
     if let Some((version_id, data)) = server_state
         .server
         .get_snapshot(client_id)
         .map_err(server_error_to_actix)?
     {
+        server_state.log_body("get-snapshot response body", &data);
+        server_state.record_download(client_id, data.len());
         Ok(HttpResponse::Ok()
             .content_type(SNAPSHOT_CONTENT_TYPE)
             .append_header((VERSION_ID_HEADER, version_id.to_string()))
             .body(data))
     } else {
-        Err(error::ErrorNotFound("no snapshot"))
+        Err(ApiError::new(StatusCode::NOT_FOUND, "no_snapshot", "no snapshot").into())
     }
 }
 
 #[cfg(test)]
 mod test {
     use crate::api::CLIENT_ID_HEADER;
-    use crate::WebServer;
+    use crate::{WebConfig, WebServer};
     use actix_web::{http::StatusCode, test, App};
     use chrono::{TimeZone, Utc};
     use pretty_assertions::assert_eq;
@@ -53,7 +60,7 @@ mod test {
             txn.commit().unwrap();
         }
 
-        let server = WebServer::new(Default::default(), None, storage);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -84,12 +91,13 @@ mod test {
                     timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
                 },
                 snapshot_data.clone(),
+                None,
             )
             .unwrap();
             txn.commit().unwrap();
         }
 
-        let server = WebServer::new(Default::default(), None, storage);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -105,4 +113,51 @@ mod test {
         let bytes = resp.into_body().try_into_bytes().unwrap();
         assert_eq!(bytes.as_ref(), snapshot_data);
     }
+
+    #[actix_rt::test]
+    async fn test_response_compression() {
+        for response_compression in [false, true] {
+            let client_id = Uuid::new_v4();
+            let version_id = Uuid::new_v4();
+            let storage = InMemoryStorage::new();
+            {
+                let mut txn = storage.txn(client_id).unwrap();
+                txn.new_client(version_id).unwrap();
+                let snapshot = Snapshot {
+                    version_id,
+                    timestamp: Utc::now(),
+                    versions_since: 0,
+                };
+                txn.set_snapshot(snapshot, vec![0; 1024], None).unwrap();
+                txn.commit().unwrap();
+            }
+            let web_config = WebConfig {
+                response_compression,
+                ..WebConfig::default()
+            };
+            let server = WebServer::new(Default::default(), web_config, storage);
+            let app = App::new().configure(|sc| server.config(sc));
+            let app = test::init_service(app).await;
+
+            let req = test::TestRequest::get()
+                .uri("/v1/client/snapshot")
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .append_header(("Accept-Encoding", "gzip"))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(
+                resp.headers()
+                    .get("Content-Encoding")
+                    .map(|value| value.to_str().unwrap()),
+                response_compression.then_some("gzip"),
+            );
+            let body = test::read_body(resp).await;
+            if response_compression {
+                assert!(body.len() < 1024);
+            } else {
+                assert_eq!(body.as_ref(), vec![0; 1024]);
+            }
+        }
+    }
 }
@@ -0,0 +1,160 @@
+use crate::api::{
+    server_error_to_actix, ServerState, HISTORY_SEGMENT_CONTENT_TYPE, PARENT_VERSION_ID_HEADER,
+    UNKNOWN_CLIENT_HEADER, VERSION_ID_HEADER,
+};
+use crate::error::ApiError;
+use crate::rate_limit::EndpointClass;
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpRequest, HttpResponse, Result};
+use std::sync::Arc;
+use taskchampion_sync_server_core::{ServerError, VersionId};
+
+/// Get a version by its own ID. This is an extension to the sync protocol, useful for debugging
+/// and for clients that have cached a version ID.
+///
+/// This is only available if enabled in the configuration, and otherwise returns a 403
+/// FORBIDDEN. On success, the response is as for GetChildVersion. If the client has no such
+/// version, or the client does not exist, the response is a 404 NOT FOUND.
+///
+/// Returns other 4xx or 5xx responses on other errors.
+#[get("/v1/client/get-version/{version_id}")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+    path: web::Path<VersionId>,
+) -> Result<HttpResponse> {
+    if !server_state.web_config.enable_get_version {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "get_version_disabled",
+            "get-version is not enabled",
+        )
+        .into());
+    }
+
+    let version_id = path.into_inner();
+    let client_id = server_state.client_id_header(&req)?;
+    server_state.check_rate_limit(client_id, EndpointClass::Read)?;
+    server_state.check_download_quota(client_id)?;
+
+    match server_state.server.get_version(client_id, version_id) {
+        Ok(Some(version)) => {
+            server_state.log_body("get-version response body", &version.history_segment);
+            server_state.record_download(client_id, version.history_segment.len());
+            Ok(HttpResponse::Ok()
+                .content_type(HISTORY_SEGMENT_CONTENT_TYPE)
+                .append_header((VERSION_ID_HEADER, version.version_id.to_string()))
+                .append_header((
+                    PARENT_VERSION_ID_HEADER,
+                    version.parent_version_id.to_string(),
+                ))
+                .body(version.history_segment))
+        }
+        Ok(None) => {
+            Err(ApiError::new(StatusCode::NOT_FOUND, "no_such_version", "no such version").into())
+        }
+        Err(err @ ServerError::NoSuchClient) if server_state.web_config.unknown_client_header => {
+            Err(ApiError::from(err)
+                .with_header(UNKNOWN_CLIENT_HEADER, "true")
+                .into())
+        }
+        Err(e) => Err(server_error_to_actix(e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::{WebConfig, WebServer};
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    /// Create a storage containing a client with a single version, returning its ID.
+    fn storage_with_version(client_id: Uuid) -> (InMemoryStorage, Uuid) {
+        let storage = InMemoryStorage::new();
+        let version_id = Uuid::new_v4();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(version_id).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, b"abcd".to_vec())
+                .unwrap();
+            txn.commit().unwrap();
+        }
+        (storage, version_id)
+    }
+
+    /// Build a request getting the given version.
+    fn request(client_id: Uuid, version_id: Uuid) -> test::TestRequest {
+        test::TestRequest::get()
+            .uri(&format!("/v1/client/get-version/{version_id}"))
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+    }
+
+    fn enabled() -> WebConfig {
+        WebConfig {
+            enable_get_version: true,
+            ..WebConfig::default()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_success() {
+        let client_id = Uuid::new_v4();
+        let (storage, version_id) = storage_with_version(client_id);
+        let server = WebServer::new(Default::default(), enabled(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let resp = test::call_service(&app, request(client_id, version_id).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("X-Version-Id").unwrap(),
+            &version_id.to_string()
+        );
+        assert_eq!(
+            resp.headers().get("X-Parent-Version-Id").unwrap(),
+            &NIL_VERSION_ID.to_string()
+        );
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            "application/vnd.taskchampion.history-segment"
+        );
+        assert_eq!(test::read_body(resp).await.as_ref(), b"abcd");
+    }
+
+    #[actix_rt::test]
+    async fn test_not_found() {
+        let client_id = Uuid::new_v4();
+        let (storage, _) = storage_with_version(client_id);
+        let server = WebServer::new(Default::default(), enabled(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let resp = test::call_service(&app, request(client_id, Uuid::new_v4()).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "no_such_version");
+
+        let resp =
+            test::call_service(&app, request(Uuid::new_v4(), Uuid::new_v4()).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "no_such_client");
+    }
+
+    #[actix_rt::test]
+    async fn test_disabled() {
+        let client_id = Uuid::new_v4();
+        let (storage, version_id) = storage_with_version(client_id);
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let resp = test::call_service(&app, request(client_id, version_id).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "get_version_disabled");
+    }
+}
@@ -0,0 +1,118 @@
+use crate::api::{server_error_to_actix, ServerState, SNAPSHOT_CONTENT_TYPE, VERSION_ID_HEADER};
+use crate::error::ApiError;
+use crate::rate_limit::EndpointClass;
+use actix_web::http::StatusCode;
+use actix_web::{route, web, HttpRequest, HttpResponse, Result};
+use std::sync::Arc;
+
+/// Get the metadata of a snapshot, without its content.
+///
+/// If a snapshot for this client exists, the response has the same headers as for `GetSnapshot`,
+/// with `Content-Length` giving the size of the snapshot, but no body. The snapshot data is not
+/// read from storage.
+///
+/// If no snapshot exists, returns a 404.  Returns other 4xx or 5xx responses on
+/// other errors.
+#[route("/v1/client/snapshot", method = "HEAD")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+) -> Result<HttpResponse> {
+    let client_id = server_state.client_id_header(&req)?;
+    server_state.check_rate_limit(client_id, EndpointClass::Read)?;
+
+    if let Some((version_id, size)) = server_state
+        .server
+        .get_snapshot_size(client_id)
+        .map_err(server_error_to_actix)?
+    {
+        Ok(HttpResponse::Ok()
+            .content_type(SNAPSHOT_CONTENT_TYPE)
+            .append_header((VERSION_ID_HEADER, version_id.to_string()))
+            .no_chunking(size)
+            .finish())
+    } else {
+        Err(ApiError::new(StatusCode::NOT_FOUND, "no_snapshot", "no snapshot").into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::WebServer;
+    use actix_web::{http::StatusCode, test, App};
+    use chrono::{TimeZone, Utc};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Snapshot, Storage};
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn test_not_found() {
+        let client_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+
+        // set up the storage contents..
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(Uuid::new_v4()).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri("/v1/client/snapshot")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_success() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+
+        // set up the storage contents..
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(Uuid::new_v4()).unwrap();
+            txn.set_snapshot(
+                Snapshot {
+                    version_id,
+                    versions_since: 3,
+                    timestamp: Utc.with_ymd_and_hms(2001, 9, 9, 1, 46, 40).unwrap(),
+                },
+                vec![1; 1234],
+                None,
+            )
+            .unwrap();
+            txn.commit().unwrap();
+        }
+
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri("/v1/client/snapshot")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("X-Version-Id").unwrap(),
+            &version_id.to_string()
+        );
+        assert_eq!(resp.headers().get("Content-Length").unwrap(), "1234");
+
+        use actix_web::body::MessageBody;
+        let bytes = resp.into_body().try_into_bytes().unwrap();
+        assert!(bytes.is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+use crate::api::{omit_body_for_head, ServerState};
+use actix_web::{route, web, HttpRequest, HttpResponse};
+use serde_json::json;
+use std::sync::Arc;
+use taskchampion_sync_server_core::NIL_VERSION_ID;
+
+/// Check that the server is able to reach its storage backend, for use as a readiness probe.
+///
+/// This reads the client with the nil UUID as its ID, in a new transaction. If that succeeds, the
+/// response is a 200 OK with the JSON body `{"status": "ok"}`. If the storage backend fails, the
+/// response is a 503 SERVICE UNAVAILABLE with the JSON body `{"status": "unavailable"}`, and the
+/// error is logged.
+///
+/// A `HEAD` request receives the same status, with no body.
+///
+/// This endpoint is not subject to the `User-Agent` checks applied to sync requests.
+#[route("/health", method = "GET", method = "HEAD")]
+pub(crate) async fn service(
+    req: HttpRequest,
+    server_state: web::Data<Arc<ServerState>>,
+) -> HttpResponse {
+    // This only reads, so even if a client uses the nil UUID as its ID, it is not modified.
+    let result = server_state
+        .server
+        .txn(NIL_VERSION_ID)
+        .and_then(|mut txn| Ok(txn.get_client()?));
+    let resp = match result {
+        Ok(_) => HttpResponse::Ok().json(json!({ "status": "ok" })),
+        Err(e) => {
+            log::error!("health check failed: {e:?}");
+            HttpResponse::ServiceUnavailable().json(json!({ "status": "unavailable" }))
+        }
+    };
+    omit_body_for_head(&req, resp)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::WebServer;
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::{test, App};
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{
+        BackendInfo, GlobalStats, InMemoryStorage, Storage, StorageTxn,
+    };
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn test_ok() {
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get().uri("/health").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!({ "status": "ok" }));
+    }
+
+    #[actix_rt::test]
+    async fn test_head_ok() {
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::default()
+            .method(Method::HEAD)
+            .uri("/health")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(test::read_body(resp).await.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_user_agent_not_checked() {
+        let web_config = crate::WebConfig {
+            user_agent_allowlist: Some(vec!["taskchampion/*".into()]),
+            ..Default::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri("/health")
+            .append_header(("User-Agent", "ELB-HealthChecker/2.0"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    /// A storage backend which always fails.
+    struct FailingStorage;
+
+    impl Storage for FailingStorage {
+        fn txn(&self, _client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+            anyhow::bail!("storage is unavailable")
+        }
+
+        fn read_txn(&self, _client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+            anyhow::bail!("storage is unavailable")
+        }
+
+        fn global_stats(&self) -> anyhow::Result<GlobalStats> {
+            anyhow::bail!("storage is unavailable")
+        }
+
+        fn list_clients(&self) -> anyhow::Result<Vec<Uuid>> {
+            anyhow::bail!("storage is unavailable")
+        }
+
+        fn backend_info(&self) -> anyhow::Result<BackendInfo> {
+            anyhow::bail!("storage is unavailable")
+        }
+
+        fn schema_version(&self) -> anyhow::Result<u32> {
+            anyhow::bail!("storage is unavailable")
+        }
+
+        fn required_schema_version(&self) -> u32 {
+            1
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_unavailable() {
+        let server = WebServer::new(Default::default(), Default::default(), FailingStorage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get().uri("/health").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!({ "status": "unavailable" }));
+    }
+
+    #[actix_rt::test]
+    async fn test_head_unavailable() {
+        let server = WebServer::new(Default::default(), Default::default(), FailingStorage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::default()
+            .method(Method::HEAD)
+            .uri("/health")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(test::read_body(resp).await.is_empty());
+    }
+}
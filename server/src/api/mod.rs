@@ -1,13 +1,40 @@
-use std::collections::HashSet;
-
-use actix_web::{error, web, HttpRequest, Result, Scope};
-use taskchampion_sync_server_core::{ClientId, Server, ServerError};
-use uuid::Uuid;
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::rate_limit::{DownloadTracker, EndpointClass, RateLimiter};
+use crate::{CreateClients, WebConfig};
+use actix_cors::Cors;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::PathError;
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::middleware::{self, Next};
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder, Result, Scope};
+use chrono::{DateTime, Utc};
+use client_locks::{ClientLockGuard, ClientLocks};
+use request_body::RequestBody;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use taskchampion_sync_server_core::{
+    ClientId, Server, ServerError, Snapshot, SnapshotUrgency, NIL_VERSION_ID,
+};
 
+mod ack_version;
 mod add_snapshot;
 mod add_version;
+mod add_version_and_snapshot;
+mod bootstrap;
+pub(crate) mod capabilities;
+mod client_locks;
+mod delete_client;
+mod get_chain_hash;
 mod get_child_version;
+mod get_child_versions;
 mod get_snapshot;
+mod get_version;
+mod head_snapshot;
+pub(crate) mod health;
+mod request_body;
 
 /// The content-type for history segments (opaque blobs of bytes)
 pub(crate) const HISTORY_SEGMENT_CONTENT_TYPE: &str =
@@ -16,6 +43,16 @@ pub(crate) const HISTORY_SEGMENT_CONTENT_TYPE: &str =
 /// The content-type for snapshots (opaque blobs of bytes)
 pub(crate) const SNAPSHOT_CONTENT_TYPE: &str = "application/vnd.taskchampion.snapshot";
 
+/// The content-type for a history segment followed by a snapshot
+pub(crate) const VERSION_AND_SNAPSHOT_CONTENT_TYPE: &str =
+    "application/vnd.taskchampion.version-and-snapshot";
+
+/// The header name for the length of the history segment preceding a snapshot
+pub(crate) const HISTORY_SEGMENT_LENGTH_HEADER: &str = "X-History-Segment-Length";
+
+/// The header name for the expected length of a snapshot
+pub(crate) const SNAPSHOT_LENGTH_HEADER: &str = "X-Snapshot-Length";
+
 /// The header name for version ID
 pub(crate) const VERSION_ID_HEADER: &str = "X-Version-Id";
 
@@ -25,69 +62,710 @@ pub(crate) const CLIENT_ID_HEADER: &str = "X-Client-Id";
 /// The header name for parent version ID
 pub(crate) const PARENT_VERSION_ID_HEADER: &str = "X-Parent-Version-Id";
 
-/// The header name for parent version ID
+/// The header name for a snapshot request
 pub(crate) const SNAPSHOT_REQUEST_HEADER: &str = "X-Snapshot-Request";
 
+/// The header name marking a response for an unknown client
+pub(crate) const UNKNOWN_CLIENT_HEADER: &str = "X-Unknown-Client";
+
+/// The header name for the time by which the client needs a response
+pub(crate) const REQUEST_DEADLINE_HEADER: &str = "X-Request-Deadline";
+
+/// The header name for a client's use of its storage quota, when that is nearly exhausted
+pub(crate) const QUOTA_USAGE_HEADER: &str = "X-Quota-Usage";
+
+/// The percentage of its storage quota a client must use before write responses carry the
+/// `X-Quota-Usage` header.
+const QUOTA_WARNING_PERCENT: u64 = 90;
+
+/// The header name for the ID identifying a request in logs
+pub(crate) const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The longest request ID accepted from a client.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The request headers a browser-based client may send.
+const CORS_REQUEST_HEADERS: [&str; 7] = [
+    CLIENT_ID_HEADER,
+    HISTORY_SEGMENT_LENGTH_HEADER,
+    SNAPSHOT_LENGTH_HEADER,
+    REQUEST_DEADLINE_HEADER,
+    REQUEST_ID_HEADER,
+    "Authorization",
+    "Content-Type",
+];
+
+/// The response headers a browser-based client may read.
+const CORS_RESPONSE_HEADERS: [&str; 7] = [
+    VERSION_ID_HEADER,
+    PARENT_VERSION_ID_HEADER,
+    SNAPSHOT_REQUEST_HEADER,
+    UNKNOWN_CLIENT_HEADER,
+    QUOTA_USAGE_HEADER,
+    REQUEST_ID_HEADER,
+    "Retry-After",
+];
+
+/// The number of seconds after which a client should retry a request rejected in read-only mode
+const READ_ONLY_RETRY_AFTER_SECS: u64 = 60;
+
+/// The prefix of the paths of the admin endpoints
+const ADMIN_PATH_PREFIX: &str = "/v1/admin/";
+
+/// The path of the dashboard, which is an admin endpoint outside [`ADMIN_PATH_PREFIX`]
+const DASHBOARD_PATH: &str = "/dashboard";
+
 /// The type containing a reference to the persistent state for the server
 pub(crate) struct ServerState {
     pub(crate) server: Server,
-    pub(crate) client_id_allowlist: Option<HashSet<Uuid>>,
+    pub(crate) web_config: WebConfig,
+    pub(crate) metrics: Metrics,
+    client_locks: ClientLocks,
+    rate_limiter: RateLimiter,
+    download_tracker: DownloadTracker,
 }
 
 impl ServerState {
-    /// Get the client id
+    pub(crate) fn new(server: Server, web_config: WebConfig) -> Self {
+        Self {
+            server,
+            web_config,
+            metrics: Metrics::default(),
+            client_locks: ClientLocks::default(),
+            rate_limiter: RateLimiter::default(),
+            download_tracker: DownloadTracker::default(),
+        }
+    }
+
+    /// Get the client id, checking that the request is authorized for that client.
+    ///
+    /// If per-client tokens are enabled and the client has a token, the request must carry that
+    /// token, and the allowlist does not apply.
     fn client_id_header(&self, req: &HttpRequest) -> Result<ClientId> {
-        fn badrequest() -> error::Error {
-            error::ErrorBadRequest("bad x-client-id")
+        fn badrequest() -> ApiError {
+            ApiError::new(StatusCode::BAD_REQUEST, "bad_client_id", "bad x-client-id")
         }
         if let Some(client_id_hdr) = req.headers().get(CLIENT_ID_HEADER) {
             let client_id = client_id_hdr.to_str().map_err(|_| badrequest())?;
             let client_id = ClientId::parse_str(client_id).map_err(|_| badrequest())?;
-            if let Some(allow_list) = &self.client_id_allowlist {
+            if self.web_config.client_tokens {
+                let token_hash = self
+                    .server
+                    .read_only_txn(client_id)
+                    .and_then(|mut txn| Ok(txn.get_client_token_hash()?))
+                    .map_err(server_error_to_actix)?;
+                if let Some(token_hash) = token_hash {
+                    let provided_hash = bearer_token(req.headers())
+                        .map(|token| format!("{:x}", Sha256::digest(token.as_bytes())));
+                    return match provided_hash {
+                        Some(provided_hash)
+                            if constant_time_eq(
+                                provided_hash.as_bytes(),
+                                token_hash.to_ascii_lowercase().as_bytes(),
+                            ) =>
+                        {
+                            Ok(client_id)
+                        }
+                        _ => Err(unauthorized().into()),
+                    };
+                }
+            }
+            if let Some(allow_list) = &self.web_config.client_id_allowlist {
                 if !allow_list.contains(&client_id) {
-                    return Err(error::ErrorForbidden("unknown x-client-id"));
+                    return Err(ApiError::new(
+                        StatusCode::FORBIDDEN,
+                        "unknown_client_id",
+                        "unknown x-client-id",
+                    )
+                    .into());
                 }
             }
             Ok(client_id)
         } else {
-            Err(badrequest())
+            Err(badrequest().into())
+        }
+    }
+
+    /// Check that the request's `User-Agent` is allowed, returning a 403 FORBIDDEN error if not.
+    fn check_user_agent(&self, req: &ServiceRequest) -> Result<()> {
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|ua| ua.to_str().ok());
+        let allowed = match user_agent {
+            Some(user_agent) => {
+                let matches = |pattern: &String| user_agent_matches(pattern, user_agent);
+                !self.web_config.user_agent_denylist.iter().any(matches)
+                    && self
+                        .web_config
+                        .user_agent_allowlist
+                        .as_ref()
+                        .is_none_or(|allow_list| allow_list.iter().any(matches))
+            }
+            None => self.web_config.user_agent_allowlist.is_none(),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "unsupported_client",
+                "This client version is not supported by the server; please upgrade it",
+            )
+            .into())
+        }
+    }
+
+    /// Check that the request carries the required bearer token, if any, returning a 401
+    /// UNAUTHORIZED error if not. Requests to the admin endpoints require the admin token
+    /// instead, and are always rejected if there is none.
+    fn check_token(&self, req: &ServiceRequest) -> Result<()> {
+        let path = req.path();
+        let token = if path.starts_with(ADMIN_PATH_PREFIX) || path == DASHBOARD_PATH {
+            match &self.web_config.admin_token {
+                Some(admin_token) => admin_token,
+                None => return Err(unauthorized().into()),
+            }
+        } else {
+            match &self.web_config.require_token {
+                Some(token) => token,
+                None => return Ok(()),
+            }
+        };
+        if bearer_token(req.headers())
+            .is_some_and(|provided| constant_time_eq(provided.as_bytes(), token.as_bytes()))
+        {
+            Ok(())
+        } else {
+            Err(unauthorized().into())
+        }
+    }
+
+    /// Recalculate the snapshot statistics, and update the metrics with them.
+    pub(crate) fn refresh_stats(&self) -> std::result::Result<(), ServerError> {
+        let stats = self.server.snapshot_stats()?;
+        self.metrics.snapshot_stats(&stats, Utc::now());
+        Ok(())
+    }
+
+    /// Check that the server is not in read-only mode, returning a 503 SERVICE UNAVAILABLE error
+    /// if it is. Handlers which modify storage call this before doing anything else.
+    pub(crate) fn check_read_only(&self) -> Result<()> {
+        if !self.web_config.read_only {
+            return Ok(());
+        }
+        Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "read_only",
+            "The server is in read-only mode for maintenance; retry later",
+        )
+        .with_header("Retry-After", READ_ONLY_RETRY_AFTER_SECS.to_string())
+        .into())
+    }
+
+    /// Check the rate limit, if any, for the given client and class of endpoint, returning a
+    /// 429 TOO MANY REQUESTS error if it has been exceeded.
+    fn check_rate_limit(&self, client_id: ClientId, class: EndpointClass) -> Result<()> {
+        self.check_rate_limit_for(Some(client_id), class)
+    }
+
+    /// Check the overall rate limit, if any, for the client making the request, before it is
+    /// handled.
+    ///
+    /// Requests with a missing or invalid `X-Client-Id`, or for a client not in the allowlist,
+    /// share a single bucket, so that they cannot bypass the limit.
+    fn check_overall_rate_limit(&self, req: &ServiceRequest) -> Result<()> {
+        let client_id = req
+            .headers()
+            .get(CLIENT_ID_HEADER)
+            .and_then(|hdr| hdr.to_str().ok())
+            .and_then(|hdr| ClientId::parse_str(hdr).ok())
+            .filter(|client_id| {
+                self.web_config
+                    .client_id_allowlist
+                    .as_ref()
+                    .is_none_or(|allow_list| allow_list.contains(client_id))
+            });
+        self.check_rate_limit_for(client_id, EndpointClass::All)
+    }
+
+    /// Check the rate limit for the given class of endpoint, with `None` for requests sharing the
+    /// bucket for unidentified clients.
+    fn check_rate_limit_for(
+        &self,
+        client_id: Option<ClientId>,
+        class: EndpointClass,
+    ) -> Result<()> {
+        let limit = match class {
+            EndpointClass::Read => &self.web_config.rate_limit_reads,
+            EndpointClass::Write => &self.web_config.rate_limit_writes,
+            EndpointClass::All => &self.web_config.rate_limit,
+        };
+        let Some(limit) = limit else {
+            return Ok(());
+        };
+        self.rate_limiter
+            .check(limit, client_id, class, Instant::now())
+            .map_err(|retry_after| {
+                too_many_requests("rate_limited", "rate limit exceeded", retry_after).into()
+            })
+    }
+
+    /// Check that the client has not exhausted its download quota, if any, returning a 429 TOO
+    /// MANY REQUESTS error if it has.
+    fn check_download_quota(&self, client_id: ClientId) -> Result<()> {
+        let Some(quota) = &self.web_config.download_quota else {
+            return Ok(());
+        };
+        self.download_tracker
+            .check(quota, client_id, Instant::now())
+            .map_err(|retry_after| {
+                too_many_requests(
+                    "download_quota_exceeded",
+                    "download quota exceeded",
+                    retry_after,
+                )
+                .into()
+            })
+    }
+
+    /// Record a download of the given number of bytes against the client's download quota.
+    fn record_download(&self, client_id: ClientId, bytes: usize) {
+        if let Some(quota) = &self.web_config.download_quota {
+            self.download_tracker
+                .record(quota, client_id, bytes as u64, Instant::now());
         }
     }
+
+    /// Add an `X-Quota-Usage` header to a successful write response if the client is using at
+    /// least 90% of its storage quota, so that it can add a snapshot, allowing older versions to
+    /// be deleted, before the quota is reached. The header's value has the form
+    /// `bytes=USED/LIMIT`.
+    fn warn_quota_usage(&self, client_id: ClientId, rb: &mut HttpResponseBuilder) {
+        // the write has already succeeded, so a failure here is not reported to the client
+        match self.server.storage_quota_usage(client_id) {
+            Ok(Some(usage)) if usage.used * 100 >= usage.limit * QUOTA_WARNING_PERCENT => {
+                rb.append_header((
+                    QUOTA_USAGE_HEADER,
+                    format!("bytes={}/{}", usage.used, usage.limit),
+                ));
+            }
+            Ok(_) => {}
+            Err(err) => log::warn!("reading storage quota usage for {client_id}: {err}"),
+        }
+    }
+
+    /// Log a preview of a request or response body, if configured to do so.
+    fn log_body(&self, description: &str, body: &[u8]) {
+        if let Some(max_bytes) = self.web_config.debug_bodies {
+            log::debug!(
+                "{description}: {}",
+                body_preview(body, body.len(), max_bytes)
+            );
+        }
+    }
+
+    /// Log a preview of a request body, as for `log_body`.
+    fn log_request_body(&self, description: &str, body: &RequestBody) {
+        if let Some(max_bytes) = self.web_config.debug_bodies {
+            log::debug!(
+                "{description}: {}",
+                body_preview(body.iter(), body.len(), max_bytes)
+            );
+        }
+    }
+
+    /// Wait for any other in-progress writes for the given client, if configured to serialize
+    /// writes. The returned guard must be held for the duration of the write.
+    async fn lock_client_writes(&self, client_id: ClientId) -> Option<ClientLockGuard<'_>> {
+        if self.web_config.serialize_writes {
+            Some(self.client_locks.lock(client_id).await)
+        } else {
+            None
+        }
+    }
+
+    /// Create the given client, with the configured default snapshot, if any.
+    ///
+    /// If another request has created the client concurrently, this leaves that client as it is
+    /// and succeeds, so that the caller can continue with the existing client.
+    fn create_client(&self, client_id: ClientId) -> Result<()> {
+        let mut txn = self.server.txn(client_id).map_err(server_error_to_actix)?;
+        let default_snapshot = self.web_config.default_snapshot.as_ref();
+        let latest_version_id =
+            default_snapshot.map_or(NIL_VERSION_ID, |(version_id, _)| *version_id);
+        if let Err(err) = txn.new_client(latest_version_id) {
+            if let Some(ServerError::ClientExists) = err.downcast_ref() {
+                log::debug!("client {client_id} was created by a concurrent request");
+                return Ok(());
+            }
+            return Err(failure_to_ise(err));
+        }
+        if let Some((version_id, data)) = default_snapshot {
+            let snapshot = Snapshot {
+                version_id: *version_id,
+                timestamp: Utc::now(),
+                versions_since: 0,
+            };
+            txn.set_snapshot(snapshot, data.clone(), None)
+                .map_err(failure_to_ise)?;
+        }
+        txn.commit().map_err(failure_to_ise)?;
+        Ok(())
+    }
+
+    /// Determine whether the given client may be created automatically.
+    fn may_create_client(&self, client_id: ClientId) -> bool {
+        match self.web_config.create_clients {
+            CreateClients::Always => true,
+            CreateClients::Never => false,
+            CreateClients::AllowlistOnly => self
+                .web_config
+                .client_id_allowlist
+                .as_ref()
+                .is_some_and(|allow_list| allow_list.contains(&client_id)),
+        }
+    }
+}
+
+/// Build the CORS middleware, allowing the given origins to use the API.
+pub(crate) fn cors(allowed_origins: &[String]) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods([Method::GET, Method::HEAD, Method::POST, Method::DELETE])
+        .allowed_headers(CORS_REQUEST_HEADERS)
+        .expose_headers(CORS_RESPONSE_HEADERS)
+        .max_age(3600)
+        // serve requests from other origins without CORS headers, leaving the browser to block
+        // them, so that a stray `Origin` header from another kind of client is harmless
+        .block_on_origin_mismatch(false);
+    for origin in allowed_origins {
+        cors = if origin == "*" {
+            cors.allow_any_origin()
+        } else {
+            cors.allowed_origin(origin)
+        };
+    }
+    cors
 }
 
-pub(crate) fn api_scope() -> Scope {
+pub(crate) fn api_scope(web_config: &WebConfig) -> Scope {
     web::scope("")
-        .service(get_child_version::service)
+        .service(get_child_versions::service)
+        .service(get_version::service)
         .service(add_version::service)
-        .service(get_snapshot::service)
+        .service(add_version_and_snapshot::service)
+        .service(head_snapshot::service)
         .service(add_snapshot::service)
+        .service(bootstrap::service)
+        .service(get_chain_hash::service)
+        .service(delete_client::service)
+        .service(ack_version::service)
+        // The endpoints returning history segments and snapshots may compress their responses.
+        // This scope matches every path, so it must be last.
+        .service(
+            web::scope("")
+                .wrap(middleware::Condition::new(
+                    web_config.response_compression,
+                    middleware::Compress::default(),
+                ))
+                .service(get_child_version::service)
+                .service(get_snapshot::service),
+        )
+}
+
+/// Get the value of the `X-Snapshot-Request` header for the given urgency, or `None` if no
+/// snapshot is requested.
+pub(crate) fn snapshot_request(urgency: SnapshotUrgency) -> Option<&'static str> {
+    match urgency {
+        SnapshotUrgency::None => None,
+        SnapshotUrgency::Low => Some("urgency=low"),
+        SnapshotUrgency::High => Some("urgency=high"),
+    }
+}
+
+/// Build the error for a request whose path parameters could not be extracted, which is a 400
+/// BAD REQUEST naming the first parameter that is not a well-formed UUID. All path parameters
+/// are UUIDs, such as version and client IDs.
+pub(crate) fn path_error(err: PathError, req: &HttpRequest) -> actix_web::Error {
+    let name = req
+        .match_info()
+        .iter()
+        .find(|(_, value)| uuid::Uuid::parse_str(value).is_err())
+        .map(|(name, _)| name);
+    let message = match name {
+        Some(name) => format!("malformed {name} (expected UUID)"),
+        None => err.to_string(),
+    };
+    ApiError::new(StatusCode::BAD_REQUEST, "bad_path", message).into()
+}
+
+tokio::task_local! {
+    /// The ID of the request being handled, set by [`assign_request_id`].
+    static REQUEST_ID: String;
+}
+
+/// Get the ID of the request being handled, if any, so that it can be attached to log lines. This
+/// includes the log lines of the sync server core, which handles requests synchronously.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(String::clone).ok()
+}
+
+/// Middleware giving each request an ID, echoed in the response's `X-Request-Id` header so that
+/// the request's log lines can be found. The ID is available from [`current_request_id`] while
+/// the request is handled. The request's own `X-Request-Id` is used if it is
+/// valid, so that an ID assigned by a proxy or client is kept; otherwise a new ID is generated.
+///
+/// A request abandoned at its deadline is reported as an error rather than a response, and its
+/// response does not carry the ID.
+pub(crate) async fn assign_request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| valid_request_id(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    log::debug!("request {request_id}: {} {}", req.method(), req.path());
+
+    let mut resp = REQUEST_ID.scope(request_id.clone(), next.call(req)).await?;
+    resp.headers_mut().insert(
+        header::HeaderName::from_static("x-request-id"),
+        header::HeaderValue::from_str(&request_id).expect("request ID is a valid header value"),
+    );
+    Ok(resp)
+}
+
+/// Determine whether a client-supplied request ID is acceptable: non-empty, not too long, and
+/// containing only visible ASCII characters.
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Middleware rejecting requests from disallowed `User-Agent`s, before they are handled.
+pub(crate) async fn check_user_agent(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    let server_state = req
+        .app_data::<web::Data<Arc<ServerState>>>()
+        .expect("server state is configured");
+    if let Err(err) = server_state.check_user_agent(&req) {
+        return Ok(req.error_response(err).map_into_right_body());
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+/// Middleware rejecting requests from clients exceeding the overall rate limit, before they are
+/// handled.
+pub(crate) async fn check_overall_rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    let server_state = req
+        .app_data::<web::Data<Arc<ServerState>>>()
+        .expect("server state is configured");
+    if let Err(err) = server_state.check_overall_rate_limit(&req) {
+        return Ok(req.error_response(err).map_into_right_body());
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+/// Middleware rejecting requests without the required bearer token, before they are handled.
+pub(crate) async fn check_token(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    let server_state = req
+        .app_data::<web::Data<Arc<ServerState>>>()
+        .expect("server state is configured");
+    if let Err(err) = server_state.check_token(&req) {
+        return Ok(req.error_response(err).map_into_right_body());
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+/// Middleware enforcing the deadline in a request's `X-Request-Deadline` header, if any, after
+/// which the client will no longer wait for a response. A request whose deadline has already
+/// passed is rejected before it is handled, and one whose deadline passes while it is being
+/// handled receives a `504 Gateway Timeout` response.
+///
+/// Handling stops at the next point where the handler waits, such as while reading the request
+/// body or waiting for another write for the same client. A storage operation which is already
+/// running is not interrupted.
+pub(crate) async fn check_deadline(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    let remaining = match request_deadline(req.headers(), Utc::now()) {
+        Ok(None) => return Ok(next.call(req).await?.map_into_left_body()),
+        Ok(Some(remaining)) => remaining,
+        Err(err) => return Ok(req.error_response(err).map_into_right_body()),
+    };
+    if remaining.is_zero() {
+        log::debug!("rejecting request: deadline has already passed");
+        return Ok(req
+            .error_response(deadline_exceeded())
+            .map_into_right_body());
+    }
+
+    // The request cannot be cloned here, as the scope still needs to route it, so an abandoned
+    // request is reported as an error and actix renders the response.
+    match actix_web::rt::time::timeout(remaining, next.call(req)).await {
+        Ok(resp) => Ok(resp?.map_into_left_body()),
+        Err(_) => {
+            log::debug!("abandoning request: deadline passed while handling it");
+            Err(deadline_exceeded().into())
+        }
+    }
+}
+
+/// Get the time remaining until the deadline in the `X-Request-Deadline` header, if there is
+/// one, given as either an RFC 3339 timestamp or a number of milliseconds from `now`. A deadline
+/// in the past leaves no time remaining.
+fn request_deadline(headers: &header::HeaderMap, now: DateTime<Utc>) -> Result<Option<Duration>> {
+    let Some(value) = headers.get(REQUEST_DEADLINE_HEADER) else {
+        return Ok(None);
+    };
+    let invalid = || {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "bad_deadline",
+            format!("Invalid {REQUEST_DEADLINE_HEADER} header"),
+        )
+    };
+    let value = value.to_str().map_err(|_| invalid())?.trim();
+    if let Ok(millis) = value.parse::<u64>() {
+        return Ok(Some(Duration::from_millis(millis)));
+    }
+    let deadline = DateTime::parse_from_rfc3339(value).map_err(|_| invalid())?;
+    Ok(Some(
+        (deadline.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    ))
+}
+
+/// A 504 GATEWAY TIMEOUT error, for a request whose deadline has passed.
+fn deadline_exceeded() -> ApiError {
+    ApiError::new(
+        StatusCode::GATEWAY_TIMEOUT,
+        "deadline_exceeded",
+        "request deadline exceeded",
+    )
+}
+
+/// Get the token from the request's `Authorization: Bearer` header, if any.
+fn bearer_token(headers: &header::HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+}
+
+/// A 401 UNAUTHORIZED error, for a missing or incorrect bearer token.
+fn unauthorized() -> ApiError {
+    ApiError::new(
+        StatusCode::UNAUTHORIZED,
+        "unauthorized",
+        "missing or incorrect bearer token",
+    )
+    .with_header("WWW-Authenticate", "Bearer")
+}
+
+/// Compare two byte strings in time depending only on their lengths, so that the time taken to
+/// reject a token does not reveal how much of it was correct.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Determine whether a `User-Agent` matches a pattern. A pattern containing `*` is a glob which
+/// must match the entire `User-Agent`, with `*` matching any sequence of characters. Any other
+/// pattern matches a `User-Agent` containing it.
+fn user_agent_matches(pattern: &str, user_agent: &str) -> bool {
+    if !pattern.contains('*') {
+        return user_agent.contains(pattern);
+    }
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = user_agent.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let last = parts.pop().unwrap_or_default();
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Format the first `max_bytes` of a body of length `len` as hex, noting the total length if it
+/// is truncated.
+fn body_preview<'a>(
+    body: impl IntoIterator<Item = &'a u8>,
+    len: usize,
+    max_bytes: usize,
+) -> String {
+    let mut preview: String = body
+        .into_iter()
+        .take(max_bytes)
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    if len > max_bytes {
+        preview.push_str(&format!("... ({len} bytes total)"));
+    }
+    preview
+}
+
+/// Drop the body of a response to a `HEAD` request, keeping its status and headers. Handlers which
+/// serve both `GET` and `HEAD` use this so that the two methods always agree.
+pub(crate) fn omit_body_for_head(req: &HttpRequest, resp: HttpResponse) -> HttpResponse {
+    if req.method() == Method::HEAD {
+        resp.drop_body().map_into_boxed_body()
+    } else {
+        resp
+    }
+}
+
+/// Build a 429 TOO MANY REQUESTS error, with a `Retry-After` header for the given duration.
+fn too_many_requests(code: &'static str, message: &'static str, retry_after: Duration) -> ApiError {
+    // round up, so that a retry after this time will succeed
+    let retry_after = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    ApiError::new(StatusCode::TOO_MANY_REQUESTS, code, message)
+        .with_header("Retry-After", retry_after.to_string())
 }
 
 /// Convert a `anyhow::Error` to an Actix ISE
 fn failure_to_ise(err: anyhow::Error) -> actix_web::Error {
-    error::ErrorInternalServerError(err)
+    ApiError::internal(err).into()
 }
 
 /// Convert a ServerError to an Actix error
-fn server_error_to_actix(err: ServerError) -> actix_web::Error {
-    match err {
-        ServerError::NoSuchClient => error::ErrorNotFound(err),
-        ServerError::Other(err) => error::ErrorInternalServerError(err),
-    }
+pub(crate) fn server_error_to_actix(err: ServerError) -> actix_web::Error {
+    ApiError::from(err).into()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use taskchampion_sync_server_core::InMemoryStorage;
+    use taskchampion_sync_server_core::{
+        AddVersionResult, InMemoryStorage, Storage, NIL_VERSION_ID,
+    };
+    use taskchampion_sync_server_storage_sqlite::SqliteStorage;
+    use uuid::Uuid;
 
     #[test]
     fn client_id_header_allow_all() {
         let client_id = Uuid::new_v4();
-        let state = ServerState {
-            server: Server::new(Default::default(), InMemoryStorage::new()),
-            client_id_allowlist: None,
-        };
+        let state = ServerState::new(
+            Server::new(Default::default(), InMemoryStorage::new()),
+            WebConfig::default(),
+        );
         let req = actix_web::test::TestRequest::default()
             .insert_header((CLIENT_ID_HEADER, client_id.to_string()))
             .to_http_request();
@@ -98,10 +776,13 @@ mod test {
     fn client_id_header_allow_list() {
         let client_id_ok = Uuid::new_v4();
         let client_id_disallowed = Uuid::new_v4();
-        let state = ServerState {
-            server: Server::new(Default::default(), InMemoryStorage::new()),
-            client_id_allowlist: Some([client_id_ok].into()),
-        };
+        let state = ServerState::new(
+            Server::new(Default::default(), InMemoryStorage::new()),
+            WebConfig {
+                client_id_allowlist: Some([client_id_ok].into()),
+                ..WebConfig::default()
+            },
+        );
         let req = actix_web::test::TestRequest::default()
             .insert_header((CLIENT_ID_HEADER, client_id_ok.to_string()))
             .to_http_request();
@@ -118,4 +799,251 @@ mod test {
             403
         );
     }
+
+    #[test]
+    fn client_id_header_client_tokens() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_with_token = Uuid::new_v4();
+        let client_without_token = Uuid::new_v4();
+        let client_other = Uuid::new_v4();
+        for client_id in [client_with_token, client_without_token] {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.commit()?;
+        }
+        // the token hash is provisioned by an external application
+        let con =
+            rusqlite::Connection::open(tmp_dir.path().join("taskchampion-sync-server.sqlite3"))?;
+        con.execute("ALTER TABLE clients ADD COLUMN token_hash STRING", [])?;
+        con.execute(
+            "UPDATE clients SET token_hash = ? WHERE client_id = ?",
+            [
+                format!("{:x}", Sha256::digest(b"s3cret")),
+                client_with_token.to_string(),
+            ],
+        )?;
+
+        let state = ServerState::new(
+            Server::new(Default::default(), storage),
+            WebConfig {
+                client_id_allowlist: Some([client_without_token].into()),
+                client_tokens: true,
+                ..WebConfig::default()
+            },
+        );
+        let status = |client_id: Uuid, token: Option<&str>| {
+            let mut req = actix_web::test::TestRequest::default()
+                .insert_header((CLIENT_ID_HEADER, client_id.to_string()));
+            if let Some(token) = token {
+                req = req.insert_header(("Authorization", format!("Bearer {token}")));
+            }
+            match state.client_id_header(&req.to_http_request()) {
+                Ok(_) => 200,
+                Err(e) => e.as_response_error().status_code().as_u16(),
+            }
+        };
+
+        // a client with a token must present it, but need not be in the allowlist
+        assert_eq!(status(client_with_token, None), 401);
+        assert_eq!(status(client_with_token, Some("wrong")), 401);
+        assert_eq!(status(client_with_token, Some("s3cret")), 200);
+
+        // a client without a token is subject to the allowlist
+        assert_eq!(status(client_without_token, None), 200);
+        assert_eq!(status(client_other, None), 403);
+        assert_eq!(status(client_other, Some("s3cret")), 403);
+
+        // the token is read without waiting for a concurrent write transaction
+        let _write_txn = state.server.txn(client_with_token)?;
+        assert_eq!(status(client_with_token, Some("s3cret")), 200);
+
+        Ok(())
+    }
+
+    #[test]
+    fn request_deadline_absent() {
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        assert_eq!(request_deadline(req.headers(), Utc::now()).unwrap(), None);
+    }
+
+    #[test]
+    fn request_deadline_millis() {
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((REQUEST_DEADLINE_HEADER, "1500"))
+            .to_http_request();
+        assert_eq!(
+            request_deadline(req.headers(), Utc::now()).unwrap(),
+            Some(Duration::from_millis(1500))
+        );
+    }
+
+    #[test]
+    fn request_deadline_timestamp() {
+        let now = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let deadline = |value: &str| {
+            let req = actix_web::test::TestRequest::default()
+                .insert_header((REQUEST_DEADLINE_HEADER, value))
+                .to_http_request();
+            request_deadline(req.headers(), now)
+        };
+        assert_eq!(
+            deadline("2024-05-01T12:00:02.5Z").unwrap(),
+            Some(Duration::from_millis(2500))
+        );
+        assert_eq!(
+            deadline("2024-05-01T14:00:10+02:00").unwrap(),
+            Some(Duration::from_secs(10))
+        );
+        // a deadline in the past leaves no time
+        assert_eq!(
+            deadline("2024-05-01T11:59:00Z").unwrap(),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn request_deadline_invalid() {
+        for value in ["soon", "-5", "2024-05-01"] {
+            let req = actix_web::test::TestRequest::default()
+                .insert_header((REQUEST_DEADLINE_HEADER, value))
+                .to_http_request();
+            let err = request_deadline(req.headers(), Utc::now()).unwrap_err();
+            assert_eq!(err.as_response_error().status_code(), 400);
+        }
+    }
+
+    #[test]
+    fn user_agent_matches_substring() {
+        assert!(user_agent_matches("champion/1", "taskchampion/1.2"));
+        assert!(!user_agent_matches("champion/2", "taskchampion/1.2"));
+    }
+
+    #[test]
+    fn user_agent_matches_glob() {
+        assert!(user_agent_matches("*", "anything"));
+        assert!(user_agent_matches("taskchampion/1.*", "taskchampion/1.2"));
+        assert!(!user_agent_matches(
+            "taskchampion/1.*",
+            "my-taskchampion/1.2"
+        ));
+        assert!(user_agent_matches("*/1.*-beta", "taskchampion/1.2-beta"));
+        assert!(!user_agent_matches("*/1.*-beta", "taskchampion/1.2-beta.1"));
+        assert!(!user_agent_matches("a*a", "a"));
+    }
+
+    #[test]
+    fn constant_time_eq_compares() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn body_preview_short() {
+        assert_eq!(body_preview(b"\x00\x01abc", 5, 16), "0001616263");
+    }
+
+    #[test]
+    fn body_preview_truncated() {
+        let body = vec![0xffu8; 1024];
+        assert_eq!(
+            body_preview(&body, body.len(), 4),
+            "ffffffff... (1024 bytes total)"
+        );
+    }
+
+    #[test]
+    fn body_preview_empty() {
+        assert_eq!(body_preview(b"", 0, 16), "");
+    }
+
+    #[test]
+    fn create_client_concurrently() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let state = ServerState::new(
+            Server::new(Default::default(), SqliteStorage::new(tmp_dir.path())?),
+            WebConfig::default(),
+        );
+        let client_id = Uuid::new_v4();
+
+        // two requests which both found no client race to create it, and both succeed
+        let results: Vec<Result<(), String>> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..2)
+                .map(|_| s.spawn(|| state.create_client(client_id).map_err(|e| e.to_string())))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(results, vec![Ok(()), Ok(())]);
+
+        // a request losing the race after the winner has added a version continues with the
+        // existing client, rather than resetting it
+        let (result, _) = state
+            .server
+            .add_version(client_id, NIL_VERSION_ID, b"abcd".to_vec())?;
+        let AddVersionResult::Ok(version_id) = result else {
+            panic!("version not added: {result:?}");
+        };
+        state
+            .create_client(client_id)
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+        let mut txn = state.server.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        Ok(())
+    }
+
+    #[test]
+    fn may_create_client_always() {
+        let state = ServerState::new(
+            Server::new(Default::default(), InMemoryStorage::new()),
+            WebConfig::default(),
+        );
+        assert!(state.may_create_client(Uuid::new_v4()));
+    }
+
+    #[test]
+    fn may_create_client_never() {
+        let client_id = Uuid::new_v4();
+        let state = ServerState::new(
+            Server::new(Default::default(), InMemoryStorage::new()),
+            WebConfig {
+                client_id_allowlist: Some([client_id].into()),
+                create_clients: CreateClients::Never,
+                ..WebConfig::default()
+            },
+        );
+        assert!(!state.may_create_client(client_id));
+        assert!(!state.may_create_client(Uuid::new_v4()));
+    }
+
+    #[test]
+    fn may_create_client_allowlist_only() {
+        let client_id_ok = Uuid::new_v4();
+        let state = ServerState::new(
+            Server::new(Default::default(), InMemoryStorage::new()),
+            WebConfig {
+                client_id_allowlist: Some([client_id_ok].into()),
+                create_clients: CreateClients::AllowlistOnly,
+                ..WebConfig::default()
+            },
+        );
+        assert!(state.may_create_client(client_id_ok));
+        assert!(!state.may_create_client(Uuid::new_v4()));
+    }
+
+    #[test]
+    fn may_create_client_allowlist_only_allow_all() {
+        let state = ServerState::new(
+            Server::new(Default::default(), InMemoryStorage::new()),
+            WebConfig {
+                client_id_allowlist: None,
+                create_clients: CreateClients::AllowlistOnly,
+                ..WebConfig::default()
+            },
+        );
+        assert!(!state.may_create_client(Uuid::new_v4()));
+    }
 }
@@ -0,0 +1,90 @@
+use actix_web::web::Bytes;
+use std::collections::VecDeque;
+use std::io::Read;
+
+/// A request body, held as the chunks in which it was received.
+///
+/// Accumulating a body into a single buffer requires reallocating that buffer as it grows, and
+/// then copying it again to hand it to the storage backend, so the peak memory use for a request
+/// is several times the size of its body. Holding the chunks as received, and reading them with
+/// [`Read`], avoids both copies. Each chunk is freed as soon as it has been read, so a storage
+/// backend which writes data incrementally never holds more than one copy of the body.
+///
+/// The body is still held in memory until it is read. Since storage backends are synchronous,
+/// reading directly from the network while writing to storage would block the server's worker
+/// threads.
+///
+/// Cloning the body shares its chunks, rather than copying them, so a clone can be read again if
+/// an operation must be retried.
+#[derive(Clone, Default)]
+pub(crate) struct RequestBody {
+    chunks: VecDeque<Bytes>,
+    len: usize,
+}
+
+impl RequestBody {
+    /// Add a chunk to the end of the body.
+    pub(crate) fn push(&mut self, chunk: Bytes) {
+        self.len += chunk.len();
+        if !chunk.is_empty() {
+            self.chunks.push_back(chunk);
+        }
+    }
+
+    /// The number of bytes remaining to be read.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterate over the bytes remaining to be read.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &u8> {
+        self.chunks.iter().flat_map(|chunk| chunk.iter())
+    }
+}
+
+impl Read for RequestBody {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(chunk) = self.chunks.front_mut() else {
+            return Ok(0);
+        };
+        let n = buf.len().min(chunk.len());
+        buf[..n].copy_from_slice(&chunk.split_to(n));
+        if chunk.is_empty() {
+            self.chunks.pop_front();
+        }
+        self.len -= n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn read_chunks() {
+        let mut body = RequestBody::default();
+        assert!(body.is_empty());
+        body.push(Bytes::from_static(b"abc"));
+        body.push(Bytes::new());
+        body.push(Bytes::from_static(b"defgh"));
+        assert_eq!(body.len(), 8);
+        assert_eq!(body.iter().copied().collect::<Vec<_>>(), b"abcdefgh");
+
+        let mut buf = [0u8; 2];
+        assert_eq!(body.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf, b"ab");
+        assert_eq!(body.len(), 6);
+
+        let mut rest = Vec::new();
+        body.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"cdefgh");
+        assert!(body.is_empty());
+        assert_eq!(body.read(&mut buf).unwrap(), 0);
+    }
+}
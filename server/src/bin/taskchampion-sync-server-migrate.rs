@@ -0,0 +1,193 @@
+#![deny(clippy::all)]
+
+//! Copy all clients from one storage backend to another, such as when moving a server from
+//! SQLite to another backend.
+
+use anyhow::Context;
+use clap::{arg, builder::ValueParser, Command};
+use std::path::{Path, PathBuf};
+use taskchampion_sync_server_core::{check_schema_version, Server, ServerConfig, Storage};
+use taskchampion_sync_server_storage_sqlite::SqliteStorage;
+
+/// A storage backend, as given on the command line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum StorageSpec {
+    /// `sqlite:DIR`: a SQLite database in the given data directory
+    Sqlite(PathBuf),
+    /// `redis://...`: the Redis server at the given URL
+    #[cfg(feature = "redis")]
+    Redis(String),
+}
+
+impl StorageSpec {
+    /// Parse a storage specification.
+    fn parse(s: &str) -> Result<StorageSpec, String> {
+        if let Some(dir) = s.strip_prefix("sqlite:") {
+            if dir.is_empty() {
+                return Err("sqlite: requires a data directory".into());
+            }
+            return Ok(StorageSpec::Sqlite(dir.into()));
+        }
+        #[cfg(feature = "redis")]
+        if s.starts_with("redis://") || s.starts_with("rediss://") {
+            return Ok(StorageSpec::Redis(s.into()));
+        }
+        Err(format!("unsupported storage {s:?}"))
+    }
+
+    /// Open the storage, checking that its schema is the version this binary requires.
+    fn open(&self) -> anyhow::Result<Box<dyn Storage>> {
+        let storage: Box<dyn Storage> = match self {
+            StorageSpec::Sqlite(dir) => Box::new(open_sqlite(dir)?),
+            #[cfg(feature = "redis")]
+            StorageSpec::Redis(url) => Box::new(
+                taskchampion_sync_server_storage_redis::RedisStorage::new(url)?,
+            ),
+        };
+        check_schema_version(storage.as_ref())?;
+        Ok(storage)
+    }
+
+    /// Copy all clients from this storage to `target`, returning the number copied.
+    fn migrate_to(&self, target: &dyn Storage) -> anyhow::Result<u64> {
+        match self {
+            StorageSpec::Sqlite(dir) => {
+                // opening a missing database would create an empty one
+                if !dir.is_dir() {
+                    anyhow::bail!("--from data directory {dir:?} does not exist");
+                }
+                migrate(open_sqlite(dir)?, target)
+            }
+            #[cfg(feature = "redis")]
+            StorageSpec::Redis(url) => migrate(
+                taskchampion_sync_server_storage_redis::RedisStorage::new(url)?,
+                target,
+            ),
+        }
+    }
+}
+
+fn open_sqlite(dir: &Path) -> anyhow::Result<SqliteStorage> {
+    SqliteStorage::new(dir).with_context(|| format!("Opening SQLite in {dir:?}"))
+}
+
+fn command() -> Command {
+    #[cfg(feature = "redis")]
+    let storages = "`sqlite:DIR` or a `redis://` URL";
+    #[cfg(not(feature = "redis"))]
+    let storages = "`sqlite:DIR`";
+    Command::new("taskchampion-sync-server-migrate")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Copy all clients from one TaskChampion sync server storage to another")
+        .after_help(format!(
+            "Each storage is {storages}. Clients which already exist in the target are skipped, \
+             so an interrupted migration can be run again."
+        ))
+        .arg(
+            arg!(--from <STORAGE> "Storage to copy clients from")
+                .value_parser(ValueParser::new(StorageSpec::parse))
+                .required(true),
+        )
+        .arg(
+            arg!(--to <STORAGE> "Storage to copy clients to")
+                .value_parser(ValueParser::new(StorageSpec::parse))
+                .required(true),
+        )
+}
+
+/// Copy all clients from `source` to `target`, returning the number copied.
+fn migrate<ST: Storage + 'static>(source: ST, target: &dyn Storage) -> anyhow::Result<u64> {
+    check_schema_version(&source).context("Checking --from storage")?;
+    let server = Server::new(ServerConfig::default(), source);
+    let copied = server.backfill_into(target, None, |checkpoint| {
+        log::info!("Copied client {}", checkpoint.last_client_id);
+        Ok(())
+    })?;
+    Ok(copied)
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let matches = command().get_matches();
+    let from: &StorageSpec = matches.get_one("from").unwrap();
+    let to: &StorageSpec = matches.get_one("to").unwrap();
+    if from == to {
+        anyhow::bail!("--from and --to are the same storage");
+    }
+
+    let target = to.open().context("Opening --to storage")?;
+    let copied = from.migrate_to(target.as_ref())?;
+    println!("Copied {copied} clients");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    #[test]
+    fn parse_storage() {
+        assert_eq!(
+            StorageSpec::parse("sqlite:/var/lib/tss"),
+            Ok(StorageSpec::Sqlite("/var/lib/tss".into()))
+        );
+        assert!(StorageSpec::parse("sqlite:").is_err());
+        assert!(StorageSpec::parse("postgres://localhost/tss").is_err());
+        assert!(StorageSpec::parse("/var/lib/tss").is_err());
+    }
+
+    #[test]
+    fn command_requires_both() {
+        assert!(command()
+            .try_get_matches_from(["tssm", "--from", "sqlite:a"])
+            .is_err());
+        let matches =
+            command().get_matches_from(["tssm", "--from", "sqlite:a", "--to", "sqlite:b"]);
+        assert_eq!(
+            matches.get_one::<StorageSpec>("to"),
+            Some(&StorageSpec::Sqlite("b".into()))
+        );
+    }
+
+    #[test]
+    fn migrate_inmemory_to_sqlite() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let source = InMemoryStorage::new();
+        let client_ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let mut version_ids = Vec::new();
+        for client_id in &client_ids {
+            let version_id = Uuid::new_v4();
+            let mut txn = source.txn(*client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(version_id, NIL_VERSION_ID, b"v1".to_vec())?;
+            txn.set_app_metadata(b"app".to_vec())?;
+            txn.commit()?;
+            version_ids.push(version_id);
+        }
+
+        let target = SqliteStorage::new(tmp_dir.path())?;
+        assert_eq!(migrate(source, &target)?, 3);
+
+        for (client_id, version_id) in client_ids.iter().zip(&version_ids) {
+            let mut txn = target.txn(*client_id)?;
+            let client = txn.get_client()?.unwrap();
+            assert_eq!(client.latest_version_id, *version_id);
+            let version = txn.get_version(*version_id)?.unwrap();
+            assert_eq!(version.parent_version_id, NIL_VERSION_ID);
+            assert_eq!(version.history_segment, b"v1".to_vec());
+            assert_eq!(txn.get_app_metadata()?, Some(b"app".to_vec()));
+        }
+
+        // a second migration skips the clients which already exist
+        let source = InMemoryStorage::new();
+        let mut txn = source.txn(client_ids[0])?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
+        assert_eq!(migrate(source, &target)?, 0);
+        Ok(())
+    }
+}
@@ -1,36 +1,88 @@
 #![deny(clippy::all)]
 
+use actix_http::{
+    body::{BoxBody, MessageBody},
+    error::DispatchError,
+    HttpService, Protocol, Request, Response,
+};
+use actix_service::{
+    fn_service, map_config, IntoServiceFactory, Service, ServiceFactory, ServiceFactoryExt,
+};
+#[cfg(unix)]
+use actix_web::rt::net::UnixStream;
 use actix_web::{
-    dev::ServiceResponse,
+    dev::{AppConfig, ServiceRequest, ServiceResponse},
     http::StatusCode,
     middleware::{ErrorHandlerResponse, ErrorHandlers, Logger},
+    rt::net::TcpStream,
     App, HttpServer,
 };
-use clap::{arg, builder::ValueParser, value_parser, ArgAction, Command};
-use std::{collections::HashSet, ffi::OsString};
-use taskchampion_sync_server::WebServer;
-use taskchampion_sync_server_core::ServerConfig;
-use taskchampion_sync_server_storage_sqlite::SqliteStorage;
+use anyhow::Context;
+use clap::{arg, builder::ValueParser, value_parser, ArgAction, ArgMatches, Command};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::{collections::HashSet, ffi::OsString, io::Write, time::Duration};
+use taskchampion_sync_server::{
+    current_request_id, proxy_protocol, CreateClients, DownloadQuota, RateLimit, WebConfig,
+    WebServer,
+};
+use taskchampion_sync_server_core::{check_schema_version, Compression, ServerConfig, Storage};
+#[cfg(feature = "s3")]
+use taskchampion_sync_server_storage_s3::{S3Config, S3SnapshotStore};
+use taskchampion_sync_server_storage_sqlite::{SqliteOptions, SqliteStorage};
+use tokio::io::{AsyncRead, AsyncWrite};
 use uuid::Uuid;
 
+/// The time allowed for a connection to send its PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
 fn command() -> Command {
     let defaults = ServerConfig::default();
     let default_snapshot_versions = defaults.snapshot_versions.to_string();
     let default_snapshot_days = defaults.snapshot_days.to_string();
-    Command::new("taskchampion-sync-server")
+    let default_snapshot_search_len = defaults.snapshot_search_len.to_string();
+    let command = Command::new("taskchampion-sync-server")
         .version(env!("CARGO_PKG_VERSION"))
         .about("Server for TaskChampion")
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("vacuum")
+                .about("Reclaim the space left in the database by deleted data, such as pruned versions, and exit"),
+        )
         .arg(
             arg!(-l --listen <ADDRESS>)
-                .help("Address and Port on which to listen on. Can be an IP Address or a DNS name followed by a colon and a port e.g. localhost:8080")
-                .value_parser(ValueParser::string())
+                .help("Address and Port on which to listen on. Can be an IP Address or a DNS name followed by a colon and a port e.g. localhost:8080 or [::1]:8080, or `unix:` followed by the path of a Unix domain socket e.g. unix:/run/tss.sock")
+                .value_parser(parse_listen)
                 .action(ArgAction::Append)
                 .required(true),
         )
         .arg(
             arg!(-d --"data-dir" <DIR> "Directory in which to store data")
                 .value_parser(ValueParser::os_string())
-                .default_value("/var/lib/taskchampion-sync-server"),
+                .default_value("/var/lib/taskchampion-sync-server")
+                .global(true),
+        )
+        .arg(
+            arg!(--"external-history-segments" "Store each new history segment in a separate file in the data directory, rather than in the database")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"skip-integrity-check" "Do not check the database for corruption at startup, which may be slow for a large database")
+                .action(ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            arg!(--"millisecond-timestamps" "Store snapshot timestamps with millisecond, rather than second, precision")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"history-segment-compression" <CODEC> "Compress new history segments in storage with this codec")
+                .value_parser(["none", "zstd"])
+                .default_value("none"),
+        )
+        .arg(
+            arg!(--"history-segment-compression-level" <LEVEL> "Level at which to compress new history segments, with higher levels compressing better but more slowly (from -131072 to 22, or 0 for the codec's default)")
+                .value_parser(value_parser!(i32).range(compression_levels()))
+                .default_value("0"),
         )
         .arg(
             arg!(-C --"allow-client-id" <CLIENT_ID> "Client IDs to allow (can be repeated; if not specified, all clients are allowed)")
@@ -38,6 +90,160 @@ fn command() -> Command {
                 .action(ArgAction::Append)
                 .required(false),
         )
+        .arg(
+            arg!(--"allow-client-id-file" <FILE> "Also allow the client IDs in FILE, one per line")
+                .value_parser(ValueParser::os_string()),
+        )
+        .arg(
+            arg!(--"deny-user-agent" <PATTERN> "Reject sync requests with a User-Agent containing PATTERN, or matching it if it contains `*` (can be repeated)")
+                .value_parser(ValueParser::string())
+                .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--"allow-user-agent" <PATTERN> "Reject sync requests with a User-Agent not matching any PATTERN, in the same format as --deny-user-agent (can be repeated)")
+                .value_parser(ValueParser::string())
+                .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--"cors-origin" <ORIGIN> "Allow browser-based clients served from ORIGIN, such as https://example.com, or `*` for any origin (can be repeated)")
+                .value_parser(ValueParser::string())
+                .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--token <TOKEN> "Require requests to carry this token in an `Authorization: Bearer` header")
+                .value_parser(ValueParser::string())
+                .env("TOKEN")
+                .hide_env_values(true),
+        )
+        .arg(
+            arg!(--"client-tokens" "Require clients with their own token in the database to present it in an `Authorization: Bearer` header")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"create-clients" <MODE> "Which unknown clients to create on first use: all of them, none of them, or only those allowed with --allow-client-id")
+                .value_parser(["always", "never", "allowlist-only"])
+                .default_value("always"),
+        )
+        .arg(
+            arg!(--"default-snapshot" <FILE> "Create clients automatically with the snapshot in FILE, for the version given by --default-snapshot-version-id")
+                .value_parser(ValueParser::os_string())
+                .requires("default-snapshot-version-id"),
+        )
+        .arg(
+            arg!(--"default-snapshot-version-id" <VERSION_ID> "Version ID of the snapshot given by --default-snapshot")
+                .value_parser(value_parser!(Uuid))
+                .requires("default-snapshot"),
+        )
+        .arg(
+            arg!(--"unknown-client-header" "Mark 404 responses to get-child-version for unknown clients with an `X-Unknown-Client: true` header")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"allow-client-deletion" "Allow clients to delete themselves, with all of their data, with `DELETE /v1/client`")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"enable-get-version" "Serve any version by its own ID at `GET /v1/client/get-version/{VERSION_ID}`, an extension to the sync protocol")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"allow-empty-version" "Accept zero-length history segments when adding versions")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"read-only" "Reject requests which would modify stored data with 503 Service Unavailable, while still serving reads")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--banner <TEXT> "Text served at /, in which {version} is replaced by the server's version; by default, the text does not include the version")
+                .value_parser(ValueParser::string())
+                .env("BANNER"),
+        )
+        .arg(
+            arg!(--"proxy-protocol" "Expect a PROXY protocol header, as sent by a load balancer such as HAProxy, at the beginning of each connection, and use the client address it contains")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"response-compression" "Compress history segments and snapshots in responses, if the client accepts a compressed encoding")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"strict-http" "Respond with 204 No Content, rather than 200 OK, to writes without a response body")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"serialize-writes" "Serialize writes for each client within this process, avoiding conflicts in the storage backend")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"max-body-size" <SIZE> "Maximum size of the body of a request to add a version or snapshot, in bytes or with a suffix such as KB, MB, or GB")
+                .value_parser(parse_size)
+                .env("MAX_BODY_SIZE")
+                .default_value("100MB"),
+        )
+        .arg(
+            arg!(--"max-version-size" <BYTES> "Maximum size of a history segment when adding a version; larger versions are rejected")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--"max-snapshot-size" <SIZE> "Maximum size of a snapshot, in bytes or with a suffix such as KB, MB, or GB, used in place of --max-body-size when adding a snapshot")
+                .value_parser(parse_size)
+                .env("MAX_SNAPSHOT_SIZE"),
+        )
+        .arg(
+            arg!(--"max-client-bytes" <BYTES> "Maximum storage used by each client's versions and snapshot; versions which would exceed it are rejected")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--"rate-limit" <RATE> "Limit each client to RATE sync requests per second, with bursts of up to BURST, given as RATE or RATE:BURST")
+                .value_parser(value_parser!(RateLimit)),
+        )
+        .arg(
+            arg!(--"rate-limit-reads" <RATE> "Limit each client to RATE read requests per second, with bursts of up to BURST, given as RATE or RATE:BURST")
+                .value_parser(value_parser!(RateLimit)),
+        )
+        .arg(
+            arg!(--"rate-limit-writes" <RATE> "Limit each client to RATE write requests per second, with bursts of up to BURST, given as RATE or RATE:BURST")
+                .value_parser(value_parser!(RateLimit)),
+        )
+        .arg(
+            arg!(--"download-quota-bytes" <BYTES> "Limit each client to downloading BYTES of versions and snapshots in each window given by --download-quota-window")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--"download-quota-window" <SECONDS> "Length of the window for --download-quota-bytes")
+                .value_parser(value_parser!(u64).range(1..))
+                .default_value("3600"),
+        )
+        .arg(
+            arg!(--"log-format" <FORMAT> "Format of log lines: plain text, or a JSON object per line")
+                .value_parser(["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            arg!(--"debug-bodies" [MAX_BYTES] "Log a preview of up to MAX_BYTES (default 256) of each request and response body at DEBUG level. Bodies may contain sensitive data!")
+                .value_parser(value_parser!(usize))
+                .num_args(0..=1)
+                .default_missing_value("256"),
+        )
+        .arg(
+            arg!(--"record-read-activity" "Record each client's last activity on reads as well as writes, at the cost of a storage write for every read")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"chain-hash" "Maintain a hash of each client's history, which replicas can fetch to detect divergence")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"snapshot-latest-only" "Accept only snapshots for a client's latest version, rather than any recent version")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"snapshot-search-len" <NUM> "Number of recent versions for which a snapshot is accepted")
+                .value_parser(value_parser!(u32).range(1..))
+                .env("SNAPSHOT_SEARCH_LEN")
+                .default_value(default_snapshot_search_len),
+        )
         .arg(
             arg!(--"snapshot-versions" <NUM> "Target number of versions between snapshots")
                 .value_parser(value_parser!(u32))
@@ -47,53 +253,594 @@ fn command() -> Command {
             arg!(--"snapshot-days" <NUM> "Target number of days between snapshots")
                 .value_parser(value_parser!(i64))
                 .default_value(default_snapshot_days),
+        );
+    #[cfg(feature = "metrics")]
+    let command = command.arg(
+        arg!(--"stats-refresh-interval" <SECONDS> "Serve snapshot statistics, such as the age of the oldest snapshot, at /metrics, recalculating them at this interval")
+            .value_parser(value_parser!(u64).range(1..)),
+    );
+    #[cfg(feature = "admin")]
+    let command = command
+        .arg(
+            arg!(--dashboard "Serve an HTML status page at /dashboard").action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"admin-token" <TOKEN> "Require requests to the admin endpoints to carry this token in an `Authorization: Bearer` header (required)")
+                .value_parser(ValueParser::string())
+                .env("ADMIN_TOKEN")
+                .hide_env_values(true),
+        );
+    #[cfg(feature = "s3")]
+    let command = command
+        .arg(
+            arg!(--"snapshot-bucket" <BUCKET> "Store snapshot data in this S3-compatible bucket, keeping only a reference to it in the data directory")
+                .value_parser(ValueParser::string())
+                .env("SNAPSHOT_BUCKET"),
         )
+        .arg(
+            arg!(--"s3-endpoint" <URL> "Base URL of the S3-compatible service holding --snapshot-bucket")
+                .value_parser(ValueParser::string())
+                .env("S3_ENDPOINT")
+                .default_value("https://s3.amazonaws.com"),
+        )
+        .arg(
+            arg!(--"s3-region" <REGION> "Region of --snapshot-bucket")
+                .value_parser(ValueParser::string())
+                .env("AWS_REGION")
+                .default_value("us-east-1"),
+        )
+        .arg(
+            arg!(--"s3-access-key-id" <ID> "Access key ID for --snapshot-bucket")
+                .value_parser(ValueParser::string())
+                .env("AWS_ACCESS_KEY_ID")
+                .hide_env_values(true),
+        )
+        .arg(
+            arg!(--"s3-secret-access-key" <KEY> "Secret access key for --snapshot-bucket")
+                .value_parser(ValueParser::string())
+                .env("AWS_SECRET_ACCESS_KEY")
+                .hide_env_values(true),
+        );
+    command
+}
+
+/// Get the `--admin-token`, which is required when the admin endpoints are built, so that they
+/// are never served without a credential.
+#[cfg(feature = "admin")]
+fn admin_token(matches: &ArgMatches) -> anyhow::Result<String> {
+    matches
+        .get_one::<String>("admin-token")
+        .cloned()
+        .context("--admin-token is required, since this server includes the admin endpoints")
+}
+
+/// Get the configuration of the bucket in which to store snapshot data, if `--snapshot-bucket`
+/// is given.
+#[cfg(feature = "s3")]
+fn s3_config(matches: &ArgMatches) -> anyhow::Result<Option<S3Config>> {
+    let Some(bucket) = matches.get_one::<String>("snapshot-bucket") else {
+        return Ok(None);
+    };
+    let get = |name: &str| -> anyhow::Result<String> {
+        matches
+            .get_one::<String>(name)
+            .cloned()
+            .with_context(|| format!("--snapshot-bucket requires --{name}"))
+    };
+    Ok(Some(S3Config {
+        endpoint: get("s3-endpoint")?,
+        bucket: bucket.clone(),
+        region: get("s3-region")?,
+        access_key_id: get("s3-access-key-id")?,
+        secret_access_key: get("s3-secret-access-key")?,
+    }))
+}
+
+/// Parse a size in bytes, optionally followed by a suffix `K`, `M`, or `G`, with or without a
+/// trailing `B`, each of which is a power of 1024. For example, `50MB` is 50 * 1024 * 1024 bytes.
+fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(digits);
+    let number: usize = number.parse().map_err(|_| format!("invalid size {s:?}"))?;
+    let multiplier: usize = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(format!("invalid size suffix {suffix:?}")),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size {s:?} is too large"))
+}
+
+/// Open the storage in the `--data-dir`, checking that its schema is the version this server
+/// requires.
+fn open_storage(matches: &ArgMatches) -> anyhow::Result<SqliteStorage> {
+    let data_dir: &OsString = matches.get_one("data-dir").unwrap();
+    let options = SqliteOptions {
+        external_segments: matches.get_flag("external-history-segments"),
+        integrity_check: !matches.get_flag("skip-integrity-check"),
+    };
+    let mut storage = SqliteStorage::open_with_options(data_dir, options)?;
+    storage.set_millisecond_timestamps(matches.get_flag("millisecond-timestamps"));
+    check_schema_version(&storage)?;
+    Ok(storage)
+}
+
+/// Run the `vacuum` subcommand, reclaiming space in the storage in the `--data-dir`.
+fn vacuum(matches: &ArgMatches) -> anyhow::Result<()> {
+    let storage = open_storage(matches)?;
+    log::info!("Vacuuming storage");
+    storage.maintenance()?;
+    log::info!("Vacuumed storage");
+    Ok(())
+}
+
+/// Get the `--create-clients` mode.
+fn create_clients(matches: &ArgMatches) -> CreateClients {
+    match matches
+        .get_one::<String>("create-clients")
+        .map(String::as_str)
+    {
+        Some("never") => CreateClients::Never,
+        Some("allowlist-only") => CreateClients::AllowlistOnly,
+        _ => CreateClients::Always,
+    }
+}
+
+/// Get the allowed client IDs, from both `--allow-client-id` and `--allow-client-id-file`, or
+/// `None` if all clients are allowed.
+fn client_id_allowlist(matches: &ArgMatches) -> anyhow::Result<Option<HashSet<Uuid>>> {
+    let mut allowlist: Option<HashSet<Uuid>> = matches
+        .get_many("allow-client-id")
+        .map(|ids| ids.copied().collect());
+    if let Some(path) = matches.get_one::<OsString>("allow-client-id-file") {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading client ID file {path:?}"))?;
+        let client_ids = parse_client_ids(&contents)
+            .with_context(|| format!("Parsing client ID file {path:?}"))?;
+        allowlist.get_or_insert_default().extend(client_ids);
+    }
+    Ok(allowlist)
+}
+
+/// Parse a list of client IDs, one per line. Blank lines are ignored.
+fn parse_client_ids(contents: &str) -> anyhow::Result<Vec<Uuid>> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(lineno, line)| {
+            Uuid::parse_str(line)
+                .with_context(|| format!("Invalid client ID {line:?} on line {lineno}"))
+        })
+        .collect()
+}
+
+/// The supported `--history-segment-compression-level`s, as a range for the argument parser.
+fn compression_levels() -> std::ops::RangeInclusive<i64> {
+    let levels = Compression::levels();
+    (*levels.start()).into()..=(*levels.end()).into()
+}
+
+/// Get the `--history-segment-compression` codec.
+fn compression(matches: &ArgMatches) -> Compression {
+    match matches
+        .get_one::<String>("history-segment-compression")
+        .map(String::as_str)
+    {
+        Some("zstd") => Compression::Zstd,
+        _ => Compression::None,
+    }
+}
+
+/// The format of log lines, from `--log-format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// The log target of the per-request log lines, in JSON format.
+const REQUEST_LOG_TARGET: &str = "taskchampion_sync_server::request";
+
+/// Get the `--log-format`.
+fn log_format(matches: &ArgMatches) -> LogFormat {
+    match matches.get_one::<String>("log-format").map(String::as_str) {
+        Some("json") => LogFormat::Json,
+        _ => LogFormat::Text,
+    }
+}
+
+/// Initialize logging, configured by `RUST_LOG` as usual, in the given format. Lines logged while
+/// handling a request, including those of the sync server core, carry the request's ID.
+fn init_logging(log_format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    match log_format {
+        // the default format, with the request ID after the target
+        LogFormat::Text => builder.format(|buf, record| {
+            let style = buf.default_level_style(record.level());
+            write!(
+                buf,
+                "[{} {style}{:<5}{style:#} {}",
+                buf.timestamp(),
+                record.level(),
+                record.target()
+            )?;
+            if let Some(request_id) = current_request_id() {
+                write!(buf, " request={request_id}")?;
+            }
+            writeln!(buf, "] {}", record.args())
+        }),
+        LogFormat::Json => builder.format(|buf, record| {
+            let line = json_log_line(
+                chrono::Utc::now(),
+                record.level(),
+                record.target(),
+                current_request_id().as_deref(),
+                &record.args().to_string(),
+            );
+            writeln!(buf, "{line}")
+        }),
+    };
+    builder.init();
+}
+
+/// Build a JSON log line. The message of a request log line is itself a JSON object, and its
+/// fields are included directly rather than as a message.
+fn json_log_line(
+    timestamp: chrono::DateTime<chrono::Utc>,
+    level: log::Level,
+    target: &str,
+    request_id: Option<&str>,
+    message: &str,
+) -> serde_json::Value {
+    let mut line = serde_json::json!({
+        "timestamp": timestamp.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "level": level.as_str(),
+        "target": target,
+    });
+    if let Some(request_id) = request_id {
+        line["request_id"] = request_id.into();
+    }
+    let fields = match serde_json::from_str(message) {
+        Ok(serde_json::Value::Object(fields)) if target == REQUEST_LOG_TARGET => fields,
+        _ => serde_json::Map::from_iter([("message".into(), message.into())]),
+    };
+    line.as_object_mut().unwrap().extend(fields);
+    line
+}
+
+/// Build the middleware logging each request, in the given format.
+fn request_logger(log_format: LogFormat) -> Logger {
+    match log_format {
+        // the default format, plus the W3C trace context of the request, if any, and its ID
+        LogFormat::Text => Logger::new(
+            r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %{traceparent}i %{X-Request-Id}o %T"#,
+        ),
+        // each value replaced in this format is valid JSON, so the message is a JSON object
+        LogFormat::Json => {
+            let header = |name: &'static str| {
+                move |req: &ServiceRequest| {
+                    let value = req.headers().get(name).and_then(|v| v.to_str().ok());
+                    serde_json::to_string(&value).unwrap()
+                }
+            };
+            Logger::new(concat!(
+                r#"{"client_id":%{client_id}xi,"method":%{method}xi,"path":%{path}xi,"#,
+                r#""status":%s,"duration_ms":%D,"traceparent":%{traceparent}xi,"#,
+                r#""request_id":%{request_id}xo}"#,
+            ))
+            .custom_response_replace("request_id", |resp| {
+                let value = resp.headers().get("X-Request-Id");
+                serde_json::to_string(&value.and_then(|v| v.to_str().ok())).unwrap()
+            })
+            .custom_request_replace("client_id", header("X-Client-Id"))
+            .custom_request_replace("traceparent", header("traceparent"))
+            .custom_request_replace("method", |req| {
+                serde_json::to_string(req.method().as_str()).unwrap()
+            })
+            .custom_request_replace("path", |req| serde_json::to_string(req.path()).unwrap())
+            .log_target(REQUEST_LOG_TARGET)
+        }
+    }
+}
+
+/// Get the download quota, if `--download-quota-bytes` is given.
+fn download_quota(matches: &ArgMatches) -> Option<DownloadQuota> {
+    let bytes: u64 = *matches.get_one("download-quota-bytes")?;
+    let window: u64 = *matches.get_one("download-quota-window").unwrap();
+    Some(DownloadQuota {
+        bytes,
+        window: Duration::from_secs(window),
+    })
+}
+
+/// Parse a `--listen` address, which is either `unix:PATH` or `HOST:PORT`. The host is an IPv4
+/// address, an IPv6 address in brackets, or a DNS name, and the port is between 0 and 65535,
+/// where 0 lets the operating system pick an unused port.
+fn parse_listen(s: &str) -> Result<String, String> {
+    if let Some(path) = unix_socket_path(s) {
+        if path.is_empty() {
+            return Err("missing path after `unix:`".into());
+        }
+        return Ok(s.into());
+    }
+    let Some((host, port)) = s.rsplit_once(':') else {
+        return Err(format!(
+            "{s:?} has no port; expected HOST:PORT, such as localhost:8080 or [::1]:8080"
+        ));
+    };
+    if port.parse::<u16>().is_err() {
+        return Err(format!(
+            "invalid port {port:?}; expected a number from 0 to 65535"
+        ));
+    }
+    if let Some(addr) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        addr.parse::<Ipv6Addr>()
+            .map_err(|_| format!("invalid IPv6 address {addr:?}"))?;
+    } else if host.contains(':') {
+        return Err(format!(
+            "IPv6 address {host:?} must be in brackets, such as [{host}]:{port}"
+        ));
+    } else if !host.is_empty() && host.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        host.parse::<Ipv4Addr>()
+            .map_err(|_| format!("invalid IPv4 address {host:?}"))?;
+    } else if host.is_empty()
+        || !host.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+    {
+        return Err(format!("invalid host name {host:?}"));
+    }
+    Ok(s.into())
+}
+
+/// Get the path of the Unix domain socket for a `--listen` address of the form `unix:PATH`, or
+/// `None` for a TCP address.
+fn unix_socket_path(listen_address: &str) -> Option<&str> {
+    listen_address.strip_prefix("unix:")
 }
 
 fn print_error<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
     if let Some(err) = res.response().error() {
-        log::error!("Internal Server Error caused by:\n{:?}", err);
+        let request_id = res
+            .headers()
+            .get("X-Request-Id")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-");
+        log::error!(
+            "Internal Server Error for request {}, caused by:\n{:?}",
+            request_id,
+            err
+        );
     }
     Ok(ErrorHandlerResponse::Response(res.map_into_left_body()))
 }
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::init();
     let matches = command().get_matches();
+    let log_format = log_format(&matches);
+    init_logging(log_format);
+    if let Some(("vacuum", _)) = matches.subcommand() {
+        return vacuum(&matches);
+    }
 
-    let data_dir: &OsString = matches.get_one("data-dir").unwrap();
     let snapshot_versions: u32 = *matches.get_one("snapshot-versions").unwrap();
     let snapshot_days: i64 = *matches.get_one("snapshot-days").unwrap();
-    let client_id_allowlist: Option<HashSet<Uuid>> = matches
-        .get_many("allow-client-id")
-        .map(|ids| ids.copied().collect());
+    let record_read_activity = matches.get_flag("record-read-activity");
+    let chain_hash = matches.get_flag("chain-hash");
+    let snapshot_latest_only = matches.get_flag("snapshot-latest-only");
+    let snapshot_search_len: u32 = *matches.get_one("snapshot-search-len").unwrap();
+    let max_client_bytes: Option<u64> = matches.get_one("max-client-bytes").copied();
+    let compression = compression(&matches);
+    let compression_level: i32 = *matches
+        .get_one("history-segment-compression-level")
+        .unwrap();
+    let client_id_allowlist = client_id_allowlist(&matches)?;
+    let user_agent_denylist: Vec<String> = matches
+        .get_many("deny-user-agent")
+        .map(|patterns| patterns.cloned().collect())
+        .unwrap_or_default();
+    let user_agent_allowlist: Option<Vec<String>> = matches
+        .get_many("allow-user-agent")
+        .map(|patterns| patterns.cloned().collect());
+    let cors_allowed_origins: Option<Vec<String>> = matches
+        .get_many("cors-origin")
+        .map(|origins| origins.cloned().collect());
+    let require_token: Option<String> = matches.get_one("token").cloned();
+    let client_tokens = matches.get_flag("client-tokens");
+    let allow_empty_version = matches.get_flag("allow-empty-version");
+    let allow_client_deletion = matches.get_flag("allow-client-deletion");
+    let enable_get_version = matches.get_flag("enable-get-version");
+    let read_only = matches.get_flag("read-only");
+    let response_compression = matches.get_flag("response-compression");
+    let server_banner: Option<String> = matches.get_one("banner").cloned();
+    let proxy_protocol = matches.get_flag("proxy-protocol");
+    let create_clients = create_clients(&matches);
+    let default_snapshot = match (
+        matches.get_one::<Uuid>("default-snapshot-version-id"),
+        matches.get_one::<OsString>("default-snapshot"),
+    ) {
+        (Some(version_id), Some(path)) => Some((
+            *version_id,
+            std::fs::read(path).with_context(|| format!("Reading default snapshot {path:?}"))?,
+        )),
+        _ => None,
+    };
+    let unknown_client_header = matches.get_flag("unknown-client-header");
+    let strict_http = matches.get_flag("strict-http");
+    let serialize_writes = matches.get_flag("serialize-writes");
+    let max_body_size: usize = *matches.get_one("max-body-size").unwrap();
+    let max_version_size: Option<usize> = matches.get_one("max-version-size").copied();
+    let max_snapshot_size: Option<usize> = matches.get_one("max-snapshot-size").copied();
+    let rate_limit: Option<RateLimit> = matches.get_one("rate-limit").copied();
+    let rate_limit_reads: Option<RateLimit> = matches.get_one("rate-limit-reads").copied();
+    let rate_limit_writes: Option<RateLimit> = matches.get_one("rate-limit-writes").copied();
+    let download_quota = download_quota(&matches);
+    let debug_bodies: Option<usize> = matches.get_one("debug-bodies").copied();
+    #[cfg(feature = "admin")]
+    let dashboard = matches.get_flag("dashboard");
+    #[cfg(not(feature = "admin"))]
+    let dashboard = false;
+    #[cfg(feature = "admin")]
+    let admin_token = Some(admin_token(&matches)?);
+    #[cfg(not(feature = "admin"))]
+    let admin_token = None;
+    #[cfg(feature = "metrics")]
+    let stats_refresh_interval = matches
+        .get_one::<u64>("stats-refresh-interval")
+        .map(|secs| Duration::from_secs(*secs));
+    #[cfg(not(feature = "metrics"))]
+    let stats_refresh_interval = None;
 
     let config = ServerConfig {
         snapshot_days,
         snapshot_versions,
+        record_read_activity,
+        chain_hash,
+        max_client_bytes,
+        compression,
+        compression_level,
+        snapshot_latest_only,
+        snapshot_search_len,
+        ..ServerConfig::default()
     };
-    let server = WebServer::new(config, client_id_allowlist, SqliteStorage::new(data_dir)?);
+    config.validate()?;
+    let web_config = WebConfig {
+        client_id_allowlist,
+        user_agent_denylist,
+        user_agent_allowlist,
+        allow_empty_version,
+        create_clients,
+        default_snapshot,
+        strict_http,
+        serialize_writes,
+        debug_bodies,
+        max_body_size,
+        max_version_size,
+        max_snapshot_size,
+        rate_limit,
+        rate_limit_reads,
+        rate_limit_writes,
+        download_quota,
+        dashboard,
+        require_token,
+        admin_token,
+        client_tokens,
+        unknown_client_header,
+        allow_client_deletion,
+        enable_get_version,
+        cors_allowed_origins,
+        read_only,
+        response_compression,
+        server_banner,
+        proxy_protocol,
+        stats_refresh_interval,
+    };
+    let storage = open_storage(&matches)?;
+    #[cfg(feature = "s3")]
+    let server = match s3_config(&matches)? {
+        Some(s3_config) => {
+            log::info!("Storing snapshots in bucket {}", s3_config.bucket);
+            let storage = S3SnapshotStore::new(storage, s3_config)?;
+            WebServer::new(config, web_config, storage)
+        }
+        None => WebServer::new(config, web_config, storage),
+    };
+    #[cfg(not(feature = "s3"))]
+    let server = WebServer::new(config, web_config, storage);
+    server.spawn_stats_refresh();
 
-    let mut http_server = HttpServer::new(move || {
+    let app = move || {
         App::new()
             .wrap(ErrorHandlers::new().handler(StatusCode::INTERNAL_SERVER_ERROR, print_error))
-            .wrap(Logger::default())
+            .wrap(request_logger(log_format))
             .configure(|cfg| server.config(cfg))
-    });
-    for listen_address in matches.get_many::<&str>("listen").unwrap() {
+    };
+    if proxy_protocol {
+        // HttpServer cannot read the PROXY header, so build the server from its parts.
+        let mut builder = actix_server::Server::build();
+        for listen_address in matches.get_many::<String>("listen").unwrap() {
+            log::info!("Serving on {} with PROXY protocol", listen_address);
+            let app = app.clone();
+            builder = match unix_socket_path(listen_address) {
+                #[cfg(unix)]
+                Some(path) => builder.bind_uds(listen_address, path, move || {
+                    proxy_protocol_service(app(), |_: &UnixStream| None)
+                })?,
+                #[cfg(not(unix))]
+                Some(_) => anyhow::bail!("Unix domain sockets are not supported on this platform"),
+                None => builder.bind(listen_address, listen_address.as_str(), move || {
+                    proxy_protocol_service(app(), |io: &TcpStream| io.peer_addr().ok())
+                })?,
+            };
+        }
+        builder.run().await?;
+        return Ok(());
+    }
+
+    let mut http_server = HttpServer::new(app);
+    for listen_address in matches.get_many::<String>("listen").unwrap() {
         log::info!("Serving on {}", listen_address);
-        http_server = http_server.bind(listen_address)?
+        http_server = match unix_socket_path(listen_address) {
+            #[cfg(unix)]
+            Some(path) => http_server.bind_uds(path)?,
+            #[cfg(not(unix))]
+            Some(_) => anyhow::bail!("Unix domain sockets are not supported on this platform"),
+            None => http_server.bind(listen_address)?,
+        };
     }
     http_server.run().await?;
     Ok(())
 }
 
+/// Serve HTTP/1.1 on connections which begin with a PROXY protocol header, using the address in
+/// that header as the peer address of each request. For a header without an address, the peer
+/// address is that of the connection, as given by `peer_addr`.
+fn proxy_protocol_service<T, I, S, B>(
+    app: I,
+    peer_addr: fn(&T) -> Option<SocketAddr>,
+) -> impl ServiceFactory<T, Config = (), Response = (), Error = DispatchError, InitError = ()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + 'static,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<Response<BoxBody>> + 'static,
+    S::InitError: std::fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service<Request>>::Future: 'static,
+    B: MessageBody + 'static,
+{
+    fn_service(move |mut io: T| async move {
+        let header_addr =
+            tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(&mut io))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "Timed out reading PROXY header",
+                    ))
+                })
+                .map_err(|err| {
+                    log::warn!("Closing connection: {err}");
+                    DispatchError::Io(err)
+                })?;
+        let peer_addr = header_addr.or_else(|| peer_addr(&io));
+        Ok((io, Protocol::Http1, peer_addr))
+    })
+    .and_then(HttpService::build().finish(map_config(app, |_| AppConfig::default())))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use actix_web::{self, App};
-    use clap::ArgMatches;
+    use chrono::TimeZone;
     use taskchampion_sync_server_core::InMemoryStorage;
 
     /// Get the list of allowed client IDs
@@ -147,6 +894,654 @@ mod test {
         );
     }
 
+    #[test]
+    fn command_allowed_client_ids_file() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let path = tmp_dir.path().join("clients");
+        std::fs::write(
+            &path,
+            "711d5cf3-0cf0-4eb8-9eca-6f7f220638c0\n\n  bbaf4b61-344a-4a39-a19e-8caa0669b353  \n",
+        )?;
+        let matches = command().get_matches_from([
+            "tss".into(),
+            "--listen".into(),
+            "localhost:8080".into(),
+            "-C".into(),
+            "1b8a8d36-4a4c-4e88-a2b5-6d2e5cba4c62".into(),
+            "--allow-client-id-file".into(),
+            path.into_os_string(),
+        ]);
+        let allowlist = client_id_allowlist(&matches)?.unwrap();
+        assert_eq!(
+            allowlist,
+            HashSet::from([
+                Uuid::parse_str("711d5cf3-0cf0-4eb8-9eca-6f7f220638c0")?,
+                Uuid::parse_str("bbaf4b61-344a-4a39-a19e-8caa0669b353")?,
+                Uuid::parse_str("1b8a8d36-4a4c-4e88-a2b5-6d2e5cba4c62")?,
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn command_allowed_client_ids_file_invalid() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let path = tmp_dir.path().join("clients");
+        std::fs::write(
+            &path,
+            "711d5cf3-0cf0-4eb8-9eca-6f7f220638c0\n\nnot-a-uuid\n",
+        )?;
+        let matches = command().get_matches_from([
+            "tss".into(),
+            "--listen".into(),
+            "localhost:8080".into(),
+            "--allow-client-id-file".into(),
+            path.into_os_string(),
+        ]);
+        let err = client_id_allowlist(&matches).unwrap_err();
+        assert!(format!("{err:#}").contains("\"not-a-uuid\" on line 3"));
+        Ok(())
+    }
+
+    #[test]
+    fn command_create_clients_default() {
+        let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+        assert_eq!(create_clients(&matches), CreateClients::Always);
+    }
+
+    #[test]
+    fn command_create_clients_allowlist_only() {
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--create-clients",
+            "allowlist-only",
+        ]);
+        assert_eq!(create_clients(&matches), CreateClients::AllowlistOnly);
+    }
+
+    #[test]
+    fn command_create_clients_invalid() {
+        assert!(command()
+            .try_get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--create-clients",
+                "sometimes",
+            ])
+            .is_err());
+    }
+
+    #[test]
+    fn command_history_segment_compression() {
+        let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+        assert_eq!(compression(&matches), Compression::None);
+
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--history-segment-compression",
+            "zstd",
+        ]);
+        assert_eq!(compression(&matches), Compression::Zstd);
+
+        assert!(command()
+            .try_get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--history-segment-compression",
+                "lzma",
+            ])
+            .is_err());
+    }
+
+    #[test]
+    fn command_history_segment_compression_level() {
+        let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+        assert_eq!(
+            matches.get_one::<i32>("history-segment-compression-level"),
+            Some(&0)
+        );
+
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--history-segment-compression-level",
+            "19",
+        ]);
+        assert_eq!(
+            matches.get_one::<i32>("history-segment-compression-level"),
+            Some(&19)
+        );
+
+        // levels outside those supported by zstd are rejected at startup
+        assert!(command()
+            .try_get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--history-segment-compression-level",
+                "100",
+            ])
+            .is_err());
+    }
+
+    #[test]
+    fn command_log_format() {
+        let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+        assert_eq!(log_format(&matches), LogFormat::Text);
+
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--log-format",
+            "json",
+        ]);
+        assert_eq!(log_format(&matches), LogFormat::Json);
+
+        assert!(command()
+            .try_get_matches_from(["tss", "--listen", "localhost:8080", "--log-format", "xml"])
+            .is_err());
+    }
+
+    #[test]
+    fn json_log_line_message() {
+        let timestamp = chrono::Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap();
+        assert_eq!(
+            json_log_line(
+                timestamp,
+                log::Level::Debug,
+                "core",
+                None,
+                r#"add_version("x")"#
+            ),
+            serde_json::json!({
+                "timestamp": "2025-01-02T03:04:05.000Z",
+                "level": "DEBUG",
+                "target": "core",
+                "message": r#"add_version("x")"#,
+            })
+        );
+
+        // a line logged while handling a request carries its ID
+        assert_eq!(
+            json_log_line(
+                timestamp,
+                log::Level::Debug,
+                "core",
+                Some("proxy-1234"),
+                r#"add_version("x")"#
+            )["request_id"],
+            "proxy-1234"
+        );
+    }
+
+    #[test]
+    fn json_log_line_request() {
+        let timestamp = chrono::Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap();
+        let message =
+            r#"{"client_id":null,"method":"GET","path":"/","status":200,"duration_ms":1.5}"#;
+        assert_eq!(
+            json_log_line(
+                timestamp,
+                log::Level::Info,
+                REQUEST_LOG_TARGET,
+                None,
+                message
+            ),
+            serde_json::json!({
+                "timestamp": "2025-01-02T03:04:05.000Z",
+                "level": "INFO",
+                "target": REQUEST_LOG_TARGET,
+                "client_id": null,
+                "method": "GET",
+                "path": "/",
+                "status": 200,
+                "duration_ms": 1.5,
+            })
+        );
+
+        // a JSON message from another target is left as a message
+        assert_eq!(
+            json_log_line(timestamp, log::Level::Info, "other", None, message)["message"],
+            message
+        );
+    }
+
+    #[test]
+    fn command_download_quota() {
+        let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+        assert_eq!(download_quota(&matches), None);
+
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--download-quota-bytes",
+            "1000000",
+        ]);
+        assert_eq!(
+            download_quota(&matches),
+            Some(DownloadQuota {
+                bytes: 1_000_000,
+                window: Duration::from_secs(3600),
+            })
+        );
+
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--download-quota-bytes",
+            "1000",
+            "--download-quota-window",
+            "60",
+        ]);
+        assert_eq!(
+            download_quota(&matches),
+            Some(DownloadQuota {
+                bytes: 1000,
+                window: Duration::from_secs(60),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("123"), Ok(123));
+        assert_eq!(parse_size("123B"), Ok(123));
+        assert_eq!(parse_size("2K"), Ok(2048));
+        assert_eq!(parse_size("50MB"), Ok(50 * 1024 * 1024));
+        assert_eq!(parse_size("50mb"), Ok(50 * 1024 * 1024));
+        assert_eq!(parse_size("1 GB"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("1.5MB").is_err());
+        assert!(parse_size("10TB").is_err());
+    }
+
+    #[test]
+    fn command_max_body_size() {
+        let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+        assert_eq!(
+            matches.get_one::<usize>("max-body-size"),
+            Some(&(100 * 1024 * 1024))
+        );
+
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--max-body-size",
+            "50MB",
+        ]);
+        assert_eq!(
+            matches.get_one::<usize>("max-body-size"),
+            Some(&(50 * 1024 * 1024))
+        );
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn command_admin_token() {
+        let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+        assert!(admin_token(&matches).is_err());
+
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--admin-token",
+            "s3cret",
+        ]);
+        assert_eq!(admin_token(&matches).unwrap(), "s3cret");
+    }
+
+    #[cfg(feature = "s3")]
+    #[test]
+    fn command_snapshot_bucket() -> anyhow::Result<()> {
+        let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+        assert!(s3_config(&matches)?.is_none());
+
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--snapshot-bucket",
+            "snapshots",
+            "--s3-endpoint",
+            "http://localhost:9000",
+            "--s3-access-key-id",
+            "key-id",
+            "--s3-secret-access-key",
+            "secret",
+        ]);
+        let s3_config = s3_config(&matches)?.unwrap();
+        assert_eq!(s3_config.bucket, "snapshots");
+        assert_eq!(s3_config.endpoint, "http://localhost:9000");
+        assert_eq!(s3_config.region, "us-east-1");
+        assert_eq!(s3_config.access_key_id, "key-id");
+        assert_eq!(s3_config.secret_access_key, "secret");
+        Ok(())
+    }
+
+    #[test]
+    fn command_banner() {
+        let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+        assert_eq!(matches.get_one::<String>("banner"), None);
+
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--banner",
+            "Sync v{version}",
+        ]);
+        assert_eq!(
+            matches.get_one::<String>("banner").map(String::as_str),
+            Some("Sync v{version}")
+        );
+    }
+
+    #[test]
+    fn command_max_snapshot_size() {
+        let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+        assert_eq!(matches.get_one::<usize>("max-snapshot-size"), None);
+
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--max-snapshot-size",
+            "500MB",
+        ]);
+        assert_eq!(
+            matches.get_one::<usize>("max-snapshot-size"),
+            Some(&(500 * 1024 * 1024))
+        );
+    }
+
+    #[test]
+    fn command_snapshot_search_len() {
+        let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+        assert_eq!(matches.get_one::<u32>("snapshot-search-len"), Some(&5));
+
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--snapshot-search-len",
+            "20",
+        ]);
+        assert_eq!(matches.get_one::<u32>("snapshot-search-len"), Some(&20));
+
+        assert!(command()
+            .try_get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--snapshot-search-len",
+                "0",
+            ])
+            .is_err());
+    }
+
+    #[test]
+    fn command_debug_bodies() {
+        let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+        assert_eq!(matches.get_one::<usize>("debug-bodies"), None);
+
+        let matches =
+            command().get_matches_from(["tss", "--listen", "localhost:8080", "--debug-bodies"]);
+        assert_eq!(matches.get_one::<usize>("debug-bodies"), Some(&256));
+
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--debug-bodies",
+            "16",
+        ]);
+        assert_eq!(matches.get_one::<usize>("debug-bodies"), Some(&16));
+    }
+
+    #[test]
+    fn command_token() {
+        let matches =
+            command().get_matches_from(["tss", "--listen", "localhost:8080", "--token", "s3cret"]);
+        assert_eq!(
+            matches.get_one::<String>("token").map(String::as_str),
+            Some("s3cret")
+        );
+    }
+
+    #[test]
+    fn command_listen() {
+        for listen in [
+            "127.0.0.1:8080",
+            "0.0.0.0:1",
+            "localhost:0",
+            "[::1]:8080",
+            "[::]:65535",
+            "localhost:8080",
+            "sync.example-1.com:443",
+            "unix:/run/tss.sock",
+        ] {
+            assert_eq!(
+                parse_listen(listen),
+                Ok(listen.to_string()),
+                "with {listen}"
+            );
+        }
+        for listen in [
+            "localhost",
+            "localhost:",
+            "localhost:65536",
+            "localhost:http",
+            "::1:8080",
+            "[::1:8080",
+            "[not-ipv6]:8080",
+            "256.0.0.1:8080",
+            ":8080",
+            "bad_host:8080",
+            "a..b:8080",
+            "unix:",
+        ] {
+            assert!(parse_listen(listen).is_err(), "with {listen}");
+        }
+
+        // the error is reported when parsing arguments
+        let err = command()
+            .try_get_matches_from(["tss", "--listen", "::1:8080"])
+            .unwrap_err();
+        assert!(err.to_string().contains("must be in brackets"));
+    }
+
+    #[test]
+    fn command_listen_unix() {
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--listen",
+            "unix:/run/tss.sock",
+        ]);
+        let listen: Vec<&str> = matches
+            .get_many::<String>("listen")
+            .unwrap()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(listen, vec!["localhost:8080", "unix:/run/tss.sock"]);
+        assert_eq!(unix_socket_path(listen[0]), None);
+        assert_eq!(unix_socket_path(listen[1]), Some("/run/tss.sock"));
+    }
+
+    #[cfg(unix)]
+    #[actix_rt::test]
+    async fn test_unix_socket() -> anyhow::Result<()> {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+
+        let tmp_dir = tempfile::TempDir::new()?;
+        let listen_address = format!("unix:{}", tmp_dir.path().join("tss.sock").display());
+        let path = unix_socket_path(&listen_address).unwrap().to_owned();
+
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let http_server = HttpServer::new(move || App::new().configure(|cfg| server.config(cfg)))
+            .workers(1)
+            .bind_uds(&path)?
+            .run();
+        let handle = http_server.handle();
+        actix_rt::spawn(http_server);
+
+        let response = actix_rt::task::spawn_blocking(move || -> std::io::Result<String> {
+            let mut stream = UnixStream::connect(path)?;
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        })
+        .await??;
+        handle.stop(true).await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.contains("TaskChampion sync server"), "{response}");
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_proxy_protocol() -> anyhow::Result<()> {
+        use actix_web::{web, HttpRequest};
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let app = || {
+            App::new().route(
+                "/",
+                web::get().to(|req: HttpRequest| async move { format!("{:?}", req.peer_addr()) }),
+            )
+        };
+        let server = actix_server::Server::build()
+            .workers(1)
+            .listen("test", listener, move || {
+                proxy_protocol_service(app(), |io: &TcpStream| io.peer_addr().ok())
+            })?
+            .run();
+        let handle = server.handle();
+        actix_rt::spawn(server);
+
+        let request = |header: &'static [u8]| {
+            actix_rt::task::spawn_blocking(move || -> std::io::Result<String> {
+                let mut stream = std::net::TcpStream::connect(addr)?;
+                stream.write_all(header)?;
+                stream
+                    .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+                let mut response = String::new();
+                stream.read_to_string(&mut response)?;
+                Ok(response)
+            })
+        };
+        let response = request(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").await??;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("Some(192.0.2.1:56324)"), "{response}");
+
+        // without an address in the header, the connection's address is used
+        let response = request(b"PROXY UNKNOWN\r\n").await??;
+        assert!(response.contains("Some(127.0.0.1:"), "{response}");
+
+        // a connection without a header is closed, possibly resetting it before the request is
+        // read
+        if let Ok(response) = request(b"").await? {
+            assert_eq!(response, "");
+        }
+
+        handle.stop(true).await;
+        Ok(())
+    }
+
+    #[test]
+    fn command_vacuum() -> anyhow::Result<()> {
+        let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+        assert_eq!(matches.subcommand_name(), None);
+
+        // --listen is not required, and storage options may follow the subcommand
+        let tmp_dir = tempfile::TempDir::new()?;
+        let matches = command().get_matches_from([
+            "tss".into(),
+            "vacuum".into(),
+            "--data-dir".into(),
+            tmp_dir.path().as_os_str().to_owned(),
+        ]);
+        assert_eq!(matches.subcommand_name(), Some("vacuum"));
+        assert_eq!(
+            matches.get_one::<OsString>("data-dir").unwrap(),
+            tmp_dir.path().as_os_str()
+        );
+        vacuum(&matches)?;
+        assert!(tmp_dir
+            .path()
+            .join("taskchampion-sync-server.sqlite3")
+            .exists());
+        Ok(())
+    }
+
+    #[test]
+    fn command_proxy_protocol() {
+        let matches = command().get_matches_from(["tss", "--listen", "localhost:8080"]);
+        assert!(!matches.get_flag("proxy-protocol"));
+        let matches =
+            command().get_matches_from(["tss", "--listen", "localhost:8080", "--proxy-protocol"]);
+        assert!(matches.get_flag("proxy-protocol"));
+    }
+
+    #[test]
+    fn command_default_snapshot() {
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--default-snapshot",
+            "/foo/snapshot",
+            "--default-snapshot-version-id",
+            "711d5cf3-0cf0-4eb8-9eca-6f7f220638c0",
+        ]);
+        assert_eq!(
+            matches.get_one::<OsString>("default-snapshot").unwrap(),
+            "/foo/snapshot"
+        );
+
+        // each option requires the other
+        assert!(command()
+            .try_get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--default-snapshot",
+                "/foo/snapshot",
+            ])
+            .is_err());
+        assert!(command()
+            .try_get_matches_from([
+                "tss",
+                "--listen",
+                "localhost:8080",
+                "--default-snapshot-version-id",
+                "711d5cf3-0cf0-4eb8-9eca-6f7f220638c0",
+            ])
+            .is_err());
+    }
+
     #[test]
     fn command_data_dir() {
         let matches = command().get_matches_from([
@@ -157,11 +1552,53 @@ mod test {
             "localhost:8080",
         ]);
         assert_eq!(matches.get_one::<OsString>("data-dir").unwrap(), "/foo/bar");
+        assert!(!matches.get_flag("external-history-segments"));
+        assert!(!matches.get_flag("millisecond-timestamps"));
+    }
+
+    #[test]
+    fn command_external_history_segments() {
+        let matches = command().get_matches_from([
+            "tss",
+            "--listen",
+            "localhost:8080",
+            "--external-history-segments",
+        ]);
+        assert!(matches.get_flag("external-history-segments"));
+    }
+
+    #[test]
+    fn open_storage_schema_version() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let matches = command().get_matches_from([
+            "tss".into(),
+            "--listen".into(),
+            "localhost:8080".into(),
+            "--data-dir".into(),
+            tmp_dir.path().as_os_str().to_owned(),
+        ]);
+        open_storage(&matches)?;
+
+        // a database migrated by a newer server is rejected at startup
+        let con =
+            rusqlite::Connection::open(tmp_dir.path().join("taskchampion-sync-server.sqlite3"))?;
+        let version: u32 = con.pragma_query_value(None, "user_version", |r| r.get(0))?;
+        con.pragma_update(None, "user_version", version + 1)?;
+        drop(con);
+        let Err(err) = open_storage(&matches) else {
+            panic!("storage with a newer schema was opened");
+        };
+        assert!(err.to_string().contains("upgrade this server"));
+        Ok(())
     }
 
     #[actix_rt::test]
     async fn test_index_get() {
-        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
         let app = App::new().configure(|sc| server.config(sc));
         let app = actix_web::test::init_service(app).await;
 
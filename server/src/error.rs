@@ -0,0 +1,140 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+use taskchampion_sync_server_core::{ServerError, TxnConflict};
+
+/// An error response from the server, with a JSON body of the form
+/// `{"error": "<message>", "code": "<code>"}`.
+///
+/// The code is a stable, machine-readable identifier for the kind of error, such as
+/// `bad_client_id` or `no_such_client`, while the message is meant for people and may change.
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    headers: Vec<(&'static str, String)>,
+}
+
+/// The JSON body of an error response.
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    code: &'a str,
+}
+
+impl ApiError {
+    pub(crate) fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// Add a header to the error response.
+    pub(crate) fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    /// A 500 INTERNAL SERVER ERROR, for an unexpected failure.
+    pub(crate) fn internal(err: impl fmt::Display) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            err.to_string(),
+        )
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut rb = HttpResponse::build(self.status);
+        for (name, value) in &self.headers {
+            rb.insert_header((*name, value.as_str()));
+        }
+        rb.json(ErrorBody {
+            error: &self.message,
+            code: self.code,
+        })
+    }
+}
+
+impl From<ServerError> for ApiError {
+    fn from(err: ServerError) -> Self {
+        match err {
+            ServerError::NoSuchClient => {
+                Self::new(StatusCode::NOT_FOUND, "no_such_client", err.to_string())
+            }
+            ServerError::ClientExists => {
+                Self::new(StatusCode::CONFLICT, "client_exists", err.to_string())
+            }
+            ServerError::BranchedHistory => {
+                Self::new(StatusCode::CONFLICT, "conflict", err.to_string())
+            }
+            // a conflict which persisted through any retries is not a fault of the server
+            ServerError::Other(err) if err.downcast_ref::<TxnConflict>().is_some() => Self::new(
+                StatusCode::CONFLICT,
+                "txn_conflict",
+                "client was modified concurrently; retry the request",
+            ),
+            ServerError::Other(err) => Self::internal(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::body::MessageBody;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn error_response() {
+        let err = ApiError::new(StatusCode::TOO_MANY_REQUESTS, "rate_limited", "slow down")
+            .with_header("Retry-After", "3");
+        let resp = err.error_response();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "3");
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            "application/json"
+        );
+        let body: serde_json::Value =
+            serde_json::from_slice(&resp.into_body().try_into_bytes().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"error": "slow down", "code": "rate_limited"})
+        );
+    }
+
+    #[test]
+    fn from_server_error() {
+        let err = ApiError::from(ServerError::NoSuchClient);
+        assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(err.code, "no_such_client");
+        let err = ApiError::from(ServerError::Other(anyhow::anyhow!("oops")));
+        assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(err.code, "internal_error");
+        assert_eq!(err.to_string(), "oops");
+        let err = ApiError::from(ServerError::Other(
+            anyhow::Error::new(TxnConflict(uuid::Uuid::new_v4()))
+                .context("Error committing transaction"),
+        ));
+        assert_eq!(err.status_code(), StatusCode::CONFLICT);
+        assert_eq!(err.code, "txn_conflict");
+    }
+}
@@ -1,16 +1,236 @@
 #![deny(clippy::all)]
 
+#[cfg(feature = "admin")]
+mod admin;
 mod api;
+mod error;
+mod metrics;
+pub mod proxy_protocol;
+mod rate_limit;
 
-use actix_web::{get, middleware, web, Responder};
-use api::{api_scope, ServerState};
-use std::{collections::HashSet, sync::Arc};
-use taskchampion_sync_server_core::{Server, ServerConfig, Storage};
+use actix_web::{middleware, route, web, HttpRequest, HttpResponse};
+use api::{api_scope, omit_body_for_head, ServerState};
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use taskchampion_sync_server_core::{Server, ServerConfig, Storage, VersionId};
 use uuid::Uuid;
 
-#[get("/")]
-async fn index() -> impl Responder {
-    format!("TaskChampion sync server v{}", env!("CARGO_PKG_VERSION"))
+pub use api::current_request_id;
+pub use rate_limit::{DownloadQuota, RateLimit};
+
+/// The text served at `/` when [`WebConfig::server_banner`] is not set.
+const DEFAULT_BANNER: &str = "TaskChampion sync server";
+
+#[route("/", method = "GET", method = "HEAD")]
+async fn index(req: HttpRequest, server_state: web::Data<Arc<ServerState>>) -> HttpResponse {
+    let banner = match &server_state.web_config.server_banner {
+        Some(banner) => banner.replace("{version}", env!("CARGO_PKG_VERSION")),
+        None => DEFAULT_BANNER.to_string(),
+    };
+    let resp = HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(banner);
+    omit_body_for_head(&req, resp)
+}
+
+/// The default maximum size of a request body: 100MiB.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 100 * 1024 * 1024;
+
+/// WebConfig contains configuration parameters for the web server, on top of those in
+/// [`ServerConfig`].
+pub struct WebConfig {
+    /// Client IDs to allow. If `None`, all client IDs are allowed.
+    pub client_id_allowlist: Option<HashSet<Uuid>>,
+
+    /// `User-Agent` patterns to reject in sync requests. A pattern containing `*` is a glob
+    /// matching the entire `User-Agent`; any other pattern matches as a substring.
+    pub user_agent_denylist: Vec<String>,
+
+    /// `User-Agent` patterns to allow in sync requests, in the same format as
+    /// `user_agent_denylist`. If set, requests not matching any of these patterns, including
+    /// those without a `User-Agent`, are rejected.
+    pub user_agent_allowlist: Option<Vec<String>>,
+
+    /// Allow zero-length history segments in `add-version` requests.
+    pub allow_empty_version: bool,
+
+    /// Which clients to create automatically when they first add a version.
+    pub create_clients: CreateClients,
+
+    /// A snapshot, given as its version ID and data, with which to create clients automatically.
+    /// The client's latest version is the snapshot's version, so the `add-version` request
+    /// which creates the client receives a `409 Conflict`, after which the replica can fetch the
+    /// snapshot.
+    ///
+    /// The server does not interpret the snapshot, so it must be one which new replicas can read.
+    /// Note that TaskChampion replicas encrypt snapshots with a key derived from the client ID.
+    pub default_snapshot: Option<(VersionId, Vec<u8>)>,
+
+    /// Respond to writes that have no response body with `204 No Content` rather than `200 OK`.
+    pub strict_http: bool,
+
+    /// If set, log a hex preview of request and response bodies at DEBUG level, limited to this
+    /// many bytes. Bodies may contain sensitive data, so this should only be used for debugging.
+    pub debug_bodies: Option<usize>,
+
+    /// Serialize writes for each client within this process, so that concurrent writes for the
+    /// same client wait for one another rather than conflicting in the storage backend.
+    pub serialize_writes: bool,
+
+    /// Maximum size, in bytes, of the body of `add-version` and `add-snapshot` requests. Larger
+    /// requests receive a `400 Bad Request` response.
+    pub max_body_size: usize,
+
+    /// Maximum size, in bytes, of a history segment in `add-version` requests. This may be much
+    /// smaller than the maximum snapshot size, encouraging clients to snapshot rather than add
+    /// very large versions. If `None`, only `max_body_size` applies.
+    pub max_version_size: Option<usize>,
+
+    /// Maximum size, in bytes, of a snapshot in `add-snapshot` requests, replacing
+    /// `max_body_size` for those requests. This may be larger or smaller than `max_body_size`,
+    /// since a snapshot contains the client's entire task database. Larger snapshots receive a
+    /// `413 Payload Too Large` response. If `None`, only `max_body_size` applies.
+    pub max_snapshot_size: Option<usize>,
+
+    /// Limit on the rate of all sync requests from each client, applied before the request is
+    /// handled and in addition to the limits on reads and writes. Requests with a missing or
+    /// invalid client ID, or a client ID not in the allowlist, share a single limit.
+    pub rate_limit: Option<RateLimit>,
+
+    /// Limit on the rate of read requests from each client. Requests beyond this limit receive a
+    /// `429 Too Many Requests` response.
+    pub rate_limit_reads: Option<RateLimit>,
+
+    /// Limit on the rate of write requests (adding versions and snapshots) from each client.
+    /// This is independent of the limit on read requests.
+    pub rate_limit_writes: Option<RateLimit>,
+
+    /// Limit on the number of bytes of versions and snapshots downloaded by each client. Downloads
+    /// beyond this limit receive a `429 Too Many Requests` response until the client's window
+    /// ends.
+    pub download_quota: Option<DownloadQuota>,
+
+    /// Serve an HTML status page at `/dashboard`. This requires the `admin` feature.
+    pub dashboard: bool,
+
+    /// Require every request, other than to `/`, `/health`, and `/v1/capabilities`, to carry this
+    /// token in an `Authorization: Bearer <token>` header. Requests without it receive a `401
+    /// Unauthorized` response.
+    pub require_token: Option<String>,
+
+    /// Require requests to the admin endpoints, under `/v1/admin/`, to carry this token in an
+    /// `Authorization: Bearer <token>` header, in place of `require_token`. This keeps the admin
+    /// credential distinct from those given to clients. If this is `None`, every request to the
+    /// admin endpoints is rejected. The admin endpoints require the `admin` feature.
+    pub admin_token: Option<String>,
+
+    /// Authenticate clients which have their own token, as provided by the storage backend (see
+    /// `StorageTxn::get_client_token_hash`). Requests for such a client must carry its token in an
+    /// `Authorization: Bearer <token>` header, and the client ID allowlist does not apply to it.
+    /// Clients without a token are subject to the allowlist as usual.
+    ///
+    /// This requires an additional storage transaction for each request. Since a request carries
+    /// only one bearer token, it should not be combined with `require_token`.
+    pub client_tokens: bool,
+
+    /// Add an `X-Unknown-Client: true` header to the `404 Not Found` response to
+    /// `get-child-version` for a client the server does not know, distinguishing it from a
+    /// response for a version that does not exist. This is useful when clients are not created
+    /// automatically, so that replicas can tell that adding a version would also fail.
+    pub unknown_client_header: bool,
+
+    /// Serve `DELETE /v1/client`, allowing a client to delete itself and all of its data. This is
+    /// disabled by default, so that data cannot be removed unless the operator intends it.
+    pub allow_client_deletion: bool,
+
+    /// Serve `GET /v1/client/get-version/{version_id}`, returning a version by its own ID. This
+    /// is an extension beyond the sync protocol, so it is disabled by default.
+    pub enable_get_version: bool,
+
+    /// Allow browser-based clients served from these origins, such as `https://example.com`, to
+    /// call the server, by answering CORS preflight requests and adding CORS headers to
+    /// responses. An origin of `*` allows any origin. If this is `None`, no CORS headers are
+    /// added.
+    pub cors_allowed_origins: Option<Vec<String>>,
+
+    /// Reject every request which would modify storage, such as adding a version or snapshot,
+    /// with a 503 SERVICE UNAVAILABLE, while continuing to serve reads. This is useful during
+    /// maintenance, such as a backup or migration.
+    pub read_only: bool,
+
+    /// Compress responses containing history segments or snapshots, from `get-child-version` and
+    /// `get-snapshot`, with an encoding given in the request's `Accept-Encoding` header. This data
+    /// is encrypted, so it may not compress well, and compression is disabled by default.
+    pub response_compression: bool,
+
+    /// The text served at `/`, in which `{version}` is replaced by the server's version. For
+    /// example, `TaskChampion sync server v{version}` identifies the exact version, as earlier
+    /// releases did, and an empty banner serves an empty body. If this is `None`, the text
+    /// identifies the server without its version.
+    pub server_banner: Option<String>,
+
+    /// Expect each connection to begin with a PROXY protocol header, version 1 or 2, as sent by a
+    /// load balancer such as HAProxy, and use the client address it contains as the peer address
+    /// of requests on that connection. Connections without a valid header are closed. This must
+    /// only be enabled when every connection comes through such a load balancer, as otherwise
+    /// clients could claim any address. See [`proxy_protocol`].
+    pub proxy_protocol: bool,
+
+    /// Recalculate the snapshot statistics served at `/metrics`, such as the age of the oldest
+    /// snapshot, at this interval. Calculating these reads every client, so they are not
+    /// calculated for each scrape. If this is `None`, they are not served. See
+    /// [`WebServer::spawn_stats_refresh`].
+    pub stats_refresh_interval: Option<Duration>,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        WebConfig {
+            client_id_allowlist: None,
+            user_agent_denylist: Vec::new(),
+            user_agent_allowlist: None,
+            allow_empty_version: false,
+            create_clients: CreateClients::default(),
+            default_snapshot: None,
+            strict_http: false,
+            debug_bodies: None,
+            serialize_writes: false,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_version_size: None,
+            max_snapshot_size: None,
+            rate_limit: None,
+            rate_limit_reads: None,
+            rate_limit_writes: None,
+            download_quota: None,
+            dashboard: false,
+            require_token: None,
+            admin_token: None,
+            client_tokens: false,
+            unknown_client_header: false,
+            allow_client_deletion: false,
+            enable_get_version: false,
+            cors_allowed_origins: None,
+            read_only: false,
+            response_compression: false,
+            server_banner: None,
+            proxy_protocol: false,
+            stats_refresh_interval: None,
+        }
+    }
+}
+
+/// The clients for which the server creates a client record on first use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CreateClients {
+    /// Create any client that is not already known.
+    #[default]
+    Always,
+
+    /// Never create clients; they must be created by some other means.
+    Never,
+
+    /// Only create clients whose IDs appear in [`WebConfig::client_id_allowlist`]. If there is no
+    /// allowlist, no clients are created, even though all clients are allowed.
+    AllowlistOnly,
 }
 
 /// A Server represents a sync server.
@@ -23,41 +243,86 @@ impl WebServer {
     /// Create a new sync server with the given storage implementation.
     pub fn new<ST: Storage + 'static>(
         config: ServerConfig,
-        client_id_allowlist: Option<HashSet<Uuid>>,
+        web_config: WebConfig,
         storage: ST,
     ) -> Self {
         Self {
-            server_state: Arc::new(ServerState {
-                server: Server::new(config, storage),
-                client_id_allowlist,
-            }),
+            server_state: Arc::new(ServerState::new(Server::new(config, storage), web_config)),
         }
     }
 
     /// Get an Actix-web service for this server.
     pub fn config(&self, cfg: &mut web::ServiceConfig) {
-        cfg.service(
-            web::scope("")
-                .app_data(web::Data::new(self.server_state.clone()))
-                .wrap(
-                    middleware::DefaultHeaders::new().add(("Cache-Control", "no-store, max-age=0")),
-                )
-                .service(index)
-                .service(api_scope()),
+        let cors_allowed_origins = &self.server_state.web_config.cors_allowed_origins;
+        let scope = web::scope("")
+            .app_data(web::Data::new(self.server_state.clone()))
+            .app_data(web::PathConfig::default().error_handler(api::path_error))
+            .wrap(middleware::DefaultHeaders::new().add(("Cache-Control", "no-store, max-age=0")))
+            // outside authentication, so that preflight requests are answered without a token;
+            // request-ID assignment wraps this, so preflight responses also carry an ID
+            .wrap(middleware::Condition::new(
+                cors_allowed_origins.is_some(),
+                api::cors(cors_allowed_origins.as_deref().unwrap_or_default()),
+            ))
+            .wrap(middleware::from_fn(api::assign_request_id))
+            .service(index)
+            .service(api::health::service)
+            .service(api::capabilities::service);
+        let authenticated = web::scope("").wrap(middleware::from_fn(api::check_token));
+        #[cfg(feature = "admin")]
+        let authenticated = authenticated.configure(admin::configure);
+        #[cfg(feature = "metrics")]
+        let authenticated = authenticated.service(metrics::service);
+        let authenticated = authenticated.service(
+            api_scope(&self.server_state.web_config)
+                .wrap(middleware::from_fn(api::check_deadline))
+                .wrap(middleware::from_fn(api::check_overall_rate_limit))
+                .wrap(middleware::from_fn(api::check_user_agent)),
         );
+        cfg.service(scope.service(authenticated));
+    }
+
+    /// Start refreshing the snapshot statistics every [`WebConfig::stats_refresh_interval`], if
+    /// that is set, in a task on the current Actix runtime. Call this once, before running the
+    /// server.
+    pub fn spawn_stats_refresh(&self) {
+        let Some(interval) = self.server_state.web_config.stats_refresh_interval else {
+            return;
+        };
+        let server_state = self.server_state.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let server_state = server_state.clone();
+                // the statistics are calculated from storage, which may block
+                let result =
+                    actix_web::rt::task::spawn_blocking(move || server_state.refresh_stats()).await;
+                match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => log::error!("refreshing snapshot statistics: {err}"),
+                    Err(err) => log::error!("refreshing snapshot statistics: {err}"),
+                }
+            }
+        });
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use actix_web::http::{Method, StatusCode};
     use actix_web::{test, App};
     use pretty_assertions::assert_eq;
-    use taskchampion_sync_server_core::InMemoryStorage;
+    use taskchampion_sync_server_core::{InMemoryStorage, Snapshot, NIL_VERSION_ID};
 
     #[actix_rt::test]
     async fn test_cache_control() {
-        let server = WebServer::new(Default::default(), None, InMemoryStorage::new());
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
         let app = App::new().configure(|sc| server.config(sc));
         let app = test::init_service(app).await;
 
@@ -69,4 +334,578 @@ mod test {
             &"no-store, max-age=0".to_string()
         )
     }
+
+    #[actix_rt::test]
+    async fn test_request_id() {
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        // a request without an ID is given one
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let request_id = resp
+            .headers()
+            .get("X-Request-Id")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(uuid::Uuid::parse_str(request_id).is_ok());
+
+        // a request's own ID is kept, including on an error response
+        let req = test::TestRequest::get()
+            .uri("/v1/client/snapshot")
+            .append_header(("X-Client-Id", uuid::Uuid::new_v4().to_string()))
+            .append_header(("X-Request-Id", "proxy-1234"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers().get("X-Request-Id").unwrap(), "proxy-1234");
+
+        // an invalid ID is replaced
+        let req = test::TestRequest::get()
+            .uri("/")
+            .append_header(("X-Request-Id", "x".repeat(200)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let request_id = resp
+            .headers()
+            .get("X-Request-Id")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(uuid::Uuid::parse_str(request_id).is_ok());
+    }
+
+    /// A logger recording the lines of the sync server core, each with the ID of the request
+    /// during which it was logged.
+    struct CoreLogger(std::sync::Mutex<Vec<(Option<String>, String)>>);
+
+    impl log::Log for CoreLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata
+                .target()
+                .starts_with("taskchampion_sync_server_core")
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                let line = (current_request_id(), record.args().to_string());
+                self.0.lock().unwrap().push(line);
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[actix_rt::test]
+    async fn test_request_id_core_log() {
+        static LOGGER: CoreLogger = CoreLogger(std::sync::Mutex::new(Vec::new()));
+        // The logger is process-global, so tolerate one installed by another test. Lines are
+        // matched by a fresh client ID below, so lines from other tests do not interfere.
+        if log::set_logger(&LOGGER).is_err() && !std::ptr::addr_eq(log::logger(), &LOGGER) {
+            eprintln!("another logger is installed; not checking the core log");
+            return;
+        }
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let client_id = Uuid::new_v4();
+        let req = test::TestRequest::post()
+            .uri(&format!("/v1/client/add-version/{}", Uuid::nil()))
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.history-segment",
+            ))
+            .append_header(("X-Client-Id", client_id.to_string()))
+            .append_header(("X-Request-Id", "proxy-1234"))
+            .set_payload(b"abcd".to_vec())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let lines = LOGGER.0.lock().unwrap();
+        let (request_id, _) = lines
+            .iter()
+            .find(|(_, message)| message.contains(&format!("client_id: {client_id}")))
+            .expect("the core logs the request");
+        assert_eq!(request_id.as_deref(), Some("proxy-1234"));
+        // outside a request, there is no ID
+        assert_eq!(current_request_id(), None);
+    }
+
+    /// Get the body of the response to `/` with the given banner configured.
+    async fn index_body(server_banner: Option<&str>) -> String {
+        let web_config = WebConfig {
+            server_banner: server_banner.map(String::from),
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        String::from_utf8(test::read_body(resp).await.to_vec()).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_index_banner() {
+        // by default, the version is not revealed
+        assert_eq!(index_body(None).await, "TaskChampion sync server");
+        assert_eq!(index_body(Some("Tasks")).await, "Tasks");
+        assert_eq!(
+            index_body(Some("TaskChampion sync server v{version}")).await,
+            format!("TaskChampion sync server v{}", env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(index_body(Some("")).await, "");
+    }
+
+    #[actix_rt::test]
+    async fn test_index_head() {
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!test::read_body(resp).await.is_empty());
+
+        let req = test::TestRequest::default()
+            .method(Method::HEAD)
+            .uri("/")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(test::read_body(resp).await.is_empty());
+    }
+
+    /// Make a sync request from the given origin, returning the response.
+    async fn cors_request(
+        cors_allowed_origins: Option<Vec<String>>,
+        method: Method,
+        origin: &str,
+    ) -> actix_web::dev::ServiceResponse {
+        let web_config = WebConfig {
+            cors_allowed_origins,
+            require_token: Some("secret".into()),
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::default()
+            .method(method)
+            .uri(&format!("/v1/client/get-child-version/{NIL_VERSION_ID}"))
+            .append_header(("Origin", origin))
+            .append_header(("Access-Control-Request-Method", "GET"))
+            .append_header(("Access-Control-Request-Headers", "x-client-id"))
+            .append_header(("Authorization", "Bearer secret"))
+            .append_header(("X-Client-Id", Uuid::new_v4().to_string()))
+            .to_request();
+        test::call_service(&app, req).await
+    }
+
+    #[actix_rt::test]
+    async fn test_cors_allowed_origin() {
+        let origins = Some(vec!["https://tasks.example.com".to_string()]);
+
+        // the preflight request is answered, without authentication
+        let resp = cors_request(
+            origins.clone(),
+            Method::OPTIONS,
+            "https://tasks.example.com",
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Access-Control-Allow-Origin").unwrap(),
+            "https://tasks.example.com"
+        );
+
+        let resp = cors_request(origins, Method::GET, "https://tasks.example.com").await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            resp.headers().get("Access-Control-Allow-Origin").unwrap(),
+            "https://tasks.example.com"
+        );
+        let exposed = resp.headers().get("Access-Control-Expose-Headers").unwrap();
+        assert!(exposed.to_str().unwrap().contains("x-version-id"));
+    }
+
+    #[actix_rt::test]
+    async fn test_cors_any_origin() {
+        let origins = Some(vec!["*".to_string()]);
+        let resp = cors_request(origins, Method::GET, "https://elsewhere.example.com").await;
+        assert!(resp.headers().get("Access-Control-Allow-Origin").is_some());
+    }
+
+    #[actix_rt::test]
+    async fn test_cors_disallowed_origin() {
+        let origins = Some(vec!["https://tasks.example.com".to_string()]);
+        let resp = cors_request(origins, Method::GET, "https://evil.example.com").await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers().get("Access-Control-Allow-Origin"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_cors_disabled() {
+        let resp = cors_request(None, Method::GET, "https://tasks.example.com").await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers().get("Access-Control-Allow-Origin"), None);
+    }
+
+    /// Make a sync request with the given `User-Agent`, returning the response status.
+    async fn user_agent_status(web_config: WebConfig, user_agent: Option<&str>) -> StatusCode {
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let mut req = test::TestRequest::get()
+            .uri(&format!("/v1/client/get-child-version/{NIL_VERSION_ID}"))
+            .append_header(("X-Client-Id", Uuid::new_v4().to_string()));
+        if let Some(user_agent) = user_agent {
+            req = req.append_header(("User-Agent", user_agent));
+        }
+        test::call_service(&app, req.to_request()).await.status()
+    }
+
+    #[actix_rt::test]
+    async fn test_user_agent_denylist() {
+        let web_config = || WebConfig {
+            user_agent_denylist: vec!["taskchampion/0.1.".into()],
+            ..WebConfig::default()
+        };
+        assert_eq!(
+            user_agent_status(web_config(), Some("taskchampion/0.1.3")).await,
+            StatusCode::FORBIDDEN
+        );
+        // the client does not exist, so this is a 404
+        assert_eq!(
+            user_agent_status(web_config(), Some("taskchampion/0.2.0")).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            user_agent_status(web_config(), None).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_user_agent_allowlist() {
+        let web_config = || WebConfig {
+            user_agent_allowlist: Some(vec!["taskchampion/2.*".into()]),
+            ..WebConfig::default()
+        };
+        assert_eq!(
+            user_agent_status(web_config(), Some("taskchampion/2.0.1")).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            user_agent_status(web_config(), Some("taskchampion/1.9.0")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            user_agent_status(web_config(), None).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    /// Make a request to the given URI with the given `Authorization` header, returning the
+    /// response status.
+    async fn token_status(uri: &str, authorization: Option<&str>) -> StatusCode {
+        let web_config = WebConfig {
+            require_token: Some("s3cret".into()),
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let mut req = test::TestRequest::get()
+            .uri(uri)
+            .append_header(("X-Client-Id", Uuid::new_v4().to_string()));
+        if let Some(authorization) = authorization {
+            req = req.append_header(("Authorization", authorization));
+        }
+        test::call_service(&app, req.to_request()).await.status()
+    }
+
+    #[actix_rt::test]
+    async fn test_token() {
+        let uri = format!("/v1/client/get-child-version/{NIL_VERSION_ID}");
+        assert_eq!(token_status(&uri, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            token_status(&uri, Some("Bearer wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            token_status(&uri, Some("s3cret")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        // the client does not exist, so this is a 404
+        assert_eq!(
+            token_status(&uri, Some("Bearer s3cret")).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_token_unauthenticated_endpoints() {
+        assert_eq!(token_status("/", None).await, StatusCode::OK);
+        assert_eq!(token_status("/health", None).await, StatusCode::OK);
+    }
+
+    #[cfg(feature = "admin")]
+    #[actix_rt::test]
+    async fn test_token_admin() {
+        let uri = format!("/v1/admin/client/{}/snapshots", Uuid::new_v4());
+        assert_eq!(token_status(&uri, None).await, StatusCode::UNAUTHORIZED);
+        // without an admin token, the sync token does not give access to the admin endpoints
+        assert_eq!(
+            token_status(&uri, Some("Bearer s3cret")).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[cfg(feature = "admin")]
+    #[actix_rt::test]
+    async fn test_admin_token_not_configured() {
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        // with no tokens configured, the sync endpoints are open but the admin endpoints are not
+        for authorization in [None, Some("Bearer anything")] {
+            let mut req = test::TestRequest::get().uri("/v1/admin/clients");
+            if let Some(authorization) = authorization {
+                req = req.append_header(("Authorization", authorization));
+            }
+            assert_eq!(
+                test::call_service(&app, req.to_request()).await.status(),
+                StatusCode::UNAUTHORIZED,
+                "with {authorization:?}"
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_token_not_required() {
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/client/get-child-version/{NIL_VERSION_ID}"))
+            .append_header(("X-Client-Id", Uuid::new_v4().to_string()))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    /// Add a version for a new client with the given `X-Request-Deadline` header, returning the
+    /// response status and whether the client exists afterward.
+    async fn deadline_add_version(deadline: &str) -> (StatusCode, bool) {
+        let storage = InMemoryStorage::new();
+        let server = WebServer::new(Default::default(), Default::default(), storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        let client_id = Uuid::new_v4();
+        let req = test::TestRequest::post()
+            .uri(&format!("/v1/client/add-version/{NIL_VERSION_ID}"))
+            .append_header((
+                "Content-Type",
+                "application/vnd.taskchampion.history-segment",
+            ))
+            .append_header(("X-Client-Id", client_id.to_string()))
+            .append_header(("X-Request-Deadline", deadline))
+            .set_payload(b"abcd".to_vec())
+            .to_request();
+        let status = test::call_service(&app, req).await.status();
+
+        let mut txn = server.server_state.server.txn(client_id).unwrap();
+        let exists = txn.get_client().unwrap().is_some();
+        (status, exists)
+    }
+
+    #[actix_rt::test]
+    async fn test_deadline_passed() {
+        let (status, exists) = deadline_add_version("2001-09-09T01:46:40Z").await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        // the request was not handled, so the client was not created
+        assert!(!exists);
+
+        let (status, exists) = deadline_add_version("0").await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert!(!exists);
+    }
+
+    #[actix_rt::test]
+    async fn test_deadline_generous() {
+        let deadline = (chrono::Utc::now() + chrono::Duration::minutes(5)).to_rfc3339();
+        let (status, exists) = deadline_add_version(&deadline).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(exists);
+
+        let (status, exists) = deadline_add_version("60000").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(exists);
+    }
+
+    #[actix_rt::test]
+    async fn test_deadline_invalid() {
+        let (status, exists) = deadline_add_version("whenever").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(!exists);
+    }
+
+    #[actix_rt::test]
+    async fn test_read_only() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(version_id).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, b"abcd".to_vec())
+                .unwrap();
+            txn.set_snapshot(
+                Snapshot {
+                    version_id,
+                    timestamp: chrono::Utc::now(),
+                    versions_since: 0,
+                },
+                b"snap".to_vec(),
+                None,
+            )
+            .unwrap();
+            txn.commit().unwrap();
+        }
+        let web_config = WebConfig {
+            read_only: true,
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+
+        // reads are served
+        for uri in [
+            format!("/v1/client/get-child-version/{NIL_VERSION_ID}"),
+            "/v1/client/snapshot".to_string(),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&uri)
+                .append_header(("X-Client-Id", client_id.to_string()))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK, "for {uri}");
+        }
+
+        // writes are rejected
+        for (uri, content_type) in [
+            (
+                format!("/v1/client/add-version/{version_id}"),
+                "application/vnd.taskchampion.history-segment",
+            ),
+            (
+                format!("/v1/client/add-snapshot/{version_id}"),
+                "application/vnd.taskchampion.snapshot",
+            ),
+        ] {
+            let req = test::TestRequest::post()
+                .uri(&uri)
+                .append_header(("Content-Type", content_type))
+                .append_header(("X-Client-Id", client_id.to_string()))
+                .set_payload(b"efgh".to_vec())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE, "for {uri}");
+            assert_eq!(resp.headers().get("Retry-After").unwrap(), "60");
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(body["code"], "read_only");
+        }
+
+        // and storage is unchanged
+        let mut txn = server.server_state.server.txn(client_id).unwrap();
+        let client = txn.get_client().unwrap().unwrap();
+        assert_eq!(client.latest_version_id, version_id);
+        assert_eq!(client.snapshot.unwrap().versions_since, 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_stats_refresh() {
+        let storage = InMemoryStorage::new();
+        let snapshot_timestamp = chrono::Utc::now() - chrono::Duration::hours(1);
+        for versions_since in [2, 5] {
+            let version_id = Uuid::new_v4();
+            let mut txn = storage.txn(Uuid::new_v4()).unwrap();
+            txn.new_client(NIL_VERSION_ID).unwrap();
+            txn.add_version(version_id, NIL_VERSION_ID, b"abcd".to_vec())
+                .unwrap();
+            let snapshot = Snapshot {
+                version_id,
+                timestamp: snapshot_timestamp,
+                versions_since,
+            };
+            txn.set_snapshot(snapshot, b"snap".to_vec(), None).unwrap();
+            txn.commit().unwrap();
+        }
+        let web_config = WebConfig {
+            stats_refresh_interval: Some(Duration::from_secs(3600)),
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, storage);
+
+        // the first refresh happens immediately
+        server.spawn_stats_refresh();
+        let mut rendered = String::new();
+        for _ in 0..100 {
+            rendered = server.server_state.metrics.render();
+            if rendered.contains("taskchampion_max_versions_since_snapshot") {
+                break;
+            }
+            actix_rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        let line = |name: &str| {
+            rendered
+                .lines()
+                .find_map(|line| line.strip_prefix(&format!("{name} ")))
+                .map(|value| value.parse::<u64>().unwrap())
+        };
+        assert_eq!(line("taskchampion_max_versions_since_snapshot"), Some(5));
+        let age = line("taskchampion_oldest_snapshot_age_seconds").unwrap();
+        // allow for time passing during the test
+        assert!((3600..3660).contains(&age), "age {age}");
+    }
 }
@@ -0,0 +1,445 @@
+use chrono::{DateTime, Utc};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use taskchampion_sync_server_core::{
+    AddVersionResult, GetVersionResult, SnapshotStats, SnapshotUrgency,
+};
+
+#[cfg(feature = "metrics")]
+use crate::api::ServerState;
+#[cfg(feature = "metrics")]
+use actix_web::{get, web, HttpResponse};
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+
+/// Label values for the `add_version` counter.
+const ADD_VERSION_RESULTS: [&str; 3] = ["ok", "conflict", "quota_exceeded"];
+
+/// Label values for the `add_snapshot` counter.
+const ADD_SNAPSHOT_RESULTS: [&str; 2] = ["accepted", "rejected"];
+
+/// Label values for the `get_child_version` counter.
+const GET_CHILD_VERSION_RESULTS: [&str; 3] = ["success", "not_found", "gone"];
+
+/// Label values for the `snapshot_urgency` counter.
+const SNAPSHOT_URGENCIES: [&str; 3] = ["none", "low", "high"];
+
+/// Upper bounds, in bytes, of the buckets of request body size histograms. Bodies larger than the
+/// last bound are counted only in the implicit `+Inf` bucket.
+const BODY_SIZE_BUCKETS: [u64; 10] = [
+    256,
+    1 << 10,
+    4 << 10,
+    16 << 10,
+    64 << 10,
+    256 << 10,
+    1 << 20,
+    4 << 20,
+    16 << 20,
+    64 << 20,
+];
+
+/// A histogram of sizes, with buckets given by [`BODY_SIZE_BUCKETS`].
+#[derive(Default)]
+struct Histogram {
+    /// The number of observations in each bucket, with the last element counting observations
+    /// larger than all bounds. Unlike Prometheus buckets, these are not cumulative.
+    buckets: [AtomicU64; BODY_SIZE_BUCKETS.len() + 1],
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, size: u64) {
+        let bucket = BODY_SIZE_BUCKETS
+            .iter()
+            .position(|bound| size <= *bound)
+            .unwrap_or(BODY_SIZE_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+/// Gauges calculated from [`SnapshotStats`].
+struct SnapshotGauges {
+    /// Age of the oldest snapshot, in seconds, if there is a snapshot
+    oldest_snapshot_age: Option<u64>,
+    max_versions_since_snapshot: u32,
+}
+
+/// Counters of protocol operations, for export to Prometheus.
+///
+/// These are always collected, as they are cheap to update, but are only served, at `/metrics`,
+/// with the `metrics` feature. The snapshot gauges are only present once they have been
+/// refreshed, as they are expensive to calculate.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    add_version: [AtomicU64; ADD_VERSION_RESULTS.len()],
+    add_snapshot: [AtomicU64; ADD_SNAPSHOT_RESULTS.len()],
+    get_child_version: [AtomicU64; GET_CHILD_VERSION_RESULTS.len()],
+    snapshot_urgency: [AtomicU64; SNAPSHOT_URGENCIES.len()],
+    add_version_body: Histogram,
+    add_snapshot_body: Histogram,
+    snapshot_gauges: Mutex<Option<SnapshotGauges>>,
+}
+
+impl Metrics {
+    /// Count the result of an `add_version` operation, along with the urgency of the snapshot
+    /// request in a successful result.
+    pub(crate) fn add_version(&self, result: &AddVersionResult, urgency: SnapshotUrgency) {
+        let result = match result {
+            AddVersionResult::Ok(_) => 0,
+            AddVersionResult::ExpectedParentVersion(_) => 1,
+            AddVersionResult::QuotaExceeded => 2,
+        };
+        self.add_version[result].fetch_add(1, Ordering::Relaxed);
+        if result == 0 {
+            let urgency = match urgency {
+                SnapshotUrgency::None => 0,
+                SnapshotUrgency::Low => 1,
+                SnapshotUrgency::High => 2,
+            };
+            self.snapshot_urgency[urgency].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count the result of an `add_snapshot` operation.
+    pub(crate) fn add_snapshot(&self, accepted: bool) {
+        let result = if accepted { 0 } else { 1 };
+        self.add_snapshot[result].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the result of a `get_child_version` operation.
+    pub(crate) fn get_child_version(&self, result: &GetVersionResult) {
+        let result = match result {
+            GetVersionResult::Success { .. } => 0,
+            GetVersionResult::NotFound => 1,
+            GetVersionResult::Gone => 2,
+        };
+        self.get_child_version[result].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the size of the body of an `add_version` request.
+    pub(crate) fn add_version_body(&self, size: usize) {
+        self.add_version_body.observe(size as u64);
+    }
+
+    /// Record the size of the body of an `add_snapshot` request.
+    pub(crate) fn add_snapshot_body(&self, size: usize) {
+        self.add_snapshot_body.observe(size as u64);
+    }
+
+    /// Update the snapshot gauges from freshly-calculated statistics, with ages as of `now`.
+    pub(crate) fn snapshot_stats(&self, stats: &SnapshotStats, now: DateTime<Utc>) {
+        let gauges = SnapshotGauges {
+            oldest_snapshot_age: stats
+                .oldest_snapshot_timestamp
+                .map(|timestamp| (now - timestamp).num_seconds().max(0) as u64),
+            max_versions_since_snapshot: stats.max_versions_since_snapshot,
+        };
+        *self.snapshot_gauges.lock().expect("poisoned lock") = Some(gauges);
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    #[cfg_attr(not(any(test, feature = "metrics")), allow(dead_code))]
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        render_counter(
+            &mut out,
+            "taskchampion_add_version_total",
+            "Number of versions added, or rejected due to a conflict or the client's quota.",
+            "result",
+            &ADD_VERSION_RESULTS,
+            &self.add_version,
+        );
+        render_counter(
+            &mut out,
+            "taskchampion_add_snapshot_total",
+            "Number of snapshots accepted or rejected.",
+            "result",
+            &ADD_SNAPSHOT_RESULTS,
+            &self.add_snapshot,
+        );
+        render_counter(
+            &mut out,
+            "taskchampion_get_child_version_total",
+            "Number of get-child-version requests, by result.",
+            "result",
+            &GET_CHILD_VERSION_RESULTS,
+            &self.get_child_version,
+        );
+        render_counter(
+            &mut out,
+            "taskchampion_snapshot_urgency_total",
+            "Number of added versions, by the urgency of the snapshot request in the response.",
+            "urgency",
+            &SNAPSHOT_URGENCIES,
+            &self.snapshot_urgency,
+        );
+
+        let name = "taskchampion_request_body_bytes";
+        writeln!(out, "# HELP {name} Size of request bodies, in bytes.").unwrap();
+        writeln!(out, "# TYPE {name} histogram").unwrap();
+        for (endpoint, histogram) in [
+            ("add_version", &self.add_version_body),
+            ("add_snapshot", &self.add_snapshot_body),
+        ] {
+            let mut count = 0;
+            for (i, bucket) in histogram.buckets.iter().enumerate() {
+                count += bucket.load(Ordering::Relaxed);
+                let le = BODY_SIZE_BUCKETS
+                    .get(i)
+                    .map_or_else(|| "+Inf".to_string(), |bound| bound.to_string());
+                writeln!(
+                    out,
+                    "{name}_bucket{{endpoint=\"{endpoint}\",le=\"{le}\"}} {count}"
+                )
+                .unwrap();
+            }
+            let sum = histogram.sum.load(Ordering::Relaxed);
+            writeln!(out, "{name}_sum{{endpoint=\"{endpoint}\"}} {sum}").unwrap();
+            writeln!(out, "{name}_count{{endpoint=\"{endpoint}\"}} {count}").unwrap();
+        }
+
+        if let Some(gauges) = &*self.snapshot_gauges.lock().expect("poisoned lock") {
+            if let Some(age) = gauges.oldest_snapshot_age {
+                render_gauge(
+                    &mut out,
+                    "taskchampion_oldest_snapshot_age_seconds",
+                    "Age of the oldest of the clients' latest snapshots, as of the last refresh.",
+                    age,
+                );
+            }
+            render_gauge(
+                &mut out,
+                "taskchampion_max_versions_since_snapshot",
+                "Largest number of versions since a client's latest snapshot, as of the last refresh.",
+                gauges.max_versions_since_snapshot.into(),
+            );
+        }
+        out
+    }
+}
+
+/// Render a counter with a single label.
+#[cfg_attr(not(any(test, feature = "metrics")), allow(dead_code))]
+fn render_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    label_values: &[&str],
+    values: &[AtomicU64],
+) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} counter").unwrap();
+    for (label_value, value) in label_values.iter().zip(values) {
+        let value = value.load(Ordering::Relaxed);
+        writeln!(out, "{name}{{{label}=\"{label_value}\"}} {value}").unwrap();
+    }
+}
+
+/// Render a gauge without labels.
+#[cfg_attr(not(any(test, feature = "metrics")), allow(dead_code))]
+fn render_gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} gauge").unwrap();
+    writeln!(out, "{name} {value}").unwrap();
+}
+
+/// Get the server's metrics, in the Prometheus text exposition format.
+///
+/// Like the admin endpoints, this requires the bearer token if the server is configured with one,
+/// but is not subject to the `User-Agent` checks applied to sync requests.
+#[cfg(feature = "metrics")]
+#[get("/metrics")]
+pub(crate) async fn service(server_state: web::Data<Arc<ServerState>>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(server_state.metrics.render())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    /// Assert that each of the given lines appears in the rendered metrics.
+    fn assert_lines(rendered: &str, lines: &[&str]) {
+        for line in lines {
+            assert!(
+                rendered.lines().any(|l| l == *line),
+                "{line:?} not found in:\n{rendered}"
+            );
+        }
+    }
+
+    #[test]
+    fn counters() {
+        let metrics = Metrics::default();
+        metrics.add_version(&AddVersionResult::Ok(Uuid::new_v4()), SnapshotUrgency::High);
+        metrics.add_version(
+            &AddVersionResult::ExpectedParentVersion(Uuid::new_v4()),
+            SnapshotUrgency::None,
+        );
+        metrics.add_version(&AddVersionResult::QuotaExceeded, SnapshotUrgency::None);
+        metrics.add_snapshot(false);
+        metrics.get_child_version(&GetVersionResult::Gone);
+        assert_lines(
+            &metrics.render(),
+            &[
+                "taskchampion_add_version_total{result=\"ok\"} 1",
+                "taskchampion_add_version_total{result=\"conflict\"} 1",
+                "taskchampion_add_version_total{result=\"quota_exceeded\"} 1",
+                "taskchampion_add_snapshot_total{result=\"accepted\"} 0",
+                "taskchampion_add_snapshot_total{result=\"rejected\"} 1",
+                "taskchampion_get_child_version_total{result=\"gone\"} 1",
+                // the urgency is only counted for added versions
+                "taskchampion_snapshot_urgency_total{urgency=\"none\"} 0",
+                "taskchampion_snapshot_urgency_total{urgency=\"high\"} 1",
+            ],
+        );
+    }
+
+    #[test]
+    fn histogram() {
+        let metrics = Metrics::default();
+        metrics.add_version_body(100);
+        metrics.add_version_body(256);
+        metrics.add_version_body(2000);
+        metrics.add_version_body(100 << 20);
+        let name = "taskchampion_request_body_bytes";
+        assert_lines(
+            &metrics.render(),
+            &[
+                &format!("{name}_bucket{{endpoint=\"add_version\",le=\"256\"}} 2"),
+                &format!("{name}_bucket{{endpoint=\"add_version\",le=\"1024\"}} 2"),
+                &format!("{name}_bucket{{endpoint=\"add_version\",le=\"4096\"}} 3"),
+                &format!("{name}_bucket{{endpoint=\"add_version\",le=\"67108864\"}} 3"),
+                &format!("{name}_bucket{{endpoint=\"add_version\",le=\"+Inf\"}} 4"),
+                &format!("{name}_sum{{endpoint=\"add_version\"}} 104859956"),
+                &format!("{name}_count{{endpoint=\"add_version\"}} 4"),
+                &format!("{name}_count{{endpoint=\"add_snapshot\"}} 0"),
+            ],
+        );
+    }
+
+    #[test]
+    fn snapshot_gauges() {
+        let metrics = Metrics::default();
+        // the gauges are absent until refreshed
+        assert!(!metrics.render().contains("snapshot_age"));
+
+        let now = Utc::now();
+        metrics.snapshot_stats(
+            &SnapshotStats {
+                oldest_snapshot_timestamp: Some(now - chrono::Duration::seconds(90)),
+                max_versions_since_snapshot: 12,
+            },
+            now,
+        );
+        assert_lines(
+            &metrics.render(),
+            &[
+                "taskchampion_oldest_snapshot_age_seconds 90",
+                "taskchampion_max_versions_since_snapshot 12",
+            ],
+        );
+
+        // with no snapshots, there is no oldest snapshot
+        metrics.snapshot_stats(&SnapshotStats::default(), now);
+        let rendered = metrics.render();
+        assert!(!rendered.contains("snapshot_age"));
+        assert_lines(&rendered, &["taskchampion_max_versions_since_snapshot 0"]);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[actix_rt::test]
+    async fn test_service() {
+        use crate::api::{
+            CLIENT_ID_HEADER, HISTORY_SEGMENT_CONTENT_TYPE, SNAPSHOT_CONTENT_TYPE,
+            VERSION_ID_HEADER,
+        };
+        use crate::WebServer;
+        use actix_web::{http::StatusCode, test, App};
+        use pretty_assertions::assert_eq;
+        use taskchampion_sync_server_core::{InMemoryStorage, NIL_VERSION_ID};
+
+        let server = WebServer::new(
+            Default::default(),
+            Default::default(),
+            InMemoryStorage::new(),
+        );
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = test::init_service(app).await;
+        let client_id = Uuid::new_v4();
+
+        // add a version, and then a conflicting version
+        let mut version_id = None;
+        for expected_status in [StatusCode::OK, StatusCode::CONFLICT] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/v1/client/add-version/{NIL_VERSION_ID}"))
+                .append_header(("Content-Type", HISTORY_SEGMENT_CONTENT_TYPE))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .set_payload(b"abcd".to_vec())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), expected_status);
+            if let Some(hdr) = resp.headers().get(VERSION_ID_HEADER) {
+                version_id = Some(hdr.to_str().unwrap().to_string());
+            }
+        }
+        let version_id = version_id.unwrap();
+
+        // get the version, and its nonexistent child
+        for (parent_version_id, expected_status) in [
+            (NIL_VERSION_ID.to_string(), StatusCode::OK),
+            (version_id.clone(), StatusCode::NOT_FOUND),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/v1/client/get-child-version/{parent_version_id}"))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), expected_status);
+        }
+
+        // add a snapshot, and a snapshot for an unknown version, which is rejected
+        for snapshot_version_id in [version_id, Uuid::new_v4().to_string()] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/v1/client/add-snapshot/{snapshot_version_id}"))
+                .append_header(("Content-Type", SNAPSHOT_CONTENT_TYPE))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .set_payload(b"snapshot".to_vec())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            "text/plain; version=0.0.4"
+        );
+        let body = test::read_body(resp).await;
+        let name = "taskchampion_request_body_bytes";
+        assert_lines(
+            std::str::from_utf8(&body).unwrap(),
+            &[
+                "taskchampion_add_version_total{result=\"ok\"} 1",
+                "taskchampion_add_version_total{result=\"conflict\"} 1",
+                "taskchampion_add_snapshot_total{result=\"accepted\"} 1",
+                "taskchampion_add_snapshot_total{result=\"rejected\"} 1",
+                "taskchampion_get_child_version_total{result=\"success\"} 1",
+                "taskchampion_get_child_version_total{result=\"not_found\"} 1",
+                // a new client has no snapshot, so a snapshot is urgently requested
+                "taskchampion_snapshot_urgency_total{urgency=\"high\"} 1",
+                &format!("{name}_count{{endpoint=\"add_version\"}} 2"),
+                &format!("{name}_sum{{endpoint=\"add_version\"}} 8"),
+                &format!("{name}_count{{endpoint=\"add_snapshot\"}} 2"),
+            ],
+        );
+    }
+}
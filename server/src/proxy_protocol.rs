@@ -0,0 +1,197 @@
+//! Support for the [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt),
+//! with which a load balancer such as HAProxy sends the address of the client at the beginning of
+//! each connection it forwards to the server.
+//!
+//! Actix does not read this header itself, so when [`crate::WebConfig::proxy_protocol`] is set,
+//! the accepted stream must be passed to [`read_header`] before it is handed to the HTTP service,
+//! with the address it returns as the peer address of the connection. Handlers then see the
+//! client's address, rather than the load balancer's, in `HttpRequest::peer_addr`.
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The signature beginning a version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The maximum length of a version 1 header, including the terminating CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// The length of the fixed part of a version 2 header, including the signature.
+const V2_HEADER_LENGTH: usize = 16;
+
+/// Read a PROXY protocol header, of either version, from the beginning of a connection, and return
+/// the source address it contains. This returns `None` for a header which does not carry an
+/// address, such as a version 1 `UNKNOWN` header or a version 2 `LOCAL` header, for which the
+/// connection's own peer address should be used.
+///
+/// This reads exactly the bytes of the header, leaving the HTTP request in the stream. A
+/// connection which does not begin with a valid header is an error.
+pub async fn read_header<T: AsyncRead + Unpin>(io: &mut T) -> io::Result<Option<SocketAddr>> {
+    let mut header = vec![0; 5];
+    io.read_exact(&mut header).await?;
+    if header == b"PROXY" {
+        // Read up to the CRLF one byte at a time, so as not to read past the header.
+        while !header.ends_with(b"\r\n") {
+            if header.len() == V1_MAX_LENGTH {
+                return Err(invalid("PROXY header is too long"));
+            }
+            header.push(io.read_u8().await?);
+        }
+        parse_v1(&header)
+    } else if header == V2_SIGNATURE[..5] {
+        header.resize(V2_HEADER_LENGTH, 0);
+        io.read_exact(&mut header[5..]).await?;
+        let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+        header.resize(V2_HEADER_LENGTH + length, 0);
+        io.read_exact(&mut header[V2_HEADER_LENGTH..]).await?;
+        parse_v2(&header)
+    } else {
+        Err(invalid("Connection does not begin with a PROXY header"))
+    }
+}
+
+/// Parse a version 1 (text) header, such as `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`.
+fn parse_v1(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let header = header
+        .strip_suffix(b"\r\n")
+        .and_then(|header| std::str::from_utf8(header).ok())
+        .ok_or_else(|| invalid("Invalid PROXY header"))?;
+    let fields: Vec<&str> = header.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _, source_port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("Invalid source address in PROXY header"))?;
+            if ip.is_ipv4() != (protocol == "TCP4") {
+                return Err(invalid(
+                    "Source address does not match protocol in PROXY header",
+                ));
+            }
+            let port: u16 = source_port
+                .parse()
+                .map_err(|_| invalid("Invalid source port in PROXY header"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("Invalid PROXY header")),
+    }
+}
+
+/// Parse a version 2 (binary) header, including its signature and any TLVs following the
+/// addresses, which are ignored.
+fn parse_v2(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if header.len() < V2_HEADER_LENGTH || !header.starts_with(V2_SIGNATURE) {
+        return Err(invalid("Invalid PROXY header"));
+    }
+    let version_command = header[12];
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+    let addresses = &header[V2_HEADER_LENGTH..];
+    match (version_command & 0x0f, header[13] >> 4) {
+        // The LOCAL command, for connections made by the proxy itself, such as health checks.
+        (0, _) => Ok(None),
+        // The PROXY command, with an IPv4 address.
+        (1, 1) => {
+            let Some(addresses) = addresses.get(..12) else {
+                return Err(invalid("Truncated PROXY header"));
+            };
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // The PROXY command, with an IPv6 address.
+        (1, 2) => {
+            let Some(addresses) = addresses.get(..36) else {
+                return Err(invalid("Truncated PROXY header"));
+            };
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // The PROXY command, with an unspecified or Unix address.
+        (1, _) => Ok(None),
+        _ => Err(invalid("Unsupported PROXY command")),
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_v1() {
+        assert_eq!(
+            parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+        ] {
+            assert!(parse_v1(header).is_err(), "with {header:?}");
+        }
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        // PROXY command, TCP over IPv4, 12 bytes of addresses
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(
+            parse_v2(&header).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+
+        // LOCAL command
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(parse_v2(&header).unwrap(), None);
+
+        // truncated addresses
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x21, 0, 12]);
+        header.extend_from_slice(&[0; 12]);
+        assert!(parse_v2(&header).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_read_header() {
+        let mut io = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n"[..];
+        assert_eq!(
+            read_header(&mut io).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(io, b"GET / HTTP/1.1\r\n");
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0, 15]);
+        data.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        // a TLV, which is ignored
+        data.extend_from_slice(&[0x04, 0, 0]);
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let mut io = &data[..];
+        assert_eq!(
+            read_header(&mut io).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(io, b"GET / HTTP/1.1\r\n");
+
+        let mut io = &b"GET / HTTP/1.1\r\n"[..];
+        assert!(read_header(&mut io).await.is_err());
+    }
+}
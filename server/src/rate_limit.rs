@@ -0,0 +1,508 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use taskchampion_sync_server_core::ClientId;
+
+/// A limit on the rate of requests, implemented as a token bucket.
+///
+/// Each request takes a token from the bucket, which holds at most `burst` tokens and is refilled
+/// at `per_second` tokens per second. Requests when the bucket is empty are rejected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    /// Number of requests allowed per second, on average.
+    pub per_second: f64,
+
+    /// Number of requests allowed in a burst.
+    pub burst: u32,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    /// Parse a rate limit of the form `PER_SECOND` or `PER_SECOND:BURST`. If the burst is not
+    /// given, it is the per-second rate, rounded up.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (per_second, burst) = match s.split_once(':') {
+            Some((per_second, burst)) => (per_second, Some(burst)),
+            None => (s, None),
+        };
+        let per_second: f64 = per_second
+            .parse()
+            .map_err(|_| format!("invalid rate {per_second:?}"))?;
+        if !per_second.is_finite() || per_second <= 0.0 {
+            return Err(format!("rate must be positive, not {per_second}"));
+        }
+        let burst: u32 = match burst {
+            Some(burst) => burst
+                .parse()
+                .map_err(|_| format!("invalid burst {burst:?}"))?,
+            None => per_second.ceil() as u32,
+        };
+        if burst == 0 {
+            return Err("burst must be positive".into());
+        }
+        Ok(RateLimit { per_second, burst })
+    }
+}
+
+/// A limit on the number of bytes of versions and snapshots downloaded by each client.
+///
+/// Each client's window begins with its first download, and once `bytes` have been downloaded in
+/// the window, further downloads are rejected until the window ends. A single download may take
+/// the client over the limit, so this bounds the number of large downloads rather than their
+/// exact size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DownloadQuota {
+    /// Number of bytes allowed in each window.
+    pub bytes: u64,
+
+    /// Length of the window.
+    pub window: Duration,
+}
+
+/// The class of an endpoint, each of which has its own rate limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum EndpointClass {
+    /// Endpoints which only read data.
+    Read,
+    /// Endpoints which write data.
+    Write,
+    /// All sync endpoints, for the overall limit on each client.
+    All,
+}
+
+/// The state of a single token bucket.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets for each client and endpoint class. Requests without a known client ID share the
+/// bucket for client `None`.
+#[derive(Default)]
+pub(crate) struct RateLimiter(Mutex<HashMap<(Option<ClientId>, EndpointClass), Bucket>>);
+
+impl RateLimiter {
+    /// Take a token from the bucket for the given client and endpoint class, as of `now`.
+    ///
+    /// If the bucket is empty, this returns the time until a token will be available.
+    pub(crate) fn check(
+        &self,
+        limit: &RateLimit,
+        client_id: Option<ClientId>,
+        class: EndpointClass,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let burst = f64::from(limit.burst);
+        let mut buckets = self.0.lock().expect("poisoned lock");
+        let bucket = buckets.entry((client_id, class)).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / limit.per_second,
+            ))
+        }
+    }
+}
+
+/// The bytes downloaded by a single client in its current window.
+struct DownloadWindow {
+    start: Instant,
+    bytes: u64,
+}
+
+/// Download windows for each client.
+#[derive(Default)]
+pub(crate) struct DownloadTracker(Mutex<HashMap<ClientId, DownloadWindow>>);
+
+impl DownloadTracker {
+    /// Check whether the given client may download more data as of `now`.
+    ///
+    /// If the client has exhausted its quota, this returns the time until its window ends.
+    pub(crate) fn check(
+        &self,
+        quota: &DownloadQuota,
+        client_id: ClientId,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let mut windows = self.0.lock().expect("poisoned lock");
+        let window = Self::window(&mut windows, quota, client_id, now);
+        if window.bytes >= quota.bytes {
+            Err((window.start + quota.window).saturating_duration_since(now))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Record that the given client downloaded `bytes` bytes at `now`.
+    pub(crate) fn record(
+        &self,
+        quota: &DownloadQuota,
+        client_id: ClientId,
+        bytes: u64,
+        now: Instant,
+    ) {
+        let mut windows = self.0.lock().expect("poisoned lock");
+        let window = Self::window(&mut windows, quota, client_id, now);
+        window.bytes = window.bytes.saturating_add(bytes);
+    }
+
+    /// Get the client's current window, starting a new one if the last has ended.
+    fn window<'a>(
+        windows: &'a mut HashMap<ClientId, DownloadWindow>,
+        quota: &DownloadQuota,
+        client_id: ClientId,
+        now: Instant,
+    ) -> &'a mut DownloadWindow {
+        let window = windows.entry(client_id).or_insert(DownloadWindow {
+            start: now,
+            bytes: 0,
+        });
+        if now.saturating_duration_since(window.start) >= quota.window {
+            *window = DownloadWindow {
+                start: now,
+                bytes: 0,
+            };
+        }
+        window
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::CLIENT_ID_HEADER;
+    use crate::{WebConfig, WebServer};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+    use chrono::Utc;
+    use pretty_assertions::assert_eq;
+    use taskchampion_sync_server_core::{InMemoryStorage, Snapshot, Storage, NIL_VERSION_ID};
+    use uuid::Uuid;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "10".parse(),
+            Ok(RateLimit {
+                per_second: 10.0,
+                burst: 10
+            })
+        );
+        assert_eq!(
+            "0.5:3".parse(),
+            Ok(RateLimit {
+                per_second: 0.5,
+                burst: 3
+            })
+        );
+        assert_eq!(
+            "0.1".parse(),
+            Ok(RateLimit {
+                per_second: 0.1,
+                burst: 1
+            })
+        );
+        assert!("0".parse::<RateLimit>().is_err());
+        assert!("-1".parse::<RateLimit>().is_err());
+        assert!("1:0".parse::<RateLimit>().is_err());
+        assert!("fast".parse::<RateLimit>().is_err());
+    }
+
+    #[test]
+    fn bucket_exhausted_and_refilled() {
+        let limit = RateLimit {
+            per_second: 2.0,
+            burst: 2,
+        };
+        let limiter = RateLimiter::default();
+        let client_id = Uuid::new_v4();
+        let start = Instant::now();
+
+        assert_eq!(
+            limiter.check(&limit, Some(client_id), EndpointClass::Read, start),
+            Ok(())
+        );
+        assert_eq!(
+            limiter.check(&limit, Some(client_id), EndpointClass::Read, start),
+            Ok(())
+        );
+        assert_eq!(
+            limiter.check(&limit, Some(client_id), EndpointClass::Read, start),
+            Err(Duration::from_millis(500))
+        );
+
+        // after half a second, one token has been added
+        let later = start + Duration::from_millis(500);
+        assert_eq!(
+            limiter.check(&limit, Some(client_id), EndpointClass::Read, later),
+            Ok(())
+        );
+        assert!(limiter
+            .check(&limit, Some(client_id), EndpointClass::Read, later)
+            .is_err());
+    }
+
+    #[test]
+    fn buckets_are_independent() {
+        let limit = RateLimit {
+            per_second: 1.0,
+            burst: 1,
+        };
+        let limiter = RateLimiter::default();
+        let client_id = Uuid::new_v4();
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.check(&limit, Some(client_id), EndpointClass::Read, now),
+            Ok(())
+        );
+        assert!(limiter
+            .check(&limit, Some(client_id), EndpointClass::Read, now)
+            .is_err());
+        // another class, or another client, has its own bucket
+        assert_eq!(
+            limiter.check(&limit, Some(client_id), EndpointClass::Write, now),
+            Ok(())
+        );
+        assert_eq!(
+            limiter.check(&limit, Some(Uuid::new_v4()), EndpointClass::Read, now),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn download_quota_exhausted_and_reset() {
+        let quota = DownloadQuota {
+            bytes: 100,
+            window: Duration::from_secs(60),
+        };
+        let tracker = DownloadTracker::default();
+        let client_id = Uuid::new_v4();
+        let start = Instant::now();
+
+        assert_eq!(tracker.check(&quota, client_id, start), Ok(()));
+        tracker.record(&quota, client_id, 60, start);
+        assert_eq!(tracker.check(&quota, client_id, start), Ok(()));
+        tracker.record(&quota, client_id, 60, start);
+        let later = start + Duration::from_secs(20);
+        assert_eq!(
+            tracker.check(&quota, client_id, later),
+            Err(Duration::from_secs(40))
+        );
+
+        // another client has its own window
+        assert_eq!(tracker.check(&quota, Uuid::new_v4(), later), Ok(()));
+
+        // once the window ends, the quota is available again
+        let after_window = start + Duration::from_secs(60);
+        assert_eq!(tracker.check(&quota, client_id, after_window), Ok(()));
+    }
+
+    #[actix_rt::test]
+    async fn download_quota() {
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let storage = InMemoryStorage::new();
+        {
+            let mut txn = storage.txn(client_id).unwrap();
+            txn.new_client(version_id).unwrap();
+            txn.set_snapshot(
+                Snapshot {
+                    version_id,
+                    versions_since: 0,
+                    timestamp: Utc::now(),
+                },
+                vec![0; 10],
+                None,
+            )
+            .unwrap();
+            txn.commit().unwrap();
+        }
+        let web_config = WebConfig {
+            download_quota: Some(DownloadQuota {
+                bytes: 15,
+                window: Duration::from_millis(50),
+            }),
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, storage);
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = init_service(app).await;
+
+        let get_snapshot = || {
+            TestRequest::get()
+                .uri("/v1/client/snapshot")
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .to_request()
+        };
+
+        // the second download takes the client over its quota, and the third is rejected
+        for _ in 0..2 {
+            let resp = call_service(&app, get_snapshot()).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let resp = call_service(&app, get_snapshot()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "1");
+
+        // after the window ends, downloads are allowed again
+        actix_rt::time::sleep(Duration::from_millis(60)).await;
+        let resp = call_service(&app, get_snapshot()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn read_and_write_limits() {
+        let client_id = Uuid::new_v4();
+        let web_config = WebConfig {
+            rate_limit_reads: Some(RateLimit {
+                per_second: 0.001,
+                burst: 3,
+            }),
+            rate_limit_writes: Some(RateLimit {
+                per_second: 0.001,
+                burst: 1,
+            }),
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = init_service(app).await;
+
+        let read = || {
+            TestRequest::get()
+                .uri(&format!("/v1/client/get-child-version/{NIL_VERSION_ID}"))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .to_request()
+        };
+        let write = || {
+            TestRequest::post()
+                .uri(&format!("/v1/client/add-version/{NIL_VERSION_ID}"))
+                .append_header((
+                    "Content-Type",
+                    "application/vnd.taskchampion.history-segment",
+                ))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .set_payload(b"abcd".to_vec())
+                .to_request()
+        };
+
+        // the first write succeeds, and the second exceeds the write limit
+        let resp = call_service(&app, write()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = call_service(&app, write()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key("Retry-After"));
+
+        // reads are still allowed, up to the read limit
+        for _ in 0..3 {
+            let resp = call_service(&app, read()).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let resp = call_service(&app, read()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key("Retry-After"));
+    }
+
+    #[actix_rt::test]
+    async fn overall_limit_exhausted_and_refilled() {
+        let client_id = Uuid::new_v4();
+        let web_config = WebConfig {
+            rate_limit: Some(RateLimit {
+                per_second: 20.0,
+                burst: 2,
+            }),
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = init_service(app).await;
+
+        let read = || {
+            TestRequest::get()
+                .uri(&format!("/v1/client/get-child-version/{NIL_VERSION_ID}"))
+                .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+                .to_request()
+        };
+
+        // the burst is shared by all endpoints, so the snapshot request exhausts it
+        let resp = call_service(&app, read()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let req = TestRequest::get()
+            .uri("/v1/client/snapshot")
+            .append_header((CLIENT_ID_HEADER, client_id.to_string()))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = call_service(&app, read()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "1");
+
+        // another client is not affected
+        let req = TestRequest::get()
+            .uri(&format!("/v1/client/get-child-version/{NIL_VERSION_ID}"))
+            .append_header((CLIENT_ID_HEADER, Uuid::new_v4().to_string()))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // after the refill interval of 50ms, a request is allowed again
+        actix_rt::time::sleep(Duration::from_millis(60)).await;
+        let resp = call_service(&app, read()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn overall_limit_unidentified_clients_share_bucket() {
+        let allowed_client_id = Uuid::new_v4();
+        let web_config = WebConfig {
+            client_id_allowlist: Some([allowed_client_id].into()),
+            rate_limit: Some(RateLimit {
+                per_second: 0.001,
+                burst: 2,
+            }),
+            ..WebConfig::default()
+        };
+        let server = WebServer::new(Default::default(), web_config, InMemoryStorage::new());
+        let app = App::new().configure(|sc| server.config(sc));
+        let app = init_service(app).await;
+
+        let read = |client_id: Option<&str>| {
+            let req =
+                TestRequest::get().uri(&format!("/v1/client/get-child-version/{NIL_VERSION_ID}"));
+            match client_id {
+                Some(client_id) => req.append_header((CLIENT_ID_HEADER, client_id)),
+                None => req,
+            }
+            .to_request()
+        };
+
+        // a missing client ID, an invalid client ID, and a client ID not in the allowlist all
+        // take from the same bucket
+        let resp = call_service(&app, read(None)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = call_service(&app, read(Some("not-a-uuid"))).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let unknown_client_id = Uuid::new_v4().to_string();
+        let resp = call_service(&app, read(Some(&unknown_client_id))).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // an allowed client has its own bucket
+        let allowed_client_id = allowed_client_id.to_string();
+        let resp = call_service(&app, read(Some(&allowed_client_id))).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,12 +1,37 @@
 //! Tihs crate implements a SQLite storage backend for the TaskChampion sync server.
 use anyhow::Context;
-use chrono::{TimeZone, Utc};
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::blob::ZeroBlob;
 use rusqlite::types::{FromSql, ToSql};
-use rusqlite::{params, Connection, OptionalExtension};
-use std::path::Path;
-use taskchampion_sync_server_core::{Client, Snapshot, Storage, StorageTxn, Version};
+use rusqlite::{params, Connection, DatabaseName, OptionalExtension};
+use std::fs::File;
+use std::io::Read;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use taskchampion_sync_server_core::{
+    BackendInfo, ChainHash, Client, ClientUsage, GlobalStats, ServerError, Snapshot, Storage,
+    StorageTxn, Version,
+};
 use uuid::Uuid;
 
+/// The version of the schema created by [`SqliteStorage`], recorded in the database's
+/// `user_version`. Increment this whenever the schema changes.
+const SCHEMA_VERSION: u32 = 3;
+
+/// The indexes of the schema, by name, with the statement creating each.
+const INDEXES: &[(&str, &str)] = &[(
+    "versions_by_parent",
+    "CREATE INDEX versions_by_parent ON versions (parent_version_id);",
+)];
+
+/// The default time to wait for another connection's lock on the database.
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The largest number of idle connections kept for read-only transactions.
+const MAX_IDLE_READ_CONNECTIONS: usize = 8;
+
 /// Newtype to allow implementing `FromSql` for foreign `uuid::Uuid`
 struct StoredUuid(Uuid);
 
@@ -27,36 +52,250 @@ impl ToSql for StoredUuid {
     }
 }
 
+/// A history segment as stored in a row of the `versions` table.
+enum StoredSegment {
+    /// The history segment is stored in the `history_segment` column.
+    Inline(Vec<u8>),
+    /// The history segment is stored in the file with this name, in the segment directory.
+    File(String),
+}
+
+impl StoredSegment {
+    /// Get the segment from a row containing the `history_segment` and `history_segment_file`
+    /// columns.
+    fn from_row(r: &rusqlite::Row) -> rusqlite::Result<StoredSegment> {
+        match r.get("history_segment_file")? {
+            Some(file) => Ok(StoredSegment::File(file)),
+            None => Ok(StoredSegment::Inline(r.get("history_segment")?)),
+        }
+    }
+}
+
+/// Options for opening a [`SqliteStorage`] with [`SqliteStorage::open_with_options`].
+#[derive(Clone, Debug)]
+pub struct SqliteOptions {
+    /// Store each new history segment in a separate file, as for
+    /// [`SqliteStorage::with_external_segments`].
+    pub external_segments: bool,
+    /// Check the database for corruption when opening it, failing if it is corrupt. This reads
+    /// the whole database, so may be slow for a very large one.
+    pub integrity_check: bool,
+}
+
+impl Default for SqliteOptions {
+    fn default() -> Self {
+        SqliteOptions {
+            external_segments: false,
+            integrity_check: true,
+        }
+    }
+}
+
 /// An on-disk storage backend which uses SQLite.
 ///
-/// A new connection is opened for each transaction, and only one transaction may be active at a
-/// time; a second call to `txn` will block until the first transaction is dropped.
+/// The database may instead be held in memory, with [`SqliteStorage::new_in_memory`].
+///
+/// A new connection is opened for each read-write transaction, and only one such transaction may
+/// be active at a time; a second call to `txn` will block until the first transaction is dropped,
+/// failing if that takes longer than the busy timeout (see [`SqliteStorage::with_busy_timeout`]).
+/// Read-only transactions use a pool of connections, and proceed concurrently with one another
+/// and with a read-write transaction.
+///
+/// History segments are stored in the database, unless the storage is created with
+/// [`SqliteStorage::with_external_segments`].
+///
+/// Snapshot timestamps are stored with a precision of one second, unless
+/// [`SqliteStorage::set_millisecond_timestamps`] is enabled.
 pub struct SqliteStorage {
-    db_file: std::path::PathBuf,
+    db_file: PathBuf,
+    segment_dir: PathBuf,
+    external_segments: bool,
+    millisecond_timestamps: bool,
+    busy_timeout: Duration,
+    /// Idle connections for read-only transactions.
+    read_connections: Mutex<Vec<Connection>>,
+    /// For an in-memory database, a connection which keeps the database alive. It is locked
+    /// while the database is in use, as connections to a shared in-memory database fail, rather
+    /// than waiting, when it is locked by another connection.
+    memory_connection: Option<Mutex<Connection>>,
 }
 
 impl SqliteStorage {
     fn new_connection(&self) -> anyhow::Result<Connection> {
-        Ok(Connection::open(&self.db_file)?)
+        let con = Connection::open(&self.db_file)?;
+        con.busy_timeout(self.busy_timeout)
+            .context("Error setting SQLite busy timeout")?;
+        Ok(con)
+    }
+
+    /// Lock an in-memory database for exclusive use, or do nothing for a database on disk.
+    fn lock_memory(&self) -> Option<MutexGuard<'_, Connection>> {
+        self.memory_connection
+            .as_ref()
+            .map(|con| con.lock().expect("poisoned lock"))
+    }
+
+    /// Create a transaction using a connection on which it has already begun, holding the lock
+    /// on an in-memory database, if any, for the duration of the transaction.
+    fn new_txn<'a>(
+        &self,
+        con: TxnConnection<'a>,
+        client_id: Uuid,
+        memory_guard: Option<MutexGuard<'a, Connection>>,
+    ) -> Txn<'a> {
+        Txn {
+            con,
+            client_id,
+            segment_dir: self.segment_dir.clone(),
+            external_segments: self.external_segments,
+            millisecond_timestamps: self.millisecond_timestamps,
+            new_segment_files: Vec::new(),
+            deleted_segment_files: Vec::new(),
+            committed: false,
+            _memory_guard: memory_guard,
+        }
     }
 
     /// Create a new instance using a database at the given directory.
     ///
     /// The database will be stored in a file named `taskchampion-sync-server.sqlite3` in the given
-    /// directory.
+    /// directory. If the database already exists, it is checked for corruption, failing if it is
+    /// corrupt; see [`SqliteStorage::open_with_options`] to skip this check.
     pub fn new<P: AsRef<Path>>(directory: P) -> anyhow::Result<SqliteStorage> {
-        std::fs::create_dir_all(&directory)
-            .with_context(|| format!("Failed to create `{}`.", directory.as_ref().display()))?;
-        let db_file = directory.as_ref().join("taskchampion-sync-server.sqlite3");
+        Self::open_with_options(directory, SqliteOptions::default())
+    }
+
+    /// Create a new instance using a database at the given directory, as for
+    /// [`SqliteStorage::new`], storing each new history segment in a separate file.
+    ///
+    /// The files are stored in a `history-segments` subdirectory, named by version ID, with only
+    /// a reference to the file in the database. This keeps the database small, and allows the
+    /// segments to be backed up incrementally. Segments already stored in the database remain
+    /// there.
+    ///
+    /// A segment file is written before the version is added to the database, and removed if the
+    /// transaction is not committed. The files of deleted versions are removed once the deletion
+    /// is committed.
+    pub fn with_external_segments<P: AsRef<Path>>(directory: P) -> anyhow::Result<SqliteStorage> {
+        let options = SqliteOptions {
+            external_segments: true,
+            ..SqliteOptions::default()
+        };
+        Self::open_with_options(directory, options)
+    }
+
+    /// Create a new instance using a database held in memory, for tests and ephemeral servers.
+    ///
+    /// The database is lost when this instance is dropped. Each instance has its own database,
+    /// shared by all of its connections. Unlike a database on disk, only one transaction may be
+    /// active at a time, whether read-only or not.
+    pub fn new_in_memory() -> anyhow::Result<SqliteStorage> {
+        let db_file = PathBuf::from(format!(
+            "file:taskchampion-sync-server-{}?mode=memory&cache=shared",
+            Uuid::new_v4()
+        ));
+        let memory_connection =
+            Connection::open(&db_file).context("Error creating in-memory SQLite database")?;
+        let o = SqliteStorage {
+            db_file,
+            segment_dir: PathBuf::new(),
+            external_segments: false,
+            millisecond_timestamps: false,
+            busy_timeout: DEFAULT_BUSY_TIMEOUT,
+            read_connections: Mutex::new(Vec::new()),
+            memory_connection: Some(Mutex::new(memory_connection)),
+        };
+        o.migrate()?;
+        Ok(o)
+    }
+
+    /// Set the time a transaction waits for another to release its lock on the database before
+    /// failing, five seconds by default.
+    pub fn with_busy_timeout(mut self, timeout: Duration) -> SqliteStorage {
+        self.busy_timeout = timeout;
+        self
+    }
+
+    /// Store snapshot timestamps with a precision of one millisecond, rather than one second.
+    ///
+    /// The precise timestamp is stored alongside the timestamp in seconds, so snapshots stored
+    /// with either setting remain readable with the other.
+    pub fn set_millisecond_timestamps(&mut self, enabled: bool) {
+        self.millisecond_timestamps = enabled;
+    }
+
+    /// Create a new instance using a database at the given directory, as for
+    /// [`SqliteStorage::new`], with the given options.
+    pub fn open_with_options<P: AsRef<Path>>(
+        directory: P,
+        options: SqliteOptions,
+    ) -> anyhow::Result<SqliteStorage> {
+        let directory = directory.as_ref();
+        let external_segments = options.external_segments;
+        std::fs::create_dir_all(directory)
+            .with_context(|| format!("Failed to create `{}`.", directory.display()))?;
+        let db_file = directory.join("taskchampion-sync-server.sqlite3");
+
+        // Segment files may exist even if new segments are not stored externally, so this
+        // directory is always known, but only created when needed.
+        let segment_dir = directory.join("history-segments");
+        if external_segments {
+            std::fs::create_dir_all(&segment_dir)
+                .with_context(|| format!("Failed to create `{}`.", segment_dir.display()))?;
+        }
+
+        let o = SqliteStorage {
+            db_file,
+            segment_dir,
+            external_segments,
+            millisecond_timestamps: false,
+            busy_timeout: DEFAULT_BUSY_TIMEOUT,
+            read_connections: Mutex::new(Vec::new()),
+            memory_connection: None,
+        };
+        if options.integrity_check {
+            o.check_integrity()?;
+        }
+        o.migrate()?;
+        Ok(o)
+    }
 
-        let o = SqliteStorage { db_file };
+    /// Check the database for corruption, with `PRAGMA quick_check`.
+    fn check_integrity(&self) -> anyhow::Result<()> {
+        let corrupt = || {
+            format!(
+                "SQLite database `{}` is corrupt; restore it from a backup",
+                self.db_file.display()
+            )
+        };
+        let con = self.new_connection()?;
+        let problems = con
+            .prepare("PRAGMA quick_check")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |r| r.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .with_context(corrupt)?;
+        if problems != ["ok"] {
+            anyhow::bail!("{}: {}", corrupt(), problems.join("; "));
+        }
+        Ok(())
+    }
 
-        let con = o.new_connection()?;
+    /// Create or upgrade the database's schema.
+    fn migrate(&self) -> anyhow::Result<()> {
+        let con = self.new_connection()?;
 
         // Use the modern WAL mode.
         con.query_row("PRAGMA journal_mode=WAL", [], |_row| Ok(()))
             .context("Setting journal_mode=WAL")?;
 
+        // A database migrated by a newer server must not be modified, nor its version reduced, so
+        // that `check_schema_version` detects the mismatch.
+        if self.schema_version()? > SCHEMA_VERSION {
+            return Ok(());
+        }
+
         let queries = vec![
                 "CREATE TABLE IF NOT EXISTS clients (
                     client_id STRING PRIMARY KEY,
@@ -64,39 +303,257 @@ impl SqliteStorage {
                     snapshot_version_id STRING,
                     versions_since_snapshot INTEGER,
                     snapshot_timestamp INTEGER,
-                    snapshot BLOB);",
-                "CREATE TABLE IF NOT EXISTS versions (version_id STRING PRIMARY KEY, client_id STRING, parent_version_id STRING, history_segment BLOB);",
-                "CREATE INDEX IF NOT EXISTS versions_by_parent ON versions (parent_version_id);",
+                    snapshot BLOB,
+                    last_activity_at INTEGER,
+                    chain_hash INTEGER,
+                    app_metadata BLOB,
+                    snapshot_timestamp_millis INTEGER,
+                    acked_version_id STRING);",
+                "CREATE TABLE IF NOT EXISTS versions (version_id STRING PRIMARY KEY, client_id STRING, parent_version_id STRING, history_segment BLOB, history_segment_file STRING);",
             ];
+        let is_new: bool = con
+            .query_row(
+                "SELECT COUNT(*) = 0 FROM sqlite_master WHERE type = 'table' AND name = 'versions'",
+                [],
+                |r| r.get(0),
+            )
+            .context("Error checking SQLite schema")?;
         for q in queries {
             con.execute(q, [])
                 .context("Error while creating SQLite tables")?;
         }
 
-        Ok(o)
+        // Create any missing indexes. An index missing from an existing database, such as one
+        // dropped by an external tool, makes queries silently slow, so this is logged.
+        for (name, create) in INDEXES {
+            let exists: bool = con
+                .query_row(
+                    "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'index' AND name = ?",
+                    [name],
+                    |r| r.get(0),
+                )
+                .context("Error checking SQLite indexes")?;
+            if !exists {
+                if !is_new {
+                    log::warn!("Recreating missing SQLite index {name}");
+                }
+                con.execute(create, [])
+                    .context("Error while creating SQLite indexes")?;
+            }
+        }
+
+        // Databases created by older versions lack columns added since; these are NULL for
+        // existing rows.
+        for (table, column, column_type) in [
+            ("clients", "last_activity_at", "INTEGER"),
+            ("clients", "chain_hash", "INTEGER"),
+            ("clients", "app_metadata", "BLOB"),
+            ("clients", "snapshot_timestamp_millis", "INTEGER"),
+            ("clients", "acked_version_id", "STRING"),
+            ("versions", "history_segment_file", "STRING"),
+        ] {
+            let exists: bool = con
+                .query_row(
+                    &format!(
+                        "SELECT COUNT(*) > 0 FROM pragma_table_info('{table}') WHERE name = ?"
+                    ),
+                    [column],
+                    |r| r.get(0),
+                )
+                .context("Error checking SQLite schema")?;
+            if !exists {
+                con.execute(
+                    &format!("ALTER TABLE {table} ADD COLUMN {column} {column_type}"),
+                    [],
+                )
+                .context("Error while upgrading SQLite tables")?;
+            }
+        }
+        con.pragma_update(None, "user_version", SCHEMA_VERSION)
+            .context("Error recording SQLite schema version")?;
+
+        Ok(())
     }
 }
 
 impl Storage for SqliteStorage {
     fn txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+        let memory_guard = self.lock_memory();
         let con = self.new_connection()?;
         // Begin the transaction on this new connection. An IMMEDIATE connection is in
         // write (exclusive) mode from the start.
         con.execute("BEGIN IMMEDIATE", [])?;
-        let txn = Txn { con, client_id };
-        Ok(Box::new(txn))
+        Ok(Box::new(self.new_txn(
+            TxnConnection {
+                con: Some(con),
+                read_connections: None,
+            },
+            client_id,
+            memory_guard,
+        )))
+    }
+
+    fn read_txn(&self, client_id: Uuid) -> anyhow::Result<Box<dyn StorageTxn + '_>> {
+        let memory_guard = self.lock_memory();
+        let idle = self.read_connections.lock().expect("poisoned lock").pop();
+        let con = match idle {
+            Some(con) => con,
+            None => {
+                let con = self.new_connection()?;
+                con.pragma_update(None, "query_only", true)
+                    .context("Error making SQLite connection read-only")?;
+                con
+            }
+        };
+        // A DEFERRED transaction takes no lock until it first reads, and in WAL mode readers
+        // block neither one another nor the writer.
+        con.execute("BEGIN DEFERRED", [])?;
+        Ok(Box::new(self.new_txn(
+            TxnConnection {
+                con: Some(con),
+                read_connections: Some(&self.read_connections),
+            },
+            client_id,
+            memory_guard,
+        )))
+    }
+
+    fn global_stats(&self) -> anyhow::Result<GlobalStats> {
+        let _memory_guard = self.lock_memory();
+        let con = self.new_connection()?;
+        let (clients, clients_with_snapshot): (u64, u64) = con
+            .query_row(
+                "SELECT COUNT(*), COUNT(snapshot_version_id) FROM clients",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .context("Error counting clients")?;
+        let versions: u64 = con
+            .query_row("SELECT COUNT(*) FROM versions", [], |r| r.get(0))
+            .context("Error counting versions")?;
+        Ok(GlobalStats {
+            clients,
+            clients_with_snapshot,
+            versions,
+        })
+    }
+
+    fn list_clients(&self) -> anyhow::Result<Vec<Uuid>> {
+        let _memory_guard = self.lock_memory();
+        let con = self.new_connection()?;
+        let mut stmt = con
+            .prepare("SELECT client_id FROM clients")
+            .context("Error preparing query")?;
+        let client_ids = stmt
+            .query_map([], |r| r.get::<_, StoredUuid>(0))
+            .context("Error listing clients")?
+            .map(|r| r.map(|client_id| client_id.0))
+            .collect::<Result<Vec<_>, _>>()
+            .context("Error listing clients")?;
+        Ok(client_ids)
+    }
+
+    fn backend_info(&self) -> anyhow::Result<BackendInfo> {
+        let schema_version = self.schema_version()?;
+        let details = [
+            ("path", self.db_file.display().to_string()),
+            ("sqlite_version", rusqlite::version().to_string()),
+            ("external_segments", self.external_segments.to_string()),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+        Ok(BackendInfo {
+            name: "sqlite".into(),
+            schema_version: Some(schema_version.to_string()),
+            details,
+        })
+    }
+
+    fn schema_version(&self) -> anyhow::Result<u32> {
+        let _memory_guard = self.lock_memory();
+        let con = self.new_connection()?;
+        con.pragma_query_value(None, "user_version", |r| r.get(0))
+            .context("Error reading SQLite schema version")
+    }
+
+    fn required_schema_version(&self) -> u32 {
+        SCHEMA_VERSION
+    }
+
+    /// Rebuild the database with `VACUUM`, shrinking the file to the size of its data, and then
+    /// truncate the write-ahead log, which `VACUUM` fills with a copy of the database.
+    fn maintenance(&self) -> anyhow::Result<()> {
+        let _memory_guard = self.lock_memory();
+        // `VACUUM` cannot run within a transaction, so it runs on a new connection, which is
+        // in autocommit mode.
+        let con = self.new_connection()?;
+        con.execute_batch("VACUUM")
+            .context("Error vacuuming SQLite database")?;
+        con.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_row| Ok(()))
+            .context("Error checkpointing SQLite database")?;
+        Ok(())
+    }
+}
+
+/// The connection of a transaction. A connection for a read-only transaction is returned to the
+/// pool of idle read connections when the transaction is dropped.
+struct TxnConnection<'a> {
+    con: Option<Connection>,
+    read_connections: Option<&'a Mutex<Vec<Connection>>>,
+}
+
+impl Deref for TxnConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.con
+            .as_ref()
+            .expect("connection is present until dropped")
+    }
+}
+
+impl Drop for TxnConnection<'_> {
+    fn drop(&mut self) {
+        let (Some(con), Some(read_connections)) = (self.con.take(), self.read_connections) else {
+            return;
+        };
+        // End the transaction if it was not committed; a connection which cannot be rolled back
+        // is simply closed.
+        if !con.is_autocommit() && con.execute("ROLLBACK", []).is_err() {
+            return;
+        }
+        let mut read_connections = read_connections.lock().expect("poisoned lock");
+        if read_connections.len() < MAX_IDLE_READ_CONNECTIONS {
+            read_connections.push(con);
+        }
     }
 }
 
-struct Txn {
+struct Txn<'a> {
     // SQLite only allows one concurrent transaction per connection, and rusqlite emulates
     // transactions by running `BEGIN ...` and `COMMIT` at appropriate times. So we will do
     // the same.
-    con: Connection,
+    con: TxnConnection<'a>,
     client_id: Uuid,
+    /// Directory containing history segments stored as files.
+    segment_dir: PathBuf,
+    /// Store new history segments as files.
+    external_segments: bool,
+    /// Store snapshot timestamps in milliseconds, as well as seconds.
+    millisecond_timestamps: bool,
+    /// Segment files written in this transaction, which are removed if it is not committed.
+    new_segment_files: Vec<PathBuf>,
+    /// Segment files of versions deleted in this transaction, which are removed once it is
+    /// committed.
+    deleted_segment_files: Vec<PathBuf>,
+    committed: bool,
+    /// The lock on an in-memory database, released only after the connection, above, has ended
+    /// its transaction.
+    _memory_guard: Option<MutexGuard<'a, Connection>>,
 }
 
-impl Txn {
+impl Txn<'_> {
     /// Implementation for queries from the versions table
     fn get_version_impl(
         &mut self,
@@ -112,21 +569,214 @@ impl Txn {
                 |r| {
                     let version_id: StoredUuid = r.get("version_id")?;
                     let parent_version_id: StoredUuid = r.get("parent_version_id")?;
-
-                    Ok(Version {
-                        version_id: version_id.0,
-                        parent_version_id: parent_version_id.0,
-                        history_segment: r.get("history_segment")?,
-                    })
+                    Ok((
+                        version_id.0,
+                        parent_version_id.0,
+                        StoredSegment::from_row(r)?,
+                    ))
                 },
             )
             .optional()
             .context("Error getting version")?;
-        Ok(r)
+        r.map(|(version_id, parent_version_id, segment)| {
+            Ok(Version {
+                version_id,
+                parent_version_id,
+                history_segment: self.read_segment(segment)?,
+            })
+        })
+        .transpose()
+    }
+
+    /// Get the data of a stored history segment, reading it from its file if necessary.
+    fn read_segment(&self, segment: StoredSegment) -> anyhow::Result<Vec<u8>> {
+        match segment {
+            StoredSegment::Inline(data) => Ok(data),
+            StoredSegment::File(file) => {
+                let path = self.segment_dir.join(file);
+                std::fs::read(&path)
+                    .with_context(|| format!("Error reading history segment `{}`", path.display()))
+            }
+        }
+    }
+
+    /// Write a history segment of the given size to a new file for the given version, returning
+    /// the name of the file. The file is removed if the transaction is not committed.
+    fn write_segment_file(
+        &mut self,
+        version_id: Uuid,
+        size: u64,
+        reader: &mut dyn Read,
+    ) -> anyhow::Result<String> {
+        let file_name = version_id.to_string();
+        let path = self.segment_dir.join(&file_name);
+        self.new_segment_files.push(path.clone());
+        let mut file = File::create_new(&path)
+            .with_context(|| format!("Error creating history segment `{}`", path.display()))?;
+        let written = std::io::copy(&mut reader.take(size), &mut file)
+            .context("Error writing history segment")?;
+        if written != size {
+            anyhow::bail!("Expected {size} bytes of data, but got {written}");
+        }
+        // ensure the data is durable before the version referring to it is committed
+        file.sync_all().context("Error writing history segment")?;
+        Ok(file_name)
+    }
+
+    /// Set the client's snapshot, if its current snapshot has the expected version, returning
+    /// true if it was set.
+    fn update_snapshot(
+        &mut self,
+        snapshot: Snapshot,
+        data: &dyn ToSql,
+        expected_previous_version_id: Option<Uuid>,
+    ) -> anyhow::Result<bool> {
+        // `IS` compares NULL values as equal, so this matches a missing snapshot when
+        // `expected_previous_version_id` is None.
+        let modified = self
+            .con
+            .execute(
+                "UPDATE clients
+             SET
+               snapshot_version_id = ?,
+               snapshot_timestamp = ?,
+               snapshot_timestamp_millis = ?,
+               versions_since_snapshot = ?,
+               snapshot = ?
+             WHERE client_id = ? AND snapshot_version_id IS ?",
+                params![
+                    &StoredUuid(snapshot.version_id),
+                    snapshot.timestamp.timestamp(),
+                    self.millisecond_timestamps
+                        .then(|| snapshot.timestamp.timestamp_millis()),
+                    snapshot.versions_since,
+                    data,
+                    &StoredUuid(self.client_id),
+                    expected_previous_version_id.map(StoredUuid),
+                ],
+            )
+            .context("Error creating/updating snapshot")?;
+        Ok(modified == 1)
+    }
+
+    /// Insert a version and update the client accordingly, returning the rowid of the version.
+    /// The history segment is given either as data, or as the name of the file containing it.
+    fn insert_version(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        history_segment: &dyn ToSql,
+        history_segment_file: Option<&str>,
+    ) -> anyhow::Result<i64> {
+        self.con.execute(
+            "INSERT INTO versions (version_id, client_id, parent_version_id, history_segment, history_segment_file) VALUES(?, ?, ?, ?, ?)",
+            params![
+                StoredUuid(version_id),
+                StoredUuid(self.client_id),
+                StoredUuid(parent_version_id),
+                history_segment,
+                history_segment_file,
+            ]
+        )
+        .context("Error adding version")?;
+        let rowid = self.con.last_insert_rowid();
+        self.con
+            .execute(
+                "UPDATE clients
+             SET
+               latest_version_id = ?,
+               versions_since_snapshot = MIN(versions_since_snapshot + 1, ?)
+             WHERE client_id = ?",
+                params![StoredUuid(version_id), u32::MAX, StoredUuid(self.client_id),],
+            )
+            .context("Error updating client for new version")?;
+
+        Ok(rowid)
+    }
+
+    /// Fill in a blob, previously set to a [`ZeroBlob`] of the given size, from a reader. This
+    /// writes the data incrementally, without reading it into memory.
+    fn write_blob(
+        &mut self,
+        table: &str,
+        column: &str,
+        rowid: i64,
+        size: u64,
+        reader: &mut dyn Read,
+    ) -> anyhow::Result<()> {
+        let mut blob = self
+            .con
+            .blob_open(DatabaseName::Main, table, column, rowid, false)
+            .context("Error opening blob")?;
+        let written =
+            std::io::copy(&mut reader.take(size), &mut blob).context("Error writing blob")?;
+        if written != size {
+            anyhow::bail!("Expected {size} bytes of data, but got {written}");
+        }
+        Ok(())
     }
 }
 
-impl StorageTxn for Txn {
+impl Drop for Txn<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            // The versions referring to these files were never committed. Any file which cannot
+            // be removed is merely orphaned.
+            for path in &self.new_segment_files {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Get a placeholder for a blob of the given size, to be filled in with [`Txn::write_blob`].
+fn zero_blob(size: u64) -> anyhow::Result<ZeroBlob> {
+    Ok(ZeroBlob(size.try_into().context("Data too large")?))
+}
+
+/// Build a `Client` from a row containing the `latest_version_id`, `snapshot_timestamp`,
+/// `snapshot_timestamp_millis`, `versions_since_snapshot`, `snapshot_version_id`,
+/// `last_activity_at`, `chain_hash`, and `acked_version_id` columns.
+fn client_from_row(r: &rusqlite::Row) -> rusqlite::Result<Client> {
+    let latest_version_id: StoredUuid = r.get("latest_version_id")?;
+    let snapshot_timestamp: Option<i64> = r.get("snapshot_timestamp")?;
+    // the precise timestamp is NULL unless millisecond timestamps were enabled when the snapshot
+    // was stored
+    let snapshot_timestamp_millis: Option<i64> = r.get("snapshot_timestamp_millis")?;
+    let versions_since_snapshot: Option<i64> = r.get("versions_since_snapshot")?;
+    let snapshot_version_id: Option<StoredUuid> = r.get("snapshot_version_id")?;
+    let last_activity_at: Option<i64> = r.get("last_activity_at")?;
+    // the u64 hash is stored as an i64 with the same bits
+    let chain_hash: Option<i64> = r.get("chain_hash")?;
+    let acked_version_id: Option<StoredUuid> = r.get("acked_version_id")?;
+
+    // if all of the relevant fields are non-NULL, return a snapshot
+    let snapshot = match (
+        snapshot_timestamp,
+        versions_since_snapshot,
+        snapshot_version_id,
+    ) {
+        (Some(ts), Some(vs), Some(v)) => Some(Snapshot {
+            version_id: v.0,
+            timestamp: match snapshot_timestamp_millis {
+                Some(ms) => Utc.timestamp_millis_opt(ms).unwrap(),
+                None => Utc.timestamp_opt(ts, 0).unwrap(),
+            },
+            // clamp out-of-range values, rather than failing to load the client
+            versions_since: vs.clamp(0, u32::MAX.into()) as u32,
+        }),
+        _ => None,
+    };
+    Ok(Client {
+        latest_version_id: latest_version_id.0,
+        snapshot,
+        last_activity_at: last_activity_at.and_then(|ts| Utc.timestamp_opt(ts, 0).single()),
+        chain_hash: chain_hash.map(|hash| ChainHash(hash as u64)),
+        acked_version_id: acked_version_id.map(|v| v.0),
+    })
+}
+
+impl StorageTxn for Txn<'_> {
     fn get_client(&mut self) -> anyhow::Result<Option<Client>> {
         let result: Option<Client> = self
             .con
@@ -134,73 +784,154 @@ impl StorageTxn for Txn {
                 "SELECT
                     latest_version_id,
                     snapshot_timestamp,
+                    snapshot_timestamp_millis,
                     versions_since_snapshot,
-                    snapshot_version_id
+                    snapshot_version_id,
+                    last_activity_at,
+                    chain_hash,
+                    acked_version_id
                  FROM clients
                  WHERE client_id = ?
                  LIMIT 1",
                 [&StoredUuid(self.client_id)],
+                client_from_row,
+            )
+            .optional()
+            .context("Error getting client")?;
+
+        Ok(result)
+    }
+
+    fn get_client_with_latest_version(
+        &mut self,
+    ) -> anyhow::Result<Option<(Client, Option<Version>)>> {
+        let result = self
+            .con
+            .query_row(
+                "SELECT
+                    clients.latest_version_id,
+                    clients.snapshot_timestamp,
+                    clients.snapshot_timestamp_millis,
+                    clients.versions_since_snapshot,
+                    clients.snapshot_version_id,
+                    clients.last_activity_at,
+                    clients.chain_hash,
+                    clients.acked_version_id,
+                    versions.version_id,
+                    versions.parent_version_id,
+                    versions.history_segment,
+                    versions.history_segment_file
+                 FROM clients
+                 LEFT JOIN versions
+                   ON versions.version_id = clients.latest_version_id
+                   AND versions.client_id = clients.client_id
+                 WHERE clients.client_id = ?
+                 LIMIT 1",
+                [&StoredUuid(self.client_id)],
                 |r| {
-                    let latest_version_id: StoredUuid = r.get(0)?;
-                    let snapshot_timestamp: Option<i64> = r.get(1)?;
-                    let versions_since_snapshot: Option<u32> = r.get(2)?;
-                    let snapshot_version_id: Option<StoredUuid> = r.get(3)?;
-
-                    // if all of the relevant fields are non-NULL, return a snapshot
-                    let snapshot = match (
-                        snapshot_timestamp,
-                        versions_since_snapshot,
-                        snapshot_version_id,
-                    ) {
-                        (Some(ts), Some(vs), Some(v)) => Some(Snapshot {
-                            version_id: v.0,
-                            timestamp: Utc.timestamp_opt(ts, 0).unwrap(),
-                            versions_since: vs,
-                        }),
-                        _ => None,
+                    let client = client_from_row(r)?;
+                    let version_id: Option<StoredUuid> = r.get("version_id")?;
+                    let version = match version_id {
+                        Some(version_id) => {
+                            let parent_version_id: StoredUuid = r.get("parent_version_id")?;
+                            Some((
+                                version_id.0,
+                                parent_version_id.0,
+                                StoredSegment::from_row(r)?,
+                            ))
+                        }
+                        None => None,
                     };
-                    Ok(Client {
-                        latest_version_id: latest_version_id.0,
-                        snapshot,
-                    })
+                    Ok((client, version))
                 },
             )
             .optional()
-            .context("Error getting client")?;
+            .context("Error getting client with latest version")?;
 
-        Ok(result)
+        let Some((client, version)) = result else {
+            return Ok(None);
+        };
+        let version = version
+            .map(|(version_id, parent_version_id, segment)| {
+                Ok::<_, anyhow::Error>(Version {
+                    version_id,
+                    parent_version_id,
+                    history_segment: self.read_segment(segment)?,
+                })
+            })
+            .transpose()?;
+        Ok(Some((client, version)))
+    }
+
+    fn get_client_token_hash(&mut self) -> anyhow::Result<Option<String>> {
+        // The `token_hash` column is not part of the schema created here, but may be added to the
+        // `clients` table by an external application which provisions tokens.
+        let has_column: bool = self
+            .con
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('clients') WHERE name = 'token_hash'",
+                [],
+                |r| r.get(0),
+            )
+            .context("Error checking for token_hash column")?;
+        if !has_column {
+            return Ok(None);
+        }
+        let token_hash: Option<Option<String>> = self
+            .con
+            .query_row(
+                "SELECT token_hash FROM clients WHERE client_id = ?",
+                [&StoredUuid(self.client_id)],
+                |r| r.get(0),
+            )
+            .optional()
+            .context("Error getting client token hash")?;
+        Ok(token_hash.flatten())
     }
 
     fn new_client(&mut self, latest_version_id: Uuid) -> anyhow::Result<()> {
-        self.con
+        let inserted = self
+            .con
             .execute(
-                "INSERT OR REPLACE INTO clients (client_id, latest_version_id) VALUES (?, ?)",
+                "INSERT OR IGNORE INTO clients (client_id, latest_version_id) VALUES (?, ?)",
                 params![&StoredUuid(self.client_id), &StoredUuid(latest_version_id)],
             )
-            .context("Error creating/updating client")?;
+            .context("Error creating client")?;
+        if inserted == 0 {
+            return Err(ServerError::ClientExists.into());
+        }
         Ok(())
     }
 
-    fn set_snapshot(&mut self, snapshot: Snapshot, data: Vec<u8>) -> anyhow::Result<()> {
-        self.con
-            .execute(
-                "UPDATE clients
-             SET
-               snapshot_version_id = ?,
-               snapshot_timestamp = ?,
-               versions_since_snapshot = ?,
-               snapshot = ?
-             WHERE client_id = ?",
-                params![
-                    &StoredUuid(snapshot.version_id),
-                    snapshot.timestamp.timestamp(),
-                    snapshot.versions_since,
-                    data,
-                    &StoredUuid(self.client_id),
-                ],
+    fn set_snapshot(
+        &mut self,
+        snapshot: Snapshot,
+        data: Vec<u8>,
+        expected_previous_version_id: Option<Uuid>,
+    ) -> anyhow::Result<bool> {
+        self.update_snapshot(snapshot, &data, expected_previous_version_id)
+    }
+
+    fn set_snapshot_from_reader(
+        &mut self,
+        snapshot: Snapshot,
+        size: u64,
+        data: &mut dyn Read,
+        expected_previous_version_id: Option<Uuid>,
+    ) -> anyhow::Result<bool> {
+        if !self.update_snapshot(snapshot, &zero_blob(size)?, expected_previous_version_id)? {
+            return Ok(false);
+        }
+        let rowid: i64 = self
+            .con
+            .query_row(
+                "SELECT rowid FROM clients WHERE client_id = ?",
+                [&StoredUuid(self.client_id)],
+                |r| r.get(0),
             )
-            .context("Error creating/updating snapshot")?;
-        Ok(())
+            .context("Error getting client")?;
+        self.write_blob("clients", "snapshot", rowid, size, data)?;
+        Ok(true)
     }
 
     fn get_snapshot_data(&mut self, version_id: Uuid) -> anyhow::Result<Option<Vec<u8>>> {
@@ -227,57 +958,322 @@ impl StorageTxn for Txn {
         .transpose()
     }
 
+    fn get_snapshot_size(&mut self, version_id: Uuid) -> anyhow::Result<Option<u64>> {
+        let r = self
+            .con
+            .query_row(
+                "SELECT length(snapshot), snapshot_version_id FROM clients WHERE client_id = ?",
+                params![&StoredUuid(self.client_id)],
+                |r| {
+                    let v: StoredUuid = r.get("snapshot_version_id")?;
+                    let s: u64 = r.get(0)?;
+                    Ok((v.0, s))
+                },
+            )
+            .optional()
+            .context("Error getting snapshot size")?;
+        r.map(|(v, s)| {
+            if v != version_id {
+                return Err(anyhow::anyhow!("unexpected snapshot_version_id"));
+            }
+
+            Ok(s)
+        })
+        .transpose()
+    }
+
     fn get_version_by_parent(
         &mut self,
 
         parent_version_id: Uuid,
     ) -> anyhow::Result<Option<Version>> {
         self.get_version_impl(
-            "SELECT version_id, parent_version_id, history_segment FROM versions WHERE parent_version_id = ? AND client_id = ?",
+            "SELECT version_id, parent_version_id, history_segment, history_segment_file FROM versions WHERE parent_version_id = ? AND client_id = ?",
             self.client_id,
             parent_version_id)
     }
 
     fn get_version(&mut self, version_id: Uuid) -> anyhow::Result<Option<Version>> {
         self.get_version_impl(
-            "SELECT version_id, parent_version_id, history_segment FROM versions WHERE version_id = ? AND client_id = ?",
+            "SELECT version_id, parent_version_id, history_segment, history_segment_file FROM versions WHERE version_id = ? AND client_id = ?",
             self.client_id,
             version_id)
     }
 
-    fn add_version(
-        &mut self,
+    fn get_head_version_ids(&mut self) -> anyhow::Result<Vec<Uuid>> {
+        let mut stmt = self
+            .con
+            .prepare(
+                "SELECT version_id FROM versions AS v
+                 WHERE client_id = ?
+                   AND NOT EXISTS (
+                     SELECT 1 FROM versions AS c
+                     WHERE c.client_id = v.client_id AND c.parent_version_id = v.version_id)",
+            )
+            .context("Error preparing query for head versions")?;
+        let version_ids = stmt
+            .query_map([&StoredUuid(self.client_id)], |r| {
+                let version_id: StoredUuid = r.get(0)?;
+                Ok(version_id.0)
+            })
+            .context("Error getting head versions")?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Error getting head versions")?;
+        Ok(version_ids)
+    }
+
+    fn get_client_usage(&mut self) -> anyhow::Result<ClientUsage> {
+        let (version_count, total_history_bytes): (u64, u64) = self
+            .con
+            .query_row(
+                "SELECT COUNT(*), COALESCE(SUM(length(history_segment)), 0)
+                 FROM versions WHERE client_id = ?",
+                [&StoredUuid(self.client_id)],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .context("Error getting version usage")?;
+
+        // segments stored in files have no length in the database
+        let mut stmt = self
+            .con
+            .prepare(
+                "SELECT history_segment_file FROM versions
+                 WHERE client_id = ? AND history_segment_file IS NOT NULL",
+            )
+            .context("Error preparing query for history segment files")?;
+        let files = stmt
+            .query_map([&StoredUuid(self.client_id)], |r| r.get::<_, String>(0))
+            .context("Error getting history segment files")?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Error getting history segment files")?;
+        drop(stmt);
+        let mut total_history_bytes = total_history_bytes;
+        for file in files {
+            let path = self.segment_dir.join(file);
+            total_history_bytes += std::fs::metadata(&path)
+                .with_context(|| format!("Error reading history segment `{}`", path.display()))?
+                .len();
+        }
+
+        let snapshot_bytes: Option<u64> = self
+            .con
+            .query_row(
+                "SELECT length(snapshot) FROM clients WHERE client_id = ?",
+                [&StoredUuid(self.client_id)],
+                |r| r.get(0),
+            )
+            .optional()
+            .context("Error getting snapshot usage")?
+            .flatten();
+        Ok(ClientUsage {
+            version_count,
+            total_history_bytes,
+            snapshot_bytes: snapshot_bytes.unwrap_or(0),
+        })
+    }
+
+    fn set_latest_version_id(&mut self, latest_version_id: Uuid) -> anyhow::Result<()> {
+        self.con
+            .execute(
+                "UPDATE clients SET latest_version_id = ? WHERE client_id = ?",
+                params![StoredUuid(latest_version_id), StoredUuid(self.client_id)],
+            )
+            .context("Error setting latest version")?;
+        Ok(())
+    }
+
+    fn set_last_activity(&mut self, timestamp: DateTime<Utc>) -> anyhow::Result<()> {
+        self.con
+            .execute(
+                "UPDATE clients SET last_activity_at = ? WHERE client_id = ?",
+                params![timestamp.timestamp(), StoredUuid(self.client_id)],
+            )
+            .context("Error setting last activity")?;
+        Ok(())
+    }
+
+    fn set_chain_hash(&mut self, chain_hash: Option<ChainHash>) -> anyhow::Result<()> {
+        self.con
+            .execute(
+                "UPDATE clients SET chain_hash = ? WHERE client_id = ?",
+                params![
+                    chain_hash.map(|hash| hash.0 as i64),
+                    StoredUuid(self.client_id)
+                ],
+            )
+            .context("Error setting chain hash")?;
+        Ok(())
+    }
+
+    fn set_acked_version_id(&mut self, acked_version_id: Option<Uuid>) -> anyhow::Result<()> {
+        self.con
+            .execute(
+                "UPDATE clients SET acked_version_id = ? WHERE client_id = ?",
+                params![acked_version_id.map(StoredUuid), StoredUuid(self.client_id)],
+            )
+            .context("Error setting acked version")?;
+        Ok(())
+    }
+
+    fn get_app_metadata(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let app_metadata: Option<Option<Vec<u8>>> = self
+            .con
+            .query_row(
+                "SELECT app_metadata FROM clients WHERE client_id = ?",
+                [&StoredUuid(self.client_id)],
+                |r| r.get(0),
+            )
+            .optional()
+            .context("Error getting app metadata")?;
+        Ok(app_metadata.flatten())
+    }
+
+    fn set_app_metadata(&mut self, app_metadata: Vec<u8>) -> anyhow::Result<()> {
+        self.con
+            .execute(
+                "UPDATE clients SET app_metadata = ? WHERE client_id = ?",
+                params![app_metadata, StoredUuid(self.client_id)],
+            )
+            .context("Error setting app metadata")?;
+        Ok(())
+    }
+
+    fn add_version(
+        &mut self,
 
         version_id: Uuid,
         parent_version_id: Uuid,
         history_segment: Vec<u8>,
     ) -> anyhow::Result<()> {
-        self.con.execute(
-            "INSERT INTO versions (version_id, client_id, parent_version_id, history_segment) VALUES(?, ?, ?, ?)",
-            params![
-                StoredUuid(version_id),
-                StoredUuid(self.client_id),
-                StoredUuid(parent_version_id),
-                history_segment
-            ]
-        )
-        .context("Error adding version")?;
+        if self.external_segments {
+            let size = history_segment.len() as u64;
+            let file =
+                self.write_segment_file(version_id, size, &mut history_segment.as_slice())?;
+            self.insert_version(version_id, parent_version_id, &None::<Vec<u8>>, Some(&file))?;
+        } else {
+            self.insert_version(version_id, parent_version_id, &history_segment, None)?;
+        }
+        Ok(())
+    }
+
+    fn add_version_from_reader(
+        &mut self,
+        version_id: Uuid,
+        parent_version_id: Uuid,
+        size: u64,
+        history_segment: &mut dyn Read,
+    ) -> anyhow::Result<()> {
+        if self.external_segments {
+            let file = self.write_segment_file(version_id, size, history_segment)?;
+            self.insert_version(version_id, parent_version_id, &None::<Vec<u8>>, Some(&file))?;
+            return Ok(());
+        }
+        let rowid = self.insert_version(version_id, parent_version_id, &zero_blob(size)?, None)?;
+        self.write_blob("versions", "history_segment", rowid, size, history_segment)
+    }
+
+    fn delete_versions_before(&mut self, version_id: Uuid) -> anyhow::Result<()> {
+        // UNION, rather than UNION ALL, ensures this terminates even if the chain has a cycle.
+        let mut stmt = self
+            .con
+            .prepare(
+                "WITH RECURSIVE ancestors(version_id) AS (
+                   SELECT parent_version_id FROM versions
+                   WHERE client_id = ?1 AND version_id = ?2
+                   UNION
+                   SELECT v.parent_version_id FROM versions AS v
+                   JOIN ancestors AS a ON v.version_id = a.version_id
+                   WHERE v.client_id = ?1)
+                 DELETE FROM versions
+                 WHERE client_id = ?1 AND version_id IN ancestors
+                 RETURNING history_segment_file",
+            )
+            .context("Error preparing query to delete versions")?;
+        let files = stmt
+            .query_map(
+                params![StoredUuid(self.client_id), StoredUuid(version_id)],
+                |r| r.get::<_, Option<String>>(0),
+            )
+            .context("Error deleting versions")?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Error deleting versions")?;
+        drop(stmt);
+        self.deleted_segment_files.extend(
+            files
+                .into_iter()
+                .flatten()
+                .map(|file| self.segment_dir.join(file)),
+        );
+        Ok(())
+    }
+
+    fn rename_client(&mut self, new_client_id: Uuid) -> anyhow::Result<bool> {
+        let exists: bool = self
+            .con
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM clients WHERE client_id = ?)",
+                [&StoredUuid(new_client_id)],
+                |r| r.get(0),
+            )
+            .context("Error checking for client")?;
+        if exists {
+            return Ok(false);
+        }
+        let updated = self
+            .con
+            .execute(
+                "UPDATE clients SET client_id = ? WHERE client_id = ?",
+                params![StoredUuid(new_client_id), StoredUuid(self.client_id)],
+            )
+            .context("Error renaming client")?;
+        if updated == 0 {
+            anyhow::bail!("Client {} does not exist", self.client_id);
+        }
         self.con
             .execute(
-                "UPDATE clients
-             SET
-               latest_version_id = ?,
-               versions_since_snapshot = versions_since_snapshot + 1
-             WHERE client_id = ?",
-                params![StoredUuid(version_id), StoredUuid(self.client_id),],
+                "UPDATE versions SET client_id = ? WHERE client_id = ?",
+                params![StoredUuid(new_client_id), StoredUuid(self.client_id)],
             )
-            .context("Error updating client for new version")?;
+            .context("Error renaming client versions")?;
+        self.client_id = new_client_id;
+        Ok(true)
+    }
 
+    fn delete_client(&mut self) -> anyhow::Result<()> {
+        let mut stmt = self
+            .con
+            .prepare("DELETE FROM versions WHERE client_id = ? RETURNING history_segment_file")
+            .context("Error preparing query to delete versions")?;
+        let files = stmt
+            .query_map([&StoredUuid(self.client_id)], |r| {
+                r.get::<_, Option<String>>(0)
+            })
+            .context("Error deleting versions")?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Error deleting versions")?;
+        drop(stmt);
+        self.deleted_segment_files.extend(
+            files
+                .into_iter()
+                .flatten()
+                .map(|file| self.segment_dir.join(file)),
+        );
+        self.con
+            .execute(
+                "DELETE FROM clients WHERE client_id = ?",
+                [&StoredUuid(self.client_id)],
+            )
+            .context("Error deleting client")?;
         Ok(())
     }
 
     fn commit(&mut self) -> anyhow::Result<()> {
         self.con.execute("COMMIT", [])?;
+        self.committed = true;
+        // The versions are gone, so any file which cannot be removed is merely orphaned.
+        for path in self.deleted_segment_files.drain(..) {
+            let _ = std::fs::remove_file(path);
+        }
         Ok(())
     }
 }
@@ -285,8 +1281,10 @@ impl StorageTxn for Txn {
 #[cfg(test)]
 mod test {
     use super::*;
-    use chrono::DateTime;
     use pretty_assertions::assert_eq;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use taskchampion_sync_server_core::{check_schema_version, NIL_VERSION_ID};
     use tempfile::TempDir;
 
     #[test]
@@ -301,6 +1299,297 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_global_stats() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        assert_eq!(storage.global_stats()?, GlobalStats::default());
+
+        for i in 0..3 {
+            let mut txn = storage.txn(Uuid::new_v4())?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![])?;
+            if i == 0 {
+                let snap = Snapshot {
+                    version_id: Uuid::new_v4(),
+                    timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+                    versions_since: 0,
+                };
+                txn.set_snapshot(snap, vec![1], None)?;
+            }
+            txn.commit()?;
+        }
+
+        assert_eq!(
+            storage.global_stats()?,
+            GlobalStats {
+                clients: 3,
+                clients_with_snapshot: 1,
+                versions: 3,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_clients() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        assert!(storage.list_clients()?.is_empty());
+
+        let client_ids: HashSet<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for client_id in &client_ids {
+            let mut txn = storage.txn(*client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.commit()?;
+        }
+
+        let listed: HashSet<Uuid> = storage.list_clients()?.into_iter().collect();
+        assert_eq!(listed, client_ids);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backend_info() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let info = storage.backend_info()?;
+        assert_eq!(info.name, "sqlite");
+        assert_eq!(info.schema_version, Some(SCHEMA_VERSION.to_string()));
+        assert_eq!(
+            info.details.get("path"),
+            Some(
+                &tmp_dir
+                    .path()
+                    .join("taskchampion-sync-server.sqlite3")
+                    .display()
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            info.details.get("external_segments"),
+            Some(&"false".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_client_usage() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_client_usage()?, ClientUsage::default());
+
+        let (v1, v2, v3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        txn.add_version(v1, NIL_VERSION_ID, vec![1; 10])?;
+        txn.add_version(v2, v1, vec![2; 20])?;
+        txn.add_version(v3, v2, vec![3; 30])?;
+        let snap = Snapshot {
+            version_id: v3,
+            timestamp: Utc::now(),
+            versions_since: 0,
+        };
+        txn.set_snapshot(snap, vec![4; 5], None)?;
+        txn.commit()?;
+        drop(txn);
+
+        // another client's versions are not counted
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![5; 100])?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(
+            txn.get_client_usage()?,
+            ClientUsage {
+                version_count: 3,
+                total_history_bytes: 60,
+                snapshot_bytes: 5,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema_version() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        assert_eq!(storage.schema_version()?, SCHEMA_VERSION);
+        check_schema_version(&storage)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema_version_newer() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        SqliteStorage::new(tmp_dir.path())?;
+
+        // simulate a migration by a newer server
+        let con = Connection::open(tmp_dir.path().join("taskchampion-sync-server.sqlite3"))?;
+        con.pragma_update(None, "user_version", SCHEMA_VERSION + 1)?;
+        drop(con);
+
+        // opening the storage leaves the newer version in place, and the check fails
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        assert_eq!(storage.schema_version()?, SCHEMA_VERSION + 1);
+        let err = check_schema_version(&storage).unwrap_err();
+        assert!(err.to_string().contains("newer"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_client_exists() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(version_id)?;
+        txn.commit()?;
+        drop(txn);
+
+        // a second transaction creating the same client sees that it exists, and does not
+        // change it
+        let mut txn = storage.txn(client_id)?;
+        let err = txn.new_client(NIL_VERSION_ID).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ServerError::ClientExists)
+        ));
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory() -> anyhow::Result<()> {
+        let storage = SqliteStorage::new_in_memory()?;
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, b"abc".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        // the data is visible to later transactions, on other connections
+        let mut txn = storage.read_txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        drop(txn);
+        let mut txn = storage.txn(client_id)?;
+        let version = txn.get_version(version_id)?.unwrap();
+        assert_eq!(version.history_segment, b"abc".to_vec());
+        drop(txn);
+        assert_eq!(storage.global_stats()?.versions, 1);
+        check_schema_version(&storage)?;
+
+        // each instance has its own database
+        let other = SqliteStorage::new_in_memory()?;
+        assert_eq!(other.txn(client_id)?.get_client()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_concurrent_writes() -> anyhow::Result<()> {
+        let storage = SqliteStorage::new_in_memory()?;
+
+        // transactions on different threads wait for one another
+        std::thread::scope(|scope| {
+            let writers: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| -> anyhow::Result<()> {
+                        let mut txn = storage.txn(Uuid::new_v4())?;
+                        txn.new_client(NIL_VERSION_ID)?;
+                        std::thread::sleep(Duration::from_millis(10));
+                        txn.commit()
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.join().unwrap()?;
+            }
+            anyhow::Ok(())
+        })?;
+        assert_eq!(storage.global_stats()?.clients, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_txns_concurrent() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(version_id)?;
+        txn.commit()?;
+        drop(txn);
+
+        // while a write is in progress, any number of reads proceed, seeing only committed data
+        let mut write_txn = storage.txn(client_id)?;
+        write_txn.add_version(Uuid::new_v4(), version_id, b"abc".to_vec())?;
+        let mut read_txn1 = storage.read_txn(client_id)?;
+        let mut read_txn2 = storage.read_txn(client_id)?;
+        assert_eq!(
+            read_txn1.get_client()?.unwrap().latest_version_id,
+            version_id
+        );
+        assert_eq!(
+            read_txn2.get_client()?.unwrap().latest_version_id,
+            version_id
+        );
+
+        // a read-only transaction cannot write
+        assert!(read_txn1.set_last_activity(Utc::now()).is_err());
+
+        write_txn.commit()?;
+        drop(write_txn);
+        drop(read_txn1);
+        read_txn2.commit()?;
+        drop(read_txn2);
+
+        // the connections are reused, without any uncommitted transaction
+        assert_eq!(storage.read_connections.lock().unwrap().len(), 2);
+        let mut read_txn = storage.read_txn(client_id)?;
+        assert_ne!(
+            read_txn.get_client()?.unwrap().latest_version_id,
+            version_id
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_txns_serialize() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let released = AtomicBool::new(false);
+
+        let txn = storage.txn(client_id)?;
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| -> anyhow::Result<bool> {
+                let _txn = storage.txn(client_id)?;
+                Ok(released.load(Ordering::SeqCst))
+            });
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            released.store(true, Ordering::SeqCst);
+            drop(txn);
+            // the second transaction only began once the first was dropped
+            assert!(waiter.join().unwrap()?);
+            Ok(())
+        })
+    }
+
     #[test]
     fn test_get_client_empty() -> anyhow::Result<()> {
         let tmp_dir = TempDir::new()?;
@@ -338,7 +1627,7 @@ mod test {
             timestamp: "2014-11-28T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
             versions_since: 4,
         };
-        txn.set_snapshot(snap.clone(), vec![1, 2, 3])?;
+        txn.set_snapshot(snap.clone(), vec![1, 2, 3], None)?;
 
         let client = txn.get_client()?.unwrap();
         assert_eq!(client.latest_version_id, latest_version_id);
@@ -348,40 +1637,145 @@ mod test {
     }
 
     #[test]
-    fn test_gvbp_empty() -> anyhow::Result<()> {
+    fn test_uncommitted() -> anyhow::Result<()> {
         let tmp_dir = TempDir::new()?;
         let storage = SqliteStorage::new(tmp_dir.path())?;
         let client_id = Uuid::new_v4();
+
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![])?;
+            // dropped without committing
+        }
+
         let mut txn = storage.txn(client_id)?;
-        let maybe_version = txn.get_version_by_parent(Uuid::new_v4())?;
-        assert!(maybe_version.is_none());
+        assert_eq!(txn.get_client()?, None);
+        assert!(txn.get_version_by_parent(NIL_VERSION_ID)?.is_none());
         Ok(())
     }
 
     #[test]
-    fn test_add_version_and_get_version() -> anyhow::Result<()> {
+    fn test_uncommitted_existing_client() -> anyhow::Result<()> {
         let tmp_dir = TempDir::new()?;
         let storage = SqliteStorage::new(tmp_dir.path())?;
         let client_id = Uuid::new_v4();
-        let mut txn = storage.txn(client_id)?;
-
         let version_id = Uuid::new_v4();
-        let parent_version_id = Uuid::new_v4();
-        let history_segment = b"abc".to_vec();
-        txn.add_version(version_id, parent_version_id, history_segment.clone())?;
 
-        let expected = Version {
-            version_id,
-            parent_version_id,
-            history_segment,
-        };
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, b"v1".to_vec())?;
+        txn.commit()?;
+        drop(txn);
 
-        let version = txn.get_version_by_parent(parent_version_id)?.unwrap();
-        assert_eq!(version, expected);
+        {
+            let mut txn = storage.txn(client_id)?;
+            txn.add_version(Uuid::new_v4(), version_id, b"v2".to_vec())?;
+            let snap = Snapshot {
+                version_id,
+                timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+                versions_since: 0,
+            };
+            txn.set_snapshot(snap, vec![1, 2, 3], None)?;
+            // dropped without committing
+        }
 
-        let version = txn.get_version(version_id)?.unwrap();
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, version_id);
+        assert_eq!(client.snapshot, None);
+        assert!(txn.get_version_by_parent(version_id)?.is_none());
+        assert_eq!(txn.get_head_version_ids()?, vec![version_id]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_client_with_latest_version() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        // no such client
+        assert_eq!(txn.get_client_with_latest_version()?, None);
+
+        // client without any versions
+        txn.new_client(NIL_VERSION_ID)?;
+        let (client, version) = txn.get_client_with_latest_version()?.unwrap();
+        assert_eq!(Some(client), txn.get_client()?);
+        assert_eq!(version, None);
+
+        // client with versions
+        let version_id_1 = Uuid::new_v4();
+        let version_id_2 = Uuid::new_v4();
+        txn.add_version(version_id_1, NIL_VERSION_ID, b"v1".to_vec())?;
+        txn.add_version(version_id_2, version_id_1, b"v2".to_vec())?;
+        let (client, version) = txn.get_client_with_latest_version()?.unwrap();
+        assert_eq!(client.latest_version_id, version_id_2);
+        assert_eq!(Some(client), txn.get_client()?);
+        assert_eq!(version, txn.get_version(version_id_2)?);
+        assert_eq!(version.unwrap().history_segment, b"v2".to_vec());
+
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_gvbp_empty() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        let maybe_version = txn.get_version_by_parent(Uuid::new_v4())?;
+        assert!(maybe_version.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_version_and_get_version() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        let version_id = Uuid::new_v4();
+        let parent_version_id = Uuid::new_v4();
+        let history_segment = b"abc".to_vec();
+        txn.add_version(version_id, parent_version_id, history_segment.clone())?;
+
+        let expected = Version {
+            version_id,
+            parent_version_id,
+            history_segment,
+        };
+
+        let version = txn.get_version_by_parent(parent_version_id)?.unwrap();
         assert_eq!(version, expected);
 
+        let version = txn.get_version(version_id)?.unwrap();
+        assert_eq!(version, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_version_empty() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        let version_id = Uuid::new_v4();
+        let parent_version_id = Uuid::new_v4();
+        txn.add_version(version_id, parent_version_id, vec![])?;
+
+        // a zero-length history segment is stored as an empty BLOB, not NULL
+        let version = txn.get_version(version_id)?.unwrap();
+        assert_eq!(version.history_segment, Vec::<u8>::new());
+
+        let version = txn.get_version_by_parent(parent_version_id)?.unwrap();
+        assert_eq!(version.history_segment, Vec::<u8>::new());
+
         Ok(())
     }
 
@@ -402,6 +1796,800 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_head_version_ids() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+
+        // versions for another client are not included
+        {
+            let mut txn = storage.txn(Uuid::new_v4())?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(Uuid::new_v4(), NIL_VERSION_ID, vec![])?;
+            txn.commit()?;
+        }
+
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        assert!(txn.get_head_version_ids()?.is_empty());
+
+        let version_id_1 = Uuid::new_v4();
+        let version_id_2 = Uuid::new_v4();
+        txn.add_version(version_id_1, NIL_VERSION_ID, vec![])?;
+        txn.add_version(version_id_2, version_id_1, vec![])?;
+        assert_eq!(txn.get_head_version_ids()?, vec![version_id_2]);
+
+        // a second child of the same parent is another head
+        let version_id_3 = Uuid::new_v4();
+        txn.add_version(version_id_3, version_id_1, vec![])?;
+        let mut heads = txn.get_head_version_ids()?;
+        heads.sort();
+        let mut expected = vec![version_id_2, version_id_3];
+        expected.sort();
+        assert_eq!(heads, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_latest_version_id() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        let snap = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 3,
+        };
+        txn.set_snapshot(snap.clone(), vec![1], None)?;
+
+        let latest_version_id = Uuid::new_v4();
+        txn.set_latest_version_id(latest_version_id)?;
+
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.latest_version_id, latest_version_id);
+        assert_eq!(client.snapshot, Some(snap));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_last_activity() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_client()?.unwrap().last_activity_at, None);
+
+        let timestamp = "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap();
+        txn.set_last_activity(timestamp)?;
+        txn.commit()?;
+
+        let mut txn = storage.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.last_activity_at, Some(timestamp));
+        assert_eq!(client.latest_version_id, NIL_VERSION_ID);
+        let (client, _) = txn.get_client_with_latest_version()?.unwrap();
+        assert_eq!(client.last_activity_at, Some(timestamp));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_chain_hash() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_client()?.unwrap().chain_hash, None);
+
+        // hashes with the high bit set round-trip through SQLite's signed integers
+        for chain_hash in [ChainHash(u64::MAX), ChainHash(1), ChainHash::EMPTY] {
+            txn.set_chain_hash(Some(chain_hash))?;
+            assert_eq!(txn.get_client()?.unwrap().chain_hash, Some(chain_hash));
+            let (client, _) = txn.get_client_with_latest_version()?.unwrap();
+            assert_eq!(client.chain_hash, Some(chain_hash));
+        }
+
+        txn.set_chain_hash(None)?;
+        assert_eq!(txn.get_client()?.unwrap().chain_hash, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_acked_version_id() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_client()?.unwrap().acked_version_id, None);
+
+        let version_id = Uuid::new_v4();
+        txn.set_acked_version_id(Some(version_id))?;
+        assert_eq!(
+            txn.get_client()?.unwrap().acked_version_id,
+            Some(version_id)
+        );
+        let (client, _) = txn.get_client_with_latest_version()?.unwrap();
+        assert_eq!(client.acked_version_id, Some(version_id));
+
+        txn.set_acked_version_id(None)?;
+        assert_eq!(txn.get_client()?.unwrap().acked_version_id, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_app_metadata() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_app_metadata()?, None);
+
+        txn.new_client(NIL_VERSION_ID)?;
+        assert_eq!(txn.get_app_metadata()?, None);
+        txn.set_app_metadata(b"meta".to_vec())?;
+        assert_eq!(txn.get_app_metadata()?, Some(b"meta".to_vec()));
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_app_metadata()?, Some(b"meta".to_vec()));
+        txn.set_app_metadata(vec![])?;
+        assert_eq!(txn.get_app_metadata()?, Some(vec![]));
+        drop(txn);
+
+        // the new value was not committed
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_app_metadata()?, Some(b"meta".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_client_token_hash() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+
+        // without the column, there is no token
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client_token_hash()?, None);
+        drop(txn);
+
+        let con = storage.new_connection()?;
+        con.execute("ALTER TABLE clients ADD COLUMN token_hash STRING", [])?;
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client_token_hash()?, None);
+        drop(txn);
+
+        con.execute(
+            "UPDATE clients SET token_hash = 'abc123' WHERE client_id = ?",
+            [StoredUuid(client_id)],
+        )?;
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client_token_hash()?, Some("abc123".into()));
+        drop(txn);
+
+        // a nonexistent client has no token
+        let mut txn = storage.txn(Uuid::new_v4())?;
+        assert_eq!(txn.get_client_token_hash()?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_database() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        std::fs::write(
+            tmp_dir.path().join("taskchampion-sync-server.sqlite3"),
+            vec![0x55; 8192],
+        )?;
+        let Err(err) = SqliteStorage::new(tmp_dir.path()) else {
+            panic!("corrupt database was opened");
+        };
+        assert!(err.to_string().contains("is corrupt"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_without_integrity_check() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let options = SqliteOptions {
+            integrity_check: false,
+            ..SqliteOptions::default()
+        };
+        let storage = SqliteStorage::open_with_options(tmp_dir.path(), options.clone())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
+        drop(storage);
+
+        let storage = SqliteStorage::open_with_options(tmp_dir.path(), options)?;
+        assert!(storage.txn(client_id)?.get_client()?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_recreate_missing_index() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let db_file = tmp_dir.path().join("taskchampion-sync-server.sqlite3");
+        let index_exists = || -> anyhow::Result<bool> {
+            let con = Connection::open(&db_file)?;
+            Ok(con.query_row(
+                "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'index' AND name = 'versions_by_parent'",
+                [],
+                |r| r.get(0),
+            )?)
+        };
+
+        SqliteStorage::new(tmp_dir.path())?;
+        assert!(index_exists()?);
+
+        Connection::open(&db_file)?.execute("DROP INDEX versions_by_parent", [])?;
+        assert!(!index_exists()?);
+
+        SqliteStorage::new(tmp_dir.path())?;
+        assert!(index_exists()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_schema() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let client_id = Uuid::new_v4();
+
+        // create a database with the schema from before `last_activity_at` was added
+        let con = Connection::open(tmp_dir.path().join("taskchampion-sync-server.sqlite3"))?;
+        con.execute(
+            "CREATE TABLE clients (
+                client_id STRING PRIMARY KEY,
+                latest_version_id STRING,
+                snapshot_version_id STRING,
+                versions_since_snapshot INTEGER,
+                snapshot_timestamp INTEGER,
+                snapshot BLOB)",
+            [],
+        )?;
+        con.execute(
+            "INSERT INTO clients (client_id, latest_version_id) VALUES (?, ?)",
+            params![StoredUuid(client_id), StoredUuid(NIL_VERSION_ID)],
+        )?;
+        drop(con);
+
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().last_activity_at, None);
+        assert_eq!(txn.get_client()?.unwrap().chain_hash, None);
+        assert_eq!(txn.get_app_metadata()?, None);
+        let timestamp = "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap();
+        txn.set_last_activity(timestamp)?;
+        txn.set_app_metadata(b"meta".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        // opening the upgraded database again leaves it unchanged
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().last_activity_at, Some(timestamp));
+        assert_eq!(txn.get_app_metadata()?, Some(b"meta".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_version_versions_since_saturates() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        let snap = Snapshot {
+            version_id: NIL_VERSION_ID,
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: u32::MAX - 1,
+        };
+        txn.set_snapshot(snap, vec![1], None)?;
+
+        let mut parent_version_id = NIL_VERSION_ID;
+        for expected in [u32::MAX, u32::MAX] {
+            let version_id = Uuid::new_v4();
+            txn.add_version(version_id, parent_version_id, vec![])?;
+            parent_version_id = version_id;
+            let client = txn.get_client()?.unwrap();
+            assert_eq!(client.snapshot.unwrap().versions_since, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_client_versions_since_out_of_range() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(NIL_VERSION_ID)?;
+        let snap = Snapshot {
+            version_id: NIL_VERSION_ID,
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 0,
+        };
+        txn.set_snapshot(snap, vec![1], None)?;
+        txn.commit()?;
+
+        // values outside the range of u32 are clamped
+        let con = storage.new_connection()?;
+        for (stored, expected) in [(-5i64, 0u32), (i64::MAX, u32::MAX)] {
+            con.execute(
+                "UPDATE clients SET versions_since_snapshot = ? WHERE client_id = ?",
+                params![stored, StoredUuid(client_id)],
+            )?;
+            let mut txn = storage.txn(client_id)?;
+            let client = txn.get_client()?.unwrap();
+            assert_eq!(client.snapshot.unwrap().versions_since, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_versions_before() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        let version_ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        let mut parent_version_id = NIL_VERSION_ID;
+        for version_id in &version_ids {
+            txn.add_version(*version_id, parent_version_id, b"abc".to_vec())?;
+            parent_version_id = *version_id;
+        }
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        // deleting before a nonexistent version does nothing
+        txn.delete_versions_before(Uuid::new_v4())?;
+        txn.delete_versions_before(version_ids[2])?;
+        assert_eq!(txn.get_version(version_ids[1])?, None);
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version(version_ids[0])?, None);
+        assert_eq!(txn.get_version(version_ids[1])?, None);
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, None);
+        for version_id in &version_ids[2..] {
+            assert!(txn.get_version(*version_id)?.is_some());
+        }
+        // the oldest remaining version can still be found from its (deleted) parent
+        assert_eq!(
+            txn.get_version_by_parent(version_ids[1])?
+                .map(|v| v.version_id),
+            Some(version_ids[2])
+        );
+        assert_eq!(txn.get_head_version_ids()?, vec![version_ids[3]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_maintenance() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_ids: Vec<Uuid> = (0..10).map(|_| Uuid::new_v4()).collect();
+        for client_id in &client_ids {
+            let mut txn = storage.txn(*client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            let mut parent_version_id = NIL_VERSION_ID;
+            for _ in 0..10 {
+                let version_id = Uuid::new_v4();
+                txn.add_version(version_id, parent_version_id, vec![b'x'; 4096])?;
+                parent_version_id = version_id;
+            }
+            txn.commit()?;
+        }
+        for client_id in &client_ids[..5] {
+            let mut txn = storage.txn(*client_id)?;
+            txn.delete_client()?;
+            txn.commit()?;
+        }
+
+        storage.maintenance()?;
+
+        // the remaining data is intact
+        assert_eq!(storage.list_clients()?.len(), 5);
+        let mut txn = storage.read_txn(client_ids[9])?;
+        assert!(txn.get_client()?.is_some());
+        assert_eq!(
+            txn.get_version_by_parent(NIL_VERSION_ID)?
+                .unwrap()
+                .history_segment,
+            vec![b'x'; 4096]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_client() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let other_client_id = Uuid::new_v4();
+        let new_client_id = Uuid::new_v4();
+        let version_id_1 = Uuid::new_v4();
+        let version_id_2 = Uuid::new_v4();
+        let snap = Snapshot {
+            version_id: version_id_1,
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 1,
+        };
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id_1, NIL_VERSION_ID, b"v1".to_vec())?;
+        txn.add_version(version_id_2, version_id_1, b"v2".to_vec())?;
+        txn.set_snapshot(snap.clone(), vec![1, 2, 3], None)?;
+        txn.set_app_metadata(b"meta".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(other_client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+        drop(txn);
+
+        // renaming onto an existing client fails
+        let mut txn = storage.txn(client_id)?;
+        assert!(!txn.rename_client(other_client_id)?);
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert!(txn.rename_client(new_client_id)?);
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(txn.get_version(version_id_1)?, None);
+        assert_eq!(txn.get_app_metadata()?, None);
+        drop(txn);
+
+        let mut txn = storage.txn(new_client_id)?;
+        assert_eq!(
+            txn.get_client()?,
+            Some(Client {
+                latest_version_id: version_id_2,
+                snapshot: Some(snap),
+                last_activity_at: None,
+                chain_hash: None,
+                acked_version_id: None,
+            })
+        );
+        assert_eq!(txn.get_snapshot_data(version_id_1)?, Some(vec![1, 2, 3]));
+        assert_eq!(txn.get_app_metadata()?, Some(b"meta".to_vec()));
+        assert_eq!(
+            txn.get_version_by_parent(NIL_VERSION_ID)?
+                .map(|v| v.history_segment),
+            Some(b"v1".to_vec())
+        );
+        assert_eq!(
+            txn.get_version(version_id_2)?.map(|v| v.parent_version_id),
+            Some(version_id_1)
+        );
+        assert_eq!(txn.get_head_version_ids()?, vec![version_id_2]);
+        drop(txn);
+
+        assert_eq!(storage.global_stats()?.clients, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_client() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let other_client_id = Uuid::new_v4();
+        let version_id_1 = Uuid::new_v4();
+        let version_id_2 = Uuid::new_v4();
+        let other_version_id = Uuid::new_v4();
+        let snap = Snapshot {
+            version_id: version_id_1,
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 1,
+        };
+
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id_1, NIL_VERSION_ID, b"v1".to_vec())?;
+        txn.add_version(version_id_2, version_id_1, b"v2".to_vec())?;
+        txn.set_snapshot(snap, vec![1, 2, 3], None)?;
+        txn.set_app_metadata(b"meta".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(other_client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(other_version_id, NIL_VERSION_ID, b"other".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_client()?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?, None);
+        assert_eq!(txn.get_version(version_id_1)?, None);
+        assert_eq!(txn.get_version(version_id_2)?, None);
+        assert_eq!(txn.get_version_by_parent(NIL_VERSION_ID)?, None);
+        assert_eq!(txn.get_app_metadata()?, None);
+        assert_eq!(txn.get_client_usage()?, ClientUsage::default());
+
+        // deleting a client which does not exist does nothing
+        txn.delete_client()?;
+        txn.commit()?;
+        drop(txn);
+
+        // the other client is unaffected
+        let mut txn = storage.txn(other_client_id)?;
+        assert_eq!(
+            txn.get_version(other_version_id)?
+                .map(|v| v.history_segment),
+            Some(b"other".to_vec())
+        );
+        drop(txn);
+        assert_eq!(storage.list_clients()?, vec![other_client_id]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_reader() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+
+        // a 10MB payload
+        let data: Vec<u8> = (0..10 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let version_id = Uuid::new_v4();
+        txn.add_version_from_reader(
+            version_id,
+            NIL_VERSION_ID,
+            data.len() as u64,
+            &mut data.as_slice(),
+        )?;
+        let snap = Snapshot {
+            version_id,
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 0,
+        };
+        assert!(txn.set_snapshot_from_reader(
+            snap,
+            data.len() as u64,
+            &mut data.as_slice(),
+            None
+        )?);
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().latest_version_id, version_id);
+        assert_eq!(txn.get_version(version_id)?.unwrap().history_segment, data);
+        assert_eq!(txn.get_snapshot_data(version_id)?, Some(data));
+
+        // a reader with fewer bytes than promised is an error
+        assert!(txn
+            .add_version_from_reader(Uuid::new_v4(), version_id, 10, &mut &b"abc"[..])
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_external_segments() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let segment_dir = tmp_dir.path().join("history-segments");
+        let client_id = Uuid::new_v4();
+
+        // a version stored in the database before external segments were enabled
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        let v1 = Uuid::new_v4();
+        txn.add_version(v1, NIL_VERSION_ID, b"inline".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+        assert!(!segment_dir.exists());
+
+        let storage = SqliteStorage::with_external_segments(tmp_dir.path())?;
+        let mut txn = storage.txn(client_id)?;
+        let (v2, v3) = (Uuid::new_v4(), Uuid::new_v4());
+        txn.add_version(v2, v1, b"external".to_vec())?;
+        txn.add_version_from_reader(v3, v2, 6, &mut &b"reader"[..])?;
+        txn.commit()?;
+        drop(txn);
+
+        // the segments are stored in files, and not in the database
+        assert_eq!(
+            std::fs::read(segment_dir.join(v2.to_string()))?,
+            b"external"
+        );
+        assert_eq!(std::fs::read(segment_dir.join(v3.to_string()))?, b"reader");
+        let con = storage.new_connection()?;
+        let inline: Option<Vec<u8>> = con.query_row(
+            "SELECT history_segment FROM versions WHERE version_id = ?",
+            [StoredUuid(v2)],
+            |r| r.get(0),
+        )?;
+        assert_eq!(inline, None);
+        drop(con);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version(v1)?.unwrap().history_segment, b"inline");
+        assert_eq!(txn.get_version(v2)?.unwrap().history_segment, b"external");
+        assert_eq!(
+            txn.get_version_by_parent(v2)?.unwrap(),
+            Version {
+                version_id: v3,
+                parent_version_id: v2,
+                history_segment: b"reader".to_vec(),
+            }
+        );
+        let (_, latest) = txn.get_client_with_latest_version()?.unwrap();
+        assert_eq!(latest.unwrap().history_segment, b"reader");
+        assert_eq!(
+            txn.get_client_usage()?,
+            ClientUsage {
+                version_count: 3,
+                total_history_bytes: 20,
+                snapshot_bytes: 0,
+            }
+        );
+        drop(txn);
+
+        // the files remain readable when external segments are disabled again
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version(v2)?.unwrap().history_segment, b"external");
+        Ok(())
+    }
+
+    #[test]
+    fn test_external_segments_uncommitted() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::with_external_segments(tmp_dir.path())?;
+        let segment_file = |version_id: Uuid| {
+            tmp_dir
+                .path()
+                .join("history-segments")
+                .join(version_id.to_string())
+        };
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        let v1 = Uuid::new_v4();
+        txn.add_version(v1, NIL_VERSION_ID, b"abc".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        // a version added in a transaction which is not committed leaves no file
+        let mut txn = storage.txn(client_id)?;
+        let v2 = Uuid::new_v4();
+        txn.add_version(v2, v1, b"def".to_vec())?;
+        assert!(segment_file(v2).exists());
+        drop(txn);
+        assert!(!segment_file(v2).exists());
+
+        // nor does a failed read from a reader
+        let mut txn = storage.txn(client_id)?;
+        let v3 = Uuid::new_v4();
+        assert!(txn
+            .add_version_from_reader(v3, v1, 10, &mut &b"abc"[..])
+            .is_err());
+        drop(txn);
+        assert!(!segment_file(v3).exists());
+
+        // a deletion which is not committed leaves the file in place
+        let mut txn = storage.txn(client_id)?;
+        txn.add_version(v2, v1, b"def".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_versions_before(v2)?;
+        drop(txn);
+        assert!(segment_file(v1).exists());
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version(v1)?.unwrap().history_segment, b"abc");
+        Ok(())
+    }
+
+    #[test]
+    fn test_external_segments_delete_versions_before() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::with_external_segments(tmp_dir.path())?;
+        let segment_dir = tmp_dir.path().join("history-segments");
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        let version_ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        let mut parent_version_id = NIL_VERSION_ID;
+        for version_id in &version_ids {
+            txn.add_version(*version_id, parent_version_id, b"abc".to_vec())?;
+            parent_version_id = *version_id;
+        }
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_versions_before(version_ids[2])?;
+        // the files are only removed once the deletion is committed
+        assert!(segment_dir.join(version_ids[0].to_string()).exists());
+        txn.commit()?;
+        drop(txn);
+
+        for version_id in &version_ids[..2] {
+            assert!(!segment_dir.join(version_id.to_string()).exists());
+        }
+        for version_id in &version_ids[2..] {
+            assert!(segment_dir.join(version_id.to_string()).exists());
+        }
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_version(version_ids[0])?, None);
+        assert_eq!(
+            txn.get_version(version_ids[2])?.unwrap().history_segment,
+            b"abc"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_external_segments_delete_client() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::with_external_segments(tmp_dir.path())?;
+        let segment_dir = tmp_dir.path().join("history-segments");
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(version_id, NIL_VERSION_ID, b"abc".to_vec())?;
+        txn.commit()?;
+        drop(txn);
+
+        let mut txn = storage.txn(client_id)?;
+        txn.delete_client()?;
+        // the file is only removed once the deletion is committed
+        assert!(segment_dir.join(version_id.to_string()).exists());
+        txn.commit()?;
+        drop(txn);
+
+        assert!(!segment_dir.join(version_id.to_string()).exists());
+        Ok(())
+    }
+
     #[test]
     fn test_snapshots() -> anyhow::Result<()> {
         let tmp_dir = TempDir::new()?;
@@ -417,20 +2605,20 @@ mod test {
             timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
             versions_since: 3,
         };
-        txn.set_snapshot(snap.clone(), vec![9, 8, 9])?;
+        assert!(txn.set_snapshot(snap.clone(), vec![9, 8, 9], None)?);
 
         assert_eq!(
             txn.get_snapshot_data(snap.version_id)?.unwrap(),
             vec![9, 8, 9]
         );
-        assert_eq!(txn.get_client()?.unwrap().snapshot, Some(snap));
+        assert_eq!(txn.get_client()?.unwrap().snapshot, Some(snap.clone()));
 
         let snap2 = Snapshot {
             version_id: Uuid::new_v4(),
             timestamp: "2014-11-28T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
             versions_since: 10,
         };
-        txn.set_snapshot(snap2.clone(), vec![0, 2, 4, 6])?;
+        assert!(txn.set_snapshot(snap2.clone(), vec![0, 2, 4, 6], Some(snap.version_id))?);
 
         assert_eq!(
             txn.get_snapshot_data(snap2.version_id)?.unwrap(),
@@ -443,4 +2631,171 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_concurrent_writes_wait() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+
+        // each thread holds the write lock for a while, so the other must wait for it
+        std::thread::scope(|scope| {
+            let writers: Vec<_> = (0..2)
+                .map(|_| {
+                    scope.spawn(|| -> anyhow::Result<()> {
+                        let mut txn = storage.txn(Uuid::new_v4())?;
+                        txn.new_client(NIL_VERSION_ID)?;
+                        std::thread::sleep(Duration::from_millis(100));
+                        txn.commit()
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.join().unwrap()?;
+            }
+            anyhow::Ok(())
+        })?;
+        assert_eq!(storage.global_stats()?.clients, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_busy_timeout_zero() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?.with_busy_timeout(Duration::ZERO);
+        let client_id = Uuid::new_v4();
+
+        // without a timeout, a second write fails at once
+        let _txn = storage.txn(client_id)?;
+        assert!(storage.txn(client_id).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_millisecond_timestamps() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let mut storage = SqliteStorage::new(tmp_dir.path())?;
+        storage.set_millisecond_timestamps(true);
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(Uuid::new_v4())?;
+        let snap = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: "2013-10-08T12:00:09.123Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 3,
+        };
+        assert!(txn.set_snapshot(snap.clone(), vec![9, 8, 9], None)?);
+        assert_eq!(txn.get_client()?.unwrap().snapshot, Some(snap.clone()));
+        txn.commit()?;
+        drop(txn);
+
+        // without millisecond timestamps, the precise timestamp is still read, but a new one is
+        // stored only to the second
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(txn.get_client()?.unwrap().snapshot, Some(snap.clone()));
+        let snap2 = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: "2014-11-28T12:00:09.456Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 10,
+        };
+        assert!(txn.set_snapshot(snap2.clone(), vec![0, 2], Some(snap.version_id))?);
+        assert_eq!(
+            txn.get_client()?.unwrap().snapshot.unwrap().timestamp,
+            "2014-11-28T12:00:09Z".parse::<DateTime<Utc>>().unwrap()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_legacy_timestamp() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let mut storage = SqliteStorage::new(tmp_dir.path())?;
+        storage.set_millisecond_timestamps(true);
+        let client_id = Uuid::new_v4();
+        let version_id = Uuid::new_v4();
+
+        // a snapshot stored by an older server has only a timestamp in seconds
+        let con = Connection::open(tmp_dir.path().join("taskchampion-sync-server.sqlite3"))?;
+        con.execute(
+            "INSERT INTO clients (client_id, latest_version_id, snapshot_version_id,
+                versions_since_snapshot, snapshot_timestamp, snapshot)
+             VALUES (?, ?, ?, 3, 1381233609, x'0102')",
+            params![
+                StoredUuid(client_id),
+                StoredUuid(version_id),
+                StoredUuid(version_id)
+            ],
+        )?;
+        drop(con);
+
+        let mut txn = storage.txn(client_id)?;
+        assert_eq!(
+            txn.get_client()?.unwrap().snapshot,
+            Some(Snapshot {
+                version_id,
+                timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+                versions_since: 3,
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_size() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(Uuid::new_v4())?;
+        let snap = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 3,
+        };
+        assert!(txn.set_snapshot(snap.clone(), vec![7; 1000], None)?);
+
+        assert_eq!(txn.get_snapshot_size(snap.version_id)?, Some(1000));
+
+        // check that mismatched version is detected
+        assert!(txn.get_snapshot_size(Uuid::new_v4()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_snapshot_precondition() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let storage = SqliteStorage::new(tmp_dir.path())?;
+        let client_id = Uuid::new_v4();
+        let mut txn = storage.txn(client_id)?;
+
+        txn.new_client(Uuid::new_v4())?;
+
+        let snap = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: "2013-10-08T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 3,
+        };
+        // there is no existing snapshot, so this precondition fails
+        assert!(!txn.set_snapshot(snap.clone(), vec![1], Some(Uuid::new_v4()))?);
+        assert_eq!(txn.get_client()?.unwrap().snapshot, None);
+
+        assert!(txn.set_snapshot(snap.clone(), vec![1], None)?);
+
+        let snap2 = Snapshot {
+            version_id: Uuid::new_v4(),
+            timestamp: "2014-11-28T12:00:09Z".parse::<DateTime<Utc>>().unwrap(),
+            versions_since: 0,
+        };
+        // the existing snapshot is not for the expected version, so this precondition fails
+        assert!(!txn.set_snapshot(snap2.clone(), vec![2], None)?);
+        assert!(!txn.set_snapshot(snap2.clone(), vec![2], Some(Uuid::new_v4()))?);
+        assert_eq!(txn.get_client()?.unwrap().snapshot, Some(snap.clone()));
+        assert_eq!(txn.get_snapshot_data(snap.version_id)?.unwrap(), vec![1]);
+
+        Ok(())
+    }
 }
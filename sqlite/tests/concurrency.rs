@@ -1,5 +1,7 @@
+use chrono::Utc;
+use std::sync::Barrier;
 use std::thread;
-use taskchampion_sync_server_core::{Storage, NIL_VERSION_ID};
+use taskchampion_sync_server_core::{Server, Snapshot, Storage, NIL_VERSION_ID};
 use taskchampion_sync_server_storage_sqlite::SqliteStorage;
 use tempfile::TempDir;
 use uuid::Uuid;
@@ -65,3 +67,111 @@ fn add_version_concurrency() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Test that racing calls to `add_snapshot` never replace a newer snapshot with an older one.
+#[test]
+fn add_snapshot_concurrency() -> anyhow::Result<()> {
+    let tmp_dir = TempDir::new()?;
+
+    const N: usize = 20;
+
+    for _ in 0..N {
+        let client_id = Uuid::new_v4();
+        let older_version_id = Uuid::new_v4();
+        let newer_version_id = Uuid::new_v4();
+
+        {
+            let con = SqliteStorage::new(tmp_dir.path())?;
+            let mut txn = con.txn(client_id)?;
+            txn.new_client(NIL_VERSION_ID)?;
+            txn.add_version(older_version_id, NIL_VERSION_ID, b"data".to_vec())?;
+            txn.add_version(newer_version_id, older_version_id, b"data".to_vec())?;
+            txn.commit()?;
+        }
+
+        let barrier = Barrier::new(2);
+        let add_snapshot = |version_id: Uuid| {
+            let server = Server::new(Default::default(), SqliteStorage::new(tmp_dir.path())?);
+            barrier.wait();
+            server.add_snapshot(client_id, version_id, version_id.as_bytes().to_vec())?;
+            Ok::<_, anyhow::Error>(())
+        };
+
+        thread::scope(|s| {
+            let older = s.spawn(|| add_snapshot(older_version_id));
+            let newer = s.spawn(|| add_snapshot(newer_version_id));
+            older.join().unwrap()?;
+            newer.join().unwrap()?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+
+        // Regardless of the order in which the snapshots were added, the newer snapshot must
+        // remain.
+        let con = SqliteStorage::new(tmp_dir.path())?;
+        let mut txn = con.txn(client_id)?;
+        let client = txn.get_client()?.unwrap();
+        assert_eq!(client.snapshot.unwrap().version_id, newer_version_id);
+        assert_eq!(
+            txn.get_snapshot_data(newer_version_id)?.unwrap(),
+            newer_version_id.as_bytes().to_vec()
+        );
+    }
+
+    Ok(())
+}
+
+/// Test that a snapshot written based on a stale read of the client does not replace a snapshot
+/// added in the interim.
+#[test]
+fn set_snapshot_stale_precondition() -> anyhow::Result<()> {
+    let tmp_dir = TempDir::new()?;
+    let client_id = Uuid::new_v4();
+    let older_version_id = Uuid::new_v4();
+    let newer_version_id = Uuid::new_v4();
+    let con = SqliteStorage::new(tmp_dir.path())?;
+
+    {
+        let mut txn = con.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.add_version(older_version_id, NIL_VERSION_ID, b"data".to_vec())?;
+        txn.add_version(newer_version_id, older_version_id, b"data".to_vec())?;
+        txn.commit()?;
+    }
+
+    // Both writers observe that there is no snapshot yet.
+    let observed = {
+        let mut txn = con.txn(client_id)?;
+        txn.get_client()?.unwrap().snapshot.map(|s| s.version_id)
+    };
+    assert_eq!(observed, None);
+
+    let snapshot = |version_id| Snapshot {
+        version_id,
+        timestamp: Utc::now(),
+        versions_since: 0,
+    };
+
+    // The newer snapshot is written first..
+    {
+        let mut txn = con.txn(client_id)?;
+        assert!(txn.set_snapshot(snapshot(newer_version_id), b"newer".to_vec(), observed)?);
+        txn.commit()?;
+    }
+
+    // ..and the older snapshot, based on the same observation, is not applied.
+    {
+        let mut txn = con.txn(client_id)?;
+        assert!(!txn.set_snapshot(snapshot(older_version_id), b"older".to_vec(), observed)?);
+        txn.commit()?;
+    }
+
+    let mut txn = con.txn(client_id)?;
+    let client = txn.get_client()?.unwrap();
+    assert_eq!(client.snapshot.unwrap().version_id, newer_version_id);
+    assert_eq!(
+        txn.get_snapshot_data(newer_version_id)?.unwrap(),
+        b"newer".to_vec()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use taskchampion_sync_server_core::{AddVersionResult, Server, Storage, NIL_VERSION_ID};
+use taskchampion_sync_server_storage_sqlite::SqliteStorage;
+use tempfile::TempDir;
+use uuid::Uuid;
+
+/// Test that a version added in a caller-supplied transaction is rolled back, along with the
+/// caller's own changes, when that transaction is not committed.
+#[test]
+fn add_version_in_txn_rollback() -> anyhow::Result<()> {
+    let tmp_dir = TempDir::new()?;
+    let client_id = Uuid::new_v4();
+
+    {
+        let con = SqliteStorage::new(tmp_dir.path())?;
+        let mut txn = con.txn(client_id)?;
+        txn.new_client(NIL_VERSION_ID)?;
+        txn.commit()?;
+    }
+
+    let server = Server::new(Default::default(), SqliteStorage::new(tmp_dir.path())?);
+    {
+        let mut txn = server.txn(client_id)?;
+        let (result, _) =
+            server.add_version_in_txn(txn.as_mut(), NIL_VERSION_ID, b"v1".to_vec())?;
+        assert!(matches!(result, AddVersionResult::Ok(_)));
+        txn.set_app_metadata(b"app".to_vec())?;
+        // the caller abandons the transaction, without committing
+    }
+
+    let mut txn = server.txn(client_id)?;
+    let client = txn.get_client()?.unwrap();
+    assert_eq!(client.latest_version_id, NIL_VERSION_ID);
+    assert_eq!(txn.get_app_metadata()?, None);
+    Ok(())
+}